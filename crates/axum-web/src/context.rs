@@ -15,6 +15,7 @@ pub struct ReqContext {
     pub rid: String,   // from x-request-id header
     pub user: xid::Id, // from x-auth-user header
     pub rating: i8,    // from x-auth-user-rating header, 0 if not present
+    pub lang: String,  // from accept-language header, the primary tag of the preferred language
     pub unix_ms: u64,
     pub start: Instant,
     pub kv: RwLock<BTreeMap<String, Value>>,
@@ -26,6 +27,7 @@ impl ReqContext {
             rid,
             user,
             rating,
+            lang: "".to_string(),
             unix_ms: unix_ms(),
             start: Instant::now(),
             kv: RwLock::new(BTreeMap::new()),
@@ -61,9 +63,13 @@ pub async fn middleware<B>(mut req: Request<B>, next: Next<B>) -> Response {
     let rating = extract_header(req.headers(), "x-auth-user-rating", || "0".to_string());
     let rating = i8::from_str(&rating).unwrap_or(0);
 
+    let lang = extract_header(req.headers(), "accept-language", || "".to_string());
+
     let uid = xid::Id::from_str(&user).unwrap_or_default();
 
-    let ctx = Arc::new(ReqContext::new(rid.clone(), uid, rating));
+    let mut ctx = ReqContext::new(rid.clone(), uid, rating);
+    ctx.lang = parse_accept_language(&lang);
+    let ctx = Arc::new(ctx);
     req.extensions_mut().insert(ctx.clone());
 
     let res = next.run(req).await;
@@ -104,3 +110,47 @@ pub fn extract_header(hm: &HeaderMap, key: &str, or: impl FnOnce() -> String) ->
         },
     }
 }
+
+// the lowercase primary tag of the language with the highest quality, e.g. "zh" for
+// "zh-CN,zh;q=0.9,en;q=0.8", empty if there is none or only "*".
+pub fn parse_accept_language(value: &str) -> String {
+    let mut lang = "";
+    let mut quality = 0f32;
+    for item in value.split(',') {
+        let mut parts = item.split(';');
+        let tag = parts.next().unwrap_or_default().trim();
+        if tag.is_empty() || tag == "*" {
+            continue;
+        }
+        let q = parts
+            .find_map(|p| p.trim().strip_prefix("q="))
+            .map_or(Some(1f32), |q| f32::from_str(q.trim()).ok())
+            .unwrap_or(0f32);
+        // the first one wins with the same quality.
+        if q > quality {
+            lang = tag;
+            quality = q;
+        }
+    }
+    lang.split(['-', '_'])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_accept_language_works() {
+        assert_eq!(parse_accept_language(""), "");
+        assert_eq!(parse_accept_language("*"), "");
+        assert_eq!(parse_accept_language("en"), "en");
+        assert_eq!(parse_accept_language("zh-CN,zh;q=0.9,en;q=0.8"), "zh");
+        assert_eq!(parse_accept_language("en-US;q=0.5, ZH-Hans;q=0.8"), "zh");
+        assert_eq!(parse_accept_language("fr;q=0, en;q=0.1"), "en");
+        assert_eq!(parse_accept_language("de;q=abc, ja"), "ja");
+        assert_eq!(parse_accept_language("zh_TW, en"), "zh");
+    }
+}
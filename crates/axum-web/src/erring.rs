@@ -1,5 +1,5 @@
 use axum::{
-    http::StatusCode,
+    http::{header, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
@@ -64,6 +64,54 @@ impl HTTPError {
             data: None,
         }
     }
+
+    /// A client-facing error with the message of the error code in the language,
+    /// the "{0}", "{1}"... of the message are replaced by the args.
+    /// The error code is in the data, the clients should check it rather than the message.
+    pub fn localized(code: u16, error_code: &str, lang: &str, args: &[&dyn fmt::Display]) -> Self {
+        let mut message = localize(error_code, lang).to_string();
+        for (i, arg) in args.iter().enumerate() {
+            message = message.replace(&format!("{{{}}}", i), &arg.to_string());
+        }
+        HTTPError {
+            code,
+            message,
+            data: Some(serde_json::json!({ "error_code": error_code })),
+        }
+    }
+}
+
+// the languages of the messages, the first one is the fallback.
+static LANGUAGES: [&str; 2] = ["en", "zh"];
+
+// the messages of the client-facing errors by error code, in the order of LANGUAGES.
+static MESSAGES: [(&str, [&str; 2]); 4] = [
+    ("invalid_language", ["Invalid language", "无效的语言"]),
+    (
+        "invalid_script",
+        [
+            "Invalid script '{0}' for '{1}'",
+            "文字 '{0}' 不适用于语言 '{1}'",
+        ],
+    ),
+    ("empty_content", ["Empty content to translate", "内容为空"]),
+    (
+        "untranslatable",
+        [
+            "can not translate from '{0}' to '{1}'",
+            "无法从 '{0}' 翻译为 '{1}'",
+        ],
+    ),
+];
+
+/// The message of the error code in the language, a primary language tag such as "zh",
+/// the English message if the language is not supported, the code itself if it is unknown.
+pub fn localize<'a>(code: &'a str, lang: &str) -> &'a str {
+    let i = LANGUAGES.iter().position(|l| *l == lang).unwrap_or(0);
+    MESSAGES
+        .iter()
+        .find(|(c, _)| *c == code)
+        .map_or(code, |(_, messages)| messages[i])
 }
 
 impl fmt::Display for HTTPError {
@@ -86,8 +134,21 @@ impl IntoResponse for HTTPError {
             StatusCode::from_u16(self.code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
         };
 
+        // the "retry_after" seconds of the data is the Retry-After hint of 429 and 503.
+        let retry_after = match status {
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => self
+                .data
+                .as_ref()
+                .and_then(|d| d.get("retry_after"))
+                .and_then(|v| v.as_u64()),
+            _ => None,
+        };
+
         let body = Json(ErrorResponse { error: self });
-        (status, body).into_response()
+        match retry_after {
+            Some(secs) => (status, [(header::RETRY_AFTER, secs.to_string())], body).into_response(),
+            None => (status, body).into_response(),
+        }
     }
 }
 
@@ -121,3 +182,50 @@ pub fn valid_user(uid: xid::Id) -> Result<(), HTTPError> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn localize_works() {
+        assert_eq!(localize("invalid_language", "en"), "Invalid language");
+        assert_eq!(localize("invalid_language", "zh"), "无效的语言");
+        assert_eq!(localize("invalid_language", "fr"), "Invalid language");
+        assert_eq!(localize("invalid_language", ""), "Invalid language");
+        assert_eq!(localize("unknown_code", "zh"), "unknown_code");
+
+        for (code, messages) in MESSAGES.iter() {
+            assert_eq!(localize(code, "en"), messages[0]);
+            assert!(messages.iter().all(|m| !m.is_empty()), "{}", code);
+        }
+
+        let err = HTTPError::localized(400, "untranslatable", "zh", &[&"eng", &"eng"]);
+        assert_eq!(err.code, 400);
+        assert_eq!(err.message, "无法从 'eng' 翻译为 'eng'");
+        assert_eq!(
+            err.data,
+            Some(serde_json::json!({ "error_code": "untranslatable" }))
+        );
+
+        let err = HTTPError::localized(400, "invalid_script", "en", &[&"Hant", &"jpn"]);
+        assert_eq!(err.message, "Invalid script 'Hant' for 'jpn'");
+    }
+
+    #[test]
+    fn retry_after_works() {
+        let mut err = HTTPError::new(429, "too many jobs".to_string());
+        err.data = Some(serde_json::json!({ "retry_after": 30 }));
+        let res = err.into_response();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()[header::RETRY_AFTER], "30");
+
+        let mut err = HTTPError::new(400, "bad request".to_string());
+        err.data = Some(serde_json::json!({ "retry_after": 30 }));
+        let res = err.into_response();
+        assert!(res.headers().get(header::RETRY_AFTER).is_none());
+
+        let res = HTTPError::new(503, "shutting down".to_string()).into_response();
+        assert!(res.headers().get(header::RETRY_AFTER).is_none());
+    }
+}
@@ -64,9 +64,13 @@ impl ToCqlVal for i32 {
     }
 }
 
+// a COUNTER column is read as i64.
 impl FromCqlVal for i64 {
     fn from_cql(cql_val: &CqlValue) -> Result<Self, FromCqlValError> {
-        cql_to_rust::FromCqlVal::from_cql(cql_val.to_owned())
+        match cql_val {
+            CqlValue::Counter(v) => Ok(v.0),
+            _ => cql_to_rust::FromCqlVal::from_cql(cql_val.to_owned()),
+        }
     }
 }
 
@@ -165,6 +169,26 @@ impl ToCqlVal for CqlValue {
     }
 }
 
+// a nullable column, the empty value is None. A null column is not filled.
+impl<T: FromCqlVal> FromCqlVal for Option<T> {
+    fn from_cql(cql_val: &CqlValue) -> Result<Self, FromCqlValError> {
+        match cql_val {
+            CqlValue::Empty => Ok(None),
+            _ => T::from_cql(cql_val).map(Some),
+        }
+    }
+}
+
+// None is written as the empty value, CqlValue has no null.
+impl<T: ToCqlVal> ToCqlVal for Option<T> {
+    fn to_cql(&self) -> CqlValue {
+        match self {
+            Some(v) => v.to_cql(),
+            None => CqlValue::Empty,
+        }
+    }
+}
+
 impl<T: FromCqlVal> FromCqlVal for Vec<T> {
     fn from_cql(cql_val: &CqlValue) -> Result<Self, FromCqlValError> {
         match cql_val {
@@ -251,4 +275,23 @@ mod tests {
             CqlValue::Text("hello".to_string())
         );
     }
+
+    #[test]
+    fn from_cql_val_works() {
+        assert_eq!(i64::from_cql(&CqlValue::BigInt(42)).unwrap(), 42);
+        assert_eq!(
+            i64::from_cql(&CqlValue::Counter(scylla::frame::value::Counter(42))).unwrap(),
+            42
+        );
+        assert!(i64::from_cql(&CqlValue::Int(42)).is_err());
+
+        assert_eq!(
+            Option::<i64>::from_cql(&CqlValue::BigInt(42)).unwrap(),
+            Some(42)
+        );
+        assert_eq!(Option::<i64>::from_cql(&CqlValue::Empty).unwrap(), None);
+        assert!(Option::<i64>::from_cql(&CqlValue::Int(42)).is_err());
+        assert_eq!(Some(42i64).to_cql(), CqlValue::BigInt(42));
+        assert_eq!(None::<i64>.to_cql(), CqlValue::Empty);
+    }
 }
@@ -0,0 +1,330 @@
+use axum::{extract::State, Extension};
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Instant};
+use validator::Validate;
+
+use axum_web::context::{unix_ms, ReqContext};
+use axum_web::erring::{HTTPError, SuccessResponse};
+use axum_web::object::PackObject;
+use scylla_orm::ColumnsMap;
+
+use crate::api::{validate_version, AppState, Dedup, JobKind};
+use crate::db;
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct DeleteVersionInput {
+    pub gid: PackObject<xid::Id>, // group id, content belong to
+    pub cid: PackObject<xid::Id>, // creation id
+    #[validate(custom = "validate_version")]
+    pub version: u16,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct DeleteVersionOutput {
+    pub gid: PackObject<xid::Id>,
+    pub cid: PackObject<xid::Id>,
+    pub version: u16,
+    pub progress: i8,
+    pub updated_at: i64,
+    pub translating: u32,
+    pub summarizing: u32,
+    pub embedding: u32,
+    pub qdrant: u32,
+    pub qdrant_public: u32,
+    pub error: String,
+}
+
+impl DeleteVersionOutput {
+    fn from<T>(to: &PackObject<T>, doc: db::Deleting) -> Self {
+        Self {
+            gid: to.with(doc.gid),
+            cid: to.with(doc.cid),
+            version: doc.version as u16,
+            progress: doc.progress,
+            updated_at: doc.updated_at,
+            translating: doc.translating as u32,
+            summarizing: doc.summarizing as u32,
+            embedding: doc.embedding as u32,
+            qdrant: doc.qdrant as u32,
+            qdrant_public: doc.qdrant_public as u32,
+            error: doc.error,
+        }
+    }
+}
+
+pub async fn get_delete_version(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<DeleteVersionInput>,
+) -> Result<PackObject<SuccessResponse<DeleteVersionOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+
+    let gid = *input.gid;
+    let cid = *input.cid;
+
+    ctx.set_kvs(vec![
+        ("action", "get_delete_version".into()),
+        ("gid", gid.to_string().into()),
+        ("cid", cid.to_string().into()),
+        ("version", input.version.into()),
+    ])
+    .await;
+
+    let mut doc = db::Deleting::with_pk(gid, cid, input.version as i16);
+    doc.get_one(&app.scylla, vec![]).await?;
+    Ok(to.with(SuccessResponse::new(DeleteVersionOutput::from(&to, doc))))
+}
+
+// delete all translating, summarizing and embedding data of the version in the background.
+// it is idempotent, calling it again will rerun the deleting unless a job is running.
+pub async fn delete_version(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<DeleteVersionInput>,
+) -> Result<PackObject<SuccessResponse<DeleteVersionOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+    app.jobs.check_open()?;
+
+    let gid = *input.gid;
+    let cid = *input.cid;
+    let version = input.version as i16;
+
+    ctx.set_kvs(vec![
+        ("action", "delete_version".into()),
+        ("gid", gid.to_string().into()),
+        ("cid", cid.to_string().into()),
+        ("version", input.version.into()),
+    ])
+    .await;
+
+    let now = unix_ms() as i64;
+    let mut doc = db::Deleting::with_pk(gid, cid, version);
+    if doc.get_one(&app.scylla, vec![]).await.is_ok() && is_running(&doc, now, app.dedup.in_flight)
+    {
+        ctx.set("running", true.into()).await;
+        return Ok(to.with(SuccessResponse::new(DeleteVersionOutput::from(&to, doc))));
+    }
+
+    let mut doc = db::Deleting::with_pk(gid, cid, version);
+    doc.updated_at = now;
+    let mut cols = ColumnsMap::with_capacity(8);
+    cols.set_as("progress", &0i8);
+    cols.set_as("updated_at", &now);
+    cols.set_as("translating", &0i32);
+    cols.set_as("summarizing", &0i32);
+    cols.set_as("embedding", &0i32);
+    cols.set_as("qdrant", &0i32);
+    cols.set_as("qdrant_public", &0i32);
+    cols.set_as("error", &"".to_string());
+    doc.upsert_fields(&app.scylla, cols).await?;
+
+    let user = app.job_user(ctx.user);
+    let jobs = app.jobs.clone();
+    jobs.spawn(
+        JobKind::DeleteVersion,
+        delete_version_job(app, ctx.rid.clone(), user, gid, cid, version),
+    );
+
+    Ok(to.with(SuccessResponse::new(DeleteVersionOutput::from(&to, doc))))
+}
+
+// a deleting job is not started again while another one is running: not finished, without
+// error and updated within the in-flight window. a finished or failed one is rerun at once.
+fn is_running(doc: &db::Deleting, now: i64, in_flight: u64) -> bool {
+    let dedup = Dedup::check(
+        now - doc.updated_at,
+        false,
+        doc.progress < 100 && doc.error.is_empty(),
+        0,
+        in_flight,
+        false,
+    );
+    dedup == Dedup::InFlight
+}
+
+async fn save_progress(app: &AppState, doc: &mut db::Deleting, progress: i8, errors: &[String]) {
+    doc.progress = progress;
+    doc.updated_at = unix_ms() as i64;
+    let mut cols = ColumnsMap::with_capacity(8);
+    cols.set_as("progress", &doc.progress);
+    cols.set_as("updated_at", &doc.updated_at);
+    cols.set_as("translating", &doc.translating);
+    cols.set_as("summarizing", &doc.summarizing);
+    cols.set_as("embedding", &doc.embedding);
+    cols.set_as("qdrant", &doc.qdrant);
+    cols.set_as("qdrant_public", &doc.qdrant_public);
+    cols.set_as("error", &errors.join("; "));
+    let _ = doc.upsert_fields(&app.scylla, cols).await;
+}
+
+async fn delete_version_job(
+    app: Arc<AppState>,
+    rid: String,
+    user: xid::Id,
+    gid: xid::Id,
+    cid: xid::Id,
+    version: i16,
+) {
+    let start = Instant::now();
+
+    log::info!(target: "deleting",
+        action = "start_job",
+        rid = rid,
+        user = user.to_string(),
+        gid = gid.to_string(),
+        cid = cid.to_string(),
+        version = version;
+        "",
+    );
+
+    let mut doc = db::Deleting::with_pk(gid, cid, version);
+    let mut errors: Vec<String> = Vec::new();
+
+    match db::Translating::list_by_cid(
+        &app.scylla,
+        gid,
+        cid,
+        Some(version),
+        vec!["version".to_string()],
+    )
+    .await
+    {
+        Ok(docs) => {
+            for mut d in docs {
+                match d.delete(&app.scylla).await {
+                    Ok(_) => doc.translating += 1,
+                    Err(err) => errors.push(format!("translating: {}", err)),
+                }
+            }
+        }
+        Err(err) => errors.push(format!("translating: {}", err)),
+    }
+    save_progress(&app, &mut doc, 20, &errors).await;
+
+    match db::Summarizing::list_by_cid(
+        &app.scylla,
+        gid,
+        cid,
+        Some(version),
+        vec!["version".to_string()],
+    )
+    .await
+    {
+        Ok(docs) => {
+            for mut d in docs {
+                match d.delete(&app.scylla).await {
+                    Ok(_) => doc.summarizing += 1,
+                    Err(err) => errors.push(format!("summarizing: {}", err)),
+                }
+            }
+        }
+        Err(err) => errors.push(format!("summarizing: {}", err)),
+    }
+    save_progress(&app, &mut doc, 40, &errors).await;
+
+    match db::Embedding::list_all_by_cid(
+        &app.scylla,
+        cid,
+        gid,
+        None,
+        Some(version),
+        vec!["uuid".to_string()],
+    )
+    .await
+    {
+        Ok(docs) => {
+            let points: Vec<uuid::Uuid> = docs.into_iter().map(|d| d.uuid).collect();
+            let mut qdrant_ok = true;
+            match app.qdrant.delete_points(points.clone()).await {
+                Ok(()) => doc.qdrant = points.len() as i32,
+                Err(err) => {
+                    qdrant_ok = false;
+                    errors.push(format!("qdrant: {}", err));
+                }
+            }
+            save_progress(&app, &mut doc, 60, &errors).await;
+
+            match app.qdrant.delete_public_points(points.clone()).await {
+                Ok(()) => doc.qdrant_public = doc.qdrant,
+                Err(err) => {
+                    qdrant_ok = false;
+                    errors.push(format!("qdrant_public: {}", err));
+                }
+            }
+            save_progress(&app, &mut doc, 80, &errors).await;
+
+            // keep the embedding rows to retry with if failed to delete points.
+            if qdrant_ok {
+                match db::Embedding::delete_by_uuids(&app.scylla, &points).await {
+                    Ok(deleted) => doc.embedding = deleted as i32,
+                    Err(err) => errors.push(format!("embedding: {}", err)),
+                }
+            }
+        }
+        Err(err) => errors.push(format!("embedding: {}", err)),
+    }
+    save_progress(&app, &mut doc, 100, &errors).await;
+
+    if errors.is_empty() {
+        log::info!(target: "deleting",
+            action = "finish_job",
+            rid = rid,
+            cid = cid.to_string(),
+            version = version,
+            elapsed = start.elapsed().as_millis() as u64,
+            translating = doc.translating,
+            summarizing = doc.summarizing,
+            embedding = doc.embedding,
+            qdrant = doc.qdrant,
+            qdrant_public = doc.qdrant_public;
+            "",
+        );
+    } else {
+        log::error!(target: "deleting",
+            action = "finish_job",
+            rid = rid,
+            cid = cid.to_string(),
+            version = version,
+            elapsed = start.elapsed().as_millis() as u64,
+            translating = doc.translating,
+            summarizing = doc.summarizing,
+            embedding = doc.embedding,
+            qdrant = doc.qdrant,
+            qdrant_public = doc.qdrant_public;
+            "{}", errors.join("; "),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_running_works() {
+        let now = unix_ms() as i64;
+        let in_flight = 3600;
+        let mut doc = db::Deleting::with_pk(xid::new(), xid::new(), 1);
+
+        // started, then in progress.
+        doc.updated_at = now;
+        assert!(is_running(&doc, now, in_flight));
+        doc.progress = 60;
+        assert!(is_running(&doc, now + 1000, in_flight));
+
+        // stalled longer than the in-flight window.
+        assert!(!is_running(&doc, now + 3600 * 1000, in_flight));
+
+        // failed.
+        doc.error = "qdrant: timeout".to_string();
+        assert!(!is_running(&doc, now + 1000, in_flight));
+
+        // finished, with or without errors.
+        doc.progress = 100;
+        assert!(!is_running(&doc, now + 1000, in_flight));
+        doc.error = "".to_string();
+        assert!(!is_running(&doc, now + 1000, in_flight));
+    }
+}
@@ -1,36 +1,163 @@
 use axum::{extract::State, Extension};
 use qdrant_client::qdrant::point_id::PointIdOptions;
 use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
 use std::time::Instant;
-use std::{str::FromStr, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    sync::Arc,
+};
+use tokio::sync::{mpsc, Semaphore};
 use validator::Validate;
 
-use axum_web::context::ReqContext;
+use axum_web::context::{unix_ms, ReqContext};
 use axum_web::erring::{HTTPError, SuccessResponse};
-use axum_web::object::{cbor_from_slice, PackObject};
+use axum_web::object::{cbor_from_slice, cbor_to_vec, PackObject};
+use scylla_orm::ColumnsMap;
 
-use crate::api::{AppState, TEContentList, TEOutput, TEParams, TESegmenter};
+use crate::api::{
+    check_content_size, check_content_tokens, content_bytes, encode_blocking, incr_counter,
+    publish_event, tokenize_content, validate_version, AppState, Dedup, EstimateOutput, JobEvent,
+    JobKind, SegmentTimings, TEContentInput, TEContentList, TEOutput, TEParams, TESegmenter,
+    TEUnit, TaskContext, ABORTED_BY_SHUTDOWN, CREATE_STARTED, JOB_DONE, JOB_FAILED, PARALLEL_WORKS,
+};
 use crate::db::{self, qdrant};
 use crate::lang::Language;
+use crate::openai;
 use crate::tokenizer;
+use crate::trace;
 
 #[derive(Debug, Deserialize, Validate)]
 pub struct SearchInput {
-    pub input: String,                          // the input text
-    pub public: Option<bool>,                   // search public content
-    pub gid: Option<PackObject<xid::Id>>,       // group id, content belong to
+    pub input: String,                    // the input text
+    pub public: Option<bool>,             // search public content
+    pub gid: Option<PackObject<xid::Id>>, // group id, content belong to
+    #[validate(length(min = 1, max = 20))]
+    pub gids: Option<Vec<PackObject<xid::Id>>>, // search any of the groups, merged with gid
     pub language: Option<PackObject<Language>>, // the target language
-    pub cid: Option<PackObject<xid::Id>>,       // creation id
+    pub cid: Option<PackObject<xid::Id>>, // creation id
+    #[validate(range(min = 1, max = 50))]
+    pub limit: Option<u16>, // the max number of creations to return, default 3
+    #[validate(range(max = 10000))]
+    pub offset: Option<u16>, // the offset of candidate points in qdrant
+    #[validate(range(min = -1.0, max = 1.0))]
+    pub score_threshold: Option<f32>, // ignore the points with a lower score
+    pub with_content: Option<bool>,       // return the matched ids and content
+    #[validate(range(min = 1, max = 8192))]
+    pub snippet_tokens: Option<u16>, // truncate the returned content to this tokens
+    pub with_vectors: Option<bool>,       // return the embedding vector of the matched point
+    pub model: Option<String>,            // only return the points embedded by this model
+    pub diversify: Option<bool>,          // re-rank the candidates by maximal marginal relevance
+    #[validate(range(min = 0.0, max = 1.0))]
+    pub mmr_lambda: Option<f32>, // the relevance weight of MMR, default 0.5
+    pub must: Option<BTreeMap<String, String>>, // the custom payload fields must match all
+    pub should: Option<BTreeMap<String, String>>, // the custom payload fields should match any
 }
 
-#[derive(Debug, Default, Serialize, Validate)]
+// the number of default results.
+static SEARCH_DEFAULT_LIMIT: u16 = 3;
+// request a multiple of the limit from qdrant, since segments of the same creation are deduped.
+static SEARCH_CANDIDATES_FACTOR: u64 = 3;
+// the max rounds to fetch candidates from qdrant.
+static SEARCH_MAX_ROUNDS: usize = 5;
+// the default relevance weight of MMR, 1.0 means no diversity.
+static SEARCH_MMR_LAMBDA: f32 = 0.5;
+// the TTL of the cached search results, short enough to not invalidate them.
+static SEARCH_CACHE_TTL_MS: u64 = 60_000;
+
+#[derive(Debug, Default, Deserialize, Serialize, Validate)]
 pub struct SearchOutput {
     pub gid: PackObject<xid::Id>,       // group id, content belong to
     pub cid: PackObject<xid::Id>,       // creation id
     pub language: PackObject<Language>, // the target language
     pub version: u16,
-    pub ids: String,
-    pub content: PackObject<Vec<u8>>,
+    pub ids: String,     // the matched node ids, separated by ","
+    pub content: String, // the matched text, only returned if `with_content` is true
+    pub score: f32,      // the best score of the creation's points
+    pub model: String,   // the embedding model of the matched point
+    pub created_at: i64, // the time the matched point was written, unix time, ms
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vector: Option<Vec<f32>>, // only returned if `with_vectors` is true
+}
+
+// the search parameters that change the results, hashed as the cache key.
+#[derive(Serialize)]
+struct SearchCacheKey<'a> {
+    q: &'a str,
+    gids: Vec<String>,
+    public: bool,
+    language: Option<&'a str>,
+    cid: Option<String>,
+    model: Option<&'a str>,
+    limit: usize,
+    offset: u64,
+    score_threshold: Option<f32>,
+    with_vectors: bool,
+    diversify: bool,
+    mmr_lambda: f32,
+    must: &'a BTreeMap<String, String>,
+    should: &'a BTreeMap<String, String>,
+}
+
+impl SearchCacheKey<'_> {
+    fn to_key(&self) -> String {
+        let data = serde_json::to_vec(self).expect("SearchCacheKey serde_json::to_vec error");
+        let digest = Sha3_256::digest(data);
+        let hex: String = digest[..16].iter().map(|b| format!("{:02x}", b)).collect();
+        format!("SEARCH:{}", hex)
+    }
+}
+
+// re-pack the results as the request, the cached ones are decoded as they were packed.
+fn repack_outputs(to: &PackObject<()>, res: Vec<SearchOutput>) -> Vec<SearchOutput> {
+    res.into_iter()
+        .map(|v| SearchOutput {
+            gid: to.with(v.gid.unwrap()),
+            cid: to.with(v.cid.unwrap()),
+            language: to.with(v.language.unwrap()),
+            ..v
+        })
+        .collect()
+}
+
+// push the result if the creation is not in the list, otherwise keep the best scored one.
+fn push_dedup(res: &mut Vec<SearchOutput>, item: SearchOutput) {
+    match res.iter_mut().find(|v| v.cid == item.cid) {
+        Some(v) => {
+            if item.score > v.score {
+                *v = item;
+            }
+        }
+        None => res.push(item),
+    }
+}
+
+// sort the results by descending score, the best matched creation first.
+fn sort_by_score(res: &mut [SearchOutput]) {
+    res.sort_by(|a, b| b.score.total_cmp(&a.score));
+}
+
+// decode the embedding content and return its text, truncated to `snippet_tokens` if provided.
+fn search_snippet(content: &[u8], snippet_tokens: Option<u16>) -> Result<String, HTTPError> {
+    let content: TEContentList = cbor_from_slice(content).map_err(|e| HTTPError {
+        code: 500,
+        message: format!("Invalid content: {}", e),
+        data: None,
+    })?;
+
+    let text = TEUnit { tokens: 0, content }.to_embedding_string();
+    match snippet_tokens {
+        Some(max) => Ok(tokenizer::truncate_to_tokens(&text, max as usize)),
+        None => Ok(text),
+    }
+}
+
+// qdrant only returns the points whose score reach the threshold, so fewer points than
+// requested means that the candidates are exhausted, we should return what we have got
+// (maybe empty) rather than fetching more rounds for low-quality fillers.
+fn search_done(found: usize, limit: usize, got: usize, candidates: u64) -> bool {
+    found >= limit || (got as u64) < candidates
 }
 
 pub async fn search(
@@ -56,412 +183,2797 @@ pub async fn search(
         return Ok(to.with(SuccessResponse::new(vec![])));
     }
 
-    let rctx = ctx.as_ref();
-    let embedding_res = app
-        .ai
-        .embedding(rctx, &vec![q.clone()])
-        .await
-        .map_err(HTTPError::from)?;
-
-    let mut f = qdrant::Filter {
-        should: Vec::new(),
-        must: Vec::new(),
-        must_not: Vec::new(),
-    };
-
+    let gids = merge_gids(
+        input.gid.map(|v| v.unwrap()),
+        input.gids.unwrap_or_default(),
+    );
     let mut public = input.public.unwrap_or(false);
-    if input.gid.is_none() {
+    if gids.is_empty() {
         public = true;
     }
 
-    if let Some(gid) = input.gid.clone().map(|v| v.unwrap()) {
-        ctx.set("gid", gid.to_string().into()).await;
-        let fc = qdrant::FieldCondition {
-            key: "gid".to_string(),
-            r#match: Some(qdrant::Match {
-                match_value: Some(qdrant::MatchValue::Text(gid.to_string())),
-            }),
-            ..qdrant::FieldCondition::default()
-        };
-        f.must.push(qdrant::Condition::from(fc))
+    let language = input.language.map(|v| v.unwrap());
+    let cid = input.cid.map(|v| v.unwrap());
+    let model = input.model.filter(|m| !m.is_empty());
+    match gids.as_slice() {
+        [] => {}
+        [gid] => ctx.set("gid", gid.to_string().into()).await,
+        gids => ctx.set("gids", gids.len().into()).await,
     }
-
-    if let Some(language) = input.language.map(|v| v.unwrap()) {
+    if let Some(language) = language {
         ctx.set("language", language.to_639_3().into()).await;
-        let fc = qdrant::FieldCondition {
-            key: "language".to_string(),
-            r#match: Some(qdrant::Match {
-                match_value: Some(qdrant::MatchValue::Text(language.to_639_3().to_string())),
-            }),
-            ..qdrant::FieldCondition::default()
-        };
-        f.must.push(qdrant::Condition::from(fc))
     }
-
-    if let Some(cid) = input.cid.map(|v| v.unwrap()) {
+    if let Some(cid) = cid {
         ctx.set("cid", cid.to_string().into()).await;
-        let fc = qdrant::FieldCondition {
-            key: "cid".to_string(),
-            r#match: Some(qdrant::Match {
-                match_value: Some(qdrant::MatchValue::Text(cid.to_string())),
-            }),
-            ..qdrant::FieldCondition::default()
-        };
-        f.must.push(qdrant::Condition::from(fc))
+    }
+    if let Some(model) = &model {
+        ctx.set("model", model.clone().into()).await;
+    }
+    let must = input.must.unwrap_or_default();
+    let should = input.should.unwrap_or_default();
+    check_payload(&must)?;
+    check_payload(&should)?;
+    if !must.is_empty() || !should.is_empty() {
+        ctx.set_kvs(vec![
+            ("must", must.len().into()),
+            ("should", should.len().into()),
+        ])
+        .await;
     }
 
-    let f = if !f.must.is_empty() { Some(f) } else { None };
-    let embedding = embedding_res.1[0].to_owned();
-    let qd_res = if public {
-        app.qdrant
-            .search_public_points(embedding, f)
-            .await
-            .map_err(HTTPError::from)?
+    let limit = input.limit.unwrap_or(SEARCH_DEFAULT_LIMIT) as usize;
+    let candidates = limit as u64 * SEARCH_CANDIDATES_FACTOR;
+    let mut offset = input.offset.unwrap_or(0) as u64;
+    ctx.set_kvs(vec![("limit", limit.into()), ("offset", offset.into())])
+        .await;
+    if let Some(score_threshold) = input.score_threshold {
+        ctx.set("score_threshold", score_threshold.into()).await;
+    }
+
+    let with_content = input.with_content.unwrap_or(false);
+    let with_vectors = input.with_vectors.unwrap_or(false);
+    let diversify = input.diversify.unwrap_or(false);
+    let mmr_lambda = input.mmr_lambda.unwrap_or(SEARCH_MMR_LAMBDA);
+    if diversify {
+        ctx.set("mmr_lambda", mmr_lambda.into()).await;
+    }
+
+    // the snippets are not cached, they should be fresh.
+    let cache_key = if with_content {
+        None
     } else {
-        app.qdrant
-            .search_points(embedding, f)
-            .await
-            .map_err(HTTPError::from)?
+        let mut gids: Vec<String> = gids.iter().map(|gid| gid.to_string()).collect();
+        gids.sort();
+        Some(
+            SearchCacheKey {
+                q: &q,
+                gids,
+                public,
+                language: language.map(|v| v.to_639_3()),
+                cid: cid.map(|v| v.to_string()),
+                model: model.as_deref(),
+                limit,
+                offset,
+                score_threshold: input.score_threshold,
+                with_vectors,
+                diversify,
+                mmr_lambda,
+                must: &must,
+                should: &should,
+            }
+            .to_key(),
+        )
     };
+    if let Some(key) = &cache_key {
+        let cached = app
+            .redis
+            .get_data(key)
+            .await
+            .ok()
+            .and_then(|data| cbor_from_slice::<Vec<SearchOutput>>(&data).ok());
+        let cache = if cached.is_some() { "hit" } else { "miss" };
+        ctx.set("cache", cache.into()).await;
+        if let Some(res) = cached {
+            ctx.set("results", res.len().into()).await;
+            return Ok(to.with(SuccessResponse::new(repack_outputs(&to, res))));
+        }
+    }
 
-    ctx.set("qd_results", qd_res.result.len().into()).await;
-    let mut res: Vec<SearchOutput> = Vec::with_capacity(qd_res.result.len());
-    for q in qd_res.result {
-        let id = match q.id {
-            None => {
-                return Err(HTTPError {
-                    code: 500,
-                    message: "Invalid ScoredPoint id from result".to_string(),
-                    data: Some(serde_json::Value::String(format!("{:?}", q.id))),
-                });
-            }
-            Some(id) => match id.point_id_options {
-                Some(PointIdOptions::Uuid(x)) => x,
-                _ => {
-                    return Err(HTTPError {
-                        code: 500,
-                        message: "Invalid ScoredPoint id from result".to_string(),
-                        data: Some(serde_json::Value::String(format!("{:?}", id))),
-                    });
-                }
-            },
-        };
+    let rctx = ctx.as_ref();
+    let embedding_res = app
+        .ai
+        .embedding(rctx, &vec![q.clone()])
+        .await
+        .map_err(HTTPError::from)?;
 
-        let id = uuid::Uuid::from_str(&id).map_err(|e| HTTPError {
-            code: 500,
-            message: format!("Extract uuid error: {}", e),
-            data: None,
-        })?;
+    let f = search_filter(&gids, language, cid, model, &must, &should);
+    let embedding = embedding_res.1[0].to_owned();
+    let mut fields = vec![
+        "gid".to_string(),
+        "cid".to_string(),
+        "language".to_string(),
+        "version".to_string(),
+        "model".to_string(),
+        "created_at".to_string(),
+        "deleted_at".to_string(),
+    ];
+    if with_content {
+        fields.push("ids".to_string());
+        fields.push("content".to_string());
+    }
 
-        let mut doc = db::Embedding::with_pk(id);
+    let mut res: Vec<SearchOutput> = Vec::with_capacity(limit);
+    let mut qd_results = 0usize;
+    // keep fetching until got `limit` unique creations or the candidates are exhausted.
+    for _ in 0..SEARCH_MAX_ROUNDS {
+        let qd_res = if public {
+            app.qdrant
+                .search_public_points(
+                    embedding.clone(),
+                    f.clone(),
+                    candidates,
+                    offset,
+                    input.score_threshold,
+                    with_vectors || diversify,
+                )
+                .await
+                .map_err(HTTPError::from)?
+        } else {
+            app.qdrant
+                .search_points(
+                    embedding.clone(),
+                    f.clone(),
+                    candidates,
+                    offset,
+                    input.score_threshold,
+                    with_vectors || diversify,
+                )
+                .await
+                .map_err(HTTPError::from)?
+        };
 
-        doc.get_one(
-            &app.scylla,
-            vec![
-                "gid".to_string(),
-                "cid".to_string(),
-                "language".to_string(),
-                "version".to_string(),
-            ],
-        )
-        .await
-        .map_err(HTTPError::from)?;
+        let got = qd_res.result.len();
+        qd_results += got;
+        offset += got as u64;
+        let points = if diversify {
+            mmr_rerank_points(qd_res.result, app.qdrant.vector_name(), mmr_lambda)
+        } else {
+            qd_res.result
+        };
+        for q in points {
+            let id = extract_point_uuid(q.id)?;
+            let mut doc = db::Embedding::with_pk(id);
+            doc.get_one(&app.scylla, fields.clone())
+                .await
+                .map_err(HTTPError::from)?;
+            // the point of a tombstoned row may be left if its removal failed.
+            if doc.deleted_at.is_some() {
+                continue;
+            }
 
-        let to_cid = to.with(doc.cid);
-        if res.iter().any(|v| v.cid == to_cid) {
-            continue;
+            let content = if with_content {
+                search_snippet(&doc.content, input.snippet_tokens)?
+            } else {
+                String::new()
+            };
+            push_dedup(
+                &mut res,
+                SearchOutput {
+                    gid: to.with(doc.gid),
+                    cid: to.with(doc.cid),
+                    language: to.with(doc.language),
+                    version: doc.version as u16,
+                    ids: doc.ids,
+                    content,
+                    score: q.score,
+                    model: doc.model,
+                    created_at: doc.created_at,
+                    vector: if with_vectors {
+                        qdrant::vector_data(q.vectors, app.qdrant.vector_name())
+                    } else {
+                        None
+                    },
+                },
+            );
+            if res.len() >= limit {
+                break;
+            }
         }
 
-        res.push(SearchOutput {
-            gid: to.with(doc.gid),
-            cid: to_cid,
-            language: to.with(doc.language),
-            version: doc.version as u16,
-            ..Default::default()
-        });
+        if search_done(res.len(), limit, got, candidates) {
+            break;
+        }
     }
 
+    sort_by_score(&mut res);
+    ctx.set("qd_results", qd_results.into()).await;
     ctx.set("results", res.len().into()).await;
+    if let Some(key) = &cache_key {
+        // cache the results packed as CBOR, the languages packed as JSON can not be decoded.
+        res = repack_outputs(&PackObject::Cbor(()), res);
+        if let Ok(data) = cbor_to_vec(&res) {
+            if let Err(err) = app.redis.new_data(key, data, SEARCH_CACHE_TTL_MS).await {
+                ctx.set("cache_error", err.to_string().into()).await;
+            }
+        }
+        res = repack_outputs(&to, res);
+    }
     Ok(to.with(SuccessResponse::new(res)))
 }
 
 #[derive(Debug, Deserialize, Validate)]
-pub struct EmbeddingInput {
+pub struct SimilarInput {
     pub gid: PackObject<xid::Id>, // group id, content belong to
-    pub cid: PackObject<xid::Id>, // creation id
-    pub language: PackObject<Language>,
-    #[validate(range(min = 1, max = 10000))]
-    pub version: u16,
-    pub content: PackObject<Vec<u8>>,
+    pub cid: PackObject<xid::Id>, // the source creation id
+    pub language: Option<PackObject<Language>>, // the source and target language
+    pub public: Option<bool>,     // search public content
+    #[validate(range(min = 1, max = 50))]
+    pub limit: Option<u16>, // the max number of creations to return, default 3
+    #[validate(range(min = -1.0, max = 1.0))]
+    pub score_threshold: Option<f32>, // ignore the points with a lower score
 }
 
-pub async fn create(
+// the max number of source points used to find similar creations.
+static SIMILAR_MAX_SOURCES: usize = 5;
+
+// find the creations similar to the given one with its stored vectors, the source creation
+// is excluded from the results.
+pub async fn similar(
     State(app): State<Arc<AppState>>,
     Extension(ctx): Extension<Arc<ReqContext>>,
-    to: PackObject<EmbeddingInput>,
-) -> Result<PackObject<SuccessResponse<TEOutput>>, HTTPError> {
+    to: PackObject<SimilarInput>,
+) -> Result<PackObject<SuccessResponse<Vec<SearchOutput>>>, HTTPError> {
     let (to, input) = to.unpack();
     input.validate()?;
 
     let gid = *input.gid;
     let cid = *input.cid;
-    let language = *input.language;
-
-    if language == Language::Und {
-        return Err(HTTPError::new(400, "Invalid language".to_string()));
-    }
-
+    let language = input.language.map(|v| v.unwrap());
+    let public = input.public.unwrap_or(false) || ctx.user.is_zero();
+    let limit = input.limit.unwrap_or(SEARCH_DEFAULT_LIMIT) as usize;
     ctx.set_kvs(vec![
-        ("action", "create_embedding".into()),
+        ("action", "similar".into()),
         ("gid", gid.to_string().into()),
         ("cid", cid.to_string().into()),
-        ("language", language.to_639_3().into()),
-        ("version", input.version.into()),
+        ("public", public.into()),
+        ("limit", limit.into()),
     ])
     .await;
 
-    if input.content.is_empty() {
-        return Err(HTTPError::new(
-            400,
-            "Empty content to translate".to_string(),
-        ));
+    let docs = db::Embedding::list_by_cid_with(
+        &app.scylla,
+        cid,
+        gid,
+        language,
+        None,
+        vec!["uuid".to_string()],
+    )
+    .await?;
+    let sources: Vec<uuid::Uuid> = docs
+        .into_iter()
+        .take(SIMILAR_MAX_SOURCES)
+        .map(|doc| doc.uuid)
+        .collect();
+    ctx.set("sources", sources.len().into()).await;
+    if sources.is_empty() {
+        return Ok(to.with(SuccessResponse::new(vec![])));
     }
 
-    let content: TEContentList = cbor_from_slice(&input.content).map_err(|e| HTTPError {
-        code: 400,
-        message: format!("Invalid content: {}", e),
-        data: None,
-    })?;
-
-    // start embedding in the background immediately.
-    tokio::spawn(embedding(
-        app,
-        ctx.rid.clone(),
-        ctx.user,
-        TEParams {
-            gid,
-            cid,
-            language,
-            version: input.version as i16,
-            content,
-        },
-    ));
-
-    Ok(to.with(SuccessResponse::new(TEOutput {
-        cid: to.with(cid),
-        detected_language: to.with(language),
-    })))
-}
-
-async fn embedding(app: Arc<AppState>, rid: String, user: xid::Id, te: TEParams) {
-    let content = te.content.segment_for_embedding(tokenizer::tokens_len);
-    if content.is_empty() {
-        return;
+    let vectors = if public {
+        app.qdrant.get_public_vectors(sources).await
+    } else {
+        app.qdrant.get_vectors(sources).await
     }
+    .map_err(HTTPError::from)?;
 
-    let pieces = content.len();
-    let start = Instant::now();
-
-    log::info!(target: "embedding",
-        action = "start_job",
-        rid = rid,
-        user = user.to_string(),
-        gid = te.gid.to_string(),
-        cid = te.cid.to_string(),
-        language = te.language.to_639_3().to_string(),
-        pieces = pieces;
-        "",
-    );
-
-    let tokio_embedding = app.embedding.clone();
-    let mut total_tokens: i32 = 0;
-    let mut progress = 0usize;
-    for unit_group in content {
-        let ctx = ReqContext::new(rid.clone(), user, 0);
-        let embedding_input: Vec<String> = unit_group
-            .iter()
-            .map(|unit| unit.to_embedding_string())
-            .collect();
+    let mut f = qdrant::Filter {
+        should: Vec::new(),
+        must: Vec::new(),
+        must_not: vec![match_condition("cid", cid.to_string())],
+    };
+    if !public {
+        f.must.push(match_condition("gid", gid.to_string()));
+    }
+    if let Some(language) = language {
+        f.must
+            .push(match_condition("language", language.to_639_3().to_string()));
+    }
 
-        let res = app.ai.embedding(&ctx, &embedding_input).await;
-        let ai_elapsed = ctx.start.elapsed().as_millis() as u64;
-        let kv = ctx.get_kv().await;
-        if let Err(err) = res {
-            log::error!(target: "embedding",
-                action = "call_openai",
-                rid = ctx.rid,
-                cid = te.cid.to_string(),
-                elapsed = ai_elapsed,
-                kv = log::as_serde!(kv);
-                "{}", err.to_string(),
-            );
-            continue;
+    let fields = vec![
+        "gid".to_string(),
+        "cid".to_string(),
+        "language".to_string(),
+        "version".to_string(),
+        "model".to_string(),
+        "created_at".to_string(),
+        "deleted_at".to_string(),
+    ];
+    let candidates = limit as u64 * SEARCH_CANDIDATES_FACTOR;
+    let mut res: Vec<SearchOutput> = Vec::with_capacity(limit);
+    let mut qd_results = 0usize;
+    for vector in vectors.into_values() {
+        let qd_res = if public {
+            app.qdrant
+                .search_public_points(
+                    vector,
+                    Some(f.clone()),
+                    candidates,
+                    0,
+                    input.score_threshold,
+                    false,
+                )
+                .await
+        } else {
+            app.qdrant
+                .search_points(
+                    vector,
+                    Some(f.clone()),
+                    candidates,
+                    0,
+                    input.score_threshold,
+                    false,
+                )
+                .await
         }
+        .map_err(HTTPError::from)?;
 
-        progress += 1;
-        let (used_tokens, embeddings) = res.unwrap();
-        total_tokens += used_tokens as i32;
-        log::info!(target: "embedding",
-            action = "call_openai",
-            rid = ctx.rid,
-            cid = te.cid.to_string(),
-            elapsed = ai_elapsed,
-            tokens = used_tokens,
-            total_elapsed = start.elapsed().as_millis(),
-            total_tokens = total_tokens,
-            kv = log::as_serde!(kv);
-            "{}/{}", progress, pieces,
-        );
-
-        for (i, unit) in unit_group.iter().enumerate() {
-            let unit_elapsed = ctx.start.elapsed().as_millis() as u64;
-            let mut doc = db::Embedding::from(te.cid, te.language, unit.ids().join(","));
-            doc.gid = te.gid;
-            doc.version = te.version;
-
-            if let Err(err) = ciborium::into_writer(&unit.content, &mut doc.content) {
-                log::error!(target: "embedding",
-                    action = "to_cbor",
-                    rid = ctx.rid,
-                    cid = te.cid.to_string();
-                    "{}", err,
-                );
+        qd_results += qd_res.result.len();
+        for q in qd_res.result {
+            let id = extract_point_uuid(q.id)?;
+            let mut doc = db::Embedding::with_pk(id);
+            doc.get_one(&app.scylla, fields.clone())
+                .await
+                .map_err(HTTPError::from)?;
+            // the point of a tombstoned row may be left if its removal failed.
+            if doc.deleted_at.is_some() {
                 continue;
             }
 
-            let res = doc.save(&app.scylla).await;
-            let scylla_elapsed = ctx.start.elapsed().as_millis() as u64 - unit_elapsed;
-            match res {
-                Err(err) => {
-                    log::error!(target: "embedding",
-                        action = "to_scylla",
-                        rid = ctx.rid,
-                        cid = te.cid.to_string(),
-                        ids = log::as_serde!(unit.ids()),
-                        elapsed = scylla_elapsed;
-                        "{}", err,
-                    );
-                }
-                Ok(_) => {
-                    log::info!(target: "embedding",
-                        action = "to_scylla",
-                        rid = ctx.rid,
-                        ids = log::as_serde!(unit.ids()),
-                        elapsed = scylla_elapsed;
-                        "",
-                    );
-
-                    let vectors = embeddings[i].to_vec();
-                    match app.qdrant.add_points(vec![doc.qdrant_point(vectors)]).await {
-                        Ok(()) => {
-                            log::info!(target: "qdrant",
-                                action = "to_qdrant",
-                                rid = ctx.rid,
-                                cid = te.cid.to_string(),
-                                elapsed = ctx.start.elapsed().as_millis() as u64 - scylla_elapsed - unit_elapsed;
-                                "",
-                            )
-                        }
-                        Err(err) => {
-                            log::error!(target: "qdrant",
-                                action = "to_qdrant",
-                                rid = ctx.rid,
-                                cid = te.cid.to_string(),
-                                elapsed = ctx.start.elapsed().as_millis() as u64- scylla_elapsed- unit_elapsed;
-                                "{}", err,
-                            )
-                        }
-                    }
-                }
-            };
+            push_dedup(
+                &mut res,
+                SearchOutput {
+                    gid: to.with(doc.gid),
+                    cid: to.with(doc.cid),
+                    language: to.with(doc.language),
+                    version: doc.version as u16,
+                    score: q.score,
+                    model: doc.model,
+                    created_at: doc.created_at,
+                    ..Default::default()
+                },
+            );
         }
     }
 
-    log::info!(target: "embedding",
-        action = "finish_job",
-        rid = rid,
-        cid = te.cid.to_string(),
-        elapsed = start.elapsed().as_millis() as u64,
-        pieces = pieces,
-        total_tokens = total_tokens;
-        "",
-    );
-
-    let _ = tokio_embedding.as_str(); // avoid unused warning
+    sort_by_score(&mut res);
+    res.truncate(limit);
+    ctx.set("qd_results", qd_results.into()).await;
+    ctx.set("results", res.len().into()).await;
+    Ok(to.with(SuccessResponse::new(res)))
 }
 
 #[derive(Debug, Deserialize, Validate)]
-pub struct EmbeddingPublicInput {
-    pub gid: PackObject<xid::Id>,       // group id, content belong to
-    pub cid: PackObject<xid::Id>,       // creation id
-    pub language: PackObject<Language>, // the target language translate to
-    #[validate(range(min = 1, max = 10000))]
-    pub version: u16,
+pub struct ListPointsInput {
+    pub gid: PackObject<xid::Id>, // group id, content belong to
+    pub cid: PackObject<xid::Id>, // creation id
+    pub public: Option<bool>,     // list the points in the public collection
+    #[validate(range(min = 1, max = 1000))]
+    pub limit: Option<u32>, // the max number of points to return, default 100
+    pub page_token: Option<String>, // the next_page_token of the previous page
+    pub with_vectors: Option<bool>, // return the embedding vectors of the points
 }
 
-pub async fn public(
+#[derive(Debug, Serialize)]
+pub struct PointOutput {
+    pub id: String, // the point id, the same as the embedding uuid
+    pub payload: serde_json::Map<String, serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vector: Option<Vec<f32>>, // only returned if `with_vectors` is true
+}
+
+#[derive(Debug, Serialize)]
+pub struct ListPointsOutput {
+    pub points: Vec<PointOutput>,
+    pub next_page_token: Option<String>, // None if there is no more points
+}
+
+// the default number of points to return in a page.
+static LIST_POINTS_DEFAULT_LIMIT: u32 = 100;
+
+pub async fn list_points(
     State(app): State<Arc<AppState>>,
     Extension(ctx): Extension<Arc<ReqContext>>,
-    to: PackObject<EmbeddingPublicInput>,
-) -> Result<PackObject<SuccessResponse<()>>, HTTPError> {
+    to: PackObject<ListPointsInput>,
+) -> Result<PackObject<SuccessResponse<ListPointsOutput>>, HTTPError> {
     let (to, input) = to.unpack();
     input.validate()?;
 
     let gid = *input.gid;
     let cid = *input.cid;
-    let language = *input.language;
+    let public = input.public.unwrap_or(false);
+    let offset = match input.page_token {
+        None => None,
+        Some(token) => Some(uuid::Uuid::from_str(&token).map_err(|e| HTTPError {
+            code: 400,
+            message: format!("Invalid page_token: {}", e),
+            data: None,
+        })?),
+    };
 
     ctx.set_kvs(vec![
-        ("action", "make_public".into()),
+        ("action", "list_points".into()),
         ("gid", gid.to_string().into()),
         ("cid", cid.to_string().into()),
-        ("language", language.to_639_3().into()),
-        ("version", input.version.into()),
+        ("public", public.into()),
     ])
     .await;
-    let docs = db::Embedding::list_by_cid(
-        &app.scylla,
-        cid,
-        gid,
-        language,
-        input.version as i16,
-        vec!["cid".to_string()],
-    )
-    .await?;
-    ctx.set("pieces", docs.len().into()).await;
+
+    let f = qdrant::Filter {
+        should: Vec::new(),
+        must: vec![
+            match_condition("gid", gid.to_string()),
+            match_condition("cid", cid.to_string()),
+        ],
+        must_not: Vec::new(),
+    };
+    let res = app
+        .qdrant
+        .scroll_points(
+            public,
+            Some(f),
+            input.limit.unwrap_or(LIST_POINTS_DEFAULT_LIMIT),
+            offset,
+            input.with_vectors.unwrap_or(false),
+        )
+        .await
+        .map_err(HTTPError::from)?;
+
+    let mut points: Vec<PointOutput> = Vec::with_capacity(res.result.len());
+    for p in res.result {
+        points.push(PointOutput {
+            id: extract_point_uuid(p.id)?.to_string(),
+            payload: p
+                .payload
+                .into_iter()
+                .map(|(k, v)| (k, qdrant::value_to_json(v)))
+                .collect(),
+            vector: qdrant::vector_data(p.vectors, app.qdrant.vector_name()),
+        });
+    }
+    let next_page_token = match res.next_page_offset {
+        None => None,
+        Some(id) => Some(extract_point_uuid(Some(id))?.to_string()),
+    };
+
+    ctx.set("points", points.len().into()).await;
+    Ok(to.with(SuccessResponse::new(ListPointsOutput {
+        points,
+        next_page_token,
+    })))
+}
+
+// the gid and gids of a search, deduplicated in order.
+fn merge_gids(gid: Option<xid::Id>, gids: Vec<PackObject<xid::Id>>) -> Vec<xid::Id> {
+    let mut res: Vec<xid::Id> = Vec::with_capacity(gids.len() + 1);
+    for gid in gid.into_iter().chain(gids.into_iter().map(|v| v.unwrap())) {
+        if !res.contains(&gid) {
+            res.push(gid);
+        }
+    }
+    res
+}
+
+// the filter of the search, None if no condition.
+// the custom fields are not indexed, filtering by them is slower on a large collection.
+// multiple gids are matched by a nested should (OR) filter inside must, so they
+// combine with the other must conditions and are not mixed with the custom should fields.
+// the filter does not change the scores, the points of all the groups are ranked together,
+// so a large group may take most of the results.
+fn search_filter(
+    gids: &[xid::Id],
+    language: Option<Language>,
+    cid: Option<xid::Id>,
+    model: Option<String>,
+    must: &BTreeMap<String, String>,
+    should: &BTreeMap<String, String>,
+) -> Option<qdrant::Filter> {
+    let mut f = qdrant::Filter {
+        should: Vec::new(),
+        must: Vec::new(),
+        must_not: Vec::new(),
+    };
+    match gids {
+        [] => {}
+        [gid] => f.must.push(match_condition("gid", gid.to_string())),
+        gids => f.must.push(any_condition(
+            gids.iter()
+                .map(|gid| match_condition("gid", gid.to_string()))
+                .collect(),
+        )),
+    }
+    if let Some(language) = language {
+        f.must
+            .push(match_condition("language", language.to_639_3().to_string()));
+    }
+    if let Some(cid) = cid {
+        f.must.push(match_condition("cid", cid.to_string()));
+    }
+    if let Some(model) = model {
+        f.must.push(match_condition("model", model));
+    }
+    for (k, v) in must {
+        f.must.push(match_condition(k, v.clone()));
+    }
+    for (k, v) in should {
+        f.should.push(match_condition(k, v.clone()));
+    }
+
+    if f.must.is_empty() && f.should.is_empty() {
+        None
+    } else {
+        Some(f)
+    }
+}
+
+// the condition that the payload field exactly matches the value,
+// Text is a full-text match which requires a text index.
+fn match_condition(key: &str, value: String) -> qdrant::Condition {
+    qdrant::Condition::from(qdrant::FieldCondition {
+        key: key.to_string(),
+        r#match: Some(qdrant::Match {
+            match_value: Some(qdrant::MatchValue::Keyword(value)),
+        }),
+        ..qdrant::FieldCondition::default()
+    })
+}
+
+// the condition that any of the conditions matches.
+fn any_condition(should: Vec<qdrant::Condition>) -> qdrant::Condition {
+    qdrant::Condition {
+        condition_one_of: Some(qdrant::ConditionOneOf::Filter(qdrant::Filter {
+            should,
+            must: Vec::new(),
+            must_not: Vec::new(),
+        })),
+    }
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let (mut dot, mut na, mut nb) = (0f32, 0f32, 0f32);
+    for (x, y) in a.iter().zip(b) {
+        dot += x * y;
+        na += x * x;
+        nb += y * y;
+    }
+    if na == 0.0 || nb == 0.0 {
+        return 0.0;
+    }
+    dot / (na.sqrt() * nb.sqrt())
+}
+
+// re-rank the candidates by maximal marginal relevance, return their indexes in the new order.
+// `scores` are the relevance to the query, `vectors` are used to penalize the candidates that
+// are similar to the selected ones, `lambda` is the weight of relevance over diversity.
+fn mmr_rerank(scores: &[f32], vectors: &[Vec<f32>], lambda: f32) -> Vec<usize> {
+    let mut selected: Vec<usize> = Vec::with_capacity(scores.len());
+    let mut remaining: Vec<usize> = (0..scores.len()).collect();
+    while !remaining.is_empty() {
+        let mut best = 0usize;
+        let mut best_value = f32::MIN;
+        for (i, &c) in remaining.iter().enumerate() {
+            let max_sim = selected
+                .iter()
+                .map(|&s| cosine_similarity(&vectors[c], &vectors[s]))
+                .fold(0f32, f32::max);
+            let value = lambda * scores[c] - (1.0 - lambda) * max_sim;
+            if value > best_value {
+                best = i;
+                best_value = value;
+            }
+        }
+        selected.push(remaining.remove(best));
+    }
+    selected
+}
+
+fn mmr_rerank_points(
+    points: Vec<qdrant::ScoredPoint>,
+    vector_name: Option<&str>,
+    lambda: f32,
+) -> Vec<qdrant::ScoredPoint> {
+    let scores: Vec<f32> = points.iter().map(|p| p.score).collect();
+    let vectors: Vec<Vec<f32>> = points
+        .iter()
+        .map(|p| qdrant::vector_data(p.vectors.clone(), vector_name).unwrap_or_default())
+        .collect();
+    let mut points: Vec<Option<qdrant::ScoredPoint>> = points.into_iter().map(Some).collect();
+    mmr_rerank(&scores, &vectors, lambda)
+        .into_iter()
+        .filter_map(|i| points[i].take())
+        .collect()
+}
+
+fn extract_point_uuid(id: Option<qdrant::PointId>) -> Result<uuid::Uuid, HTTPError> {
+    let id = match id {
+        None => {
+            return Err(HTTPError {
+                code: 500,
+                message: "Invalid ScoredPoint id from result".to_string(),
+                data: Some(serde_json::Value::String(format!("{:?}", id))),
+            });
+        }
+        Some(id) => match id.point_id_options {
+            Some(PointIdOptions::Uuid(x)) => x,
+            _ => {
+                return Err(HTTPError {
+                    code: 500,
+                    message: "Invalid ScoredPoint id from result".to_string(),
+                    data: Some(serde_json::Value::String(format!("{:?}", id))),
+                });
+            }
+        },
+    };
+
+    uuid::Uuid::from_str(&id).map_err(|e| HTTPError {
+        code: 500,
+        message: format!("Extract uuid error: {}", e),
+        data: None,
+    })
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct EmbeddingJobInput {
+    pub gid: PackObject<xid::Id>, // group id, content belong to
+    pub cid: PackObject<xid::Id>, // creation id
+    pub language: PackObject<Language>,
+    #[validate(custom = "validate_version")]
+    pub version: u16,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct EmbeddingJobOutput {
+    pub gid: PackObject<xid::Id>,
+    pub cid: PackObject<xid::Id>,
+    pub language: PackObject<Language>,
+    pub version: u16,
+    pub progress: i8,
+    pub updated_at: i64,
+    pub pieces: u32,
+    pub tokens: u32,
+    pub error: String,
+}
+
+impl EmbeddingJobOutput {
+    fn from<T>(to: &PackObject<T>, doc: db::EmbeddingJob) -> Self {
+        Self {
+            gid: to.with(doc.gid),
+            cid: to.with(doc.cid),
+            language: to.with(doc.language),
+            version: doc.version as u16,
+            progress: doc.progress,
+            updated_at: doc.updated_at,
+            pieces: doc.pieces as u32,
+            tokens: doc.tokens as u32,
+            error: doc.error,
+        }
+    }
+}
+
+pub async fn get(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<EmbeddingJobInput>,
+) -> Result<PackObject<SuccessResponse<EmbeddingJobOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+
+    let gid = *input.gid;
+    let cid = *input.cid;
+    let language = *input.language;
+
+    ctx.set_kvs(vec![
+        ("action", "get_embedding".into()),
+        ("gid", gid.to_string().into()),
+        ("cid", cid.to_string().into()),
+        ("language", language.to_639_3().into()),
+        ("version", input.version.into()),
+    ])
+    .await;
+
+    let mut doc = db::EmbeddingJob::with_pk(gid, cid, language, input.version as i16);
+    doc.get_one(&app.scylla, vec![]).await?;
+    Ok(to.with(SuccessResponse::new(EmbeddingJobOutput::from(&to, doc))))
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct EmbeddingInput {
+    pub gid: PackObject<xid::Id>, // group id, content belong to
+    pub cid: PackObject<xid::Id>, // creation id
+    pub language: PackObject<Language>,
+    #[validate(custom = "validate_version")]
+    pub version: u16,
+    pub content: TEContentInput,
+    pub force: Option<bool>, // embed all units even if they are stored and unchanged
+    pub payload: Option<BTreeMap<String, String>>, // the custom payload fields of the points
+}
+
+// the max number of custom payload fields, or conditions of a search.
+const PAYLOAD_MAX_FIELDS: usize = 16;
+const PAYLOAD_MAX_VALUE_LEN: usize = 256;
+// the built-in payload fields, filtered by the dedicated parameters.
+const PAYLOAD_RESERVED_KEYS: [&str; 5] = ["gid", "cid", "language", "model", "created_at"];
+
+// the keys should be 1 to 32 lowercase letters, digits or '_', and start with a letter.
+fn check_payload(payload: &BTreeMap<String, String>) -> Result<(), HTTPError> {
+    if payload.len() > PAYLOAD_MAX_FIELDS {
+        return Err(HTTPError::new(
+            400,
+            format!(
+                "Too many payload fields, expected at most {}, got {}",
+                PAYLOAD_MAX_FIELDS,
+                payload.len()
+            ),
+        ));
+    }
+
+    for (k, v) in payload {
+        let valid = (1..=32).contains(&k.len())
+            && k.starts_with(|c: char| c.is_ascii_lowercase())
+            && k.chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if !valid || PAYLOAD_RESERVED_KEYS.contains(&k.as_str()) {
+            return Err(HTTPError::new(400, format!("Invalid payload field: {}", k)));
+        }
+        if v.len() > PAYLOAD_MAX_VALUE_LEN {
+            return Err(HTTPError::new(
+                400,
+                format!("Payload field {} is too long", k),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// the options of an embedding job.
+struct EOptions {
+    force: bool,                      // embed all units even if they are stored and unchanged
+    payload: HashMap<String, String>, // the custom payload fields of the points
+}
+
+pub async fn create(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<EmbeddingInput>,
+) -> Result<PackObject<SuccessResponse<TEOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+    app.jobs.check_open()?;
+    app.jobs.check_capacity(JobKind::Embedding)?;
+
+    let gid = *input.gid;
+    let cid = *input.cid;
+    let language = *input.language;
+
+    if language == Language::Und {
+        return Err(HTTPError::localized(
+            400,
+            "invalid_language",
+            &ctx.lang,
+            &[],
+        ));
+    }
+
+    ctx.set_kvs(vec![
+        ("action", "create_embedding".into()),
+        ("gid", gid.to_string().into()),
+        ("cid", cid.to_string().into()),
+        ("language", language.to_639_3().into()),
+        ("version", input.version.into()),
+    ])
+    .await;
+
+    let mut content = input.content.decode(app.normalize_text)?;
+    if content.is_empty() {
+        return Err(HTTPError::localized(400, "empty_content", &ctx.lang, &[]));
+    }
+    check_content_size(&content, &app.max_content)?;
+    // the same tokens as the estimate, segmented as the job does.
+    let limits = app.ai.embedding_limits();
+    let tokens = tokenize_content(&mut content, move |c| {
+        embedding_tokens(
+            &c.segment_for_embedding(openai::embedding_encoding().tokens_len_fn(), limits),
+        )
+    })
+    .await;
+    ctx.set("content_tokens", tokens.into()).await;
+    check_content_tokens(tokens, app.max_tokens.embedding)?;
+
+    let force = input.force.unwrap_or(false);
+    if force {
+        ctx.set("force", force.into()).await;
+    }
+    let payload = input.payload.unwrap_or_default();
+    check_payload(&payload)?;
+    if !payload.is_empty() {
+        ctx.set("payload", payload.len().into()).await;
+    }
+
+    let now = unix_ms() as i64;
+    let mut doc = db::EmbeddingJob::with_pk(gid, cid, language, input.version as i16);
+    let dedup = match doc
+        .get_one(
+            &app.scylla,
+            vec![
+                "updated_at".to_string(),
+                "progress".to_string(),
+                "error".to_string(),
+            ],
+        )
+        .await
+    {
+        Ok(_) if doc.error.is_empty() => Dedup::check(
+            now - doc.updated_at,
+            doc.progress == 100,
+            doc.progress < 100,
+            app.dedup.embedding,
+            app.dedup.in_flight,
+            force,
+        ),
+        _ => Dedup::Run,
+    };
+    match dedup {
+        Dedup::Exists | Dedup::InFlight => {
+            ctx.set_kvs(vec![
+                ("exists", true.into()),
+                ("in_flight", (dedup == Dedup::InFlight).into()),
+            ])
+            .await;
+            return Ok(to.with(SuccessResponse::new(TEOutput {
+                cid: to.with(cid),
+                detected_language: to.with(language),
+                status: dedup.status().to_string(),
+            })));
+        }
+        Dedup::Rerun => {
+            ctx.set("rerun", true.into()).await;
+        }
+        Dedup::Run => {}
+    }
+
+    let mut cols = ColumnsMap::with_capacity(5);
+    cols.set_as("progress", &0i8);
+    cols.set_as("updated_at", &now);
+    cols.set_as("pieces", &0i32);
+    cols.set_as("tokens", &0i32);
+    cols.set_as("error", &"".to_string());
+    doc.upsert_fields(&app.scylla, cols).await?;
+
+    // start embedding in the background immediately.
+    let span_attrs = vec![
+        ("gid", gid.to_string()),
+        ("cid", cid.to_string()),
+        ("model", openai::MODEL_EMBEDDING.to_string()),
+    ];
+    let user = app.job_user(ctx.user);
+    let jobs = app.jobs.clone();
+    let job = embedding(
+        app,
+        ctx.rid.clone(),
+        user,
+        TEParams {
+            gid,
+            cid,
+            language,
+            version: input.version as i16,
+            content,
+        },
+        EOptions {
+            force,
+            payload: payload.into_iter().collect(),
+        },
+    );
+    jobs.spawn(
+        JobKind::Embedding,
+        trace::in_span("embedding", span_attrs, job),
+    );
+
+    Ok(to.with(SuccessResponse::new(TEOutput {
+        cid: to.with(cid),
+        detected_language: to.with(language),
+        status: CREATE_STARTED.to_string(),
+    })))
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct EstimateInput {
+    pub content: TEContentInput,
+}
+
+// the tokens of the embedding strings of the groups, as the job sends them.
+fn embedding_tokens(groups: &[Vec<TEUnit>]) -> usize {
+    groups
+        .iter()
+        .flatten()
+        .map(|unit| {
+            openai::embedding_encoding()
+                .tokens_len(&unit.to_embedding_string())
+                .min(openai::EMBEDDING_MAX_INPUT_TOKENS)
+        })
+        .sum()
+}
+
+// truncate the input to the tokens limit of the embedding model,
+// return the tokens of the original and the truncated input if truncated.
+fn truncate_embedding_input(input: String, max: usize) -> (String, Option<(usize, usize)>) {
+    // a token has one byte at least, a short input can not exceed the limit.
+    if input.len() <= max {
+        return (input, None);
+    }
+
+    let encoding = openai::embedding_encoding();
+    let tokens = encoding.tokens_len(&input);
+    if tokens <= max {
+        return (input, None);
+    }
+
+    let truncated = tokenizer::truncate_to_tokens(&input, max);
+    let truncated_tokens = encoding.tokens_len(&truncated);
+    (truncated, Some((tokens, truncated_tokens)))
+}
+
+pub async fn estimate(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<EstimateInput>,
+) -> Result<PackObject<SuccessResponse<EstimateOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+
+    ctx.set("action", "estimate_embedding".into()).await;
+
+    let mut content = input.content.decode(app.normalize_text)?;
+    let limits = app.ai.embedding_limits();
+    let (segments, prompt_tokens) = tokenize_content(&mut content, move |c| {
+        let groups = c.segment_for_embedding(openai::embedding_encoding().tokens_len_fn(), limits);
+        (groups.len(), embedding_tokens(&groups))
+    })
+    .await;
+
+    let res = EstimateOutput::new(segments, prompt_tokens, openai::PRICE_EMBEDDING);
+    ctx.set_kvs(vec![
+        ("segments", res.segments.into()),
+        ("prompt_tokens", res.prompt_tokens.into()),
+    ])
+    .await;
+    Ok(to.with(SuccessResponse::new(res)))
+}
+
+// `opts.force` embeds all units, otherwise the stored and unchanged units are skipped.
+async fn embedding(
+    app: Arc<AppState>,
+    rid: String,
+    user: xid::Id,
+    mut te: TEParams,
+    opts: EOptions,
+) {
+    let task = TaskContext::new(&rid, user, &te);
+    let mut job = db::EmbeddingJob::with_pk(te.gid, te.cid, te.language, te.version);
+    let limits = app.ai.embedding_limits();
+    let content = tokenize_content(&mut te.content, move |c| {
+        c.segment_for_embedding(openai::embedding_encoding().tokens_len_fn(), limits)
+    })
+    .await;
+    if content.is_empty() {
+        let mut cols = ColumnsMap::with_capacity(2);
+        cols.set_as("progress", &100i8);
+        cols.set_as("updated_at", &(unix_ms() as i64));
+        let _ = job.upsert_fields(&app.scylla, cols).await;
+        let event = JobEvent::new(db::CounterKind::Embedding, &te, JOB_DONE, 0);
+        publish_event(&app, &task, event).await;
+        return;
+    }
+
+    let pieces = content.len();
+    let start = Instant::now();
+    let mut cols = ColumnsMap::with_capacity(2);
+    cols.set_as("pieces", &(pieces as i32));
+    cols.set_as("updated_at", &(unix_ms() as i64));
+    let _ = job.upsert_fields(&app.scylla, cols).await;
+
+    log::info!(target: "embedding",
+        action = "start_job",
+        task = log::as_serde!(task),
+        pieces = pieces;
+        "",
+    );
+
+    let te = Arc::new(te);
+    let opts = Arc::new(opts);
+    // unlike translating, a failed group does not stop the others.
+    let semaphore = Arc::new(Semaphore::new(PARALLEL_WORKS));
+    let (tx, mut rx) = mpsc::channel::<GroupResult>(pieces);
+    for (i, unit_group) in content.into_iter().enumerate() {
+        let rid = rid.clone();
+        let app = app.clone();
+        let task = task.clone();
+        let te = te.clone();
+        let opts = opts.clone();
+        let tx = tx.clone();
+        let sem = semaphore.clone();
+        trace::spawn(async move {
+            if let Ok(permit) = sem.acquire().await {
+                let ctx = ReqContext::new(rid, user, 0);
+                let res = embedding_group(&app, &ctx, &task, &te, i, unit_group, &opts).await;
+                drop(permit);
+                let _ = tx.send(res).await;
+            }
+        });
+    }
+    drop(tx);
+
+    let mut total_tokens: i32 = 0;
+    let mut progress = 0usize;
+    let mut calls = 0usize;
+    let mut errors: Vec<String> = Vec::new();
+    let mut embedded = 0usize;
+    let mut skipped = 0usize;
+    let mut timings = SegmentTimings::with_capacity(pieces);
+    // the groups finish out of order, their logs are emitted in the group order.
+    let mut finished: Vec<Option<u32>> = vec![None; pieces];
+    let mut next_log = 0usize;
+    loop {
+        let res = tokio::select! {
+            res = rx.recv() => match res {
+                Some(res) => res,
+                None => break,
+            },
+            _ = app.jobs.aborted() => {
+                // the groups not finished are lost, the job fails with the shutdown.
+                errors.push(ABORTED_BY_SHUTDOWN.to_string());
+                let mut cols = ColumnsMap::with_capacity(2);
+                cols.set_as("updated_at", &(unix_ms() as i64));
+                cols.set_as("error", &errors.join("; "));
+                let _ = job.upsert_fields(&app.scylla, cols).await;
+                break;
+            }
+        };
+        total_tokens += res.tokens as i32;
+        if res.tokens > 0 {
+            calls += 1;
+            timings.push(res.piece_at, res.elapsed, res.tokens);
+        }
+        embedded += res.embedded;
+        skipped += res.skipped;
+        if res.embedding_ok {
+            progress += 1;
+        }
+        errors.extend(res.errors);
+        finished[res.piece_at] = Some(res.tokens);
+
+        // persist the progress as the groups arrive.
+        let mut cols = ColumnsMap::with_capacity(4);
+        cols.set_as("progress", &((progress * 100 / pieces) as i8));
+        cols.set_as("updated_at", &(unix_ms() as i64));
+        cols.set_as("tokens", &total_tokens);
+        cols.set_as("error", &errors.join("; "));
+        let _ = job.upsert_fields(&app.scylla, cols).await;
+
+        while let Some(Some(tokens)) = finished.get(next_log) {
+            log::info!(target: "embedding",
+                action = "finish_group",
+                task = log::as_serde!(task),
+                piece_at = next_log,
+                tokens = *tokens,
+                total_elapsed = start.elapsed().as_millis(),
+                total_tokens = total_tokens;
+                "{}/{}", next_log + 1, pieces,
+            );
+            next_log += 1;
+        }
+    }
+
+    if let Some(summary) = timings.summary() {
+        log::log!(target: "embedding", timings.level(),
+            action = "segment_timings",
+            task = log::as_serde!(task),
+            summary = log::as_serde!(summary);
+            "",
+        );
+    }
+    trace::set_attribute("tokens", total_tokens as i64);
+    log::info!(target: "embedding",
+        action = "finish_job",
+        task = log::as_serde!(task),
+        elapsed = start.elapsed().as_millis() as u64,
+        pieces = pieces,
+        embedded = embedded,
+        skipped = skipped,
+        total_tokens = total_tokens;
+        "",
+    );
+
+    incr_counter(
+        &app,
+        db::CounterKind::Embedding,
+        &task,
+        user,
+        te.gid,
+        calls,
+        total_tokens as usize,
+    )
+    .await;
+    // a failed group does not stop the others, the job fails if any group failed.
+    let status = if errors.is_empty() {
+        JOB_DONE
+    } else {
+        JOB_FAILED
+    };
+    let event = JobEvent::new(
+        db::CounterKind::Embedding,
+        &te,
+        status,
+        total_tokens as usize,
+    );
+    publish_event(&app, &task, event).await;
+}
+
+// the result of embedding a unit group.
+#[derive(Default)]
+struct GroupResult {
+    piece_at: usize,
+    embedding_ok: bool, // got vectors for all units, by OpenAI or from the stored points
+    tokens: u32,
+    elapsed: u64, // ms, the elapsed time of the AI call, 0 if not called
+    embedded: usize,
+    skipped: usize,
+    errors: Vec<String>,
+}
+
+// embed a unit group and save the units to scylla and qdrant, errors are collected in the result.
+async fn embedding_group(
+    app: &AppState,
+    ctx: &ReqContext,
+    task: &TaskContext,
+    te: &TEParams,
+    piece_at: usize,
+    unit_group: Vec<TEUnit>,
+    opts: &EOptions,
+) -> GroupResult {
+    let mut res = GroupResult {
+        piece_at,
+        ..Default::default()
+    };
+    let mut docs: Vec<db::Embedding> = Vec::with_capacity(unit_group.len());
+    let mut inputs: Vec<String> = Vec::with_capacity(unit_group.len());
+    for unit in unit_group.iter() {
+        let input = unit.to_embedding_string();
+        let mut doc = db::Embedding::from(te.cid, te.language, unit.ids().join(","));
+        doc.gid = te.gid;
+        doc.version = te.version;
+        doc.hash = db::Embedding::content_hash(&input);
+        doc.model = openai::MODEL_EMBEDDING.to_string();
+        doc.payload = opts.payload.clone();
+
+        let (input, truncated) =
+            truncate_embedding_input(input, openai::EMBEDDING_MAX_INPUT_TOKENS);
+        if let Some((tokens, truncated_tokens)) = truncated {
+            log::warn!(target: "embedding",
+                action = "truncate_input",
+                task = log::as_serde!(task),
+                piece_at = piece_at,
+                ids = doc.ids.as_str(),
+                tokens = tokens,
+                truncated_tokens = truncated_tokens;
+                "",
+            );
+        }
+        docs.push(doc);
+        inputs.push(input);
+    }
+
+    // only call OpenAI for the units that changed or have no vector yet,
+    // the forgotten units are never embedded again, even if forced.
+    let stored = stored_units(app, &ctx.rid, &docs, opts.force).await;
+    let embedding_input: Vec<String> = inputs
+        .into_iter()
+        .zip(stored.iter())
+        .filter(|(_, s)| matches!(s, StoredUnit::Changed))
+        .map(|(input, _)| input)
+        .collect();
+    res.skipped = docs.len() - embedding_input.len();
+    let mut vectors: Vec<Option<Vec<f32>>> = Vec::with_capacity(docs.len());
+    let mut unchanged: Vec<bool> = Vec::with_capacity(docs.len());
+    for s in stored {
+        unchanged.push(matches!(s, StoredUnit::Unchanged | StoredUnit::Forgotten));
+        vectors.push(match s {
+            StoredUnit::Moved(v) => Some(v),
+            _ => None,
+        });
+    }
+
+    if !embedding_input.is_empty() {
+        let ai_res = app.ai.embedding(ctx, &embedding_input).await;
+        let ai_elapsed = ctx.start.elapsed().as_millis() as u64;
+        let kv = ctx.get_kv().await;
+        let (used_tokens, embeddings) = match ai_res {
+            Ok(v) => v,
+            Err(err) => {
+                log::error!(target: "embedding",
+                    action = "call_openai",
+                    task = log::as_serde!(task),
+                    elapsed = ai_elapsed,
+                    piece_at = piece_at,
+                    kv = log::as_serde!(kv);
+                    "{}", err.to_string(),
+                );
+                res.errors.push(err.to_string());
+                return res;
+            }
+        };
+
+        res.tokens = used_tokens;
+        res.elapsed = ai_elapsed;
+        res.embedded = embeddings.len();
+        let mut embeddings = embeddings.into_iter();
+        for (v, _) in vectors
+            .iter_mut()
+            .zip(unchanged.iter())
+            .filter(|(v, u)| v.is_none() && !**u)
+        {
+            *v = embeddings.next();
+        }
+
+        log::info!(target: "embedding",
+            action = "call_openai",
+            task = log::as_serde!(task),
+            elapsed = ai_elapsed,
+            tokens = used_tokens,
+            piece_at = piece_at,
+            kv = log::as_serde!(kv);
+            "",
+        );
+    }
+
+    res.embedding_ok = true;
+    // the unchanged units are stored already, the forgotten ones are not stored again.
+    let pending: Vec<(TEContentList, db::Embedding, Vec<f32>)> = unit_group
+        .into_iter()
+        .zip(docs.into_iter().zip(vectors))
+        .filter_map(|(unit, (doc, vectors))| vectors.map(|vectors| (unit.content, doc, vectors)))
+        .collect();
+    // the contents of the rows are encoded in one call, in the blocking pool if the group is large,
+    // see BLOCKING_ENCODE_BYTES.
+    let size = pending.iter().map(|(c, _, _)| content_bytes(c)).sum();
+    let encoded = encode_blocking(size, move || {
+        pending
+            .into_iter()
+            .map(|(content, mut doc, vectors)| {
+                ciborium::into_writer(&content, &mut doc.content).map(|_| (doc, vectors))
+            })
+            .collect::<Vec<_>>()
+    })
+    .await;
+
+    let mut rows: Vec<db::Embedding> = Vec::with_capacity(encoded.len());
+    let mut rows_vectors: Vec<Vec<f32>> = Vec::with_capacity(encoded.len());
+    for item in encoded {
+        let (mut doc, vectors) = match item {
+            Ok(item) => item,
+            Err(err) => {
+                res.errors.push(err.to_string());
+                log::error!(target: "embedding",
+                    action = "to_cbor",
+                    task = log::as_serde!(task);
+                    "{}", err,
+                );
+                continue;
+            }
+        };
+
+        doc.created_at = unix_ms() as i64;
+        rows.push(doc);
+        rows_vectors.push(vectors);
+    }
+    if rows.is_empty() {
+        return res;
+    }
+
+    // write all rows of the group in batches before upserting their points.
+    let start = Instant::now();
+    let saved = db::Embedding::save_batch(&app.scylla, &mut rows).await;
+    let scylla_elapsed = start.elapsed().as_millis() as u64;
+    if let Err(err) = saved {
+        res.errors.push(err.to_string());
+        log::error!(target: "embedding",
+            action = "to_scylla",
+            task = log::as_serde!(task),
+            piece_at = piece_at,
+            rows = rows.len(),
+            elapsed = scylla_elapsed;
+            "{}", err,
+        );
+        return res;
+    }
+    log::info!(target: "embedding",
+        action = "to_scylla",
+        task = log::as_serde!(task),
+        piece_at = piece_at,
+        rows = rows.len(),
+        elapsed = scylla_elapsed,
+        elapsed_per_row = scylla_elapsed / rows.len() as u64;
+        "",
+    );
+
+    let start = Instant::now();
+    let points: Vec<qdrant::PointStruct> = rows
+        .iter()
+        .zip(rows_vectors)
+        .map(|(doc, vectors)| doc.qdrant_point(app.qdrant.vector_name(), vectors))
+        .collect();
+    match app.qdrant.add_points(points).await {
+        Ok(()) => {
+            log::info!(target: "qdrant",
+                action = "to_qdrant",
+                task = log::as_serde!(task),
+                piece_at = piece_at,
+                points = rows.len(),
+                elapsed = start.elapsed().as_millis() as u64;
+                "",
+            )
+        }
+        Err(err) => {
+            res.errors.push(err.to_string());
+            log::error!(target: "qdrant",
+                action = "to_qdrant",
+                task = log::as_serde!(task),
+                piece_at = piece_at,
+                points = rows.len(),
+                elapsed = start.elapsed().as_millis() as u64;
+                "{}", err,
+            );
+            let uuids: Vec<uuid::Uuid> = rows.iter().map(|doc| doc.uuid).collect();
+            save_qdrant_pending(app, &ctx.rid, uuids, &err.to_string()).await;
+        }
+    }
+
+    res
+}
+
+// the stored state of a unit.
+#[derive(Debug, PartialEq)]
+enum StoredUnit {
+    Changed,         // new or changed content, should be embedded
+    Moved(Vec<f32>), // the same content of another group or version, the vector can be reused
+    Unchanged,       // the same row and point are stored, nothing to do
+    Forgotten,       // the row is tombstoned by an erasure request, never stored again
+}
+
+// compare the units with the stored rows (same hash and model) and points,
+// so the unchanged ones don't need to be embedded again. with `force`, only the
+// tombstones are checked.
+async fn stored_units(
+    app: &AppState,
+    rid: &str,
+    docs: &[db::Embedding],
+    force: bool,
+) -> Vec<StoredUnit> {
+    let mut existing: Vec<Option<db::Embedding>> = Vec::with_capacity(docs.len());
+    let mut same_content: Vec<uuid::Uuid> = Vec::new();
+    for doc in docs {
+        let mut row = db::Embedding::with_pk(doc.uuid);
+        let found = row
+            .get_one(
+                &app.scylla,
+                vec![
+                    "gid".to_string(),
+                    "version".to_string(),
+                    "hash".to_string(),
+                    "model".to_string(),
+                    "payload".to_string(),
+                    "deleted_at".to_string(),
+                ],
+            )
+            .await
+            .is_ok();
+        if found && row.deleted_at.is_some() {
+            existing.push(Some(row));
+        } else if found && !force && row.hash == doc.hash && row.model == doc.model {
+            same_content.push(doc.uuid);
+            existing.push(Some(row));
+        } else {
+            existing.push(None);
+        }
+    }
+    if same_content.is_empty() {
+        return docs
+            .iter()
+            .zip(existing)
+            .map(|(doc, row)| stored_unit(doc, row.as_ref(), None))
+            .collect();
+    }
+
+    let mut vectors = match app.qdrant.get_vectors(same_content).await {
+        Ok(vectors) => vectors,
+        Err(err) => {
+            log::warn!(target: "qdrant",
+                action = "get_vectors",
+                rid = rid;
+                "{}", err,
+            );
+            HashMap::new()
+        }
+    };
+    docs.iter()
+        .zip(existing)
+        .map(|(doc, row)| stored_unit(doc, row.as_ref(), vectors.remove(&doc.uuid)))
+        .collect()
+}
+
+fn stored_unit(
+    doc: &db::Embedding,
+    row: Option<&db::Embedding>,
+    vector: Option<Vec<f32>>,
+) -> StoredUnit {
+    match (row, vector) {
+        (Some(row), _) if row.deleted_at.is_some() => StoredUnit::Forgotten,
+        (Some(row), Some(_))
+            if row.gid == doc.gid && row.version == doc.version && row.payload == doc.payload =>
+        {
+            StoredUnit::Unchanged
+        }
+        (Some(_), Some(vector)) => StoredUnit::Moved(vector),
+        _ => StoredUnit::Changed,
+    }
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct EmbeddingPublicInput {
+    pub gid: PackObject<xid::Id>,       // group id, content belong to
+    pub cid: PackObject<xid::Id>,       // creation id
+    pub language: PackObject<Language>, // the target language translate to
+    #[validate(custom = "validate_version")]
+    pub version: u16,
+    pub retry: Option<bool>, // retry a failed copy
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PublicCopyOutput {
+    pub gid: PackObject<xid::Id>,
+    pub cid: PackObject<xid::Id>,
+    pub language: PackObject<Language>,
+    pub version: u16,
+    pub status: String,
+    pub points: u32,
+    pub updated_at: i64,
+    pub error: String,
+}
+
+impl PublicCopyOutput {
+    fn from<T>(to: &PackObject<T>, doc: db::PublicCopy) -> Self {
+        Self {
+            gid: to.with(doc.gid),
+            cid: to.with(doc.cid),
+            language: to.with(doc.language),
+            version: doc.version as u16,
+            status: doc.status,
+            points: doc.points as u32,
+            updated_at: doc.updated_at,
+            error: doc.error,
+        }
+    }
+}
+
+// a pending public copy is not started again within the in-flight window, a pending one not
+// updated for longer is stalled and is copied again.
+fn public_copy_running(doc: &db::PublicCopy, now: i64, in_flight: u64) -> bool {
+    let dedup = Dedup::check(
+        now - doc.updated_at,
+        false,
+        doc.status == db::PUBLIC_COPY_PENDING,
+        0,
+        in_flight,
+        false,
+    );
+    dedup == Dedup::InFlight
+}
+
+pub async fn public_status(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<EmbeddingPublicInput>,
+) -> Result<PackObject<SuccessResponse<PublicCopyOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+
+    let gid = *input.gid;
+    let cid = *input.cid;
+    let language = *input.language;
+
+    ctx.set_kvs(vec![
+        ("action", "get_public_status".into()),
+        ("gid", gid.to_string().into()),
+        ("cid", cid.to_string().into()),
+        ("language", language.to_639_3().into()),
+        ("version", input.version.into()),
+    ])
+    .await;
+
+    let mut doc = db::PublicCopy::with_pk(gid, cid, language, input.version as i16);
+    doc.get_one(&app.scylla, vec![]).await?;
+    Ok(to.with(SuccessResponse::new(PublicCopyOutput::from(&to, doc))))
+}
+
+pub async fn public(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<EmbeddingPublicInput>,
+) -> Result<PackObject<SuccessResponse<PublicCopyOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+    app.jobs.check_open()?;
+    app.jobs.check_capacity(JobKind::PublicCopy)?;
+
+    let gid = *input.gid;
+    let cid = *input.cid;
+    let language = *input.language;
+    let version = input.version as i16;
+    let retry = input.retry.unwrap_or(false);
+
+    ctx.set_kvs(vec![
+        ("action", "make_public".into()),
+        ("gid", gid.to_string().into()),
+        ("cid", cid.to_string().into()),
+        ("language", language.to_639_3().into()),
+        ("version", input.version.into()),
+        ("retry", retry.into()),
+    ])
+    .await;
+
+    let now = unix_ms() as i64;
+    let mut doc = db::PublicCopy::with_pk(gid, cid, language, version);
+    if doc.get_one(&app.scylla, vec![]).await.is_ok() {
+        // a running copy, or a failed copy that should be retried explicitly.
+        if public_copy_running(&doc, now, app.dedup.in_flight)
+            || (doc.status == db::PUBLIC_COPY_FAILED && !retry)
+        {
+            ctx.set("status", doc.status.clone().into()).await;
+            return Ok(to.with(SuccessResponse::new(PublicCopyOutput::from(&to, doc))));
+        }
+    }
+
+    let docs = db::Embedding::list_by_cid(
+        &app.scylla,
+        cid,
+        gid,
+        language,
+        version,
+        vec!["cid".to_string()],
+    )
+    .await?;
+    ctx.set("pieces", docs.len().into()).await;
+
+    let mut doc = db::PublicCopy::with_pk(gid, cid, language, version);
+    doc.status = db::PUBLIC_COPY_PENDING.to_string();
+    doc.points = docs.len() as i32;
+    doc.updated_at = now;
+    let mut cols = ColumnsMap::with_capacity(4);
+    cols.set_as("status", &doc.status);
+    cols.set_as("points", &doc.points);
+    cols.set_as("updated_at", &doc.updated_at);
+    cols.set_as("error", &doc.error);
+    doc.upsert_fields(&app.scylla, cols).await?;
 
     let rid = ctx.rid.clone();
     let points = docs.into_iter().map(|doc| doc.uuid).collect();
     let qdrant = app.qdrant.clone();
-    tokio::spawn(async move {
+    app.jobs.clone().spawn(JobKind::PublicCopy, async move {
         let start = Instant::now();
-        let tokio_embedding = app.embedding.clone();
+        let mut job = db::PublicCopy::with_pk(gid, cid, language, version);
+        let mut cols = ColumnsMap::with_capacity(4);
         match qdrant.copy_to_public(points).await {
-            Ok(()) => {
+            Ok(copied) => {
+                cols.set_as("status", &db::PUBLIC_COPY_DONE.to_string());
+                cols.set_as("points", &(copied as i32));
                 log::info!(target: "qdrant",
                     action = "to_public",
                     rid = rid,
                     gid = gid.to_string(),
                     cid = cid.to_string(),
-                    language = language.to_639_3().to_string(),
-                    elapsed = start.elapsed().as_millis() as u64;
-                    "success",
-                )
+                    language = language.to_639_3().to_string(),
+                    elapsed = start.elapsed().as_millis() as u64,
+                    copied = copied;
+                    "success",
+                )
+            }
+            Err(err) => {
+                cols.set_as("status", &db::PUBLIC_COPY_FAILED.to_string());
+                cols.set_as("error", &err.to_string());
+                log::error!(target: "qdrant",
+                    action = "to_public",
+                    rid = rid,
+                    gid = gid.to_string(),
+                    cid = cid.to_string(),
+                    language = language.to_639_3().to_string(),
+                    elapsed = start.elapsed().as_millis() as u64;
+                    "{}", err,
+                )
+            }
+        }
+
+        cols.set_as("updated_at", &(unix_ms() as i64));
+        if let Err(err) = job.upsert_fields(&app.scylla, cols).await {
+            log::error!(target: "qdrant",
+                action = "save_public_status",
+                rid = rid,
+                gid = gid.to_string(),
+                cid = cid.to_string(),
+                language = language.to_639_3().to_string();
+                "{}", err,
+            )
+        }
+    });
+
+    Ok(to.with(SuccessResponse::new(PublicCopyOutput::from(&to, doc))))
+}
+
+// remove the points of a creation from the public collection, the reverse of `public`.
+pub async fn unpublic(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<EmbeddingPublicInput>,
+) -> Result<PackObject<SuccessResponse<()>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+    app.jobs.check_open()?;
+
+    let gid = *input.gid;
+    let cid = *input.cid;
+    let language = *input.language;
+    let version = input.version as i16;
+
+    ctx.set_kvs(vec![
+        ("action", "make_unpublic".into()),
+        ("gid", gid.to_string().into()),
+        ("cid", cid.to_string().into()),
+        ("language", language.to_639_3().into()),
+        ("version", input.version.into()),
+    ])
+    .await;
+
+    let docs = db::Embedding::list_by_cid(
+        &app.scylla,
+        cid,
+        gid,
+        language,
+        version,
+        vec!["cid".to_string()],
+    )
+    .await?;
+    ctx.set("pieces", docs.len().into()).await;
+
+    let rid = ctx.rid.clone();
+    let points: Vec<uuid::Uuid> = docs.into_iter().map(|doc| doc.uuid).collect();
+    let qdrant = app.qdrant.clone();
+    app.jobs.clone().spawn(JobKind::PublicCopy, async move {
+        let start = Instant::now();
+        let mut job = db::PublicCopy::with_pk(gid, cid, language, version);
+        let mut cols = ColumnsMap::with_capacity(4);
+        let removed = points.len();
+        match qdrant.delete_public_points(points).await {
+            Ok(()) => {
+                cols.set_as("status", &db::PUBLIC_COPY_REMOVED.to_string());
+                cols.set_as("points", &(removed as i32));
+                cols.set_as("error", &"".to_string());
+                log::info!(target: "qdrant",
+                    action = "to_unpublic",
+                    rid = rid,
+                    gid = gid.to_string(),
+                    cid = cid.to_string(),
+                    language = language.to_639_3().to_string(),
+                    elapsed = start.elapsed().as_millis() as u64,
+                    removed = removed;
+                    "success",
+                )
+            }
+            Err(err) => {
+                cols.set_as("error", &err.to_string());
+                log::error!(target: "qdrant",
+                    action = "to_unpublic",
+                    rid = rid,
+                    gid = gid.to_string(),
+                    cid = cid.to_string(),
+                    language = language.to_639_3().to_string(),
+                    elapsed = start.elapsed().as_millis() as u64;
+                    "{}", err,
+                )
+            }
+        }
+
+        cols.set_as("updated_at", &(unix_ms() as i64));
+        if let Err(err) = job.upsert_fields(&app.scylla, cols).await {
+            log::error!(target: "qdrant",
+                action = "save_public_status",
+                rid = rid,
+                gid = gid.to_string(),
+                cid = cid.to_string(),
+                language = language.to_639_3().to_string();
+                "{}", err,
+            )
+        }
+    });
+
+    Ok(to.with(SuccessResponse::new(())))
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct EmbeddingDeleteInput {
+    pub gid: PackObject<xid::Id>, // group id, content belong to
+    pub cid: PackObject<xid::Id>, // creation id
+    pub language: Option<PackObject<Language>>, // delete all languages if not provided
+    #[validate(custom = "validate_version")]
+    pub version: Option<u16>, // delete all versions if not provided
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct DeleteStatus {
+    pub deleted: usize,
+    pub error: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct EmbeddingDeleteOutput {
+    pub scylla: DeleteStatus,
+    pub qdrant: DeleteStatus,
+    pub qdrant_public: DeleteStatus,
+}
+
+pub async fn delete(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<EmbeddingDeleteInput>,
+) -> Result<PackObject<SuccessResponse<EmbeddingDeleteOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+
+    let gid = *input.gid;
+    let cid = *input.cid;
+    let language = input.language.map(|v| v.unwrap());
+    let version = input.version.map(|v| v as i16);
+
+    ctx.set_kvs(vec![
+        ("action", "delete_embedding".into()),
+        ("gid", gid.to_string().into()),
+        ("cid", cid.to_string().into()),
+    ])
+    .await;
+    if let Some(language) = language {
+        ctx.set("language", language.to_639_3().into()).await;
+    }
+    if let Some(version) = version {
+        ctx.set("version", version.into()).await;
+    }
+
+    // the tombstoned rows are deleted too, their points were removed already.
+    let docs = db::Embedding::list_all_by_cid(
+        &app.scylla,
+        cid,
+        gid,
+        language,
+        version,
+        vec!["uuid".to_string()],
+    )
+    .await?;
+    ctx.set("pieces", docs.len().into()).await;
+
+    let points: Vec<uuid::Uuid> = docs.into_iter().map(|doc| doc.uuid).collect();
+    let mut output = EmbeddingDeleteOutput::default();
+    if points.is_empty() {
+        return Ok(to.with(SuccessResponse::new(output)));
+    }
+
+    // delete vectors first, so the scylla rows are still there to retry with if it failed.
+    let start = Instant::now();
+    match app.qdrant.delete_points(points.clone()).await {
+        Ok(()) => output.qdrant.deleted = points.len(),
+        Err(err) => {
+            log::error!(target: "qdrant",
+                action = "delete_points",
+                rid = ctx.rid,
+                gid = gid.to_string(),
+                cid = cid.to_string(),
+                elapsed = start.elapsed().as_millis() as u64;
+                "{}", err,
+            );
+            output.qdrant.error = err.to_string();
+        }
+    }
+
+    let start = Instant::now();
+    match app.qdrant.delete_public_points(points.clone()).await {
+        Ok(()) => output.qdrant_public.deleted = points.len(),
+        Err(err) => {
+            log::error!(target: "qdrant",
+                action = "delete_public_points",
+                rid = ctx.rid,
+                gid = gid.to_string(),
+                cid = cid.to_string(),
+                elapsed = start.elapsed().as_millis() as u64;
+                "{}", err,
+            );
+            output.qdrant_public.error = err.to_string();
+        }
+    }
+
+    if !output.qdrant.error.is_empty() || !output.qdrant_public.error.is_empty() {
+        output.scylla.error = "skipped because of qdrant error".to_string();
+    } else {
+        let start = Instant::now();
+        match db::Embedding::delete_by_uuids(&app.scylla, &points).await {
+            Ok(deleted) => output.scylla.deleted = deleted,
+            Err(err) => {
+                log::error!(target: "embedding",
+                    action = "delete_by_uuids",
+                    rid = ctx.rid,
+                    gid = gid.to_string(),
+                    cid = cid.to_string(),
+                    elapsed = start.elapsed().as_millis() as u64;
+                    "{}", err,
+                );
+                output.scylla.error = err.to_string();
+            }
+        }
+    }
+
+    ctx.set_kvs(vec![
+        ("scylla_deleted", output.scylla.deleted.into()),
+        ("qdrant_deleted", output.qdrant.deleted.into()),
+        ("qdrant_public_deleted", output.qdrant_public.deleted.into()),
+    ])
+    .await;
+    Ok(to.with(SuccessResponse::new(output)))
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct EmbeddingForgetOutput {
+    pub scylla: DeleteStatus, // the rows tombstoned, they are kept for audit
+    pub qdrant: DeleteStatus,
+    pub qdrant_public: DeleteStatus,
+}
+
+// forget the embeddings for an erasure request: the rows are tombstoned and kept for audit,
+// and the points are removed from both collections before the response, so they are excluded
+// from search at once. The tombstoned rows are hard-deleted by `delete` after the retention.
+// Forget again to retry the removal of the points if it failed, the tombstones are kept.
+pub async fn forget(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<EmbeddingDeleteInput>,
+) -> Result<PackObject<SuccessResponse<EmbeddingForgetOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+
+    let gid = *input.gid;
+    let cid = *input.cid;
+    let language = input.language.map(|v| v.unwrap());
+    let version = input.version.map(|v| v as i16);
+
+    ctx.set_kvs(vec![
+        ("action", "forget_embedding".into()),
+        ("gid", gid.to_string().into()),
+        ("cid", cid.to_string().into()),
+    ])
+    .await;
+    if let Some(language) = language {
+        ctx.set("language", language.to_639_3().into()).await;
+    }
+    if let Some(version) = version {
+        ctx.set("version", version.into()).await;
+    }
+
+    let docs = db::Embedding::list_all_by_cid(
+        &app.scylla,
+        cid,
+        gid,
+        language,
+        version,
+        vec!["uuid".to_string(), "deleted_at".to_string()],
+    )
+    .await?;
+    ctx.set("pieces", docs.len().into()).await;
+
+    let mut output = EmbeddingForgetOutput::default();
+    if docs.is_empty() {
+        return Ok(to.with(SuccessResponse::new(output)));
+    }
+
+    // tombstone the rows first, so the search excludes them even if the removal failed.
+    let now = unix_ms() as i64;
+    let mut points: Vec<uuid::Uuid> = Vec::with_capacity(docs.len());
+    for mut doc in docs {
+        points.push(doc.uuid);
+        if doc.deleted_at.is_some() {
+            continue;
+        }
+        if let Err(err) = doc.set_deleted(&app.scylla, now).await {
+            log::error!(target: "embedding",
+                action = "set_deleted",
+                rid = ctx.rid,
+                gid = gid.to_string(),
+                cid = cid.to_string();
+                "{}", err,
+            );
+            output.scylla.error = err.to_string();
+            break;
+        }
+        output.scylla.deleted += 1;
+    }
+
+    let start = Instant::now();
+    match app.qdrant.delete_points(points.clone()).await {
+        Ok(()) => output.qdrant.deleted = points.len(),
+        Err(err) => {
+            log::error!(target: "qdrant",
+                action = "delete_points",
+                rid = ctx.rid,
+                gid = gid.to_string(),
+                cid = cid.to_string(),
+                elapsed = start.elapsed().as_millis() as u64;
+                "{}", err,
+            );
+            output.qdrant.error = err.to_string();
+        }
+    }
+
+    let start = Instant::now();
+    match app.qdrant.delete_public_points(points.clone()).await {
+        Ok(()) => output.qdrant_public.deleted = points.len(),
+        Err(err) => {
+            log::error!(target: "qdrant",
+                action = "delete_public_points",
+                rid = ctx.rid,
+                gid = gid.to_string(),
+                cid = cid.to_string(),
+                elapsed = start.elapsed().as_millis() as u64;
+                "{}", err,
+            );
+            output.qdrant_public.error = err.to_string();
+        }
+    }
+
+    ctx.set_kvs(vec![
+        ("scylla_tombstoned", output.scylla.deleted.into()),
+        ("qdrant_deleted", output.qdrant.deleted.into()),
+        ("qdrant_public_deleted", output.qdrant_public.deleted.into()),
+    ])
+    .await;
+    Ok(to.with(SuccessResponse::new(output)))
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct ReembeddingInput {
+    pub gid: PackObject<xid::Id>,         // group id, content belong to
+    pub cid: Option<PackObject<xid::Id>>, // creation id
+    pub all: Option<bool>,                // re-embed all creations of the group if cid not provided
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ReembeddingOutput {
+    pub gid: PackObject<xid::Id>,
+    pub cid: PackObject<xid::Id>,
+    pub progress: i8,
+    pub updated_at: i64,
+    pub pieces: u32, // the rows listed so far, all rows of the group when the progress is 100
+    pub done: u32,
+    pub tokens: u32,
+    pub error: String,
+}
+
+impl ReembeddingOutput {
+    fn from<T>(to: &PackObject<T>, doc: db::Reembedding) -> Self {
+        Self {
+            gid: to.with(doc.gid),
+            cid: to.with(doc.cid),
+            progress: doc.progress,
+            updated_at: doc.updated_at,
+            pieces: doc.pieces as u32,
+            done: doc.done as u32,
+            tokens: doc.tokens as u32,
+            error: doc.error,
+        }
+    }
+}
+
+fn reembedding_pk(input: &ReembeddingInput) -> Result<(xid::Id, xid::Id), HTTPError> {
+    let gid = *input.gid.to_owned();
+    match input.cid.to_owned() {
+        Some(cid) => Ok((gid, cid.unwrap())),
+        None if input.all.unwrap_or(false) => Ok((gid, xid::Id::default())),
+        None => Err(HTTPError::new(
+            400,
+            "cid is required unless all is true".to_string(),
+        )),
+    }
+}
+
+// a reembedding job is not started again while another one is running: not finished, without
+// error and updated within the in-flight window.
+fn reembedding_running(doc: &db::Reembedding, now: i64, in_flight: u64) -> bool {
+    let dedup = Dedup::check(
+        now - doc.updated_at,
+        false,
+        doc.progress < 100 && doc.error.is_empty(),
+        0,
+        in_flight,
+        false,
+    );
+    dedup == Dedup::InFlight
+}
+
+pub async fn get_reembedding(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<ReembeddingInput>,
+) -> Result<PackObject<SuccessResponse<ReembeddingOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+    let (gid, cid) = reembedding_pk(&input)?;
+
+    ctx.set_kvs(vec![
+        ("action", "get_reembedding".into()),
+        ("gid", gid.to_string().into()),
+        ("cid", cid.to_string().into()),
+    ])
+    .await;
+
+    let mut doc = db::Reembedding::with_pk(gid, cid);
+    doc.get_one(&app.scylla, vec![]).await?;
+    Ok(to.with(SuccessResponse::new(ReembeddingOutput::from(&to, doc))))
+}
+
+// regenerate the vectors from the content kept in the embedding table, used for embedding model migrations.
+pub async fn reembed(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<ReembeddingInput>,
+) -> Result<PackObject<SuccessResponse<ReembeddingOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+    app.jobs.check_open()?;
+    app.jobs.check_capacity(JobKind::Reembedding)?;
+    let (gid, cid) = reembedding_pk(&input)?;
+
+    ctx.set_kvs(vec![
+        ("action", "reembed".into()),
+        ("gid", gid.to_string().into()),
+        ("cid", cid.to_string().into()),
+    ])
+    .await;
+
+    let now = unix_ms() as i64;
+    let mut doc = db::Reembedding::with_pk(gid, cid);
+    if doc.get_one(&app.scylla, vec![]).await.is_ok()
+        && reembedding_running(&doc, now, app.dedup.in_flight)
+    {
+        ctx.set("running", true.into()).await;
+        return Ok(to.with(SuccessResponse::new(ReembeddingOutput::from(&to, doc))));
+    }
+
+    let mut doc = db::Reembedding::with_pk(gid, cid);
+    doc.updated_at = now;
+    let mut cols = ColumnsMap::with_capacity(6);
+    cols.set_as("progress", &0i8);
+    cols.set_as("updated_at", &now);
+    cols.set_as("pieces", &0i32);
+    cols.set_as("done", &0i32);
+    cols.set_as("tokens", &0i32);
+    cols.set_as("error", &"".to_string());
+    doc.upsert_fields(&app.scylla, cols).await?;
+
+    let user = app.job_user(ctx.user);
+    let jobs = app.jobs.clone();
+    jobs.spawn(
+        JobKind::Reembedding,
+        reembedding(app, ctx.rid.clone(), user, gid, cid),
+    );
+
+    Ok(to.with(SuccessResponse::new(ReembeddingOutput::from(&to, doc))))
+}
+
+async fn reembedding(app: Arc<AppState>, rid: String, user: xid::Id, gid: xid::Id, cid: xid::Id) {
+    let start = Instant::now();
+    let mut job = db::Reembedding::with_pk(gid, cid);
+    log::info!(target: "reembedding",
+        action = "start_job",
+        rid = rid,
+        user = user.to_string(),
+        gid = gid.to_string(),
+        cid = cid.to_string();
+        "",
+    );
+
+    // the rows of a group are listed and re-embedded page by page, so that a large group is
+    // never loaded at once. `pieces` are the rows listed so far, the total is known at the end.
+    let mut pieces = 0usize;
+    let mut done = 0usize;
+    let mut total_tokens = 0usize;
+    let mut errors = 0usize;
+    let mut paging_state: Option<db::scylladb::Bytes> = None;
+    loop {
+        let page = if cid.is_zero() {
+            db::Embedding::list_by_gid_page(&app.scylla, gid, vec![], paging_state.take()).await
+        } else {
+            db::Embedding::list_by_cid_with(&app.scylla, cid, gid, None, None, vec![])
+                .await
+                .map(|docs| (docs, None))
+        };
+        let (docs, next) = match page {
+            Ok(page) => page,
+            Err(err) => {
+                let mut cols = ColumnsMap::with_capacity(2);
+                cols.set_as("updated_at", &(unix_ms() as i64));
+                cols.set_as("error", &err.to_string());
+                let _ = job.upsert_fields(&app.scylla, cols).await;
+
+                log::error!(target: "reembedding",
+                    action = "list_embedding",
+                    rid = rid,
+                    gid = gid.to_string(),
+                    cid = cid.to_string(),
+                    pieces = pieces;
+                    "{}", err,
+                );
+                return;
+            }
+        };
+        pieces += docs.len();
+
+        // run groups one by one, do not compete with the regular embedding jobs.
+        for mut group in group_rows(&rid, docs, app.ai.embedding_limits()) {
+            let ctx = ReqContext::new(rid.clone(), user, 0);
+            let input: Vec<String> = group.iter().map(|(_, text)| text.clone()).collect();
+            let res = app.ai.embedding(&ctx, &input).await;
+            let ai_elapsed = ctx.start.elapsed().as_millis() as u64;
+            let kv = ctx.get_kv().await;
+            let (used_tokens, embeddings) = match res {
+                Ok(res) => res,
+                Err(err) => {
+                    errors += 1;
+                    log::error!(target: "reembedding",
+                        action = "call_openai",
+                        rid = ctx.rid,
+                        gid = gid.to_string(),
+                        elapsed = ai_elapsed,
+                        kv = log::as_serde!(kv);
+                        "{}", err.to_string(),
+                    );
+                    continue;
+                }
+            };
+            total_tokens += used_tokens as usize;
+
+            let now = unix_ms() as i64;
+            let points: Vec<qdrant::PointStruct> = group
+                .iter_mut()
+                .zip(embeddings)
+                .map(|((doc, _), vectors)| {
+                    doc.model = openai::MODEL_EMBEDDING.to_string();
+                    doc.created_at = now;
+                    doc.qdrant_point(app.qdrant.vector_name(), vectors)
+                })
+                .collect();
+            match app.qdrant.add_points(points).await {
+                Ok(()) => {
+                    done += group.len();
+                    // keep the model of rows in sync with their points.
+                    for (doc, _) in group.iter_mut() {
+                        if let Err(err) = doc.save(&app.scylla).await {
+                            log::warn!(target: "reembedding",
+                                action = "to_scylla",
+                                rid = ctx.rid,
+                                uuid = doc.uuid.to_string();
+                                "{}", err,
+                            );
+                        }
+                    }
+                }
+                Err(err) => {
+                    errors += 1;
+                    log::error!(target: "qdrant",
+                        action = "to_qdrant",
+                        rid = ctx.rid,
+                        gid = gid.to_string(),
+                        elapsed = ctx.start.elapsed().as_millis() as u64 - ai_elapsed;
+                        "{}", err,
+                    );
+                    let uuids = group.iter().map(|(doc, _)| doc.uuid).collect();
+                    save_qdrant_pending(&app, &ctx.rid, uuids, &err.to_string()).await;
+                }
             }
+
+            let mut cols = ColumnsMap::with_capacity(5);
+            cols.set_as("updated_at", &(unix_ms() as i64));
+            cols.set_as("progress", &((done * 100 / pieces.max(1)).min(99) as i8));
+            cols.set_as("pieces", &(pieces as i32));
+            cols.set_as("done", &(done as i32));
+            cols.set_as("tokens", &(total_tokens as i32));
+            let _ = job.upsert_fields(&app.scylla, cols).await;
+
+            log::info!(target: "reembedding",
+                action = "call_openai",
+                rid = ctx.rid,
+                gid = gid.to_string(),
+                elapsed = ai_elapsed,
+                tokens = used_tokens,
+                total_elapsed = start.elapsed().as_millis(),
+                total_tokens = total_tokens,
+                kv = log::as_serde!(kv);
+                "{}/{}", done, pieces,
+            );
+        }
+
+        match next {
+            Some(next) => paging_state = Some(next),
+            None => break,
+        }
+    }
+
+    let mut cols = ColumnsMap::with_capacity(6);
+    cols.set_as("updated_at", &(unix_ms() as i64));
+    cols.set_as("progress", &100i8);
+    cols.set_as("pieces", &(pieces as i32));
+    cols.set_as("done", &(done as i32));
+    cols.set_as("tokens", &(total_tokens as i32));
+    if errors > 0 {
+        cols.set_as("error", &format!("{} groups failed", errors));
+    }
+    let _ = job.upsert_fields(&app.scylla, cols).await;
+
+    log::info!(target: "reembedding",
+        action = "finish_job",
+        rid = rid,
+        gid = gid.to_string(),
+        cid = cid.to_string(),
+        elapsed = start.elapsed().as_millis() as u64,
+        pieces = pieces,
+        done = done,
+        errors = errors,
+        total_tokens = total_tokens;
+        "",
+    );
+}
+
+// group the rows the same way as segment_for_embedding does, with their embedding strings.
+fn group_rows(
+    rid: &str,
+    docs: Vec<db::Embedding>,
+    limits: openai::EmbeddingLimits,
+) -> Vec<Vec<(db::Embedding, String)>> {
+    let mut groups: Vec<Vec<(db::Embedding, String)>> = Vec::new();
+    let mut group: Vec<(db::Embedding, String)> = Vec::new();
+    let mut group_tokens = 0usize;
+    for doc in docs {
+        let content: TEContentList = match cbor_from_slice(&doc.content) {
+            Ok(content) => content,
             Err(err) => {
-                log::error!(target: "qdrant",
-                    action = "to_public",
+                log::warn!(target: "embedding",
+                    action = "from_cbor",
                     rid = rid,
-                    gid = gid.to_string(),
-                    cid = cid.to_string(),
-                    language = language.to_639_3().to_string(),
-                    elapsed = start.elapsed().as_millis() as u64;
+                    uuid = doc.uuid.to_string();
                     "{}", err,
-                )
+                );
+                continue;
             }
+        };
+        let text = TEUnit { tokens: 0, content }.to_embedding_string();
+        let tokens = tokenizer::tokens_len(&text);
+        if !group.is_empty()
+            && (group_tokens + tokens > limits.max_tokens || group.len() >= limits.max_array)
+        {
+            groups.push(group);
+            group = Vec::new();
+            group_tokens = 0;
         }
-        let _ = tokio_embedding.as_str(); // avoid unused warning
-    });
+        group_tokens += tokens;
+        group.push((doc, text));
+    }
+    if !group.is_empty() {
+        groups.push(group);
+    }
+    groups
+}
 
-    Ok(to.with(SuccessResponse::new(())))
+// record the points failed to write to qdrant, they are re-inserted by the repair API.
+async fn save_qdrant_pending(app: &AppState, rid: &str, uuids: Vec<uuid::Uuid>, error: &str) {
+    let now = unix_ms() as i64;
+    for uuid in uuids {
+        let mut doc = db::QdrantPending::with_pk(uuid);
+        doc.created_at = now;
+        doc.error = error.to_string();
+        if let Err(err) = doc.save(&app.scylla).await {
+            log::error!(target: "qdrant",
+                action = "save_pending",
+                rid = rid,
+                uuid = uuid.to_string();
+                "{}", err,
+            );
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct RepairInput {
+    #[validate(range(min = 1, max = 1000))]
+    pub limit: Option<u16>, // the max number of pending points to repair, default 100
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct RepairOutput {
+    pub pending: usize,  // the number of pending points listed
+    pub repaired: usize, // the number of points re-inserted
+    pub missing: usize,  // the number of points whose rows were deleted or tombstoned, dropped
+    pub tokens: u32,
+    pub errors: Vec<String>,
+}
+
+// re-embed and re-insert the points failed to write to qdrant.
+pub async fn repair(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<RepairInput>,
+) -> Result<PackObject<SuccessResponse<RepairOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+
+    ctx.set("action", "repair_qdrant".into()).await;
+
+    let pending =
+        db::QdrantPending::list(&app.scylla, input.limit.unwrap_or(100) as i32, vec![]).await?;
+    let mut res = RepairOutput {
+        pending: pending.len(),
+        ..Default::default()
+    };
+    let mut docs: Vec<db::Embedding> = Vec::with_capacity(pending.len());
+    for mut p in pending {
+        let mut doc = db::Embedding::with_pk(p.uuid);
+        match doc.get_one(&app.scylla, vec![]).await {
+            Ok(()) if doc.deleted_at.is_none() => docs.push(doc),
+            Ok(()) => {
+                // the row was tombstoned, its point must not be added back.
+                p.delete(&app.scylla).await?;
+                res.missing += 1;
+            }
+            Err(err) => {
+                let err: HTTPError = err.into();
+                if err.code != 404 {
+                    return Err(err);
+                }
+                // the row was deleted, nothing to repair.
+                p.delete(&app.scylla).await?;
+                res.missing += 1;
+            }
+        }
+    }
+
+    let user = app.job_user(ctx.user);
+    for mut group in group_rows(&ctx.rid, docs, app.ai.embedding_limits()) {
+        let actx = ReqContext::new(ctx.rid.clone(), user, 0);
+        let input: Vec<String> = group.iter().map(|(_, text)| text.clone()).collect();
+        let (used_tokens, embeddings) = match app.ai.embedding(&actx, &input).await {
+            Ok(v) => v,
+            Err(err) => {
+                res.errors.push(err.to_string());
+                continue;
+            }
+        };
+        res.tokens += used_tokens;
+
+        let now = unix_ms() as i64;
+        let points: Vec<qdrant::PointStruct> = group
+            .iter_mut()
+            .zip(embeddings)
+            .map(|((doc, _), vectors)| {
+                doc.model = openai::MODEL_EMBEDDING.to_string();
+                doc.created_at = now;
+                doc.qdrant_point(app.qdrant.vector_name(), vectors)
+            })
+            .collect();
+        if let Err(err) = app.qdrant.add_points(points).await {
+            res.errors.push(err.to_string());
+            continue;
+        }
+
+        for (doc, _) in group.iter_mut() {
+            doc.save(&app.scylla).await?;
+            db::QdrantPending::with_pk(doc.uuid)
+                .delete(&app.scylla)
+                .await?;
+            res.repaired += 1;
+        }
+    }
+
+    ctx.set_kvs(vec![
+        ("pending", res.pending.into()),
+        ("repaired", res.repaired.into()),
+        ("missing", res.missing.into()),
+        ("tokens", res.tokens.into()),
+    ])
+    .await;
+    Ok(to.with(SuccessResponse::new(res)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::TEContent;
+
+    use super::*;
+
+    fn output(cid: xid::Id, score: f32) -> SearchOutput {
+        SearchOutput {
+            cid: PackObject::Json(cid),
+            score,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn push_dedup_works() {
+        let c1 = xid::new();
+        let c2 = xid::new();
+        let mut res: Vec<SearchOutput> = Vec::new();
+
+        push_dedup(&mut res, output(c1, 0.8));
+        push_dedup(&mut res, output(c2, 0.7));
+        push_dedup(&mut res, output(c1, 0.9));
+        push_dedup(&mut res, output(c2, 0.6));
+        assert_eq!(res.len(), 2);
+        assert_eq!(*res[0].cid, c1);
+        assert_eq!(res[0].score, 0.9);
+        assert_eq!(*res[1].cid, c2);
+        assert_eq!(res[1].score, 0.7);
+    }
+
+    #[test]
+    fn stored_unit_works() {
+        let gid = xid::new();
+        let mut doc = db::Embedding::from(xid::new(), Language::Eng, "1,2".to_string());
+        doc.gid = gid;
+        doc.version = 2;
+
+        assert_eq!(stored_unit(&doc, None, None), StoredUnit::Changed);
+        assert_eq!(
+            stored_unit(&doc, None, Some(vec![0.1])),
+            StoredUnit::Changed
+        );
+
+        let mut row = db::Embedding::with_pk(doc.uuid);
+        row.gid = gid;
+        row.version = 2;
+        assert_eq!(stored_unit(&doc, Some(&row), None), StoredUnit::Changed);
+        assert_eq!(
+            stored_unit(&doc, Some(&row), Some(vec![0.1])),
+            StoredUnit::Unchanged
+        );
+
+        row.version = 1;
+        assert_eq!(
+            stored_unit(&doc, Some(&row), Some(vec![0.1])),
+            StoredUnit::Moved(vec![0.1])
+        );
+
+        row.version = 2;
+        doc.payload = HashMap::from([("category".to_string(), "tech".to_string())]);
+        assert_eq!(
+            stored_unit(&doc, Some(&row), Some(vec![0.1])),
+            StoredUnit::Moved(vec![0.1])
+        );
+
+        // a forgotten unit stays forgotten, whatever its content and point.
+        row.deleted_at = Some(unix_ms() as i64);
+        assert_eq!(stored_unit(&doc, Some(&row), None), StoredUnit::Forgotten);
+        assert_eq!(
+            stored_unit(&doc, Some(&row), Some(vec![0.1])),
+            StoredUnit::Forgotten
+        );
+    }
+
+    #[test]
+    fn reembedding_running_works() {
+        let now = unix_ms() as i64;
+        let in_flight = 3600;
+        let mut doc = db::Reembedding::with_pk(xid::new(), xid::new());
+
+        doc.updated_at = now;
+        assert!(reembedding_running(&doc, now, in_flight));
+        doc.progress = 50;
+        assert!(reembedding_running(&doc, now + 1000, in_flight));
+
+        // stalled longer than the in-flight window.
+        assert!(!reembedding_running(&doc, now + 3600 * 1000, in_flight));
+
+        doc.error = "qdrant: timeout".to_string();
+        assert!(!reembedding_running(&doc, now + 1000, in_flight));
+
+        doc.error = "".to_string();
+        doc.progress = 100;
+        assert!(!reembedding_running(&doc, now + 1000, in_flight));
+    }
+
+    #[test]
+    fn public_copy_running_works() {
+        let now = unix_ms() as i64;
+        let in_flight = 3600;
+        let mut doc = db::PublicCopy::with_pk(xid::new(), xid::new(), Language::Eng, 1);
+
+        doc.status = db::PUBLIC_COPY_PENDING.to_string();
+        doc.updated_at = now;
+        assert!(public_copy_running(&doc, now + 1000, in_flight));
+
+        // stalled longer than the in-flight window.
+        assert!(!public_copy_running(&doc, now + 3600 * 1000, in_flight));
+
+        for status in [db::PUBLIC_COPY_DONE, db::PUBLIC_COPY_FAILED] {
+            doc.status = status.to_string();
+            assert!(!public_copy_running(&doc, now + 1000, in_flight));
+        }
+    }
+
+    #[test]
+    fn check_payload_works() {
+        let mut payload = BTreeMap::new();
+        assert!(check_payload(&payload).is_ok());
+
+        payload.insert("category".to_string(), "tech".to_string());
+        payload.insert("tag_2".to_string(), "rust".to_string());
+        assert!(check_payload(&payload).is_ok());
+
+        for key in ["", "Category", "2tag", "tag-2", "gid", "created_at"] {
+            let payload = BTreeMap::from([(key.to_string(), "x".to_string())]);
+            assert_eq!(check_payload(&payload).unwrap_err().code, 400, "{}", key);
+        }
+        let payload = BTreeMap::from([("a".repeat(33), "x".to_string())]);
+        assert!(check_payload(&payload).is_err());
+        let payload = BTreeMap::from([("tag".to_string(), "x".repeat(257))]);
+        assert!(check_payload(&payload).is_err());
+
+        let payload: BTreeMap<String, String> = (0..17)
+            .map(|i| (format!("tag{}", i), "x".to_string()))
+            .collect();
+        assert!(check_payload(&payload).is_err());
+    }
+
+    #[test]
+    fn search_cache_key_works() {
+        let empty = BTreeMap::new();
+        let key = |q: &'static str, limit: usize, with_vectors: bool| {
+            SearchCacheKey {
+                q,
+                gids: vec!["9m4e2mr0ui3e8a215n4g".to_string()],
+                public: false,
+                language: Some("eng"),
+                cid: None,
+                model: None,
+                limit,
+                offset: 0,
+                score_threshold: None,
+                with_vectors,
+                diversify: false,
+                mmr_lambda: SEARCH_MMR_LAMBDA,
+                must: &empty,
+                should: &empty,
+            }
+            .to_key()
+        };
+
+        let k = key("hello world", 3, false);
+        assert!(k.starts_with("SEARCH:"));
+        assert_eq!(k.len(), "SEARCH:".len() + 32);
+        assert_eq!(k, key("hello world", 3, false));
+        assert_ne!(k, key("hello world!", 3, false));
+        assert_ne!(k, key("hello world", 5, false));
+        assert_ne!(k, key("hello world", 3, true));
+    }
+
+    #[test]
+    fn repack_outputs_works() {
+        let gid = xid::new();
+        let res = vec![SearchOutput {
+            gid: PackObject::Json(gid),
+            cid: PackObject::Json(gid),
+            language: PackObject::Json(Language::Eng),
+            score: 0.9,
+            ..Default::default()
+        }];
+
+        let res = repack_outputs(&PackObject::Cbor(()), res);
+        let data = cbor_to_vec(&res).unwrap();
+        let res: Vec<SearchOutput> = cbor_from_slice(&data).unwrap();
+        assert_eq!(res[0].gid, PackObject::Cbor(gid));
+        assert_eq!(res[0].language, PackObject::Cbor(Language::Eng));
+        assert_eq!(res[0].score, 0.9);
+
+        let res = repack_outputs(&PackObject::Json(()), res);
+        assert_eq!(res[0].cid, PackObject::Json(gid));
+        assert_eq!(res[0].language, PackObject::Json(Language::Eng));
+    }
+
+    #[test]
+    fn search_filter_works() {
+        let empty = BTreeMap::new();
+        assert_eq!(search_filter(&[], None, None, None, &empty, &empty), None);
+
+        let keyword = |key: &str, value: &str| {
+            qdrant::Condition::from(qdrant::FieldCondition {
+                key: key.to_string(),
+                r#match: Some(qdrant::Match {
+                    match_value: Some(qdrant::MatchValue::Keyword(value.to_string())),
+                }),
+                ..qdrant::FieldCondition::default()
+            })
+        };
+        let gid = xid::new();
+        let cid = xid::new();
+        let f = search_filter(
+            &[gid],
+            Some(Language::Eng),
+            Some(cid),
+            Some("text-embedding-ada-002".to_string()),
+            &empty,
+            &empty,
+        )
+        .unwrap();
+        assert_eq!(
+            f,
+            qdrant::Filter {
+                should: Vec::new(),
+                must: vec![
+                    keyword("gid", &gid.to_string()),
+                    keyword("language", "eng"),
+                    keyword("cid", &cid.to_string()),
+                    keyword("model", "text-embedding-ada-002"),
+                ],
+                must_not: Vec::new(),
+            }
+        );
+
+        let f = search_filter(&[], Some(Language::Zho), None, None, &empty, &empty).unwrap();
+        assert_eq!(f.must, vec![keyword("language", "zho")]);
+
+        let must = BTreeMap::from([("category".to_string(), "tech".to_string())]);
+        let should = BTreeMap::from([
+            ("tag".to_string(), "rust".to_string()),
+            ("topic".to_string(), "ai".to_string()),
+        ]);
+        let f = search_filter(&[], None, None, None, &must, &should).unwrap();
+        assert_eq!(f.must, vec![keyword("category", "tech")]);
+        assert_eq!(
+            f.should,
+            vec![keyword("tag", "rust"), keyword("topic", "ai")]
+        );
+        let f = search_filter(&[], None, None, None, &empty, &should).unwrap();
+        assert!(f.must.is_empty());
+
+        // the gids are OR-ed in must, apart from the custom should fields
+        let gid2 = xid::new();
+        let f = search_filter(
+            &[gid, gid2],
+            Some(Language::Eng),
+            None,
+            None,
+            &empty,
+            &should,
+        )
+        .unwrap();
+        assert_eq!(
+            f.must,
+            vec![
+                any_condition(vec![
+                    keyword("gid", &gid.to_string()),
+                    keyword("gid", &gid2.to_string()),
+                ]),
+                keyword("language", "eng"),
+            ]
+        );
+        assert_eq!(
+            f.should,
+            vec![keyword("tag", "rust"), keyword("topic", "ai")]
+        );
+    }
+
+    #[test]
+    fn merge_gids_works() {
+        let to = PackObject::Json(());
+        let (g1, g2) = (xid::new(), xid::new());
+        assert!(merge_gids(None, vec![]).is_empty());
+        assert_eq!(merge_gids(Some(g1), vec![]), vec![g1]);
+        assert_eq!(
+            merge_gids(Some(g1), vec![to.with(g2), to.with(g1), to.with(g2)]),
+            vec![g1, g2]
+        );
+        assert_eq!(merge_gids(None, vec![to.with(g2)]), vec![g2]);
+    }
+
+    #[test]
+    fn sort_by_score_works() {
+        let c1 = xid::new();
+        let c2 = xid::new();
+        let c3 = xid::new();
+        let mut res = vec![output(c1, 0.71), output(c2, 0.92), output(c3, 0.8)];
+
+        sort_by_score(&mut res);
+        assert_eq!(*res[0].cid, c2);
+        assert_eq!(*res[1].cid, c3);
+        assert_eq!(*res[2].cid, c1);
+    }
+
+    #[test]
+    fn mmr_rerank_works() {
+        // 0 and 1 are near duplicates, 2 is less relevant but different.
+        let scores = vec![0.9, 0.89, 0.8];
+        let vectors = vec![vec![1.0, 0.0], vec![0.99, 0.01], vec![0.0, 1.0]];
+
+        assert_eq!(mmr_rerank(&scores, &vectors, 1.0), vec![0, 1, 2]);
+        assert_eq!(mmr_rerank(&scores, &vectors, 0.5), vec![0, 2, 1]);
+        assert_eq!(mmr_rerank(&[], &[], 0.5), Vec::<usize>::new());
+
+        // missing vectors do not penalize
+        let vectors = vec![vec![], vec![], vec![]];
+        assert_eq!(mmr_rerank(&scores, &vectors, 0.5), vec![0, 1, 2]);
+
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[1.0, 0.0]), 1.0);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]), 0.0);
+        assert_eq!(cosine_similarity(&[], &[1.0]), 0.0);
+    }
+
+    #[test]
+    fn truncate_embedding_input_works() {
+        let input = "Hello world. ".repeat(10);
+        let (res, truncated) = truncate_embedding_input(input.clone(), 100);
+        assert_eq!(res, input);
+        assert!(truncated.is_none());
+
+        let (res, truncated) = truncate_embedding_input(input.clone(), 10);
+        let (tokens, truncated_tokens) = truncated.unwrap();
+        assert_eq!(tokens, openai::embedding_encoding().tokens_len(&input));
+        assert!(tokens > 10);
+        assert!(truncated_tokens <= 10);
+        assert!(input.starts_with(&res));
+        assert_eq!(
+            truncated_tokens,
+            openai::embedding_encoding().tokens_len(&res)
+        );
+    }
+
+    #[test]
+    fn search_snippet_works() {
+        let content: TEContentList = vec![
+            TEContent {
+                id: "abc".to_string(),
+                texts: vec!["Hello world".to_string()],
+            },
+            TEContent {
+                id: "efg".to_string(),
+                texts: vec!["this is a test.".to_string()],
+            },
+        ];
+        let data = cbor_to_vec(&content).unwrap();
+
+        assert_eq!(
+            search_snippet(&data, None).unwrap(),
+            "Hello world. this is a test."
+        );
+        assert_eq!(search_snippet(&data, Some(2)).unwrap(), "Hello world");
+        assert!(search_snippet(b"not cbor", None).is_err());
+    }
+
+    #[test]
+    fn search_done_works() {
+        // got enough creations
+        assert!(search_done(3, 3, 9, 9));
+        // all candidates reach the threshold, fetch next round
+        assert!(!search_done(1, 3, 9, 9));
+        // candidates below the threshold are not returned by qdrant, stop with fewer results
+        assert!(search_done(1, 3, 4, 9));
+        // no candidate reaches the threshold, return empty result
+        assert!(search_done(0, 3, 0, 9));
+    }
 }
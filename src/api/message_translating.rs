@@ -1,39 +1,52 @@
 use axum::{extract::State, Extension};
 use serde::{Deserialize, Serialize};
-use std::{str::FromStr, sync::Arc, time::Instant};
+use std::{
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::sync::{mpsc, Semaphore};
 use validator::Validate;
 
-use axum_web::context::ReqContext;
+use axum_web::context::{unix_ms, ReqContext};
 use axum_web::erring::{HTTPError, SuccessResponse};
 use axum_web::object::{cbor_from_slice, cbor_to_vec, PackObject};
 
-use crate::api::{AppState, TEContentList, TESegmenter, PARALLEL_WORKS};
+use crate::api::{
+    check_content_size, job_deadline, tokenize_content, validate_version, AppState, JobKind,
+    SegmentTimings, TEContentInput, TEContentList, TESegmenter, CREATE_EXISTS, CREATE_IN_FLIGHT,
+    CREATE_STARTED, PARALLEL_WORKS,
+};
 
 use crate::lang::Language;
 use crate::openai;
-use crate::tokenizer;
+use crate::trace;
 
 #[derive(Debug, Deserialize, Validate)]
 pub struct MessageTranslatingInput {
     pub id: PackObject<xid::Id>,        // message id
     pub language: PackObject<Language>, // the target language translate to
-    #[validate(range(min = 1, max = 32767))]
+    #[validate(custom = "validate_version")]
     pub version: u16,
 
     pub from_language: Option<PackObject<Language>>,
     pub model: Option<String>,
     pub context: Option<String>,
-    pub content: Option<PackObject<Vec<u8>>>,
+    pub content: Option<TEContentInput>,
+    #[validate(range(min = 1))]
+    pub deadline: Option<u64>, // seconds, the job is stopped after it, within the configured ceiling
 }
 
 #[derive(Debug, Default, Deserialize, Serialize)]
 pub struct MessageTranslatingOutput {
     pub model: String,
     pub progress: i8,
+    pub started_at: i64,
     pub tokens: u32,
     pub error: String,
     pub content: PackObject<Vec<u8>>,
+    #[serde(default)]
+    pub status: String, // the outcome of create, empty in the stored doc
 }
 
 fn mt_key(id: &xid::Id, lang: &Language, ver: u16) -> String {
@@ -82,6 +95,8 @@ pub async fn create(
 ) -> Result<PackObject<SuccessResponse<MessageTranslatingOutput>>, HTTPError> {
     let (to, input) = to.unpack();
     input.validate()?;
+    app.jobs.check_open()?;
+    app.jobs.check_capacity(JobKind::MessageTranslating)?;
 
     let id = *input.id;
     let target_language = *input.language;
@@ -94,12 +109,11 @@ pub async fn create(
         || from_language == Language::Und
         || target_language == Language::Und
     {
-        return Err(HTTPError::new(
+        return Err(HTTPError::localized(
             400,
-            format!(
-                "can not translate from '{}' to '{}'",
-                from_language, target_language
-            ),
+            "untranslatable",
+            &ctx.lang,
+            &[&from_language, &target_language],
         ));
     }
 
@@ -113,32 +127,41 @@ pub async fn create(
     ])
     .await;
 
-    let content: TEContentList =
-        cbor_from_slice(&input.content.unwrap_or_default()).map_err(|e| HTTPError {
-            code: 400,
-            message: format!("Invalid content: {}", e),
-            data: None,
-        })?;
+    let deadline = job_deadline(&app.deadline, input.deadline);
+    ctx.set("deadline", deadline.as_secs().into()).await;
+
+    let content = input
+        .content
+        .unwrap_or_default()
+        .decode(app.normalize_text)?;
     if content.is_empty() {
-        return Err(HTTPError::new(
-            400,
-            "Empty content to translate".to_string(),
-        ));
+        return Err(HTTPError::localized(400, "empty_content", &ctx.lang, &[]));
     }
+    check_content_size(&content, &app.max_content)?;
 
     let key = mt_key(&id, &target_language, input.version);
     if let Ok(data) = app.redis.get_data(&key).await {
-        ctx.set("exists", true.into()).await;
-        let doc: MessageTranslatingOutput = cbor_from_slice(&data).map_err(|e| HTTPError {
+        let mut doc: MessageTranslatingOutput = cbor_from_slice(&data).map_err(|e| HTTPError {
             code: 500,
             message: format!("Invalid content: {}", e),
             data: None,
         })?;
+        doc.status = if doc.progress < 100 && doc.error.is_empty() {
+            CREATE_IN_FLIGHT.to_string()
+        } else {
+            CREATE_EXISTS.to_string()
+        };
+        ctx.set_kvs(vec![
+            ("exists", true.into()),
+            ("in_flight", (doc.status == CREATE_IN_FLIGHT).into()),
+        ])
+        .await;
         return Ok(to.with(SuccessResponse::new(doc)));
     }
 
     let doc = MessageTranslatingOutput {
         model: model.to_string(),
+        started_at: unix_ms() as i64,
         ..Default::default()
     };
     let data = cbor_to_vec(&doc).map_err(|e| HTTPError {
@@ -147,25 +170,49 @@ pub async fn create(
         data: None,
     })?;
 
-    match app.redis.new_data(&key, data, 600 * 1000).await {
+    match app
+        .redis
+        .new_data(&key, data, app.redis.data_ttl_ms())
+        .await
+    {
         Err(err) => Err(HTTPError::new(500, err.to_string())),
-        Ok(false) => Ok(to.with(SuccessResponse::new(doc))),
+        // another request created it just now.
+        Ok(false) => {
+            ctx.set_kvs(vec![("exists", true.into()), ("in_flight", true.into())])
+                .await;
+            Ok(to.with(SuccessResponse::new(MessageTranslatingOutput {
+                status: CREATE_IN_FLIGHT.to_string(),
+                ..doc
+            })))
+        }
         Ok(true) => {
-            tokio::spawn(translate(
+            let span_attrs = vec![("cid", id.to_string()), ("model", model.to_string())];
+            let user = app.job_user(ctx.user);
+            let jobs = app.jobs.clone();
+            let job = translate(
                 app,
                 ctx.rid.clone(),
-                ctx.user,
+                user,
                 TParams {
                     id,
                     version: input.version as i16,
                     language: target_language,
                     content,
+                    started_at: doc.started_at,
+                    deadline,
                 },
                 input.context.unwrap_or_default(),
                 from_language,
                 model,
-            ));
-            Ok(to.with(SuccessResponse::new(doc)))
+            );
+            jobs.spawn(
+                JobKind::MessageTranslating,
+                trace::in_span("message_translating", span_attrs, job),
+            );
+            Ok(to.with(SuccessResponse::new(MessageTranslatingOutput {
+                status: CREATE_STARTED.to_string(),
+                ..doc
+            })))
         }
     }
 }
@@ -175,20 +222,24 @@ pub(crate) struct TParams {
     pub language: Language,
     pub version: i16,
     pub content: TEContentList,
+    pub started_at: i64,
+    pub deadline: Duration,
 }
 
 async fn translate(
     app: Arc<AppState>,
     rid: String,
     user: xid::Id,
-    te: TParams,
+    mut te: TParams,
     context: String,
     origin_language: Language,
     model: openai::AIModel,
 ) {
-    let tokio_translating = app.translating.clone();
-
-    let content = te.content.segment(&model, tokenizer::tokens_len);
+    let segment_model = model.clone();
+    let content = tokenize_content(&mut te.content, move |c| {
+        c.segment(&segment_model, segment_model.encoding().tokens_len_fn())
+    })
+    .await;
     let pieces = content.len();
     let start = Instant::now();
 
@@ -214,7 +265,7 @@ async fn translate(
         let tx = tx.clone();
         let sem = semaphore.clone();
         let context = context.clone();
-        tokio::spawn(async move {
+        trace::spawn(async move {
             if let Ok(permit) = sem.acquire().await {
                 let ctx = ReqContext::new(rid, user, 0);
                 match app
@@ -222,6 +273,7 @@ async fn translate(
                     .translate(
                         &ctx,
                         &model,
+                        &openai::Sampling::default(),
                         &context,
                         origin,
                         lang,
@@ -248,21 +300,55 @@ async fn translate(
     let mut total_tokens: usize = 0;
     let mut progress = 0usize;
     let key = mt_key(&te.id, &te.language, te.version as u16);
+    // refresh the TTL on every update, so that a long running job will not expire.
+    let ttl = Some(app.redis.data_ttl_ms());
     let mut doc = MessageTranslatingOutput {
         model: model.to_string(),
+        started_at: te.started_at,
         ..Default::default()
     };
     let mut res_list: Vec<TEContentList> = Vec::with_capacity(pieces);
     res_list.resize(pieces, vec![]);
+    let mut timings = SegmentTimings::with_capacity(pieces);
+
+    let deadline = tokio::time::Instant::now() + te.deadline;
+    loop {
+        let (i, ctx, res) = match app.jobs.until(deadline, rx.recv()).await {
+            Ok(Some(item)) => item,
+            Ok(None) => break,
+            Err(reason) => {
+                // stop the pieces not started yet, and keep the finished ones.
+                semaphore.close();
+                let content_list: TEContentList = res_list.into_iter().flatten().collect();
+                if let Ok(content) = cbor_to_vec(&content_list) {
+                    doc.content = PackObject::Cbor(content);
+                }
+                doc.error = reason.to_string();
+                if let Ok(data) = cbor_to_vec(&doc) {
+                    let _ = app.redis.update_data(&key, data, ttl).await;
+                }
+
+                log::warn!(target: "message_translating",
+                    action = "deadline",
+                    rid = &rid,
+                    id = te.id.to_string(),
+                    elapsed = start.elapsed().as_millis() as u64,
+                    pieces = pieces,
+                    finished = progress,
+                    total_tokens = total_tokens;
+                    "{}", reason,
+                );
+                return;
+            }
+        };
 
-    while let Some((i, ctx, res)) = rx.recv().await {
         let ai_elapsed = ctx.start.elapsed().as_millis() as u64;
         let kv = ctx.get_kv().await;
         if let Err(err) = res {
             doc.error = err.to_string();
             doc.progress = 0;
             if let Ok(data) = cbor_to_vec(&doc) {
-                let _ = app.redis.update_data(&key, data).await;
+                let _ = app.redis.update_data(&key, data, ttl).await;
             }
 
             log::error!(target: "message_translating",
@@ -283,11 +369,12 @@ async fn translate(
         total_tokens += used_tokens as usize;
         progress += 1;
         res_list[i] = content;
+        timings.push(i, ai_elapsed, used_tokens);
 
         doc.progress = (progress * 100 / pieces) as i8;
         doc.tokens = total_tokens as u32;
         if let Ok(data) = cbor_to_vec(&doc) {
-            let _ = app.redis.update_data(&key, data).await;
+            let _ = app.redis.update_data(&key, data, ttl).await;
         }
 
         log::info!(target: "message_translating",
@@ -317,7 +404,7 @@ async fn translate(
         doc.error = err.to_string();
         doc.progress = 0;
         if let Ok(data) = cbor_to_vec(&doc) {
-            let _ = app.redis.update_data(&key, data).await;
+            let _ = app.redis.update_data(&key, data, ttl).await;
         }
 
         log::warn!(target: "message_translating",
@@ -349,7 +436,7 @@ async fn translate(
             );
         }
         Ok(data) => {
-            match app.redis.update_data(&key, data).await {
+            match app.redis.update_data(&key, data, ttl).await {
                 Err(err) => {
                     log::error!(target: "message_translating",
                         action = "to_redis",
@@ -361,6 +448,16 @@ async fn translate(
                     );
                 }
                 Ok(_) => {
+                    if let Some(summary) = timings.summary() {
+                        log::log!(target: "message_translating", timings.level(),
+                            action = "segment_timings",
+                            rid = &rid,
+                            cid = te.id.to_string(),
+                            summary = log::as_serde!(summary);
+                            "",
+                        );
+                    }
+                    trace::set_attribute("tokens", total_tokens as i64);
                     log::info!(target: "message_translating",
                         action = "finish_job",
                         rid = rid,
@@ -374,6 +471,4 @@ async fn translate(
             };
         }
     }
-
-    let _ = tokio_translating.as_str(); // avoid unused warning
 }
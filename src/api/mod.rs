@@ -1,24 +1,58 @@
-use axum::extract::State;
-use axum_web::object::PackObject;
+use axum::extract::{Query, State};
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum_web::context::unix_ms;
+use axum_web::erring::{HTTPError, SuccessResponse};
+use axum_web::object::{cbor_from_slice, cbor_to_vec, PackObject};
 use finl_unicode::categories::CharacterCategories;
 use isolang::Language;
-use serde::{Deserialize, Serialize};
-use std::sync::Arc;
-
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::{
+    collections::HashMap,
+    future::Future,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::{oneshot, watch};
+use tokio::time::{sleep, timeout};
+use validator::ValidationError;
+
+use crate::conf;
 use crate::db::{self, qdrant};
-use crate::lang::LanguageDetector;
+use crate::lang::{normalize_lang, tag_script, LanguageDetector};
+use crate::metrics::{self, Metrics};
 use crate::openai;
+use crate::tokenizer;
 
+pub mod creation;
 pub mod embedding;
 pub mod message_translating;
 pub mod summarizing;
+pub mod tokenize;
 pub mod translating;
+pub mod usage;
 
 pub const APP_NAME: &str = env!("CARGO_PKG_NAME");
 pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 pub(crate) static PARALLEL_WORKS: usize = 8;
 
+// the TTL of a job lock, the running job renews it every third of the TTL.
+const JOB_LOCK_TTL_MS: u64 = 60_000;
+
+// healthz should not be blocked by a slow dependency.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+// the results of the dependency probes.
+const PROBE_OK: &str = "ok";
+const PROBE_ERROR: &str = "error";
+const PROBE_TIMED_OUT: &str = "timeout";
+const PROBE_SKIPPED: &str = "skipped";
+
 // dashes (------) is a horizontal rule, work as a top section separator
 static SECTION_SEPARATOR: &str = "------";
 
@@ -30,9 +64,6 @@ pub(crate) static SUMMARIZE_HIGH_TOKENS: usize = 12000;
 // https://community.openai.com/t/embedding-text-length-vs-accuracy/96564
 static EMBEDDING_SECTION_TOKENS: usize = 600;
 static EMBEDDING_HIGH_TOKENS: usize = 800;
-// https://learn.microsoft.com/zh-cn/azure/ai-services/openai/how-to/switching-endpoints#azure-openai-embeddings-multiple-input-support
-static EMBEDDING_MAX_ARRAY: usize = 16;
-static EMBEDDING_MAX_TOKENS: usize = 7000;
 
 #[derive(Clone)]
 pub struct AppState {
@@ -41,8 +72,245 @@ pub struct AppState {
     pub scylla: Arc<db::scylladb::ScyllaDB>,
     pub redis: Arc<db::redis::Redis>,
     pub qdrant: Arc<qdrant::Qdrant>,
-    pub translating: Arc<String>, // keep the number of concurrent translating tasks
-    pub embedding: Arc<String>,   // keep the number of concurrent embedding tasks
+    // the shared client for the outbound requests other than the AI services, e.g. callbacks.
+    #[allow(dead_code)]
+    pub http: reqwest::Client,
+    pub system_user: xid::Id, // the owner of the jobs not started by a user
+    pub dedup: conf::Dedup,   // the windows to reuse the existing results
+    pub max_tokens: conf::MaxTokens, // the max tokens of the content to start a job
+    pub max_content: conf::MaxContent, // the max nodes and bytes of the content to start a job
+    pub deadline: conf::Deadline, // the deadline of the jobs
+    pub normalize_text: bool, // normalize the content texts before tokenizing
+    pub reduce_strategy: conf::ReduceStrategy, // the default reduce strategy of summarizing
+    pub reduce_branching: usize, // the max summaries reduced in one call of the hierarchical reduce
+    pub jobs: Arc<JobTracker>, // the running background jobs by kind
+    pub metrics: Arc<Metrics>, // the Prometheus metrics, rendered by `metrics`
+}
+
+impl AppState {
+    // the owner of a job started by the request user, the system user when there is no user,
+    // it is the user attributed in the AI requests and counted in the counters.
+    pub fn job_user(&self, user: xid::Id) -> xid::Id {
+        if user.is_zero() {
+            self.system_user
+        } else {
+            user
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobKind {
+    Translating,
+    MessageTranslating,
+    Summarizing,
+    Embedding,
+    Reembedding,
+    PublicCopy,
+    DeleteVersion,
+}
+
+impl JobKind {
+    pub const ALL: [JobKind; 7] = [
+        JobKind::Translating,
+        JobKind::MessageTranslating,
+        JobKind::Summarizing,
+        JobKind::Embedding,
+        JobKind::Reembedding,
+        JobKind::PublicCopy,
+        JobKind::DeleteVersion,
+    ];
+
+    // the jobs calling the chat models, and the jobs writing the Qdrant points.
+    pub const TRANSLATING: [JobKind; 3] = [
+        JobKind::Translating,
+        JobKind::MessageTranslating,
+        JobKind::Summarizing,
+    ];
+    pub const EMBEDDING: [JobKind; 4] = [
+        JobKind::Embedding,
+        JobKind::Reembedding,
+        JobKind::PublicCopy,
+        JobKind::DeleteVersion,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobKind::Translating => "translating",
+            JobKind::MessageTranslating => "message_translating",
+            JobKind::Summarizing => "summarizing",
+            JobKind::Embedding => "embedding",
+            JobKind::Reembedding => "reembedding",
+            JobKind::PublicCopy => "public_copy",
+            JobKind::DeleteVersion => "delete_version",
+        }
+    }
+}
+
+// the background jobs are spawned through the tracker, it counts the running jobs by kind
+// for healthz, the metrics, the admission control and the graceful shutdown. on shutdown,
+// the tracker is closed to reject the new jobs, then the jobs still running after the grace
+// period are aborted.
+#[derive(Debug)]
+pub struct JobTracker {
+    running: [AtomicI64; JobKind::ALL.len()],
+    limits: [i64; JobKind::ALL.len()], // 0 for no limit
+    retry_after: u64,
+    closed: AtomicBool,
+    aborted: watch::Sender<bool>,
+}
+
+impl Default for JobTracker {
+    fn default() -> Self {
+        Self {
+            running: Default::default(),
+            limits: Default::default(),
+            retry_after: 0,
+            closed: AtomicBool::new(false),
+            aborted: watch::channel(false).0,
+        }
+    }
+}
+
+impl JobTracker {
+    pub fn new(cfg: &conf::MaxJobs) -> Self {
+        let mut tracker = Self::default();
+        for kind in JobKind::ALL {
+            tracker.limits[kind as usize] = match kind {
+                JobKind::Translating => cfg.translating,
+                JobKind::MessageTranslating => cfg.message_translating,
+                JobKind::Summarizing => cfg.summarizing,
+                JobKind::Embedding => cfg.embedding,
+                JobKind::Reembedding => cfg.reembedding,
+                JobKind::PublicCopy => cfg.public_copy,
+                JobKind::DeleteVersion => 0,
+            } as i64;
+        }
+        tracker.retry_after = cfg.retry_after;
+        tracker
+    }
+
+    // the job is counted from now on, not from when the runtime polls it.
+    pub fn spawn<F>(self: &Arc<Self>, kind: JobKind, job: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let guard = self.start(kind);
+        tokio::spawn(async move {
+            job.await;
+            drop(guard);
+        });
+    }
+
+    fn start(self: &Arc<Self>, kind: JobKind) -> JobGuard {
+        self.running[kind as usize].fetch_add(1, Ordering::Relaxed);
+        JobGuard {
+            tracker: self.clone(),
+            kind,
+        }
+    }
+
+    // 503 after the shutdown started, the client should retry with another instance.
+    pub fn check_open(&self) -> Result<(), HTTPError> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(HTTPError::new(
+                503,
+                "the server is shutting down".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    // 429 with the Retry-After hint if the running jobs of the kind reached the limit.
+    // the check and the spawn are not atomic, a burst may overshoot the limit a little.
+    pub fn check_capacity(&self, kind: JobKind) -> Result<(), HTTPError> {
+        if !self.is_saturated(kind) {
+            return Ok(());
+        }
+
+        let mut err = HTTPError::new(
+            429,
+            format!("too many running {} jobs, retry later", kind.as_str()),
+        );
+        err.data = Some(serde_json::json!({
+            "kind": kind.as_str(),
+            "queue_depth": self.running(kind),
+            "limit": self.limits[kind as usize],
+            "total_running": self.total(),
+            "retry_after": self.retry_after,
+        }));
+        Err(err)
+    }
+
+    pub fn is_saturated(&self, kind: JobKind) -> bool {
+        let limit = self.limits[kind as usize];
+        limit > 0 && self.running(kind) >= limit
+    }
+
+    pub fn saturated(&self) -> Vec<&'static str> {
+        JobKind::ALL
+            .iter()
+            .filter(|k| self.is_saturated(**k))
+            .map(|k| k.as_str())
+            .collect()
+    }
+
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+
+    // the running jobs stop waiting for their pieces and record ABORTED_BY_SHUTDOWN.
+    pub fn abort(&self) {
+        self.close();
+        self.aborted.send_replace(true);
+    }
+
+    pub async fn aborted(&self) {
+        let mut rx = self.aborted.subscribe();
+        let _ = rx.wait_for(|aborted| *aborted).await;
+    }
+
+    // wait for the future until the deadline, or the abort of the shutdown.
+    pub async fn until<F: Future>(
+        &self,
+        deadline: tokio::time::Instant,
+        fut: F,
+    ) -> Result<F::Output, &'static str> {
+        tokio::select! {
+            res = tokio::time::timeout_at(deadline, fut) => res.map_err(|_| DEADLINE_EXCEEDED),
+            _ = self.aborted() => Err(ABORTED_BY_SHUTDOWN),
+        }
+    }
+
+    pub fn running(&self, kind: JobKind) -> i64 {
+        self.running[kind as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self, kinds: &[JobKind]) -> i64 {
+        kinds.iter().map(|k| self.running(*k)).sum()
+    }
+
+    pub fn total(&self) -> i64 {
+        self.sum(&JobKind::ALL)
+    }
+
+    pub fn gauges(&self) -> Vec<(&'static str, i64)> {
+        JobKind::ALL
+            .iter()
+            .map(|k| (k.as_str(), self.running(*k)))
+            .collect()
+    }
+}
+
+struct JobGuard {
+    tracker: Arc<JobTracker>,
+    kind: JobKind,
+}
+
+impl Drop for JobGuard {
+    fn drop(&mut self) {
+        self.tracker.running[self.kind as usize].fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -53,8 +321,11 @@ pub struct AppVersion {
 
 #[derive(Serialize, Deserialize)]
 pub struct AppInfo {
-    pub tokio_translating_tasks: i64, // the number of concurrent translating tasks
-    pub tokio_embedding_tasks: i64,   // the number of concurrent embedding tasks
+    pub tokio_translating_tasks: i64, // the number of concurrent translating, summarizing tasks
+    pub tokio_embedding_tasks: i64,   // the number of concurrent embedding, qdrant tasks
+    pub running_jobs: HashMap<String, i64>, // the running background jobs by kind
+    pub saturated_jobs: Vec<String>,  // the job kinds at their limits, rejected with 429
+    pub lang_cache_hits: u64,         // the language detections served from the cache
 
     // https://docs.rs/scylla/latest/scylla/struct.Metrics.html
     pub scylla_latency_avg_ms: u64,
@@ -65,6 +336,37 @@ pub struct AppInfo {
     pub scylla_errors_iter_num: u64,
     pub scylla_queries_iter_num: u64,
     pub scylla_retries_num: u64,
+    pub scylla_ttl: HashMap<String, u32>, // seconds, the TTL of the rows written per table
+
+    // -1 and "unknown" if Qdrant does not respond in time
+    pub qdrant_points_num: i64,
+    pub qdrant_status: String,
+    pub qdrant_public_points_num: i64,
+    pub qdrant_public_status: String,
+    pub qdrant_errors_num: u64, // the failed operations in the last 5 minutes
+
+    // "ok", "error" or "timeout", "skipped" with `skip_probes`
+    pub scylla_probe: String,
+    pub qdrant_probe: String,
+    pub redis_probe: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct HealthzParams {
+    pub skip_probes: Option<bool>, // skip the probes for the frequent load balancer checks
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReadyInfo {
+    pub scylla: String,
+    pub qdrant: String,
+    pub redis: String,
+}
+
+impl ReadyInfo {
+    pub fn is_ready(&self) -> bool {
+        self.scylla == PROBE_OK && self.qdrant == PROBE_OK && self.redis == PROBE_OK
+    }
 }
 
 pub async fn version(to: PackObject<()>, State(_): State<Arc<AppState>>) -> PackObject<AppVersion> {
@@ -74,11 +376,46 @@ pub async fn version(to: PackObject<()>, State(_): State<Arc<AppState>>) -> Pack
     })
 }
 
-pub async fn healthz(to: PackObject<()>, State(app): State<Arc<AppState>>) -> PackObject<AppInfo> {
+// the probes run concurrently, each is bounded by PROBE_TIMEOUT.
+// the stats of the private Qdrant collection work as its probe.
+pub async fn healthz(
+    to: PackObject<()>,
+    State(app): State<Arc<AppState>>,
+    Query(params): Query<HealthzParams>,
+) -> PackObject<AppInfo> {
     let m = app.scylla.metrics();
+    let (qs, qps, scylla_probe, redis_probe) = if params.skip_probes.unwrap_or(false) {
+        (
+            qdrant::CollectionStats::skipped(),
+            qdrant::CollectionStats::skipped(),
+            PROBE_SKIPPED.to_string(),
+            PROBE_SKIPPED.to_string(),
+        )
+    } else {
+        tokio::join!(
+            app.qdrant.collection_stats(false, PROBE_TIMEOUT),
+            app.qdrant.collection_stats(true, PROBE_TIMEOUT),
+            probe(app.scylla.ping()),
+            probe(app.redis.ping()),
+        )
+    };
+    let qdrant_probe = qdrant_probe(&qs);
     to.with(AppInfo {
-        tokio_translating_tasks: Arc::strong_count(&app.translating) as i64 - 1,
-        tokio_embedding_tasks: Arc::strong_count(&app.embedding) as i64 - 1,
+        tokio_translating_tasks: app.jobs.sum(&JobKind::TRANSLATING),
+        tokio_embedding_tasks: app.jobs.sum(&JobKind::EMBEDDING),
+        running_jobs: app
+            .jobs
+            .gauges()
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect(),
+        saturated_jobs: app
+            .jobs
+            .saturated()
+            .into_iter()
+            .map(|k| k.to_string())
+            .collect(),
+        lang_cache_hits: app.ld.cache_hits(),
         scylla_latency_avg_ms: m.get_latency_avg_ms().unwrap_or(0),
         scylla_latency_p99_ms: m.get_latency_percentile_ms(99.0f64).unwrap_or(0),
         scylla_latency_p90_ms: m.get_latency_percentile_ms(90.0f64).unwrap_or(0),
@@ -87,9 +424,144 @@ pub async fn healthz(to: PackObject<()>, State(app): State<Arc<AppState>>) -> Pa
         scylla_errors_iter_num: m.get_errors_iter_num(),
         scylla_queries_iter_num: m.get_queries_iter_num(),
         scylla_retries_num: m.get_retries_num(),
+        scylla_ttl: app.scylla.ttls().clone(),
+        qdrant_points_num: qs.points,
+        qdrant_status: qs.status,
+        qdrant_public_points_num: qps.points,
+        qdrant_public_status: qps.status,
+        qdrant_errors_num: app.qdrant.errors_num(),
+        scylla_probe,
+        qdrant_probe,
+        redis_probe,
     })
 }
 
+// the Prometheus metrics in the text format, with the ScyllaDB metrics of healthz.
+pub async fn metrics(State(app): State<Arc<AppState>>) -> Result<impl IntoResponse, HTTPError> {
+    let body = app
+        .metrics
+        .render(&app.scylla.metrics(), &app.jobs.gauges())?;
+    Ok(([(header::CONTENT_TYPE, metrics::CONTENT_TYPE)], body))
+}
+
+// 503 if a dependency is not reachable, for the readiness check of the orchestrator.
+pub async fn readyz(
+    to: PackObject<()>,
+    State(app): State<Arc<AppState>>,
+) -> Result<PackObject<ReadyInfo>, HTTPError> {
+    let (qs, scylla, redis) = tokio::join!(
+        app.qdrant.collection_stats(false, PROBE_TIMEOUT),
+        probe(app.scylla.ping()),
+        probe(app.redis.ping()),
+    );
+    let info = ReadyInfo {
+        scylla,
+        qdrant: qdrant_probe(&qs),
+        redis,
+    };
+
+    if !info.is_ready() {
+        let mut err = HTTPError::new(503, "Service not ready".to_string());
+        err.data = serde_json::to_value(&info).ok();
+        return Err(err);
+    }
+    Ok(to.with(info))
+}
+
+// re-read the config file and swap the credentials and the deployments of the AI services,
+// on SIGHUP or by the admin route. Nothing is swapped if the config is invalid.
+pub(crate) fn reload_ai(app: &AppState) -> Result<openai::ReloadSummary, HTTPError> {
+    let res = conf::Conf::new()
+        .map_err(anyhow::Error::from)
+        .and_then(|cfg| app.ai.reload(&cfg.ai));
+    match res {
+        Err(err) => {
+            log::error!(target: "reload",
+                action = "reload_ai";
+                "{}", err,
+            );
+            Err(HTTPError::new(500, format!("reload refused: {}", err)))
+        }
+        Ok(summary) => {
+            log::info!(target: "reload",
+                action = "reload_ai",
+                added = log::as_serde!(summary.added),
+                removed = log::as_serde!(summary.removed),
+                deployments = summary.deployments;
+                "",
+            );
+            Ok(summary)
+        }
+    }
+}
+
+pub async fn reload(
+    to: PackObject<()>,
+    State(app): State<Arc<AppState>>,
+) -> Result<PackObject<SuccessResponse<openai::ReloadSummary>>, HTTPError> {
+    let summary = reload_ai(&app)?;
+    Ok(to.with(SuccessResponse::new(summary)))
+}
+
+async fn probe<F>(fut: F) -> String
+where
+    F: Future<Output = anyhow::Result<()>>,
+{
+    match timeout(PROBE_TIMEOUT, fut).await {
+        Ok(Ok(_)) => PROBE_OK,
+        Ok(Err(_)) => PROBE_ERROR,
+        Err(_) => PROBE_TIMED_OUT,
+    }
+    .to_string()
+}
+
+// the stats can not tell an error from a timeout.
+fn qdrant_probe(stats: &qdrant::CollectionStats) -> String {
+    if stats.is_unknown() {
+        PROBE_ERROR.to_string()
+    } else {
+        PROBE_OK.to_string()
+    }
+}
+
+// the decision on a create request for a doc that may exist.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Dedup {
+    Run,      // no reusable doc, run the job
+    Rerun,    // a recent result exists but the request is forced, run the job again
+    Exists,   // a recent result exists, reuse it
+    InFlight, // a job of the doc is running, do not start another one
+}
+
+impl Dedup {
+    // `age` is the ms since the doc was updated, `finished` and `running` are the states of
+    // a doc without error, `window` and `in_flight` are the dedup windows in seconds.
+    pub fn check(
+        age: i64,
+        finished: bool,
+        running: bool,
+        window: u64,
+        in_flight: u64,
+        force: bool,
+    ) -> Self {
+        if running && age < in_flight as i64 * 1000 {
+            return Dedup::InFlight;
+        }
+        if finished && age < window as i64 * 1000 {
+            return if force { Dedup::Rerun } else { Dedup::Exists };
+        }
+        Dedup::Run
+    }
+
+    pub fn status(&self) -> &'static str {
+        match self {
+            Dedup::Exists => CREATE_EXISTS,
+            Dedup::InFlight => CREATE_IN_FLIGHT,
+            Dedup::Run | Dedup::Rerun => CREATE_STARTED,
+        }
+    }
+}
+
 pub(crate) struct TEParams {
     pub gid: xid::Id,
     pub cid: xid::Id,
@@ -98,10 +570,503 @@ pub(crate) struct TEParams {
     pub content: TEContentList,
 }
 
+// the error of a job stopped by its deadline, the finished pieces are kept.
+pub(crate) const DEADLINE_EXCEEDED: &str = "deadline exceeded";
+pub(crate) const ABORTED_BY_SHUTDOWN: &str = "aborted by shutdown";
+
+// the deadline of a job, the requested seconds within the ceiling, or the default.
+pub(crate) fn job_deadline(cfg: &conf::Deadline, requested: Option<u64>) -> Duration {
+    let secs = requested.unwrap_or(cfg.default);
+    if cfg.max > 0 {
+        Duration::from_secs(secs.min(cfg.max))
+    } else {
+        Duration::from_secs(secs)
+    }
+}
+
+// reject the content larger than the max tokens before its job is started, 0 for no limit.
+// the error data carries the estimated tokens, so the client can split the content.
+pub(crate) fn check_content_tokens(tokens: usize, max_tokens: usize) -> Result<(), HTTPError> {
+    if max_tokens == 0 || tokens <= max_tokens {
+        return Ok(());
+    }
+
+    let mut err = HTTPError::new(
+        413,
+        format!(
+            "Content too large, {} tokens exceeds the limit {}",
+            tokens, max_tokens
+        ),
+    );
+    err.data = Some(serde_json::json!({
+        "tokens": tokens,
+        "max_tokens": max_tokens,
+    }));
+    Err(err)
+}
+
+// check the size of the content before tokenizing it, the content is cloned through segmenting.
+pub(crate) fn check_content_size(
+    content: &TEContentList,
+    max: &conf::MaxContent,
+) -> Result<(), HTTPError> {
+    let nodes = content.len();
+    if max.nodes > 0 && nodes > max.nodes {
+        let mut err = HTTPError::new(
+            413,
+            format!(
+                "Content too large, {} nodes exceeds the limit {}",
+                nodes, max.nodes
+            ),
+        );
+        err.data = Some(serde_json::json!({
+            "nodes": nodes,
+            "max_nodes": max.nodes,
+        }));
+        return Err(err);
+    }
+
+    let bytes = content_bytes(content);
+    if max.bytes > 0 && bytes > max.bytes {
+        let mut err = HTTPError::new(
+            413,
+            format!(
+                "Content too large, {} bytes exceeds the limit {}",
+                bytes, max.bytes
+            ),
+        );
+        err.data = Some(serde_json::json!({
+            "bytes": bytes,
+            "max_bytes": max.bytes,
+        }));
+        return Err(err);
+    }
+    Ok(())
+}
+
+// the tokens of all texts of the content.
+pub(crate) fn content_tokens(content: &TEContentList, tokens_len: fn(&str) -> usize) -> usize {
+    content
+        .iter()
+        .flat_map(|c| c.texts.iter())
+        .map(|t| tokens_len(t))
+        .sum()
+}
+
+// the bytes of all texts of the content.
+pub(crate) fn content_bytes(content: &TEContentList) -> usize {
+    content
+        .iter()
+        .flat_map(|c| c.texts.iter())
+        .map(|t| t.len())
+        .sum()
+}
+
+// the content larger than this is tokenized in the blocking pool, encoding it holds the BPE lock
+// and would stall the async worker and all the other tasks on it.
+pub(crate) const BLOCKING_TOKENIZE_BYTES: usize = 64 * 1024;
+
+// run the tokenizing `f` on the content, in the blocking pool if the content is large.
+// all the texts of the content are tokenized in one blocking call, the content is moved
+// to the blocking thread and back.
+pub(crate) async fn tokenize_content<T, F>(content: &mut TEContentList, f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce(&TEContentList) -> T + Send + 'static,
+{
+    if content_bytes(content) <= BLOCKING_TOKENIZE_BYTES {
+        return f(content);
+    }
+
+    let input = std::mem::take(content);
+    let (input, res) = tokio::task::spawn_blocking(move || {
+        let res = f(&input);
+        (input, res)
+    })
+    .await
+    .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()));
+    *content = input;
+    res
+}
+
+// the content larger than this is encoded to CBOR in the blocking pool. the encoding is
+// synchronous and grows linearly with the texts, a document of some megabytes holds the async
+// worker for milliseconds and stalls all the other tasks on it, e.g. the pieces still waiting
+// for their responses. below it, a blocking call costs more than the encoding itself.
+// `encode_blocking_timings` prints the encoding time by size to measure it on the target hosts.
+pub(crate) const BLOCKING_ENCODE_BYTES: usize = 256 * 1024;
+
+// run the encoding `f` of `size` bytes of texts, in the blocking pool if it is large.
+pub(crate) async fn encode_blocking<T, F>(size: usize, f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    if size <= BLOCKING_ENCODE_BYTES {
+        return f();
+    }
+
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+}
+
+// the context of a background job, attached to every log of the job as `task`,
+// so that the logs of all segments of the job can be grouped.
+#[derive(Serialize, Debug, Clone)]
+pub(crate) struct TaskContext {
+    pub rid: String,
+    pub user: String,
+    pub gid: String,
+    pub cid: String,
+    pub language: String,
+    pub version: i16,
+}
+
+impl TaskContext {
+    pub fn new(rid: &str, user: xid::Id, te: &TEParams) -> Self {
+        Self {
+            rid: rid.to_string(),
+            user: user.to_string(),
+            gid: te.gid.to_string(),
+            cid: te.cid.to_string(),
+            language: te.language.to_639_3().to_string(),
+            version: te.version,
+        }
+    }
+}
+
+// the timings of a job with fewer segments are logged at the debug level.
+const SEGMENT_TIMINGS_INFO_PIECES: usize = 10;
+
+// the elapsed time and tokens of every AI call of a job, summarized when the job finishes
+// to tell a few stragglers from a uniform slowness.
+#[derive(Debug, Default)]
+pub(crate) struct SegmentTimings(Vec<(usize, u64, u32)>); // (piece_at, elapsed_ms, tokens)
+
+#[derive(Serialize, Debug, PartialEq)]
+pub(crate) struct SegmentTimingsSummary {
+    pub segments: usize,
+    pub min_ms: u64,
+    pub p50_ms: u64,
+    pub max_ms: u64,
+    pub slowest_piece: usize,
+    pub slowest_tokens: u32,
+}
+
+impl SegmentTimings {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self(Vec::with_capacity(capacity))
+    }
+
+    pub fn push(&mut self, piece_at: usize, elapsed_ms: u64, tokens: u32) {
+        self.0.push((piece_at, elapsed_ms, tokens));
+    }
+
+    pub fn level(&self) -> log::Level {
+        if self.0.len() >= SEGMENT_TIMINGS_INFO_PIECES {
+            log::Level::Info
+        } else {
+            log::Level::Debug
+        }
+    }
+
+    pub fn summary(&self) -> Option<SegmentTimingsSummary> {
+        let slowest = self.0.iter().max_by_key(|(_, elapsed, _)| *elapsed)?;
+        let mut elapsed: Vec<u64> = self.0.iter().map(|(_, elapsed, _)| *elapsed).collect();
+        elapsed.sort_unstable();
+        Some(SegmentTimingsSummary {
+            segments: elapsed.len(),
+            min_ms: elapsed[0],
+            p50_ms: elapsed[(elapsed.len() - 1) / 2],
+            max_ms: slowest.1,
+            slowest_piece: slowest.0,
+            slowest_tokens: slowest.2,
+        })
+    }
+}
+
+// count the AI calls and tokens of a finished job for the user and the group,
+// the job is not failed by the counter, errors are only logged.
+pub(crate) async fn incr_counter(
+    app: &AppState,
+    kind: db::CounterKind,
+    task: &TaskContext,
+    user: xid::Id,
+    gid: xid::Id,
+    calls: usize,
+    tokens: usize,
+) {
+    if calls == 0 && tokens == 0 {
+        return;
+    }
+
+    let doc = db::Counter::with_pk(app.job_user(user), gid);
+    let daily = db::UsageDaily::with_pk(gid, db::day_of(unix_ms() as i64));
+    let (calls, tokens) = (calls as i64, tokens as i64);
+    let (res, daily_res) = tokio::join!(
+        doc.incr(&app.scylla, kind, calls, tokens),
+        daily.incr(&app.scylla, kind, calls, tokens),
+    );
+    for (table, res) in [("counter", res), ("usage_daily", daily_res)] {
+        if let Err(err) = res {
+            log::warn!(target: "counter",
+                action = "incr",
+                table = table,
+                kind = kind.as_str(),
+                task = log::as_serde!(task),
+                calls = calls,
+                tokens = tokens;
+                "{}", err,
+            );
+        }
+    }
+}
+
+// the status of a finished job in its event.
+pub(crate) const JOB_DONE: &str = "done";
+pub(crate) const JOB_FAILED: &str = "failed";
+
+// the event of a finished job, published to the Redis channel in CBOR,
+// the ids are bytes and the language is the ISO 639-3 code.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JobEvent {
+    pub kind: String, // "translating", "summarizing" or "embedding"
+    pub gid: PackObject<xid::Id>,
+    pub cid: PackObject<xid::Id>,
+    pub language: PackObject<Language>,
+    pub version: i16,
+    pub status: String, // "done" or "failed"
+    pub tokens: u32,
+}
+
+impl JobEvent {
+    pub(crate) fn new(kind: db::CounterKind, te: &TEParams, status: &str, tokens: usize) -> Self {
+        Self {
+            kind: kind.as_str().to_string(),
+            gid: PackObject::Cbor(te.gid),
+            cid: PackObject::Cbor(te.cid),
+            language: PackObject::Cbor(te.language),
+            version: te.version,
+            status: status.to_string(),
+            tokens: tokens as u32,
+        }
+    }
+}
+
+// publish the event of a finished job, best-effort: errors are only logged.
+pub(crate) async fn publish_event(app: &AppState, task: &TaskContext, event: JobEvent) {
+    let channel = app.redis.events_channel();
+    if channel.is_empty() {
+        return;
+    }
+
+    let res = match cbor_to_vec(&event) {
+        Ok(data) => app
+            .redis
+            .publish(channel, data)
+            .await
+            .map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+    if let Err(err) = res {
+        log::warn!(target: "events",
+            action = "publish",
+            kind = event.kind,
+            status = event.status,
+            task = log::as_serde!(task);
+            "{}", err,
+        );
+    }
+}
+
+// a cross-replica lock of a job in Redis, so two replicas do not run the same job.
+// it is renewed in the background until released or dropped,
+// the lock of a crashed replica expires after JOB_LOCK_TTL_MS.
+pub(crate) struct JobLock {
+    redis: Arc<db::redis::Redis>,
+    key: String,
+    token: String,
+    stop: oneshot::Sender<()>,
+}
+
+impl JobLock {
+    // None if the lock is held by another job.
+    pub async fn acquire(
+        redis: Arc<db::redis::Redis>,
+        key: String,
+        token: String,
+    ) -> anyhow::Result<Option<Self>> {
+        if !redis.acquire_lock(&key, &token, JOB_LOCK_TTL_MS).await? {
+            return Ok(None);
+        }
+
+        let (stop, mut stopped) = oneshot::channel::<()>();
+        let (r, k, t) = (redis.clone(), key.clone(), token.clone());
+        tokio::spawn(async move {
+            let interval = Duration::from_millis(JOB_LOCK_TTL_MS / 3);
+            loop {
+                tokio::select! {
+                    _ = &mut stopped => return,
+                    _ = sleep(interval) => match r.renew_lock(&k, &t, JOB_LOCK_TTL_MS).await {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            log::warn!(target: "job_lock",
+                                action = "renew",
+                                key = k,
+                                rid = t;
+                                "the lock is lost",
+                            );
+                            return;
+                        }
+                        Err(err) => {
+                            log::warn!(target: "job_lock",
+                                action = "renew",
+                                key = k,
+                                rid = t;
+                                "{}", err,
+                            );
+                        }
+                    },
+                }
+            }
+        });
+
+        Ok(Some(Self {
+            redis,
+            key,
+            token,
+            stop,
+        }))
+    }
+
+    pub async fn release(self) {
+        let _ = self.stop.send(());
+        if let Err(err) = self.redis.release_lock(&self.key, &self.token).await {
+            log::warn!(target: "job_lock",
+                action = "release",
+                key = self.key,
+                rid = self.token;
+                "{}", err,
+            );
+        }
+    }
+}
+
+// the lock key of a job, by its kind and the primary key of its result.
+pub(crate) fn job_lock_key(
+    kind: &str,
+    gid: xid::Id,
+    cid: xid::Id,
+    language: Language,
+    version: i16,
+) -> String {
+    format!(
+        "LOCK:{}:{}:{}:{}:{}",
+        kind,
+        gid,
+        cid,
+        language.to_639_3(),
+        version
+    )
+}
+
+// the version columns are SMALLINT, a version should fit in i16.
+pub(crate) const MAX_VERSION: u16 = i16::MAX as u16;
+
+// validate the creation version of an input, used with `#[validate(custom = "validate_version")]`.
+pub(crate) fn validate_version(version: u16) -> Result<(), ValidationError> {
+    if (1..=MAX_VERSION).contains(&version) {
+        return Ok(());
+    }
+
+    let mut err = ValidationError::new("version");
+    err.message = Some(format!("version should be in 1..={}, got {}", MAX_VERSION, version).into());
+    Err(err)
+}
+
+// deserialize a language code with `normalize_lang`, so that the common variants
+// (ISO 639-1 codes, BCP-47 tags) are accepted, used with `#[serde(deserialize_with)]`.
+pub(crate) fn deserialize_language<'de, D>(
+    deserializer: D,
+) -> Result<PackObject<Language>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    pack_language(&s).map_err(de::Error::custom)
+}
+
+fn pack_language(s: &str) -> Result<PackObject<Language>, String> {
+    let lang = normalize_lang(s).ok_or_else(|| format!("unknown language: {:?}", s))?;
+    match s.len() {
+        3 => Ok(PackObject::Cbor(lang)),
+        _ => Ok(PackObject::Json(lang)),
+    }
+}
+
+// a target language with the script of its tag, e.g. "zh-Hant" or "zh-TW",
+// deserialized as `deserialize_language` does.
+#[derive(Debug, Clone, Default)]
+pub struct LanguageTag {
+    pub language: PackObject<Language>,
+    pub script: &'static str, // "Hans" or "Hant" for Chinese, empty if the tag has no script
+}
+
+impl Deref for LanguageTag {
+    type Target = Language;
+
+    fn deref(&self) -> &Self::Target {
+        &self.language
+    }
+}
+
+impl<'de> Deserialize<'de> for LanguageTag {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Ok(LanguageTag {
+            language: pack_language(&s).map_err(de::Error::custom)?,
+            script: tag_script(&s),
+        })
+    }
+}
+
+// the outcomes of a create request, as the `status` of its output:
+// "started", a new job is started;
+// "exists", a recent result exists and is returned, no job is started;
+// "in_flight", a job of the same doc is running, the client should poll `get` for its result.
+pub(crate) const CREATE_STARTED: &str = "started";
+pub(crate) const CREATE_EXISTS: &str = "exists";
+pub(crate) const CREATE_IN_FLIGHT: &str = "in_flight";
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TEOutput {
     pub cid: PackObject<xid::Id>,                // document id
     pub detected_language: PackObject<Language>, // the origin language detected.
+    #[serde(default)]
+    pub status: String,     // CREATE_STARTED, CREATE_EXISTS or CREATE_IN_FLIGHT
+}
+
+// the estimated prompt tokens and cost of a job, without calling the AI service.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EstimateOutput {
+    pub segments: u32,
+    pub prompt_tokens: u32,
+    pub cost: f64, // in USD
+}
+
+impl EstimateOutput {
+    // price is in USD per 1K tokens.
+    pub fn new(segments: usize, prompt_tokens: usize, price: f64) -> Self {
+        EstimateOutput {
+            segments: segments as u32,
+            prompt_tokens: prompt_tokens as u32,
+            cost: prompt_tokens as f64 * price / 1000.0,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -112,6 +1077,45 @@ pub struct TEContent {
 
 pub type TEContentList = Vec<TEContent>;
 
+// the content in request, a JSON client can send the TEContentList directly,
+// otherwise it should be encoded in CBOR (a base64url string in JSON request).
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum TEContentInput {
+    List(TEContentList),
+    Cbor(PackObject<Vec<u8>>),
+}
+
+impl Default for TEContentInput {
+    fn default() -> Self {
+        TEContentInput::List(Vec::new())
+    }
+}
+
+impl TEContentInput {
+    // decode the content, and normalize its texts if `normalize` is true,
+    // so the invisible characters are neither tokenized nor sent to the model.
+    pub fn decode(self, normalize: bool) -> Result<TEContentList, HTTPError> {
+        let mut list: TEContentList = match self {
+            TEContentInput::List(list) => list,
+            TEContentInput::Cbor(data) => cbor_from_slice(&data).map_err(|e| HTTPError {
+                code: 400,
+                message: format!("Invalid content: {}", e),
+                data: None,
+            })?,
+        };
+
+        if normalize {
+            for c in list.iter_mut() {
+                for t in c.texts.iter_mut() {
+                    *t = tokenizer::normalize_text(t);
+                }
+            }
+        }
+        Ok(list)
+    }
+}
+
 impl TEContent {
     pub fn to_translating_string(&self) -> String {
         serde_json::to_string(&self.texts).expect("TEContent::to_translating_string error")
@@ -204,15 +1208,7 @@ impl TEUnit {
                 if v.is_empty() {
                     return (0, v);
                 }
-                // the ':' maybe translated by AI
-                let o = if v[0].ends_with(&COLONS) {
-                    let mut s = v[0].clone();
-                    s.pop();
-                    s.parse::<usize>().unwrap_or(0)
-                } else {
-                    0
-                };
-
+                let o = parse_order(&v[0]);
                 if o > 0 {
                     (o, &v[1..])
                 } else {
@@ -229,11 +1225,50 @@ const COLONS: [char; 8] = [
     '\u{003A}', '\u{02F8}', '\u{05C3}', '\u{2236}', '\u{A789}', '\u{FE13}', '\u{FF1A}', '\u{FE55}',
 ];
 
+// the separators the models use instead of a colon after the order, e.g. "1、" or "1)".
+// not '.', a text like "1." is more likely a text than a mistranslated order.
+const ORDER_SEPARATORS: [char; 4] = ['\u{3001}', '\u{FF64}', ')', '\u{FF09}'];
+
+// the ':' of the order prefix maybe translated by AI, so the other colons and separators,
+// the full-width digits and the surrounding whitespaces are accepted.
+// "1:" => 1, " 1 ：" => 1, "1、" => 1, "１)" => 1, "text" => 0
+fn parse_order(s: &str) -> usize {
+    let s = s.trim();
+    let s = match s
+        .strip_suffix(COLONS)
+        .or_else(|| s.strip_suffix(ORDER_SEPARATORS))
+    {
+        Some(s) => s.trim_end(),
+        None => return 0,
+    };
+    if s.is_empty() {
+        return 0;
+    }
+
+    let mut o = 0usize;
+    for c in s.chars() {
+        let d = match c {
+            '0'..='9' => c as u32 - '0' as u32,
+            '\u{FF10}'..='\u{FF19}' => c as u32 - '\u{FF10}' as u32,
+            _ => return 0,
+        };
+        o = match o.checked_mul(10).and_then(|o| o.checked_add(d as usize)) {
+            Some(o) => o,
+            None => return 0,
+        };
+    }
+    o
+}
+
 pub trait TESegmenter {
     fn detect_lang_string(&self) -> String;
     fn segment(&self, model: &openai::AIModel, tokens_len: fn(&str) -> usize) -> Vec<TEUnit>;
     fn segment_for_summarizing(&self, tokens_len: fn(&str) -> usize) -> Vec<String>;
-    fn segment_for_embedding(&self, tokens_len: fn(&str) -> usize) -> Vec<Vec<TEUnit>>;
+    fn segment_for_embedding(
+        &self,
+        tokens_len: fn(&str) -> usize,
+        limits: openai::EmbeddingLimits,
+    ) -> Vec<Vec<TEUnit>>;
 }
 
 impl TESegmenter for TEContentList {
@@ -338,7 +1373,11 @@ impl TESegmenter for TEContentList {
         list
     }
 
-    fn segment_for_embedding(&self, tokens_len: fn(&str) -> usize) -> Vec<Vec<TEUnit>> {
+    fn segment_for_embedding(
+        &self,
+        tokens_len: fn(&str) -> usize,
+        limits: openai::EmbeddingLimits,
+    ) -> Vec<Vec<TEUnit>> {
         let mut list: Vec<Vec<TEUnit>> = Vec::new();
         let mut group: Vec<TEUnit> = Vec::new();
         let mut group_tokens: usize = 0;
@@ -360,7 +1399,7 @@ impl TESegmenter for TEContentList {
                         };
                     }
 
-                    if group_tokens >= EMBEDDING_MAX_TOKENS || group.len() >= EMBEDDING_MAX_ARRAY {
+                    if group_tokens >= limits.max_tokens || group.len() >= limits.max_array {
                         list.push(group);
                         group_tokens = 0;
                         group = Vec::new();
@@ -382,7 +1421,7 @@ impl TESegmenter for TEContentList {
                     content: Vec::new(),
                 };
 
-                if group_tokens >= EMBEDDING_MAX_TOKENS || group.len() >= EMBEDDING_MAX_ARRAY {
+                if group_tokens >= limits.max_tokens || group.len() >= limits.max_array {
                     list.push(group);
                     group_tokens = 0;
                     group = Vec::new();
@@ -424,7 +1463,12 @@ pub fn extract_summary_keywords(input: &str) -> (String, Vec<String>) {
         return (ls[0].to_string(), Vec::new());
     }
 
-    let keywords: Vec<String> = ls[0]
+    (ls[1..].join("\n"), split_keywords(ls[0]))
+}
+
+// split the keywords line returned by the model by punctuations.
+pub fn split_keywords(input: &str) -> Vec<String> {
+    input
         .trim()
         .split(char::is_punctuation)
         .filter_map(|s| match s.trim_matches(|c: char| !c.is_letter()) {
@@ -432,15 +1476,182 @@ pub fn extract_summary_keywords(input: &str) -> (String, Vec<String>) {
             v => Some(v),
         })
         .map(str::to_string)
-        .collect();
-
-    (ls[1..].join("\n"), keywords)
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn job_tracker_works() {
+        let jobs = Arc::new(JobTracker::default());
+        assert_eq!(jobs.total(), 0);
+        {
+            let _t1 = jobs.start(JobKind::Translating);
+            let _t2 = jobs.start(JobKind::Translating);
+            let _s = jobs.start(JobKind::Summarizing);
+            let _e = jobs.start(JobKind::Reembedding);
+            assert_eq!(jobs.running(JobKind::Translating), 2);
+            assert_eq!(jobs.sum(&JobKind::TRANSLATING), 3);
+            assert_eq!(jobs.sum(&JobKind::EMBEDDING), 1);
+            assert_eq!(jobs.total(), 4);
+            assert!(jobs.gauges().contains(&("reembedding", 1)));
+            assert_eq!(jobs.gauges().len(), JobKind::ALL.len());
+        }
+        assert_eq!(jobs.total(), 0);
+        assert!(jobs.gauges().iter().all(|(_, v)| *v == 0));
+
+        assert!(jobs.check_open().is_ok());
+        jobs.close();
+        assert_eq!(jobs.check_open().unwrap_err().code, 503);
+    }
+
+    #[test]
+    fn job_tracker_capacity_works() {
+        let jobs = Arc::new(JobTracker::default());
+        let _t = jobs.start(JobKind::Translating);
+        assert!(jobs.check_capacity(JobKind::Translating).is_ok());
+        assert!(jobs.saturated().is_empty());
+
+        let jobs = Arc::new(JobTracker::new(&conf::MaxJobs {
+            translating: 2,
+            reembedding: 1,
+            ..Default::default()
+        }));
+        let _t1 = jobs.start(JobKind::Translating);
+        assert!(jobs.check_capacity(JobKind::Translating).is_ok());
+        let t2 = jobs.start(JobKind::Translating);
+        let _d = jobs.start(JobKind::DeleteVersion);
+        let err = jobs.check_capacity(JobKind::Translating).unwrap_err();
+        assert_eq!(err.code, 429);
+        assert_eq!(
+            err.data,
+            Some(serde_json::json!({
+                "kind": "translating",
+                "queue_depth": 2,
+                "limit": 2,
+                "total_running": 3,
+                "retry_after": 30,
+            }))
+        );
+        assert!(jobs.check_capacity(JobKind::Summarizing).is_ok());
+        assert!(jobs.check_capacity(JobKind::DeleteVersion).is_ok());
+        assert_eq!(jobs.saturated(), vec!["translating"]);
+
+        {
+            let _e = jobs.start(JobKind::Reembedding);
+            assert_eq!(jobs.saturated(), vec!["translating", "reembedding"]);
+        }
+        drop(t2);
+        assert!(jobs.check_capacity(JobKind::Translating).is_ok());
+        assert!(jobs.saturated().is_empty());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn job_tracker_abort_works() {
+        let jobs = Arc::new(JobTracker::default());
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(60);
+        assert_eq!(jobs.until(deadline, async { 1 }).await, Ok(1));
+        let past = tokio::time::Instant::now();
+        assert_eq!(
+            jobs.until(past, std::future::pending::<()>()).await,
+            Err(DEADLINE_EXCEEDED)
+        );
+
+        let (tx, rx) = oneshot::channel::<&'static str>();
+        let tracker = jobs.clone();
+        jobs.spawn(JobKind::Translating, async move {
+            let res = tracker.until(deadline, std::future::pending::<()>()).await;
+            let _ = tx.send(res.unwrap_err());
+        });
+        assert_eq!(jobs.running(JobKind::Translating), 1);
+        tokio::task::yield_now().await;
+        jobs.abort();
+        assert_eq!(rx.await.unwrap(), ABORTED_BY_SHUTDOWN);
+        assert!(jobs.check_open().is_err());
+    }
+
+    #[test]
+    fn segment_timings_works() {
+        let timings = SegmentTimings::default();
+        assert_eq!(timings.summary(), None);
+        assert_eq!(timings.level(), log::Level::Debug);
+
+        let mut timings = SegmentTimings::with_capacity(4);
+        timings.push(0, 1200, 800);
+        timings.push(2, 9000, 1500);
+        timings.push(1, 1000, 700);
+        timings.push(3, 1100, 750);
+        assert_eq!(
+            timings.summary(),
+            Some(SegmentTimingsSummary {
+                segments: 4,
+                min_ms: 1000,
+                p50_ms: 1100,
+                max_ms: 9000,
+                slowest_piece: 2,
+                slowest_tokens: 1500,
+            })
+        );
+        assert_eq!(timings.level(), log::Level::Debug);
+
+        for i in 4..SEGMENT_TIMINGS_INFO_PIECES {
+            timings.push(i, 1000, 700);
+        }
+        assert_eq!(timings.level(), log::Level::Info);
+    }
+
+    #[test]
+    fn job_event_works() {
+        let te = TEParams {
+            gid: xid::new(),
+            cid: xid::new(),
+            language: Language::Jpn,
+            version: 3,
+            content: vec![],
+        };
+        let event = JobEvent::new(db::CounterKind::Translating, &te, JOB_DONE, 1024);
+        assert_eq!(event.kind, "translating");
+        assert_eq!(event.status, "done");
+
+        let data = cbor_to_vec(&event).unwrap();
+        let res: JobEvent = cbor_from_slice(&data).unwrap();
+        assert_eq!(res, event);
+        assert_eq!(*res.gid, te.gid);
+        assert_eq!(*res.language, Language::Jpn);
+        assert_eq!(res.tokens, 1024);
+
+        // compact: the ids are 12 bytes rather than 20 chars strings
+        let json = serde_json::to_vec(&JobEvent {
+            gid: PackObject::Json(te.gid),
+            cid: PackObject::Json(te.cid),
+            language: PackObject::Json(te.language),
+            ..event.clone()
+        })
+        .unwrap();
+        assert!(data.len() < json.len());
+
+        let event = JobEvent::new(db::CounterKind::Summarizing, &te, JOB_FAILED, 0);
+        let res: JobEvent = cbor_from_slice(&cbor_to_vec(&event).unwrap()).unwrap();
+        assert_eq!(res.kind, "summarizing");
+        assert_eq!(res.status, "failed");
+    }
+
+    #[test]
+    fn job_lock_key_works() {
+        let gid = xid::new();
+        let cid = xid::new();
+        assert_eq!(
+            job_lock_key("translating", gid, cid, Language::Zho, 2),
+            format!("LOCK:translating:{}:{}:zho:2", gid, cid)
+        );
+        assert_ne!(
+            job_lock_key("translating", gid, cid, Language::Zho, 2),
+            job_lock_key("translating", gid, cid, Language::Zho, 3)
+        );
+    }
+
     #[test]
     fn tecontent_to_string() {
         assert_eq!(
@@ -457,6 +1668,38 @@ mod tests {
         );
     }
 
+    #[test]
+    fn tecontent_input_normalize_works() {
+        let input = || {
+            TEContentInput::List(vec![TEContent {
+                id: "abc".to_string(),
+                texts: vec![
+                    "\u{FEFF}Hello\u{200B} world".to_string(),
+                    "foo\u{00A0}bar\n".to_string(),
+                ],
+            }])
+        };
+
+        let content = input().decode(true).unwrap();
+        assert_eq!(content[0].texts, vec!["Hello world", "foo bar\n"]);
+        let unit = TEUnit {
+            tokens: 0,
+            content: content.clone(),
+        };
+        assert_eq!(unit.to_embedding_string(), "Hello world foo bar.");
+        assert_eq!(
+            content[0].to_translating_string(),
+            r#"["Hello world","foo bar\n"]"#
+        );
+        assert!(
+            tokenizer::tokens_len(&content[0].to_translating_string())
+                < tokenizer::tokens_len(&input().decode(false).unwrap()[0].to_translating_string())
+        );
+
+        let content = input().decode(false).unwrap();
+        assert_eq!(content[0].texts[0], "\u{FEFF}Hello\u{200B} world");
+    }
+
     #[test]
     fn teunit_to_translating() {
         let unit = TEUnit {
@@ -585,6 +1828,367 @@ mod tests {
                 texts: vec!["text_1".to_string(), "text_2".to_string()],
             },
         );
+
+        for (first, second) in [("1、", "2、"), ("1)", "2)"), (" １ ：", "２）")] {
+            let rt = unit.replace_texts(&[
+                vec![first.to_string(), "text_1".to_string()],
+                vec![second.to_string(), "text_3".to_string()],
+            ]);
+            assert_eq!(rt[0].texts, vec!["text_1".to_string()], "{:?}", first);
+            assert_eq!(rt[1].texts, vec!["text_3".to_string()], "{:?}", second);
+        }
+
+        // the second node is missed by the model
+        let rt = unit.replace_texts(&[vec!["2、".to_string(), "text_3".to_string()]]);
+        assert_eq!(rt[0].texts, Vec::<String>::new());
+        assert_eq!(rt[1].texts, vec!["text_3".to_string()]);
+    }
+
+    #[test]
+    fn parse_order_works() {
+        for (input, expected) in [
+            ("1:", 1),
+            ("12:", 12),
+            (" 1 : ", 1),
+            ("1：", 1),
+            ("1、", 1),
+            ("1)", 1),
+            ("1）", 1),
+            ("1.", 0),
+            ("１２：", 12),
+            ("1", 0),
+            (":", 0),
+            ("a:", 0),
+            ("1a、", 0),
+            ("text", 0),
+            ("", 0),
+            ("99999999999999999999999:", 0),
+        ] {
+            assert_eq!(parse_order(input), expected, "input: {:?}", input);
+        }
+    }
+
+    #[test]
+    fn split_keywords_works() {
+        assert!(split_keywords("").is_empty());
+        assert_eq!(
+            split_keywords(" Rust, \"Qdrant\"; 向量数据库。"),
+            vec!["Rust", "Qdrant", "向量数据库"]
+        );
+    }
+
+    #[tokio::test]
+    async fn probe_works() {
+        assert_eq!(probe(async { Ok(()) }).await, PROBE_OK);
+        assert_eq!(
+            probe(async { Err(anyhow::anyhow!("connection refused")) }).await,
+            PROBE_ERROR
+        );
+        assert_eq!(
+            probe(std::future::pending::<anyhow::Result<()>>()).await,
+            PROBE_TIMED_OUT
+        );
+
+        let info = ReadyInfo {
+            scylla: PROBE_OK.to_string(),
+            qdrant: PROBE_OK.to_string(),
+            redis: PROBE_OK.to_string(),
+        };
+        assert!(info.is_ready());
+        let info = ReadyInfo {
+            redis: PROBE_TIMED_OUT.to_string(),
+            ..info
+        };
+        assert!(!info.is_ready());
+    }
+
+    #[test]
+    fn check_content_tokens_works() {
+        let content: TEContentList = vec![
+            TEContent {
+                id: "abc".to_string(),
+                texts: vec!["Hello world".to_string(), "Rust".to_string()],
+            },
+            TEContent {
+                id: "efg".to_string(),
+                texts: vec!["Qdrant".to_string()],
+            },
+        ];
+        let tokens = content_tokens(&content, |s| s.len());
+        assert_eq!(tokens, 21);
+
+        assert!(check_content_tokens(tokens, 0).is_ok());
+        assert!(check_content_tokens(tokens, 21).is_ok());
+        let err = check_content_tokens(tokens, 20).unwrap_err();
+        assert_eq!(err.code, 413);
+        assert_eq!(
+            err.data,
+            Some(serde_json::json!({"tokens": 21, "max_tokens": 20}))
+        );
+    }
+
+    #[test]
+    fn validate_version_works() {
+        assert!(validate_version(1).is_ok());
+        assert!(validate_version(10000).is_ok());
+        assert!(validate_version(MAX_VERSION).is_ok());
+        assert!(validate_version(0).is_err());
+        assert!(validate_version(MAX_VERSION + 1).is_err());
+        assert!(validate_version(u16::MAX).is_err());
+    }
+
+    // every input with a version rejects 0 and the versions not fitting in i16.
+    #[test]
+    fn input_version_works() {
+        fn check<T: serde::de::DeserializeOwned + validator::Validate>(field: &str) {
+            for (version, ok) in [(0u16, false), (1, true), (32767, true), (32768, false)] {
+                let mut input = serde_json::json!({
+                    "gid": xid::new().to_string(),
+                    "cid": xid::new().to_string(),
+                    "id": xid::new().to_string(),
+                    "language": "eng",
+                    "version": 1,
+                    "content": [],
+                });
+                input[field] = version.into();
+                let input: T = serde_json::from_value(input).unwrap();
+                assert_eq!(
+                    input.validate().is_ok(),
+                    ok,
+                    "{}: {} = {}",
+                    std::any::type_name::<T>(),
+                    field,
+                    version
+                );
+            }
+        }
+
+        check::<creation::DeleteVersionInput>("version");
+        check::<embedding::EmbeddingJobInput>("version");
+        check::<embedding::EmbeddingInput>("version");
+        check::<embedding::EmbeddingPublicInput>("version");
+        check::<embedding::EmbeddingDeleteInput>("version");
+        check::<message_translating::MessageTranslatingInput>("version");
+        check::<summarizing::SummarizingInput>("version");
+        check::<summarizing::KeywordsInput>("version");
+        check::<translating::TranslatingInput>("version");
+        check::<translating::TranslatingInput>("base_version");
+        check::<translating::RetryInput>("version");
+    }
+
+    #[test]
+    fn check_content_size_works() {
+        let content: TEContentList = vec![
+            TEContent {
+                id: "abc".to_string(),
+                texts: vec!["Hello world".to_string(), "Rust".to_string()],
+            },
+            TEContent {
+                id: "efg".to_string(),
+                texts: vec!["Qdrant".to_string()],
+            },
+        ];
+
+        let max = |nodes, bytes| conf::MaxContent { nodes, bytes };
+        assert!(check_content_size(&content, &max(0, 0)).is_ok());
+        assert!(check_content_size(&content, &max(2, 21)).is_ok());
+
+        let err = check_content_size(&content, &max(1, 21)).unwrap_err();
+        assert_eq!(err.code, 413);
+        assert_eq!(
+            err.data,
+            Some(serde_json::json!({"nodes": 2, "max_nodes": 1}))
+        );
+
+        let err = check_content_size(&content, &max(2, 20)).unwrap_err();
+        assert_eq!(err.code, 413);
+        assert_eq!(
+            err.data,
+            Some(serde_json::json!({"bytes": 21, "max_bytes": 20}))
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn tokenize_content_works() {
+        let thread = std::thread::current().id();
+
+        // the small content is tokenized in place.
+        let mut content: TEContentList = vec![TEContent {
+            id: "1".to_string(),
+            texts: vec!["Hello world".to_string()],
+        }];
+        let (tokens, on) = tokenize_content(&mut content, |c| {
+            (
+                content_tokens(c, tokenizer::tokens_len),
+                std::thread::current().id(),
+            )
+        })
+        .await;
+        assert_eq!(tokens, 2);
+        assert_eq!(on, thread);
+        assert_eq!(content.len(), 1);
+
+        // the large content is tokenized in the blocking pool rather than the runtime thread,
+        // and the content is moved back.
+        let mut content: TEContentList = (0..100)
+            .map(|i| TEContent {
+                id: i.to_string(),
+                texts: vec!["Hello world. ".repeat(1000)],
+            })
+            .collect();
+        let (tokens, on) = tokenize_content(&mut content, |c| {
+            (
+                content_tokens(c, tokenizer::tokens_len),
+                std::thread::current().id(),
+            )
+        })
+        .await;
+        assert_ne!(on, thread);
+        assert_eq!(content.len(), 100);
+        assert_eq!(content[99].id, "99");
+        assert_eq!(tokens, content_tokens(&content, tokenizer::tokens_len));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn encode_blocking_works() {
+        let thread = std::thread::current().id();
+        let on = encode_blocking(BLOCKING_ENCODE_BYTES, || std::thread::current().id()).await;
+        assert_eq!(on, thread);
+
+        let content: TEContentList = (0..100)
+            .map(|i| TEContent {
+                id: i.to_string(),
+                texts: vec!["Hello world. ".repeat(1000)],
+            })
+            .collect();
+        let size = content_bytes(&content);
+        assert!(size > BLOCKING_ENCODE_BYTES);
+        let (data, on) = encode_blocking(size, move || {
+            (cbor_to_vec(&content).unwrap(), std::thread::current().id())
+        })
+        .await;
+        assert_ne!(on, thread);
+        let res: TEContentList = cbor_from_slice(&data).unwrap();
+        assert_eq!(res.len(), 100);
+        assert_eq!(res[99].id, "99");
+    }
+
+    // cargo test encode_blocking_timings -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn encode_blocking_timings() {
+        for kb in [16, 64, 256, 1024, 4096] {
+            let content: TEContentList = (0..kb)
+                .map(|i| TEContent {
+                    id: i.to_string(),
+                    texts: vec!["Hello world, 你好世界. ".repeat(1024 / 27 + 1)],
+                })
+                .collect();
+            let start = std::time::Instant::now();
+            let data = cbor_to_vec(&content).unwrap();
+            println!(
+                "{} bytes of texts, {} bytes of CBOR: {:?}",
+                content_bytes(&content),
+                data.len(),
+                start.elapsed()
+            );
+        }
+    }
+
+    #[test]
+    fn job_deadline_works() {
+        let cfg = conf::Deadline {
+            default: 1800,
+            max: 3600,
+        };
+        assert_eq!(job_deadline(&cfg, None), Duration::from_secs(1800));
+        assert_eq!(job_deadline(&cfg, Some(60)), Duration::from_secs(60));
+        assert_eq!(job_deadline(&cfg, Some(7200)), Duration::from_secs(3600));
+
+        let cfg = conf::Deadline {
+            default: 1800,
+            max: 0,
+        };
+        assert_eq!(job_deadline(&cfg, Some(7200)), Duration::from_secs(7200));
+    }
+
+    #[test]
+    fn dedup_works() {
+        // no existing doc, or it is failed
+        assert_eq!(Dedup::check(0, false, false, 600, 3600, false), Dedup::Run);
+        // finished recently
+        assert_eq!(
+            Dedup::check(1000, true, false, 600, 3600, false),
+            Dedup::Exists
+        );
+        assert_eq!(
+            Dedup::check(1000, true, false, 600, 3600, true),
+            Dedup::Rerun
+        );
+        assert_eq!(
+            Dedup::check(600_000, true, false, 600, 3600, false),
+            Dedup::Run
+        );
+        assert_eq!(Dedup::check(1000, true, false, 0, 3600, false), Dedup::Run);
+        // running
+        assert_eq!(
+            Dedup::check(1000, false, true, 600, 3600, true),
+            Dedup::InFlight
+        );
+        assert_eq!(
+            Dedup::check(3_600_000, false, true, 600, 3600, true),
+            Dedup::Run
+        );
+
+        assert_eq!(Dedup::Run.status(), CREATE_STARTED);
+        assert_eq!(Dedup::Rerun.status(), CREATE_STARTED);
+        assert_eq!(Dedup::Exists.status(), CREATE_EXISTS);
+        assert_eq!(Dedup::InFlight.status(), CREATE_IN_FLIGHT);
+    }
+
+    #[test]
+    fn estimate_output_works() {
+        let res = EstimateOutput::new(2, 3000, 0.001);
+        assert_eq!(res.segments, 2);
+        assert_eq!(res.prompt_tokens, 3000);
+        assert!((res.cost - 0.003).abs() < 1e-9);
+
+        assert_eq!(EstimateOutput::new(0, 0, 0.03).cost, 0.0);
+    }
+
+    #[test]
+    fn segment_for_embedding_works() {
+        let content: TEContentList = (0..5)
+            .map(|i| TEContent {
+                id: i.to_string(),
+                texts: vec![format!("text {}", i)],
+            })
+            .collect();
+        // every node is a unit
+        let tokens_len: fn(&str) -> usize = |_| EMBEDDING_HIGH_TOKENS;
+
+        let groups = content.segment_for_embedding(tokens_len, openai::EmbeddingLimits::default());
+        assert_eq!(groups.iter().map(|g| g.len()).collect::<Vec<_>>(), vec![5]);
+
+        let limits = openai::EmbeddingLimits {
+            max_array: 2,
+            max_tokens: 7000,
+        };
+        let groups = content.segment_for_embedding(tokens_len, limits);
+        assert_eq!(
+            groups.iter().map(|g| g.len()).collect::<Vec<_>>(),
+            vec![2, 2, 1]
+        );
+
+        let limits = openai::EmbeddingLimits {
+            max_array: 2048,
+            max_tokens: EMBEDDING_HIGH_TOKENS * 3,
+        };
+        let groups = content.segment_for_embedding(tokens_len, limits);
+        assert_eq!(
+            groups.iter().map(|g| g.len()).collect::<Vec<_>>(),
+            vec![3, 2]
+        );
     }
 
     #[test]
@@ -1,34 +1,50 @@
 use axum::{extract::State, Extension};
-use finl_unicode::categories::CharacterCategories;
 use serde::{Deserialize, Serialize};
-use std::{sync::Arc, time::Instant};
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 use tokio::sync::{mpsc, Semaphore};
 use validator::Validate;
 
 use axum_web::context::{unix_ms, ReqContext};
 use axum_web::erring::{HTTPError, SuccessResponse};
-use axum_web::object::{cbor_from_slice, PackObject};
+use axum_web::object::PackObject;
 use scylla_orm::ColumnsMap;
 
 use crate::api::{
-    extract_summary_keywords, AppState, TEContentList, TEOutput, TEParams, TESegmenter,
-    PARALLEL_WORKS, SUMMARIZE_HIGH_TOKENS,
+    check_content_size, check_content_tokens, content_tokens, deserialize_language,
+    extract_summary_keywords, incr_counter, job_deadline, publish_event, split_keywords,
+    tokenize_content, validate_version, AppState, Dedup, EstimateOutput, JobEvent, JobKind,
+    SegmentTimings, TEContentInput, TEOutput, TEParams, TESegmenter, TaskContext, CREATE_STARTED,
+    JOB_DONE, JOB_FAILED, PARALLEL_WORKS, SUMMARIZE_HIGH_TOKENS,
 };
+use crate::conf::ReduceStrategy;
 use crate::db;
 use crate::lang::Language;
 use crate::openai;
 use crate::tokenizer;
+use crate::trace;
 
 #[derive(Debug, Deserialize, Validate)]
 pub struct SummarizingInput {
-    pub gid: PackObject<xid::Id>,       // group id, content belong to
-    pub cid: PackObject<xid::Id>,       // creation id
+    pub gid: PackObject<xid::Id>, // group id, content belong to
+    pub cid: PackObject<xid::Id>, // creation id
+    #[serde(deserialize_with = "deserialize_language")]
     pub language: PackObject<Language>, // the target language translate to
-    #[validate(range(min = 1, max = 10000))]
+    #[validate(custom = "validate_version")]
     pub version: u16,
 
     pub model: Option<String>,
-    pub content: Option<PackObject<Vec<u8>>>,
+    pub content: Option<TEContentInput>,
+    pub force: Option<bool>, // summarize again even if a recent result exists
+    pub reduce_strategy: Option<ReduceStrategy>, // "drop_middle", "hierarchical_reduce" or "error", default from the config
+    #[validate(range(min = 1))]
+    pub deadline: Option<u64>, // seconds, the job is stopped after it, within the configured ceiling
 }
 
 #[derive(Debug, Default, Deserialize, Serialize)]
@@ -40,7 +56,11 @@ pub struct SummarizingOutput {
     pub model: String,
     pub progress: i8,
     pub updated_at: i64,
+    pub started_at: i64,
     pub tokens: u32,
+    pub map_tokens: u32, // the tokens breakdown by stage, sum to tokens
+    pub reduce_tokens: u32,
+    pub keyword_tokens: u32,
     pub summary: String,
     pub keywords: Vec<String>,
     pub error: String,
@@ -79,7 +99,11 @@ pub async fn get(
         model: doc.model,
         progress: doc.progress,
         updated_at: doc.updated_at,
+        started_at: doc.started_at,
         tokens: doc.tokens as u32,
+        map_tokens: doc.map_tokens as u32,
+        reduce_tokens: doc.reduce_tokens as u32,
+        keyword_tokens: doc.keyword_tokens as u32,
         summary,
         keywords,
         error: doc.error,
@@ -93,6 +117,8 @@ pub async fn create(
 ) -> Result<PackObject<SuccessResponse<TEOutput>>, HTTPError> {
     let (to, input) = to.unpack();
     input.validate()?;
+    app.jobs.check_open()?;
+    app.jobs.check_capacity(JobKind::Summarizing)?;
 
     let gid = *input.gid;
     let cid = *input.cid;
@@ -108,53 +134,109 @@ pub async fn create(
     .await;
 
     if language == Language::Und {
-        return Err(HTTPError::new(400, "Invalid language".to_string()));
+        return Err(HTTPError::localized(
+            400,
+            "invalid_language",
+            &ctx.lang,
+            &[],
+        ));
     }
 
+    let mut content = input
+        .content
+        .unwrap_or_default()
+        .decode(app.normalize_text)?;
+    check_content_size(&content, &app.max_content)?;
+    let tokens = tokenize_content(&mut content, |c| content_tokens(c, tokenizer::tokens_len)).await;
+    ctx.set("content_tokens", tokens.into()).await;
+    check_content_tokens(tokens, app.max_tokens.summarizing)?;
+
+    let strategy = input.reduce_strategy.unwrap_or(app.reduce_strategy);
+    let deadline = job_deadline(&app.deadline, input.deadline);
+    ctx.set_kvs(vec![
+        ("reduce_strategy", strategy.as_str().into()),
+        ("deadline", deadline.as_secs().into()),
+    ])
+    .await;
+
+    let force = input.force.unwrap_or(false);
     let now = unix_ms() as i64;
     let mut doc = db::Summarizing::with_pk(gid, cid, language, input.version as i16);
-    if doc
+    let dedup = match doc
         .get_one(
             &app.scylla,
             vec![
                 "model".to_string(),
                 "updated_at".to_string(),
+                "progress".to_string(),
                 "error".to_string(),
             ],
         )
         .await
-        .is_ok()
-        && doc.error.is_empty()
-        && now - doc.updated_at < 3600 * 1000
     {
-        ctx.set("exists", true.into()).await;
+        Ok(_) if doc.error.is_empty() => Dedup::check(
+            now - doc.updated_at,
+            doc.progress == 100,
+            doc.progress < 100,
+            app.dedup.summarizing,
+            app.dedup.in_flight,
+            force,
+        ),
+        _ => Dedup::Run,
+    };
+    match dedup {
+        Dedup::Exists | Dedup::InFlight => {
+            ctx.set_kvs(vec![
+                ("exists", true.into()),
+                ("in_flight", (dedup == Dedup::InFlight).into()),
+            ])
+            .await;
 
-        return Ok(to.with(SuccessResponse::new(TEOutput {
-            cid: to.with(cid),
-            detected_language: to.with(language),
-        })));
+            return Ok(to.with(SuccessResponse::new(TEOutput {
+                cid: to.with(cid),
+                detected_language: to.with(language),
+                status: dedup.status().to_string(),
+            })));
+        }
+        Dedup::Rerun => {
+            ctx.set("rerun", true.into()).await;
+            log::info!(target: "summarizing",
+                action = "force_rerun",
+                rid = ctx.rid,
+                user = ctx.user.to_string(),
+                gid = gid.to_string(),
+                cid = cid.to_string(),
+                updated_at = doc.updated_at;
+                "override the recent result",
+            );
+        }
+        Dedup::Run => {}
     }
 
-    let mut cols = ColumnsMap::with_capacity(6);
+    let mut cols = ColumnsMap::with_capacity(10);
     cols.set_as("model", &openai::AIModel::GPT3_5.to_string());
     cols.set_as("updated_at", &now);
+    cols.set_as("started_at", &now);
     cols.set_as("progress", &0i8);
     cols.set_as("tokens", &0i32);
+    cols.set_as("map_tokens", &0i32);
+    cols.set_as("reduce_tokens", &0i32);
+    cols.set_as("keyword_tokens", &0i32);
     cols.set_as("summary", &"".to_string());
     cols.set_as("error", &"".to_string());
     doc.upsert_fields(&app.scylla, cols).await?;
 
-    let content: TEContentList =
-        cbor_from_slice(&input.content.unwrap_or_default()).map_err(|e| HTTPError {
-            code: 400,
-            message: format!("Invalid content: {}", e),
-            data: None,
-        })?;
-
-    tokio::spawn(summarize(
+    let span_attrs = vec![
+        ("gid", gid.to_string()),
+        ("cid", cid.to_string()),
+        ("model", openai::AIModel::GPT3_5.to_string()),
+    ];
+    let user = app.job_user(ctx.user);
+    let jobs = app.jobs.clone();
+    let job = summarize(
         app,
         ctx.rid.clone(),
-        ctx.user,
+        user,
         TEParams {
             gid,
             cid,
@@ -162,38 +244,182 @@ pub async fn create(
             language,
             content,
         },
-    ));
+        strategy,
+        deadline,
+    );
+    jobs.spawn(
+        JobKind::Summarizing,
+        trace::in_span("summarizing", span_attrs, job),
+    );
 
     Ok(to.with(SuccessResponse::new(TEOutput {
         cid: to.with(cid),
         detected_language: to.with(language),
+        status: CREATE_STARTED.to_string(),
     })))
 }
 
-async fn summarize(app: Arc<AppState>, rid: String, user: xid::Id, te: TEParams) {
-    let content = te.content.segment_for_summarizing(tokenizer::tokens_len);
+#[derive(Debug, Deserialize, Validate)]
+pub struct EstimateInput {
+    #[serde(deserialize_with = "deserialize_language")]
+    pub language: PackObject<Language>, // the target language summarize to
+    pub content: TEContentInput,
+}
+
+pub async fn estimate(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<EstimateInput>,
+) -> Result<PackObject<SuccessResponse<EstimateOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+
+    let language = *input.language;
+    ctx.set_kvs(vec![
+        ("action", "estimate_summarizing".into()),
+        ("language", language.to_639_3().to_string().into()),
+    ])
+    .await;
+
+    if language == Language::Und {
+        return Err(HTTPError::new(400, "Invalid language".to_string()));
+    }
+
+    let mut content = input.content.decode(app.normalize_text)?;
+    let content = tokenize_content(&mut content, |c| {
+        c.segment_for_summarizing(tokenizer::tokens_len)
+    })
+    .await;
+    // the pieces too short are not summarized, the same as the job.
+    let mut segments = 0usize;
+    let mut prompt_tokens = 0usize;
+    for text in &content {
+        if tokenizer::tokens_len(text) > 100 {
+            segments += 1;
+            prompt_tokens += app.ai.estimate_summarize(language.to_name(), text);
+        }
+    }
+    // the summaries of all pieces will be summarized again, its tokens can not be estimated.
+    if content.len() > 1 {
+        segments += 1;
+    }
+
+    let res = EstimateOutput::new(
+        segments,
+        prompt_tokens,
+        openai::AIModel::GPT3_5.prompt_price(),
+    );
+    ctx.set_kvs(vec![
+        ("segments", res.segments.into()),
+        ("prompt_tokens", res.prompt_tokens.into()),
+    ])
+    .await;
+    Ok(to.with(SuccessResponse::new(res)))
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct KeywordsInput {
+    pub gid: PackObject<xid::Id>, // group id, content belong to
+    pub cid: PackObject<xid::Id>, // creation id
+    #[serde(deserialize_with = "deserialize_language")]
+    pub language: PackObject<Language>, // the language of the keywords
+    #[validate(custom = "validate_version")]
+    pub version: u16,
+    pub content: TEContentInput,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct KeywordsOutput {
+    pub tokens: u32,
+    pub keywords: Vec<String>,
+}
+
+// extract the keywords of the content without summarizing it, nothing is persisted.
+// only the first summarizing segment is used if the content is long.
+pub async fn keywords(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<KeywordsInput>,
+) -> Result<PackObject<SuccessResponse<KeywordsOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+
+    let gid = *input.gid;
+    let cid = *input.cid;
+    let language = *input.language;
+
+    ctx.set_kvs(vec![
+        ("action", "extract_keywords".into()),
+        ("gid", gid.to_string().into()),
+        ("cid", cid.to_string().into()),
+        ("language", language.to_639_3().to_string().into()),
+        ("version", input.version.into()),
+    ])
+    .await;
+
+    if language == Language::Und {
+        return Err(HTTPError::new(400, "Invalid language".to_string()));
+    }
+
+    let mut content = input.content.decode(app.normalize_text)?;
+    let content = tokenize_content(&mut content, |c| {
+        c.segment_for_summarizing(tokenizer::tokens_len)
+    })
+    .await;
+    if content.is_empty() {
+        return Err(HTTPError::new(
+            400,
+            "Empty content to extract keywords".to_string(),
+        ));
+    }
+
+    ctx.set("segments", content.len().into()).await;
+    let (tokens, output) = app
+        .ai
+        .keywords(&ctx, language.to_name(), &content[0])
+        .await?;
+    let keywords = split_keywords(&output);
+    ctx.set("keywords", keywords.len().into()).await;
+
+    Ok(to.with(SuccessResponse::new(KeywordsOutput { tokens, keywords })))
+}
+
+async fn summarize(
+    app: Arc<AppState>,
+    rid: String,
+    user: xid::Id,
+    mut te: TEParams,
+    strategy: ReduceStrategy,
+    deadline: Duration,
+) {
+    let task = TaskContext::new(&rid, user, &te);
+    let content = tokenize_content(&mut te.content, |c| {
+        c.segment_for_summarizing(tokenizer::tokens_len)
+    })
+    .await;
     if content.is_empty() {
         return;
     }
 
-    let tokio_translating = app.translating.clone();
     let pieces = content.len();
+    // the map calls of the pieces and the estimated reduce calls, to report the progress.
+    let steps = pieces + reduce_steps(strategy, pieces, app.reduce_branching);
     let start = Instant::now();
 
     log::info!(target: "summarizing",
         action = "start_job",
-        rid = rid.clone(),
-        user = user.to_string(),
-        gid = te.gid.to_string(),
-        cid = te.cid.to_string(),
-        language = te.language.to_639_3().to_string(),
-        version = te.version,
+        task = log::as_serde!(task),
         pieces = pieces;
         "",
     );
 
     let mut progress = 0usize;
+    let mut calls = 0usize;
     let mut total_tokens = 00usize;
+    // the tokens breakdown by stage: map, reduce, keywords.
+    let (mut map_tokens, mut reduce_tokens, mut keyword_tokens) = (0usize, 0usize, 0usize);
+    // the timings of the map stage, the short pieces not summarized are skipped.
+    let mut timings = SegmentTimings::with_capacity(pieces);
     let mut doc = db::Summarizing::with_pk(te.gid, te.cid, te.language, te.version);
     let mut keywords_input = content[0].clone();
 
@@ -210,7 +436,7 @@ async fn summarize(app: Arc<AppState>, rid: String, user: xid::Id, te: TEParams)
             let lang = te.language.to_name();
             let tx = tx.clone();
             let sem = semaphore.clone();
-            tokio::spawn(async move {
+            trace::spawn(async move {
                 if let Ok(permit) = sem.acquire().await {
                     let ctx = ReqContext::new(rid, user, 0);
                     let res = if tokenizer::tokens_len(&text) > 100 {
@@ -234,7 +460,40 @@ async fn summarize(app: Arc<AppState>, rid: String, user: xid::Id, te: TEParams)
         let mut res_list: Vec<String> = Vec::with_capacity(pieces);
         res_list.resize(pieces, "".to_string());
 
-        while let Some((i, ctx, res)) = rx.recv().await {
+        let deadline = tokio::time::Instant::now() + deadline;
+        loop {
+            let (i, ctx, res) = match app.jobs.until(deadline, rx.recv()).await {
+                Ok(Some(item)) => item,
+                Ok(None) => break,
+                Err(reason) => {
+                    // stop the pieces not started yet, and keep the summaries of the finished ones.
+                    semaphore.close();
+                    let summaries: Vec<String> =
+                        res_list.into_iter().filter(|s| !s.is_empty()).collect();
+                    let mut cols = ColumnsMap::with_capacity(5);
+                    cols.set_as("updated_at", &(unix_ms() as i64));
+                    cols.set_as("tokens", &(total_tokens as i32));
+                    cols.set_as("map_tokens", &(map_tokens as i32));
+                    cols.set_as("summary", &summaries.join("\n"));
+                    cols.set_as("error", &reason.to_string());
+                    let _ = doc.upsert_fields(&app.scylla, cols).await;
+
+                    log::warn!(target: "summarizing",
+                        action = "deadline",
+                        task = log::as_serde!(task),
+                        elapsed = start.elapsed().as_millis() as u64,
+                        pieces = pieces,
+                        finished = progress,
+                        total_tokens = total_tokens;
+                        "{}", reason,
+                    );
+                    let event =
+                        JobEvent::new(db::CounterKind::Summarizing, &te, JOB_FAILED, total_tokens);
+                    publish_event(&app, &task, event).await;
+                    return;
+                }
+            };
+
             let ai_elapsed = ctx.start.elapsed().as_millis() as u64;
             let kv = ctx.get_kv().await;
             if let Err(err) = res {
@@ -245,34 +504,39 @@ async fn summarize(app: Arc<AppState>, rid: String, user: xid::Id, te: TEParams)
 
                 log::error!(target: "summarizing",
                     action = "call_openai",
-                    rid = ctx.rid,
-                    cid = te.cid.to_string(),
-                    language = te.language.to_639_3().to_string(),
+                    task = log::as_serde!(task),
                     start = ctx.unix_ms,
                     elapsed = ai_elapsed,
                     piece_at = i,
                     kv = log::as_serde!(kv);
                     "{}", err.to_string(),
                 );
+                let event =
+                    JobEvent::new(db::CounterKind::Summarizing, &te, JOB_FAILED, total_tokens);
+                publish_event(&app, &task, event).await;
                 return;
             }
 
             let res = res.unwrap();
             let used_tokens = res.0 as usize;
             total_tokens += used_tokens;
+            map_tokens += used_tokens;
             progress += 1;
+            if used_tokens > 0 {
+                calls += 1;
+                timings.push(i, ai_elapsed, res.0);
+            }
             res_list[i] = res.1;
 
             let mut cols = ColumnsMap::with_capacity(3);
             cols.set_as("updated_at", &(unix_ms() as i64));
-            cols.set_as("progress", &((progress * 100 / pieces + 1) as i8));
+            cols.set_as("progress", &((progress * 100 / steps).min(99) as i8));
             cols.set_as("tokens", &(total_tokens as i32));
             let _ = doc.upsert_fields(&app.scylla, cols).await;
 
             log::info!(target: "summarizing",
                 action = "call_openai",
-                rid = ctx.rid,
-                cid = te.cid.to_string(),
+                task = log::as_serde!(task),
                 start = ctx.unix_ms,
                 elapsed = ai_elapsed,
                 tokens = used_tokens,
@@ -280,7 +544,7 @@ async fn summarize(app: Arc<AppState>, rid: String, user: xid::Id, te: TEParams)
                 total_tokens = total_tokens,
                 piece_at = i,
                 kv = log::as_serde!(kv);
-                "{}/{}", progress, pieces+1,
+                "{}/{}", progress, steps,
             );
         }
 
@@ -288,47 +552,122 @@ async fn summarize(app: Arc<AppState>, rid: String, user: xid::Id, te: TEParams)
             res_list[0].to_owned()
         } else {
             // extract summary from all pieces and summarize again.
-            let mut res_list: Vec<String> = res_list;
-            let mut tokens_list: Vec<usize> =
-                res_list.iter().map(|s| tokenizer::tokens_len(s)).collect();
-            while tokens_list.len() > 2 && tokens_list.iter().sum::<usize>() > SUMMARIZE_HIGH_TOKENS
-            {
-                let i = tokens_list.len() / 2 + 1;
-                // ignore pieces in middle.
-                res_list.remove(i);
-                tokens_list.remove(i);
-            }
+            let reduced_calls = Arc::new(AtomicUsize::new(0));
+            // the tokens of the reduce calls finished, kept when the reduce is stopped.
+            let reduced_tokens = Arc::new(AtomicUsize::new(0));
+            let summarize_fn = |text: String| {
+                let app = app.clone();
+                let task = task.clone();
+                let reduced_calls = reduced_calls.clone();
+                let reduced_tokens = reduced_tokens.clone();
+                let ctx = ReqContext::new(rid.clone(), user, 0);
+                let lang = te.language.to_name();
+                let mut doc = db::Summarizing::with_pk(te.gid, te.cid, te.language, te.version);
+                async move {
+                    let res = app.ai.summarize(&ctx, lang, &text).await;
+                    let ai_elapsed = ctx.start.elapsed().as_millis() as u64;
+                    let kv = ctx.get_kv().await;
+                    match &res {
+                        Err(err) => {
+                            log::error!(target: "summarizing",
+                                action = "call_openai",
+                                task = log::as_serde!(task),
+                                elapsed = ai_elapsed,
+                                piece_at = pieces,
+                                kv = log::as_serde!(kv);
+                                "{}", err.to_string(),
+                            );
+                        }
+                        Ok((used_tokens, _)) => {
+                            // the estimated steps may be less than the calls of the token bound groups.
+                            let done = pieces + reduced_calls.fetch_add(1, Ordering::Relaxed) + 1;
+                            reduced_tokens.fetch_add(*used_tokens as usize, Ordering::Relaxed);
+                            let mut cols = ColumnsMap::with_capacity(2);
+                            cols.set_as("updated_at", &(unix_ms() as i64));
+                            cols.set_as("progress", &((done * 100 / steps).min(99) as i8));
+                            let _ = doc.upsert_fields(&app.scylla, cols).await;
 
-            let ctx = ReqContext::new(rid.clone(), user, 0);
-            let res = app
-                .ai
-                .summarize(&ctx, te.language.to_name(), &res_list.join("\n"))
-                .await;
-            let ai_elapsed = ctx.start.elapsed().as_millis() as u64;
-            let kv = ctx.get_kv().await;
-            if let Err(err) = res {
-                let mut cols = ColumnsMap::with_capacity(2);
-                cols.set_as("updated_at", &(unix_ms() as i64));
-                cols.set_as("error", &err.to_string());
-                let _ = doc.upsert_fields(&app.scylla, cols).await;
+                            log::info!(target: "summarizing",
+                                action = "call_openai",
+                                task = log::as_serde!(task),
+                                elapsed = ai_elapsed,
+                                tokens = *used_tokens,
+                                piece_at = pieces,
+                                kv = log::as_serde!(kv);
+                                "reduce {}/{}", done, steps,
+                            );
+                        }
+                    }
+                    res
+                }
+            };
 
-                log::error!(target: "summarizing",
-                    action = "call_openai",
-                    rid = ctx.rid,
-                    cid = te.cid.to_string(),
-                    language = te.language.to_639_3().to_string(),
-                    elapsed = ai_elapsed,
-                    piece_at = pieces,
-                    kv = log::as_serde!(kv);
-                    "{}", err.to_string(),
-                );
-                return;
-            }
+            let reduced = reduce_summaries(
+                strategy,
+                res_list,
+                tokenizer::tokens_len,
+                SUMMARIZE_HIGH_TOKENS,
+                app.reduce_branching,
+                summarize_fn,
+            );
+            let reduced = match app.jobs.until(deadline, reduced).await {
+                Ok(reduced) => reduced,
+                Err(reason) => {
+                    let spent = reduced_tokens.load(Ordering::Relaxed);
+                    total_tokens += spent;
+                    let mut cols = ColumnsMap::with_capacity(5);
+                    cols.set_as("updated_at", &(unix_ms() as i64));
+                    cols.set_as("tokens", &(total_tokens as i32));
+                    cols.set_as("map_tokens", &(map_tokens as i32));
+                    cols.set_as("reduce_tokens", &(spent as i32));
+                    cols.set_as("error", &reason.to_string());
+                    let _ = doc.upsert_fields(&app.scylla, cols).await;
 
-            let res = res.unwrap();
-            let used_tokens = res.0 as usize;
-            total_tokens += used_tokens;
-            progress += 1;
+                    log::warn!(target: "summarizing",
+                        action = "deadline",
+                        task = log::as_serde!(task),
+                        elapsed = start.elapsed().as_millis() as u64,
+                        strategy = strategy.as_str(),
+                        reduced = reduced_calls.load(Ordering::Relaxed),
+                        total_tokens = total_tokens;
+                        "{}", reason,
+                    );
+                    let event =
+                        JobEvent::new(db::CounterKind::Summarizing, &te, JOB_FAILED, total_tokens);
+                    publish_event(&app, &task, event).await;
+                    return;
+                }
+            };
+            total_tokens += reduced.tokens;
+            reduce_tokens += reduced.tokens;
+            calls += reduced.calls;
+
+            let summary = match reduced.summary {
+                Ok(summary) => summary,
+                Err(err) => {
+                    let mut cols = ColumnsMap::with_capacity(5);
+                    cols.set_as("updated_at", &(unix_ms() as i64));
+                    cols.set_as("tokens", &(total_tokens as i32));
+                    cols.set_as("map_tokens", &(map_tokens as i32));
+                    cols.set_as("reduce_tokens", &(reduce_tokens as i32));
+                    cols.set_as("error", &err.to_string());
+                    let _ = doc.upsert_fields(&app.scylla, cols).await;
+
+                    log::error!(target: "summarizing",
+                        action = "reduce",
+                        task = log::as_serde!(task),
+                        strategy = strategy.as_str(),
+                        calls = reduced.calls,
+                        tokens = reduced.tokens;
+                        "{}", err.to_string(),
+                    );
+                    let event =
+                        JobEvent::new(db::CounterKind::Summarizing, &te, JOB_FAILED, total_tokens);
+                    publish_event(&app, &task, event).await;
+                    return;
+                }
+            };
+            progress += reduced.calls;
 
             let mut cols = ColumnsMap::with_capacity(3);
             cols.set_as("updated_at", &(unix_ms() as i64));
@@ -337,19 +676,17 @@ async fn summarize(app: Arc<AppState>, rid: String, user: xid::Id, te: TEParams)
             let _ = doc.upsert_fields(&app.scylla, cols).await;
 
             log::info!(target: "summarizing",
-                action = "call_openai",
-                rid = ctx.rid,
-                cid = te.cid.to_string(),
-                elapsed = ai_elapsed,
-                tokens = used_tokens,
+                action = "reduce",
+                task = log::as_serde!(task),
+                strategy = strategy.as_str(),
+                calls = reduced.calls,
+                tokens = reduced.tokens,
                 total_elapsed = start.elapsed().as_millis(),
-                total_tokens = total_tokens,
-                piece_at = pieces,
-                kv = log::as_serde!(kv);
-                "{}/{}", progress, pieces+1,
+                total_tokens = total_tokens;
+                "{} steps", progress,
             );
 
-            res.1
+            summary
         }
     };
 
@@ -370,9 +707,7 @@ async fn summarize(app: Arc<AppState>, rid: String, user: xid::Id, te: TEParams)
             Err(err) => {
                 log::error!(target: "keywords",
                     action = "call_openai",
-                    rid = ctx.rid,
-                    cid = te.cid.to_string(),
-                    language = te.language.to_639_3().to_string(),
+                    task = log::as_serde!(task),
                     elapsed = ai_elapsed,
                     piece_at = pieces,
                     kv = log::as_serde!(kv);
@@ -380,36 +715,33 @@ async fn summarize(app: Arc<AppState>, rid: String, user: xid::Id, te: TEParams)
                 );
             }
             Ok(res) => {
+                calls += 1;
                 total_tokens += res.0 as usize;
-                let keywords: Vec<&str> = res
-                    .1
-                    .trim()
-                    .split(char::is_punctuation)
-                    .filter_map(|s| match s.trim_matches(|c: char| !c.is_letter()) {
-                        "" => None,
-                        v => Some(v),
-                    })
-                    .collect();
-                output = keywords.join(", ") + "\n" + &output;
+                keyword_tokens += res.0 as usize;
+                output = split_keywords(&res.1).join(", ") + "\n" + &output;
             }
         }
     }
 
     // save target lang doc to db
-    let mut cols = ColumnsMap::with_capacity(5);
+    let mut cols = ColumnsMap::with_capacity(8);
     cols.set_as("updated_at", &(unix_ms() as i64));
     cols.set_as("progress", &100i8);
     cols.set_as("tokens", &(total_tokens as i32));
+    cols.set_as("map_tokens", &(map_tokens as i32));
+    cols.set_as("reduce_tokens", &(reduce_tokens as i32));
+    cols.set_as("keyword_tokens", &(keyword_tokens as i32));
     cols.set_as("summary", &output);
     cols.set_as("error", &"".to_string());
 
     let elapsed = start.elapsed().as_millis() as u64;
+    let mut status = JOB_DONE;
     match doc.upsert_fields(&app.scylla, cols).await {
         Err(err) => {
+            status = JOB_FAILED;
             log::error!(target: "summarizing",
                 action = "to_scylla",
-                rid = rid.clone(),
-                cid = te.cid.to_string(),
+                task = log::as_serde!(task),
                 elapsed = start.elapsed().as_millis() as u64 - elapsed,
                 summary_length = output.len();
                 "{}", err,
@@ -418,8 +750,7 @@ async fn summarize(app: Arc<AppState>, rid: String, user: xid::Id, te: TEParams)
         Ok(_) => {
             log::info!(target: "summarizing",
                 action = "to_scylla",
-                rid = rid.clone(),
-                cid = te.cid.to_string(),
+                task = log::as_serde!(task),
                 elapsed = start.elapsed().as_millis() as u64 - elapsed,
                 summary_length = output.len();
                 "",
@@ -427,15 +758,361 @@ async fn summarize(app: Arc<AppState>, rid: String, user: xid::Id, te: TEParams)
         }
     };
 
+    if let Some(summary) = timings.summary() {
+        log::log!(target: "summarizing", timings.level(),
+            action = "segment_timings",
+            task = log::as_serde!(task),
+            summary = log::as_serde!(summary);
+            "",
+        );
+    }
+    trace::set_attribute("tokens", total_tokens as i64);
     log::info!(target: "summarizing",
         action = "finish_job",
-        rid = rid,
-        cid = te.cid.to_string(),
+        task = log::as_serde!(task),
         elapsed = start.elapsed().as_millis() as u64,
         pieces = pieces,
+        map_tokens = map_tokens,
+        reduce_tokens = reduce_tokens,
+        keyword_tokens = keyword_tokens,
         total_tokens = total_tokens;
         "",
     );
 
-    let _ = tokio_translating.as_str(); // avoid unused warning
+    incr_counter(
+        &app,
+        db::CounterKind::Summarizing,
+        &task,
+        user,
+        te.gid,
+        calls,
+        total_tokens,
+    )
+    .await;
+    let event = JobEvent::new(db::CounterKind::Summarizing, &te, status, total_tokens);
+    publish_event(&app, &task, event).await;
+}
+
+// the result of reducing the summaries of the pieces, the calls and tokens are the used ones
+// even if the reduce failed.
+struct Reduced {
+    calls: usize,
+    tokens: usize,
+    summary: Result<String, HTTPError>,
+}
+
+// reduce the summaries of the pieces to one summary by the strategy.
+async fn reduce_summaries<F, Fut>(
+    strategy: ReduceStrategy,
+    summaries: Vec<String>,
+    tokens_len: fn(&str) -> usize,
+    high_tokens: usize,
+    branching: usize,
+    summarize: F,
+) -> Reduced
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<(u32, String), HTTPError>>,
+{
+    let mut res = Reduced {
+        calls: 0,
+        tokens: 0,
+        summary: Ok(String::new()),
+    };
+    let mut summaries = summaries;
+    let total_tokens = |list: &[String]| list.iter().map(|s| tokens_len(s)).sum::<usize>();
+
+    match strategy {
+        ReduceStrategy::DropMiddle => {}
+        ReduceStrategy::Error => {
+            let tokens = total_tokens(&summaries);
+            if tokens > high_tokens {
+                res.summary = Err(HTTPError::new(
+                    422,
+                    format!(
+                        "The summaries of {} pieces have {} tokens, exceed {}",
+                        summaries.len(),
+                        tokens,
+                        high_tokens
+                    ),
+                ));
+                return res;
+            }
+        }
+        ReduceStrategy::HierarchicalReduce => {
+            // every level has fewer summaries than the one below, so it always ends.
+            while summaries.len() > 1 && total_tokens(&summaries) > high_tokens {
+                let mut next: Vec<String> = Vec::new();
+                for group in group_summaries(summaries, tokens_len, high_tokens, branching) {
+                    if group.len() == 1 {
+                        next.extend(group);
+                        continue;
+                    }
+                    match summarize(group.join("\n")).await {
+                        Ok((tokens, summary)) => {
+                            res.calls += 1;
+                            res.tokens += tokens as usize;
+                            next.push(summary);
+                        }
+                        Err(err) => {
+                            res.summary = Err(err);
+                            return res;
+                        }
+                    }
+                }
+                summaries = next;
+            }
+            if summaries.len() == 1 {
+                res.summary = Ok(summaries.remove(0));
+                return res;
+            }
+        }
+    }
+
+    // the summaries of the hierarchical reduce fit here, nothing is dropped.
+    drop_middle(&mut summaries, tokens_len, high_tokens);
+    match summarize(summaries.join("\n")).await {
+        Ok((tokens, summary)) => {
+            res.calls += 1;
+            res.tokens += tokens as usize;
+            res.summary = Ok(summary);
+        }
+        Err(err) => res.summary = Err(err),
+    }
+    res
+}
+
+// ignore the summaries in the middle until they fit in the high tokens,
+// the beginning and the end of an article matter more.
+fn drop_middle(summaries: &mut Vec<String>, tokens_len: fn(&str) -> usize, high_tokens: usize) {
+    let mut tokens_list: Vec<usize> = summaries.iter().map(|s| tokens_len(s)).collect();
+    while tokens_list.len() > 2 && tokens_list.iter().sum::<usize>() > high_tokens {
+        let i = tokens_list.len() / 2 + 1;
+        summaries.remove(i);
+        tokens_list.remove(i);
+    }
+}
+
+// the estimated reduce calls of the summaries of the pieces, the hierarchical reduce may make
+// more calls if the groups are bound by the high tokens, or fewer if the summaries fit earlier.
+fn reduce_steps(strategy: ReduceStrategy, pieces: usize, branching: usize) -> usize {
+    if pieces <= 1 {
+        return 0;
+    }
+    if strategy != ReduceStrategy::HierarchicalReduce {
+        return 1;
+    }
+    let branching = branching.max(2);
+    let (mut n, mut steps) = (pieces, 0usize);
+    while n > 1 {
+        n = (n + branching - 1) / branching;
+        steps += n;
+    }
+    steps
+}
+
+// group the adjacent summaries within the high tokens and the branching factor, a group has
+// at least 2 summaries except the last one, so every round reduces the number of summaries.
+fn group_summaries(
+    summaries: Vec<String>,
+    tokens_len: fn(&str) -> usize,
+    high_tokens: usize,
+    branching: usize,
+) -> Vec<Vec<String>> {
+    let branching = branching.max(2);
+    let mut groups: Vec<Vec<String>> = Vec::new();
+    let mut group: Vec<String> = Vec::new();
+    let mut group_tokens = 0usize;
+    for s in summaries {
+        let tokens = tokens_len(&s);
+        if group.len() >= branching || (group.len() >= 2 && group_tokens + tokens > high_tokens) {
+            groups.push(group);
+            group = Vec::new();
+            group_tokens = 0;
+        }
+        group_tokens += tokens;
+        group.push(s);
+    }
+    if !group.is_empty() {
+        groups.push(group);
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn chars_len(s: &str) -> usize {
+        s.chars().count()
+    }
+
+    // the mocked AI summarizes a text to "[<chars>]" and records its inputs.
+    fn mock_ai(
+        inputs: Arc<Mutex<Vec<String>>>,
+        fail: bool,
+    ) -> impl Fn(String) -> std::future::Ready<Result<(u32, String), HTTPError>> {
+        move |text: String| {
+            inputs.lock().unwrap().push(text.clone());
+            std::future::ready(if fail {
+                Err(HTTPError::new(500, "mock error".to_string()))
+            } else {
+                Ok((10, format!("[{}]", chars_len(&text))))
+            })
+        }
+    }
+
+    fn summaries(n: usize, len: usize) -> Vec<String> {
+        (0..n)
+            .map(|i| format!("{}{}", i, "x".repeat(len - 1)))
+            .collect()
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn reduce_summaries_works() {
+        // fit at once, all strategies summarize once
+        for strategy in [
+            ReduceStrategy::DropMiddle,
+            ReduceStrategy::HierarchicalReduce,
+            ReduceStrategy::Error,
+        ] {
+            let inputs = Arc::new(Mutex::new(Vec::new()));
+            let res = reduce_summaries(
+                strategy,
+                summaries(3, 10),
+                chars_len,
+                100,
+                8,
+                mock_ai(inputs.clone(), false),
+            )
+            .await;
+            assert_eq!(res.calls, 1);
+            assert_eq!(res.tokens, 10);
+            assert_eq!(res.summary.unwrap(), "[32]");
+            assert_eq!(inputs.lock().unwrap().len(), 1);
+        }
+
+        // drop_middle drops the summary after the middle until they fit: 4, 3, then 5
+        let inputs = Arc::new(Mutex::new(Vec::new()));
+        let res = reduce_summaries(
+            ReduceStrategy::DropMiddle,
+            summaries(6, 30),
+            chars_len,
+            100,
+            8,
+            mock_ai(inputs.clone(), false),
+        )
+        .await;
+        assert_eq!(res.calls, 1);
+        assert!(res.summary.is_ok());
+        let inputs = inputs.lock().unwrap().clone();
+        assert_eq!(inputs.len(), 1);
+        let kept: Vec<char> = inputs[0]
+            .lines()
+            .map(|l| l.chars().next().unwrap())
+            .collect();
+        assert_eq!(kept, vec!['0', '1', '2']);
+
+        // error fails without calling the AI
+        let inputs = Arc::new(Mutex::new(Vec::new()));
+        let res = reduce_summaries(
+            ReduceStrategy::Error,
+            summaries(6, 30),
+            chars_len,
+            100,
+            8,
+            mock_ai(inputs.clone(), false),
+        )
+        .await;
+        assert_eq!(res.calls, 0);
+        assert_eq!(res.summary.unwrap_err().code, 422);
+        assert!(inputs.lock().unwrap().is_empty());
+
+        // hierarchical_reduce summarizes the groups, nothing is dropped
+        let inputs = Arc::new(Mutex::new(Vec::new()));
+        let res = reduce_summaries(
+            ReduceStrategy::HierarchicalReduce,
+            summaries(6, 30),
+            chars_len,
+            100,
+            8,
+            mock_ai(inputs.clone(), false),
+        )
+        .await;
+        // 2 groups of 3 summaries in the first round, then the 2 group summaries fit at once
+        assert_eq!(res.calls, 3);
+        assert_eq!(res.tokens, 30);
+        assert!(res.summary.is_ok());
+        let inputs = inputs.lock().unwrap().clone();
+        for s in summaries(6, 30) {
+            assert!(inputs.iter().any(|i| i.contains(&s)), "{}", s);
+        }
+
+        // the AI error fails the reduce
+        let inputs = Arc::new(Mutex::new(Vec::new()));
+        let res = reduce_summaries(
+            ReduceStrategy::HierarchicalReduce,
+            summaries(6, 30),
+            chars_len,
+            100,
+            8,
+            mock_ai(inputs.clone(), true),
+        )
+        .await;
+        assert_eq!(res.calls, 0);
+        assert_eq!(res.summary.unwrap_err().code, 500);
+        assert_eq!(inputs.lock().unwrap().len(), 1);
+
+        // the levels of the tree are reduced until the summaries fit
+        let inputs = Arc::new(Mutex::new(Vec::new()));
+        let res = reduce_summaries(
+            ReduceStrategy::HierarchicalReduce,
+            summaries(8, 30),
+            chars_len,
+            10,
+            2,
+            mock_ai(inputs.clone(), false),
+        )
+        .await;
+        // 4 groups of 2, then 2 groups of 2 group summaries, then the 2 summaries fit at once
+        assert_eq!(res.calls, 7);
+        assert_eq!(
+            res.calls,
+            reduce_steps(ReduceStrategy::HierarchicalReduce, 8, 2)
+        );
+        assert_eq!(res.summary.unwrap(), "[7]");
+        assert_eq!(inputs.lock().unwrap().len(), 7);
+    }
+
+    #[test]
+    fn reduce_steps_works() {
+        assert_eq!(reduce_steps(ReduceStrategy::HierarchicalReduce, 1, 8), 0);
+        assert_eq!(reduce_steps(ReduceStrategy::DropMiddle, 100, 8), 1);
+        assert_eq!(reduce_steps(ReduceStrategy::Error, 100, 8), 1);
+        assert_eq!(reduce_steps(ReduceStrategy::HierarchicalReduce, 8, 8), 1);
+        // 100 -> 13 -> 2 -> 1
+        assert_eq!(reduce_steps(ReduceStrategy::HierarchicalReduce, 100, 8), 16);
+        // the branching factor is at least 2
+        assert_eq!(reduce_steps(ReduceStrategy::HierarchicalReduce, 4, 0), 3);
+    }
+
+    #[test]
+    fn group_summaries_works() {
+        let groups = group_summaries(summaries(5, 30), chars_len, 100, 8);
+        let sizes: Vec<usize> = groups.iter().map(|g| g.len()).collect();
+        assert_eq!(sizes, vec![3, 2]);
+
+        // a group has at least 2 summaries even if they exceed the high tokens
+        let groups = group_summaries(summaries(5, 80), chars_len, 100, 8);
+        let sizes: Vec<usize> = groups.iter().map(|g| g.len()).collect();
+        assert_eq!(sizes, vec![2, 2, 1]);
+
+        // a group has at most the branching factor summaries
+        let groups = group_summaries(summaries(7, 10), chars_len, 100, 3);
+        let sizes: Vec<usize> = groups.iter().map(|g| g.len()).collect();
+        assert_eq!(sizes, vec![3, 3, 1]);
+
+        assert!(group_summaries(vec![], chars_len, 100, 8).is_empty());
+    }
 }
@@ -0,0 +1,212 @@
+use axum::{extract::State, Extension};
+use serde::{Deserialize, Serialize};
+use std::{str::FromStr, sync::Arc};
+use validator::Validate;
+
+use axum_web::context::ReqContext;
+use axum_web::erring::{HTTPError, SuccessResponse};
+use axum_web::object::PackObject;
+
+use crate::api::{tokenize_content, AppState, TEContent, TEContentInput, TEContentList};
+use crate::openai;
+use crate::tokenizer::Encoding;
+
+// the texts of a counting request, larger ones should be estimated by the job APIs.
+const COUNT_MAX_BYTES: usize = 1024 * 1024;
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct TokenizeInput {
+    #[validate(length(max = 200000))]
+    pub text: String,
+    pub model: Option<String>, // "gpt-3.5", "gpt-4" or "text-embedding-ada-002", default "gpt-3.5"
+    pub count_only: Option<bool>, // return the tokens without the ids
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct TokenizeOutput {
+    pub encoding: String, // the tiktoken encoding of the model, e.g. "cl100k_base"
+    pub tokens: usize,
+    pub ids: Vec<u32>, // empty if count_only
+}
+
+// the token counts the segmenting uses, so the clients can pre-segment identically.
+pub async fn tokenize(
+    State(_): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<TokenizeInput>,
+) -> Result<PackObject<SuccessResponse<TokenizeOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+
+    let model = input
+        .model
+        .unwrap_or_else(|| openai::AIModel::GPT3_5.to_string());
+    ctx.set_kvs(vec![
+        ("action", "tokenize".into()),
+        ("model", model.clone().into()),
+        ("text_length", input.text.len().into()),
+    ])
+    .await;
+
+    let encoding = model_encoding(&model)?;
+
+    let ids = encoding.encode(&input.text);
+    ctx.set("tokens", ids.len().into()).await;
+    Ok(to.with(SuccessResponse::new(TokenizeOutput {
+        encoding: encoding.as_str().to_string(),
+        tokens: ids.len(),
+        ids: if input.count_only.unwrap_or(false) {
+            Vec::new()
+        } else {
+            ids.into_iter().map(|id| id as u32).collect()
+        },
+    })))
+}
+
+// the encoding of the model name, the chat models or the embedding model.
+fn model_encoding(model: &str) -> Result<Encoding, HTTPError> {
+    if model == openai::MODEL_EMBEDDING {
+        return Ok(openai::embedding_encoding());
+    }
+    Ok(openai::AIModel::from_str(&model.to_lowercase())
+        .map_err(|err| HTTPError::new(400, err.to_string()))?
+        .encoding())
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct CountInput {
+    pub text: Option<String>,
+    pub content: Option<TEContentInput>, // the TEContentList, counted by node
+    pub model: Option<String>,           // the same as TokenizeInput
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct CountOutput {
+    pub encoding: String,
+    pub tokens: usize,
+    pub nodes: Vec<NodeTokens>, // empty if counting the text
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct NodeTokens {
+    pub id: String,
+    pub tokens: usize,
+}
+
+// count the tokens of a text or a content for the live estimate of the editors.
+pub async fn count(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<CountInput>,
+) -> Result<PackObject<SuccessResponse<CountOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+
+    let model = input
+        .model
+        .unwrap_or_else(|| openai::AIModel::GPT3_5.to_string());
+    let encoding = model_encoding(&model)?;
+    let by_node = input.content.is_some();
+    let mut content = match (input.text, input.content) {
+        (Some(_), Some(_)) => {
+            return Err(HTTPError::new(
+                400,
+                "Only one of text and content is allowed".to_string(),
+            ))
+        }
+        (Some(text), None) => vec![TEContent {
+            id: String::new(),
+            texts: vec![text],
+        }],
+        (None, Some(content)) => content.decode(app.normalize_text)?,
+        (None, None) => Vec::new(),
+    };
+
+    let size = content_bytes(&content);
+    ctx.set_kvs(vec![
+        ("action", "count_tokens".into()),
+        ("model", model.clone().into()),
+        ("text_length", size.into()),
+    ])
+    .await;
+    if size > COUNT_MAX_BYTES {
+        return Err(HTTPError::new(
+            413,
+            format!(
+                "Content too large, {} bytes exceeds the limit {}",
+                size, COUNT_MAX_BYTES
+            ),
+        ));
+    }
+
+    let tokens_len = encoding.tokens_len_fn();
+    let nodes = tokenize_content(&mut content, move |c| node_tokens(c, tokens_len)).await;
+    let tokens: usize = nodes.iter().map(|n| n.tokens).sum();
+    ctx.set("tokens", tokens.into()).await;
+    Ok(to.with(SuccessResponse::new(CountOutput {
+        encoding: encoding.as_str().to_string(),
+        tokens,
+        nodes: if by_node { nodes } else { Vec::new() },
+    })))
+}
+
+fn content_bytes(content: &TEContentList) -> usize {
+    content
+        .iter()
+        .flat_map(|c| c.texts.iter())
+        .map(|t| t.len())
+        .sum()
+}
+
+fn node_tokens(content: &TEContentList, tokens_len: fn(&str) -> usize) -> Vec<NodeTokens> {
+    content
+        .iter()
+        .map(|c| NodeTokens {
+            id: c.id.clone(),
+            tokens: c.texts.iter().map(|t| tokens_len(t)).sum(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_tokens_works() {
+        let content: TEContentList = vec![
+            TEContent {
+                id: "abc".to_string(),
+                texts: vec!["Hello world".to_string(), "Rust".to_string()],
+            },
+            TEContent {
+                id: "efg".to_string(),
+                texts: vec![],
+            },
+        ];
+        assert_eq!(content_bytes(&content), 15);
+        assert_eq!(
+            node_tokens(&content, |s| s.len()),
+            vec![
+                NodeTokens {
+                    id: "abc".to_string(),
+                    tokens: 15,
+                },
+                NodeTokens {
+                    id: "efg".to_string(),
+                    tokens: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn model_encoding_works() {
+        assert_eq!(
+            model_encoding(openai::MODEL_EMBEDDING).unwrap().as_str(),
+            openai::embedding_encoding().as_str()
+        );
+        assert!(model_encoding("gpt-3.5").is_ok());
+        assert_eq!(model_encoding("unknown").unwrap_err().code, 400);
+    }
+}
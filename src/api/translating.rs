@@ -1,6 +1,10 @@
 use axum::{extract::State, Extension};
 use serde::{Deserialize, Serialize};
-use std::{str::FromStr, sync::Arc, time::Instant};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::sync::{mpsc, Semaphore};
 use validator::Validate;
 
@@ -9,24 +13,39 @@ use axum_web::erring::{HTTPError, SuccessResponse};
 use axum_web::object::{cbor_from_slice, cbor_to_vec, PackObject};
 use scylla_orm::ColumnsMap;
 
-use crate::api::{AppState, TEContentList, TEOutput, TEParams, TESegmenter, PARALLEL_WORKS};
+use crate::api::{
+    check_content_size, check_content_tokens, content_bytes, content_tokens, deserialize_language,
+    encode_blocking, incr_counter, job_deadline, job_lock_key, publish_event, tokenize_content,
+    validate_version, AppState, Dedup, EstimateOutput, JobEvent, JobKind, JobLock, LanguageTag,
+    SegmentTimings, TEContent, TEContentInput, TEContentList, TEOutput, TEParams, TESegmenter,
+    TaskContext, CREATE_IN_FLIGHT, CREATE_STARTED, JOB_DONE, JOB_FAILED, PARALLEL_WORKS,
+};
 use crate::db;
-use crate::lang::Language;
+use crate::lang::{self, Language};
 use crate::openai;
-use crate::tokenizer;
+use crate::trace;
 
 #[derive(Debug, Deserialize, Validate)]
 pub struct TranslatingInput {
-    pub gid: PackObject<xid::Id>,       // group id, content belong to
-    pub cid: PackObject<xid::Id>,       // creation id
-    pub language: PackObject<Language>, // the target language translate to
-    #[validate(range(min = 1, max = 10000))]
+    pub gid: PackObject<xid::Id>, // group id, content belong to
+    pub cid: PackObject<xid::Id>, // creation id
+    pub language: LanguageTag,    // the target language translate to, "zh-Hant" sets the script
+    #[validate(custom = "validate_version")]
     pub version: u16,
 
     pub model: Option<String>,
+    pub fallback_model: Option<String>, // the model after the retries got 429, "" to disable, default from the config
     pub context: Option<String>,
     pub from_language: Option<PackObject<Language>>,
-    pub content: Option<PackObject<Vec<u8>>>,
+    pub content: Option<TEContentInput>,
+    pub force: Option<bool>, // translate even if the origin language is the same as the target, or a recent result exists
+    pub script: Option<String>, // the target script, "Hans" or "Hant" for Chinese, default from the language tag
+    pub temperature: Option<f32>, // 0.0 ~ 2.0, 0 for the most deterministic output
+    pub top_p: Option<f32>,     // 0.01 ~ 1.0
+    #[validate(custom = "validate_version")]
+    pub base_version: Option<u16>, // reuse the translated nodes of this version if their source is unchanged
+    #[validate(range(min = 1))]
+    pub deadline: Option<u64>, // seconds, the job is stopped after it, within the configured ceiling
 }
 
 #[derive(Debug, Default, Deserialize, Serialize)]
@@ -35,9 +54,12 @@ pub struct TranslatingOutput {
     pub cid: PackObject<xid::Id>,       // document id
     pub language: PackObject<Language>, // the origin language detected.
     pub version: u16,
+    pub origin_language: PackObject<Language>, // the language translated from
     pub model: String,
+    pub script: String,
     pub progress: i8,
     pub updated_at: i64,
+    pub started_at: i64,
     pub tokens: u32,
     pub error: String,
     pub content: PackObject<Vec<u8>>,
@@ -72,9 +94,12 @@ pub async fn get(
         cid: to.with(doc.cid),
         language: to.with(doc.language),
         version: doc.version as u16,
+        origin_language: to.with(doc.origin_language),
         model: doc.model,
+        script: doc.script,
         progress: doc.progress,
         updated_at: doc.updated_at,
+        started_at: doc.started_at,
         tokens: doc.tokens as u32,
         content: to.with(doc.content),
         error: doc.error,
@@ -107,6 +132,16 @@ pub async fn list_languages(
                 lg.to_name().to_string(),
                 lg.to_autonym().unwrap().to_string(),
             ));
+            // the scripts of Chinese can be the targets too, e.g. "zho-Hant".
+            if lg == Language::Zho {
+                for (script, autonym) in [("Hans", "简体中文"), ("Hant", "繁體中文")] {
+                    list.push((
+                        format!("{}-{}", lg.to_639_3(), script),
+                        lang::language_name(lg, script),
+                        autonym.to_string(),
+                    ));
+                }
+            }
         }
     }
     Ok(to.with(SuccessResponse {
@@ -118,16 +153,35 @@ pub async fn list_languages(
 
 #[derive(Debug, Deserialize, Validate)]
 pub struct DetectLangInput {
-    pub gid: PackObject<xid::Id>,       // group id, content belong to
+    pub gid: PackObject<xid::Id>, // group id, content belong to
+    #[serde(deserialize_with = "deserialize_language")]
     pub language: PackObject<Language>, // the fallback language if detect failed
-    pub content: PackObject<Vec<u8>>,
+    pub content: TEContentInput,
+    #[validate(range(min = 1, max = 10))]
+    pub top_n: Option<u8>, // return the ranked candidates if set
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DetectLangOutput {
+    pub cid: PackObject<xid::Id>,
+    pub detected_language: PackObject<Language>, // the most likely language, or the fallback
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub candidates: Option<Vec<LangCandidate>>, // ranked by confidence, only if top_n is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub script: Option<String>, // "Hans" or "Hant" if the language is Chinese and the script is detected
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LangCandidate {
+    pub language: PackObject<Language>,
+    pub confidence: f64, // 0.0 ~ 1.0
 }
 
 pub async fn detect_lang(
     State(app): State<Arc<AppState>>,
     Extension(ctx): Extension<Arc<ReqContext>>,
     to: PackObject<DetectLangInput>,
-) -> Result<PackObject<SuccessResponse<TEOutput>>, HTTPError> {
+) -> Result<PackObject<SuccessResponse<DetectLangOutput>>, HTTPError> {
     let (to, input) = to.unpack();
     input.validate()?;
 
@@ -140,22 +194,40 @@ pub async fn detect_lang(
     ])
     .await;
 
-    let content: TEContentList = cbor_from_slice(&input.content).map_err(|e| HTTPError {
-        code: 400,
-        message: format!("Invalid content: {}", e),
-        data: None,
-    })?;
+    let content = input.content.decode(app.normalize_text)?;
 
     if content.is_empty() {
-        return Err(HTTPError::new(
-            400,
-            "Empty content to translate".to_string(),
-        ));
+        // the ranked variant returns an empty list with the fallback.
+        if input.top_n.is_some() {
+            ctx.set("result", "empty".into()).await;
+            return Ok(to.with(SuccessResponse::new(DetectLangOutput {
+                cid: to.with(xid::Id::default()),
+                detected_language: to.with(fallback_language),
+                candidates: Some(Vec::new()),
+                script: None,
+            })));
+        }
+
+        return Err(HTTPError::localized(400, "empty_content", &ctx.lang, &[]));
     }
 
     let string = content.detect_lang_string();
     ctx.set("input_size", string.len().into()).await;
     let mut detected_language = app.ld.detect_lang(&string);
+    let candidates = match input.top_n {
+        Some(top_n) if detected_language != Language::Und => Some(
+            app.ld
+                .detect_lang_candidates(&string, top_n as usize)
+                .into_iter()
+                .map(|(language, confidence)| LangCandidate {
+                    language: to.with(language),
+                    confidence,
+                })
+                .collect(),
+        ),
+        Some(_) => Some(Vec::new()),
+        None => None,
+    };
     if detected_language == Language::Und {
         ctx.set("result", "failed".into()).await;
         detected_language = fallback_language;
@@ -163,13 +235,169 @@ pub async fn detect_lang(
 
     ctx.set("language", detected_language.to_639_3().to_string().into())
         .await;
+    let script = match detected_language {
+        Language::Zho => Some(lang::detect_chinese_script(&string)).filter(|s| !s.is_empty()),
+        _ => None,
+    };
+    if let Some(script) = script {
+        ctx.set("script", script.into()).await;
+    }
 
-    Ok(to.with(SuccessResponse::new(TEOutput {
+    Ok(to.with(SuccessResponse::new(DetectLangOutput {
         cid: to.with(xid::Id::default()),
         detected_language: to.with(detected_language),
+        candidates,
+        script: script.map(|s| s.to_string()),
     })))
 }
 
+#[derive(Debug, Deserialize, Validate)]
+pub struct TextTranslatingInput {
+    #[validate(length(min = 1))]
+    pub text: String, // the plain text, paragraphs are separated by blank lines
+    pub language: LanguageTag, // the target language translate to, "zh-Hant" sets the script
+    pub from_language: Option<PackObject<Language>>,
+    pub model: Option<String>,
+    pub context: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct TextTranslatingOutput {
+    pub language: PackObject<Language>,
+    pub origin_language: PackObject<Language>, // the language translated from
+    pub model: String,
+    pub script: String,
+    pub tokens: u32,
+    pub text: String,
+}
+
+// translate a plain text synchronously, for the short texts such as chat messages.
+pub async fn translate_text(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<TextTranslatingInput>,
+) -> Result<PackObject<SuccessResponse<TextTranslatingOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+
+    let target_language = *input.language;
+    let model = app
+        .ai
+        .translating_model(target_language, input.model.as_deref())?;
+    ctx.set_kvs(vec![
+        ("action", "translate_text".into()),
+        ("language", target_language.to_639_3().to_string().into()),
+        ("model", model.to_string().into()),
+    ])
+    .await;
+
+    if target_language == Language::Und {
+        return Err(HTTPError::localized(
+            400,
+            "invalid_language",
+            &ctx.lang,
+            &[],
+        ));
+    }
+
+    let script = lang::normalize_script(target_language, input.language.script).unwrap_or("");
+    let mut content =
+        TEContentInput::List(text_to_content(&input.text)).decode(app.normalize_text)?;
+    if content.is_empty() {
+        return Err(HTTPError::localized(400, "empty_content", &ctx.lang, &[]));
+    }
+    check_content_size(&content, &app.max_content)?;
+    let tokens_len = model.encoding().tokens_len_fn();
+    let tokens = tokenize_content(&mut content, move |c| content_tokens(c, tokens_len)).await;
+    ctx.set("content_tokens", tokens.into()).await;
+    check_content_tokens(tokens, app.max_tokens.translating)?;
+
+    let mut from_language = input.from_language.unwrap_or_default().unwrap();
+    let mut confidence = 1.0f64;
+    if from_language == Language::Und {
+        (from_language, confidence) = app.ld.detect_lang_confidence(&content.detect_lang_string());
+        ctx.set("detected_confidence", confidence.into()).await;
+    }
+    check_translatable(
+        from_language,
+        target_language,
+        script,
+        false,
+        confidence,
+        &ctx.lang,
+    )?;
+
+    let segment_model = model.clone();
+    let units = tokenize_content(&mut content, move |c| {
+        c.segment(&segment_model, segment_model.encoding().tokens_len_fn())
+    })
+    .await;
+    ctx.set("pieces", units.len().into()).await;
+
+    let context = input.context.unwrap_or_default();
+    let target_lang = lang::language_name(target_language, script);
+    let mut total_tokens = 0u32;
+    let mut translated: TEContentList = Vec::with_capacity(content.len());
+    for unit in units {
+        let (used_tokens, res) = app
+            .ai
+            .translate(
+                &ctx,
+                &model,
+                &openai::Sampling::default(),
+                &context,
+                from_language.to_name(),
+                &target_lang,
+                &unit.to_translating_list(),
+            )
+            .await?;
+        total_tokens += used_tokens;
+        translated.extend(unit.replace_texts(&res));
+    }
+
+    Ok(to.with(SuccessResponse::new(TextTranslatingOutput {
+        language: to.with(target_language),
+        origin_language: to.with(from_language),
+        model: model.to_string(),
+        script: script.to_string(),
+        tokens: total_tokens,
+        text: content_to_text(&translated),
+    })))
+}
+
+// one node per paragraph, one text per line of the paragraph.
+fn text_to_content(text: &str) -> TEContentList {
+    let mut content: TEContentList = Vec::new();
+    let mut texts: Vec<String> = Vec::new();
+    for line in text.lines().map(|l| l.trim_end()) {
+        if !line.trim().is_empty() {
+            texts.push(line.to_string());
+        } else if !texts.is_empty() {
+            content.push(TEContent {
+                id: (content.len() + 1).to_string(),
+                texts: std::mem::take(&mut texts),
+            });
+        }
+    }
+    if !texts.is_empty() {
+        content.push(TEContent {
+            id: (content.len() + 1).to_string(),
+            texts,
+        });
+    }
+    content
+}
+
+// the reverse of text_to_content, the paragraphs are joined by blank lines.
+fn content_to_text(content: &TEContentList) -> String {
+    content
+        .iter()
+        .map(|c| c.texts.join("\n"))
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<String>>()
+        .join("\n\n")
+}
+
 pub async fn create(
     State(app): State<Arc<AppState>>,
     Extension(ctx): Extension<Arc<ReqContext>>,
@@ -177,14 +405,18 @@ pub async fn create(
 ) -> Result<PackObject<SuccessResponse<TEOutput>>, HTTPError> {
     let (to, input) = to.unpack();
     input.validate()?;
+    app.jobs.check_open()?;
+    app.jobs.check_capacity(JobKind::Translating)?;
 
     let gid = *input.gid;
     let cid = *input.cid;
     let target_language = *input.language;
-    let model = match input.model {
-        Some(model) => openai::AIModel::from_str(&model.to_lowercase())?,
-        None => openai::AIModel::GPT3_5,
-    };
+    let model = app
+        .ai
+        .translating_model(target_language, input.model.as_deref())?;
+    let fallback = app
+        .ai
+        .fallback_model(&model, input.fallback_model.as_deref())?;
 
     ctx.set_kvs(vec![
         ("action", "create_translating".into()),
@@ -195,78 +427,220 @@ pub async fn create(
         ("model", model.to_string().into()),
     ])
     .await;
+    if let Some(fallback) = &fallback {
+        ctx.set("fallback_model", fallback.to_string().into()).await;
+    }
 
     if target_language == Language::Und {
-        return Err(HTTPError::new(400, "Invalid language".to_string()));
+        return Err(HTTPError::localized(
+            400,
+            "invalid_language",
+            &ctx.lang,
+            &[],
+        ));
     }
 
-    let content: TEContentList =
-        cbor_from_slice(&input.content.unwrap_or_default()).map_err(|e| HTTPError {
-            code: 400,
-            message: format!("Invalid content: {}", e),
-            data: None,
-        })?;
-    if content.is_empty() {
-        return Err(HTTPError::new(
+    let script = input
+        .script
+        .unwrap_or_else(|| input.language.script.to_string());
+    let script = lang::normalize_script(target_language, &script).ok_or_else(|| {
+        HTTPError::localized(
             400,
-            "Empty content to translate".to_string(),
-        ));
+            "invalid_script",
+            &ctx.lang,
+            &[&script, &target_language],
+        )
+    })?;
+    if !script.is_empty() {
+        ctx.set("script", script.into()).await;
+    }
+
+    let sampling = openai::Sampling::new(input.temperature, input.top_p);
+    let deadline = job_deadline(&app.deadline, input.deadline);
+    ctx.set_kvs(vec![
+        ("temperature", sampling.temperature.into()),
+        ("top_p", sampling.top_p.into()),
+        ("deadline", deadline.as_secs().into()),
+    ])
+    .await;
+
+    let mut content = input
+        .content
+        .unwrap_or_default()
+        .decode(app.normalize_text)?;
+    if content.is_empty() {
+        return Err(HTTPError::localized(400, "empty_content", &ctx.lang, &[]));
     }
+    check_content_size(&content, &app.max_content)?;
+    let tokens_len = model.encoding().tokens_len_fn();
+    let tokens = tokenize_content(&mut content, move |c| content_tokens(c, tokens_len)).await;
+    ctx.set("content_tokens", tokens.into()).await;
+    check_content_tokens(tokens, app.max_tokens.translating)?;
 
     let mut from_language = input.from_language.unwrap_or_default().unwrap();
+    let mut confidence = 1.0f64;
     if from_language == Language::Und {
-        from_language = app.ld.detect_lang(&content.detect_lang_string());
+        (from_language, confidence) = app.ld.detect_lang_confidence(&content.detect_lang_string());
+        ctx.set("detected_confidence", confidence.into()).await;
     }
 
-    if from_language == target_language || from_language == Language::Und {
-        return Err(HTTPError::new(
-            400,
-            format!(
-                "can not translate from '{}' to '{}'",
-                from_language, target_language
+    let force = input.force.unwrap_or(false);
+    if let Some(warning) = check_translatable(
+        from_language,
+        target_language,
+        script,
+        force,
+        confidence,
+        &ctx.lang,
+    )? {
+        ctx.set_kvs(vec![
+            (
+                "detected_language",
+                from_language.to_639_3().to_string().into(),
             ),
-        ));
+            ("warning", warning.into()),
+        ])
+        .await;
     }
 
+    let carried = match input.base_version.filter(|v| *v != input.version) {
+        Some(base_version) => {
+            let carried = base_translated(
+                &app,
+                db::Translating::with_pk(gid, cid, target_language, base_version as i16),
+                model.to_string(),
+                script,
+                &content,
+            )
+            .await;
+            ctx.set_kvs(vec![
+                ("base_version", base_version.into()),
+                ("carried", carried.len().into()),
+            ])
+            .await;
+            carried
+        }
+        None => HashMap::new(),
+    };
+
     let now = unix_ms() as i64;
     let mut doc = db::Translating::with_pk(gid, cid, target_language, input.version as i16);
-    if doc
+    let dedup = match doc
         .get_one(
             &app.scylla,
             vec![
                 "model".to_string(),
+                "script".to_string(),
                 "updated_at".to_string(),
                 "progress".to_string(),
                 "error".to_string(),
             ],
         )
         .await
-        .is_ok()
-        && doc.model == model.to_string()
-        && doc.error.is_empty()
-        && doc.progress == 100
-        && now - doc.updated_at < 600 * 1000
     {
-        ctx.set("exists", true.into()).await;
-        return Ok(to.with(SuccessResponse::new(TEOutput {
-            cid: to.with(cid),
-            detected_language: to.with(from_language),
-        })));
+        Ok(_) if doc.error.is_empty() => Dedup::check(
+            now - doc.updated_at,
+            doc.progress == 100 && doc.model == model.to_string() && doc.script == script,
+            doc.progress < 100,
+            app.dedup.translating,
+            app.dedup.in_flight,
+            force,
+        ),
+        _ => Dedup::Run,
+    };
+    match dedup {
+        Dedup::Exists | Dedup::InFlight => {
+            ctx.set_kvs(vec![
+                ("exists", true.into()),
+                ("in_flight", (dedup == Dedup::InFlight).into()),
+            ])
+            .await;
+            return Ok(to.with(SuccessResponse::new(TEOutput {
+                cid: to.with(cid),
+                detected_language: to.with(from_language),
+                status: dedup.status().to_string(),
+            })));
+        }
+        Dedup::Rerun => {
+            ctx.set("rerun", true.into()).await;
+            log::info!(target: "translating",
+                action = "force_rerun",
+                rid = ctx.rid,
+                user = ctx.user.to_string(),
+                gid = gid.to_string(),
+                cid = cid.to_string(),
+                updated_at = doc.updated_at;
+                "override the recent result",
+            );
+        }
+        Dedup::Run => {}
     }
 
-    let mut cols = ColumnsMap::with_capacity(6);
+    // another replica may pass the dedup check at the same time.
+    let lock_key = job_lock_key(
+        "translating",
+        gid,
+        cid,
+        target_language,
+        input.version as i16,
+    );
+    let lock = match JobLock::acquire(app.redis.clone(), lock_key, ctx.rid.clone()).await {
+        Ok(Some(lock)) => Some(lock),
+        Ok(None) => {
+            ctx.set_kvs(vec![("exists", true.into()), ("locked", true.into())])
+                .await;
+            return Ok(to.with(SuccessResponse::new(TEOutput {
+                cid: to.with(cid),
+                detected_language: to.with(from_language),
+                status: CREATE_IN_FLIGHT.to_string(),
+            })));
+        }
+        Err(err) => {
+            // run without the lock rather than failing the request.
+            ctx.set("lock_error", err.to_string().into()).await;
+            None
+        }
+    };
+
+    // save the source content with the job, so a failed job can be retried without it.
+    let source = match cbor_to_vec(&content) {
+        Ok(source) => source,
+        Err(err) => {
+            if let Some(lock) = lock {
+                lock.release().await;
+            }
+            return Err(HTTPError::new(500, err.to_string()));
+        }
+    };
+    let mut cols = ColumnsMap::with_capacity(10);
+    cols.set_as("origin_language", &from_language);
     cols.set_as("model", &model.to_string());
+    cols.set_as("script", &script.to_string());
     cols.set_as("updated_at", &now);
+    cols.set_as("started_at", &now);
     cols.set_as("progress", &0i8);
     cols.set_as("tokens", &0i32);
     cols.set_as("content", &Vec::<u8>::new());
+    cols.set_as("source", &source);
     cols.set_as("error", &"".to_string());
-    doc.upsert_fields(&app.scylla, cols).await?;
+    if let Err(err) = doc.upsert_fields(&app.scylla, cols).await {
+        if let Some(lock) = lock {
+            lock.release().await;
+        }
+        return Err(err.into());
+    }
 
-    tokio::spawn(translate(
+    let span_attrs = vec![
+        ("gid", gid.to_string()),
+        ("cid", cid.to_string()),
+        ("model", model.to_string()),
+    ];
+    let user = app.job_user(ctx.user);
+    let jobs = app.jobs.clone();
+    let job = translate(
         app,
         ctx.rid.clone(),
-        ctx.user,
+        user,
         TEParams {
             gid,
             cid,
@@ -274,74 +648,517 @@ pub async fn create(
             language: target_language,
             content,
         },
-        input.context.unwrap_or_default(),
-        from_language,
-        model,
-    ));
+        TOptions {
+            context: input.context.unwrap_or_default(),
+            origin_language: from_language,
+            script: script.to_string(),
+            model,
+            fallback,
+            sampling,
+            carried,
+            deadline,
+        },
+    );
+    let job = trace::in_span("translating", span_attrs, job);
+    jobs.spawn(JobKind::Translating, async move {
+        job.await;
+        if let Some(lock) = lock {
+            lock.release().await;
+        }
+    });
 
     Ok(to.with(SuccessResponse::new(TEOutput {
         cid: to.with(cid),
         detected_language: to.with(from_language),
+        status: CREATE_STARTED.to_string(),
+    })))
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct RetryInput {
+    pub gid: PackObject<xid::Id>,
+    pub cid: PackObject<xid::Id>,
+    #[serde(deserialize_with = "deserialize_language")]
+    pub language: PackObject<Language>,
+    #[validate(custom = "validate_version")]
+    pub version: u16,
+
+    pub fallback_model: Option<String>,
+    pub context: Option<String>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    #[validate(range(min = 1))]
+    pub deadline: Option<u64>,
+}
+
+// rerun a failed translating from the source content saved by `create`,
+// with the same model, script and origin language.
+pub async fn retry(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<RetryInput>,
+) -> Result<PackObject<SuccessResponse<TEOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+    app.jobs.check_open()?;
+    app.jobs.check_capacity(JobKind::Translating)?;
+
+    let gid = *input.gid;
+    let cid = *input.cid;
+    let target_language = *input.language;
+    ctx.set_kvs(vec![
+        ("action", "retry_translating".into()),
+        ("gid", gid.to_string().into()),
+        ("cid", cid.to_string().into()),
+        ("language", target_language.to_639_3().to_string().into()),
+        ("version", input.version.into()),
+    ])
+    .await;
+
+    let mut doc = db::Translating::with_pk(gid, cid, target_language, input.version as i16);
+    doc.get_one(
+        &app.scylla,
+        vec![
+            "origin_language".to_string(),
+            "model".to_string(),
+            "script".to_string(),
+            "source".to_string(),
+            "error".to_string(),
+        ],
+    )
+    .await?;
+    if doc.error.is_empty() {
+        return Err(HTTPError::new(
+            400,
+            "Only a failed translating can be retried".to_string(),
+        ));
+    }
+    // the jobs created before the source content was saved.
+    if doc.source.is_empty() {
+        return Err(HTTPError::new(
+            404,
+            "No source content to retry, create the translating again".to_string(),
+        ));
+    }
+    let content: TEContentList = cbor_from_slice(&doc.source).map_err(|e| HTTPError {
+        code: 500,
+        message: format!("Invalid source content: {}", e),
+        data: None,
+    })?;
+
+    let model = app
+        .ai
+        .translating_model(target_language, Some(doc.model.as_str()))?;
+    let fallback = app
+        .ai
+        .fallback_model(&model, input.fallback_model.as_deref())?;
+    let sampling = openai::Sampling::new(input.temperature, input.top_p);
+    let deadline = job_deadline(&app.deadline, input.deadline);
+    ctx.set_kvs(vec![
+        ("model", model.to_string().into()),
+        (
+            "origin_language",
+            doc.origin_language.to_639_3().to_string().into(),
+        ),
+        ("last_error", doc.error.clone().into()),
+        ("deadline", deadline.as_secs().into()),
+    ])
+    .await;
+
+    let lock_key = job_lock_key(
+        "translating",
+        gid,
+        cid,
+        target_language,
+        input.version as i16,
+    );
+    let lock = match JobLock::acquire(app.redis.clone(), lock_key, ctx.rid.clone()).await {
+        Ok(Some(lock)) => Some(lock),
+        Ok(None) => {
+            ctx.set_kvs(vec![("exists", true.into()), ("locked", true.into())])
+                .await;
+            return Ok(to.with(SuccessResponse::new(TEOutput {
+                cid: to.with(cid),
+                detected_language: to.with(doc.origin_language),
+                status: CREATE_IN_FLIGHT.to_string(),
+            })));
+        }
+        Err(err) => {
+            ctx.set("lock_error", err.to_string().into()).await;
+            None
+        }
+    };
+
+    let now = unix_ms() as i64;
+    let mut cols = ColumnsMap::with_capacity(7);
+    cols.set_as("model", &model.to_string());
+    cols.set_as("updated_at", &now);
+    cols.set_as("started_at", &now);
+    cols.set_as("progress", &0i8);
+    cols.set_as("tokens", &0i32);
+    cols.set_as("content", &Vec::<u8>::new());
+    cols.set_as("error", &"".to_string());
+    if let Err(err) = doc.upsert_fields(&app.scylla, cols).await {
+        if let Some(lock) = lock {
+            lock.release().await;
+        }
+        return Err(err.into());
+    }
+
+    let span_attrs = vec![
+        ("gid", gid.to_string()),
+        ("cid", cid.to_string()),
+        ("model", model.to_string()),
+    ];
+    let user = app.job_user(ctx.user);
+    let jobs = app.jobs.clone();
+    let job = translate(
+        app,
+        ctx.rid.clone(),
+        user,
+        TEParams {
+            gid,
+            cid,
+            version: input.version as i16,
+            language: target_language,
+            content,
+        },
+        TOptions {
+            context: input.context.unwrap_or_default(),
+            origin_language: doc.origin_language,
+            script: doc.script,
+            model,
+            fallback,
+            sampling,
+            carried: HashMap::new(),
+            deadline,
+        },
+    );
+    let job = trace::in_span("translating", span_attrs, job);
+    jobs.spawn(JobKind::Translating, async move {
+        job.await;
+        if let Some(lock) = lock {
+            lock.release().await;
+        }
+    });
+
+    Ok(to.with(SuccessResponse::new(TEOutput {
+        cid: to.with(cid),
+        detected_language: to.with(doc.origin_language),
+        status: CREATE_STARTED.to_string(),
     })))
 }
 
+#[derive(Debug, Deserialize, Validate)]
+pub struct EstimateInput {
+    pub language: LanguageTag, // the target language translate to, "zh-Hant" sets the script
+    pub model: Option<String>,
+    pub context: Option<String>,
+    pub from_language: Option<PackObject<Language>>,
+    pub script: Option<String>,
+    pub content: TEContentInput,
+}
+
+pub async fn estimate(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<EstimateInput>,
+) -> Result<PackObject<SuccessResponse<EstimateOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+
+    let target_language = *input.language;
+    let model = app
+        .ai
+        .translating_model(target_language, input.model.as_deref())?;
+
+    ctx.set_kvs(vec![
+        ("action", "estimate_translating".into()),
+        ("language", target_language.to_639_3().to_string().into()),
+        ("model", model.to_string().into()),
+    ])
+    .await;
+
+    if target_language == Language::Und {
+        return Err(HTTPError::new(400, "Invalid language".to_string()));
+    }
+
+    let script = input
+        .script
+        .unwrap_or_else(|| input.language.script.to_string());
+    let script = lang::normalize_script(target_language, &script).ok_or_else(|| {
+        HTTPError::new(
+            400,
+            format!("Invalid script '{}' for '{}'", script, target_language),
+        )
+    })?;
+
+    let mut content = input.content.decode(app.normalize_text)?;
+    let mut from_language = input.from_language.unwrap_or_default().unwrap();
+    if from_language == Language::Und {
+        from_language = app.ld.detect_lang(&content.detect_lang_string());
+    }
+
+    let context = input.context.unwrap_or_default();
+    let target_lang = lang::language_name(target_language, script);
+    let segment_model = model.clone();
+    let units = tokenize_content(&mut content, move |c| {
+        c.segment(&segment_model, segment_model.encoding().tokens_len_fn())
+    })
+    .await;
+    let prompt_tokens: usize = units
+        .iter()
+        .map(|unit| {
+            app.ai.estimate_translate(
+                &model,
+                &context,
+                from_language.to_name(),
+                &target_lang,
+                &unit.to_translating_list(),
+            )
+        })
+        .sum();
+
+    let res = EstimateOutput::new(units.len(), prompt_tokens, model.prompt_price());
+    ctx.set_kvs(vec![
+        ("segments", res.segments.into()),
+        ("prompt_tokens", res.prompt_tokens.into()),
+    ])
+    .await;
+    Ok(to.with(SuccessResponse::new(res)))
+}
+
+// load the finished translating of the base version, return its translated nodes whose source
+// texts are the same as the content, keyed by the node id. the new or changed nodes are not in it
+// and will be translated, the nodes deleted from the content are dropped.
+async fn base_translated(
+    app: &AppState,
+    mut base: db::Translating,
+    model: String,
+    script: &str,
+    content: &TEContentList,
+) -> HashMap<String, TEContent> {
+    let found = base
+        .get_one(
+            &app.scylla,
+            vec![
+                "model".to_string(),
+                "script".to_string(),
+                "progress".to_string(),
+                "content".to_string(),
+                "source".to_string(),
+                "error".to_string(),
+            ],
+        )
+        .await
+        .is_ok();
+    if !found
+        || base.model != model
+        || base.script != script
+        || base.progress != 100
+        || !base.error.is_empty()
+        || base.source.is_empty()
+    {
+        return HashMap::new();
+    }
+
+    match (
+        cbor_from_slice::<TEContentList>(&base.source),
+        cbor_from_slice::<TEContentList>(&base.content),
+    ) {
+        (Ok(source), Ok(translated)) => unchanged_translated(content, &source, translated),
+        _ => HashMap::new(),
+    }
+}
+
+// the translated nodes whose source texts are unchanged in the content.
+fn unchanged_translated(
+    content: &TEContentList,
+    base_source: &TEContentList,
+    base_translated: TEContentList,
+) -> HashMap<String, TEContent> {
+    let base_source: HashMap<&str, &Vec<String>> = base_source
+        .iter()
+        .map(|c| (c.id.as_str(), &c.texts))
+        .collect();
+    let unchanged: Vec<&str> = content
+        .iter()
+        .filter(|c| base_source.get(c.id.as_str()) == Some(&&c.texts))
+        .map(|c| c.id.as_str())
+        .collect();
+    base_translated
+        .into_iter()
+        .filter(|c| unchanged.contains(&c.id.as_str()))
+        .map(|c| (c.id.clone(), c))
+        .collect()
+}
+
+// merge the translated and carried nodes in the order of the source content.
+fn merge_translated(
+    source: &TEContentList,
+    translated: impl Iterator<Item = TEContent>,
+    mut carried: HashMap<String, TEContent>,
+) -> TEContentList {
+    let mut translated: HashMap<String, TEContent> =
+        translated.map(|c| (c.id.clone(), c)).collect();
+    source
+        .iter()
+        .filter_map(|c| carried.remove(&c.id).or_else(|| translated.remove(&c.id)))
+        .collect()
+}
+
+// the detection below this confidence is not trusted to refuse a translating.
+const DETECT_CONFIDENCE_THRESHOLD: f64 = 0.5;
+
+// return a warning message if the translating is allowed only because of forcing or low confidence.
+// the origin script is unknown, so translating to a requested script is always allowed.
+fn check_translatable(
+    from_language: Language,
+    target_language: Language,
+    script: &str,
+    force: bool,
+    confidence: f64,
+    lang: &str,
+) -> Result<Option<String>, HTTPError> {
+    if from_language == Language::Und {
+        return Err(HTTPError::localized(
+            400,
+            "untranslatable",
+            lang,
+            &[&from_language, &target_language],
+        ));
+    }
+
+    if from_language != target_language || !script.is_empty() {
+        return Ok(None);
+    }
+
+    if force {
+        return Ok(Some(format!(
+            "forced to translate from '{}' to '{}'",
+            from_language, target_language
+        )));
+    }
+
+    if confidence < DETECT_CONFIDENCE_THRESHOLD {
+        return Ok(Some(format!(
+            "detected language '{}' with low confidence {:.2}",
+            from_language, confidence
+        )));
+    }
+
+    Err(HTTPError::localized(
+        400,
+        "untranslatable",
+        lang,
+        &[&from_language, &target_language],
+    ))
+}
+
+// the content translated so far is saved every CONTENT_FLUSH_PIECES pieces or CONTENT_FLUSH_INTERVAL,
+// so `get` can preview it without rewriting the whole content on every piece.
+const CONTENT_FLUSH_PIECES: usize = 5;
+const CONTENT_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+fn should_flush_content(unflushed: usize, since_flush: Duration) -> bool {
+    unflushed > 0 && (unflushed >= CONTENT_FLUSH_PIECES || since_flush >= CONTENT_FLUSH_INTERVAL)
+}
+
+// the options of a translating job besides its content.
+struct TOptions {
+    context: String,
+    origin_language: Language,
+    script: String, // the target script, empty for the default
+    model: openai::AIModel,
+    fallback: Option<openai::AIModel>, // the model after the retries got 429
+    sampling: openai::Sampling,
+    carried: HashMap<String, TEContent>, // the translated nodes reused from the base version
+    deadline: Duration,                  // the job is stopped after it
+}
+
 async fn translate(
     app: Arc<AppState>,
     rid: String,
     user: xid::Id,
-    te: TEParams,
-    context: String,
-    origin_language: Language,
-    model: openai::AIModel,
+    mut te: TEParams,
+    opts: TOptions,
 ) {
-    let tokio_translating = app.translating.clone();
+    let task = TaskContext::new(&rid, user, &te);
+    let TOptions {
+        context,
+        origin_language,
+        script,
+        model,
+        fallback,
+        sampling,
+        carried,
+        deadline,
+    } = opts;
+    let target_lang = lang::language_name(te.language, &script);
 
-    let content = te.content.segment(&model, tokenizer::tokens_len);
+    // only translate the nodes not carried from the base version.
+    let mut changed: TEContentList = te
+        .content
+        .iter()
+        .filter(|c| !carried.contains_key(&c.id))
+        .cloned()
+        .collect();
+    // the segments should also fit the fallback model, so they are not re-segmented on fallback.
+    let segment_model = openai::segment_model(&model, fallback.as_ref()).clone();
+    let content = tokenize_content(&mut changed, move |c| {
+        c.segment(&segment_model, segment_model.encoding().tokens_len_fn())
+    })
+    .await;
     let pieces = content.len();
     let start = Instant::now();
 
     log::info!(target: "translating",
         action = "start_job",
-        rid = rid,
-        user = user.to_string(),
-        gid = te.gid.to_string(),
-        cid = te.cid.to_string(),
-        language = te.language.to_639_3().to_string(),
-        pieces = pieces;
+        task = log::as_serde!(task),
+        pieces = pieces,
+        carried = carried.len();
         "",
     );
 
     let semaphore = Arc::new(Semaphore::new(PARALLEL_WORKS));
-    let (tx, mut rx) =
-        mpsc::channel::<(usize, ReqContext, Result<(u32, TEContentList), HTTPError>)>(pieces);
+    #[allow(clippy::type_complexity)]
+    let (tx, mut rx) = mpsc::channel::<(
+        usize,
+        ReqContext,
+        Result<(openai::AIModel, u32, TEContentList), HTTPError>,
+    )>(pieces.max(1));
     for (i, unit) in content.into_iter().enumerate() {
         let rid = rid.clone();
         let app = app.clone();
         let origin = origin_language.to_name();
-        let lang = te.language.to_name();
+        let lang = target_lang.clone();
         let model = model.clone();
+        let fallback = fallback.clone();
         let tx = tx.clone();
         let sem = semaphore.clone();
         let context = context.clone();
-        tokio::spawn(async move {
+        trace::spawn(async move {
             if let Ok(permit) = sem.acquire().await {
                 let ctx = ReqContext::new(rid, user, 0);
                 match app
                     .ai
-                    .translate(
+                    .translate_or_fallback(
                         &ctx,
                         &model,
+                        fallback.as_ref(),
+                        &sampling,
                         &context,
                         origin,
-                        lang,
+                        &lang,
                         &unit.to_translating_list(),
                     )
                     .await
                 {
-                    Ok((used_tokens, content)) => {
+                    Ok((used_model, used_tokens, content)) => {
                         drop(permit);
+                        let content = unit.replace_texts(&content);
                         let _ = tx
-                            .send((i, ctx, Ok((used_tokens, unit.replace_texts(&content)))))
+                            .send((i, ctx, Ok((used_model, used_tokens, content))))
                             .await;
                     }
                     Err(err) => {
@@ -356,11 +1173,48 @@ async fn translate(
 
     let mut total_tokens: usize = 0;
     let mut progress = 0usize;
+    let mut fallback_pieces = 0usize;
+    let mut timings = SegmentTimings::with_capacity(pieces);
+    let (mut flushed, mut flushed_at) = (0usize, Instant::now());
     let mut doc = db::Translating::with_pk(te.gid, te.cid, te.language, te.version);
     let mut res_list: Vec<TEContentList> = Vec::with_capacity(pieces);
     res_list.resize(pieces, vec![]);
 
-    while let Some((i, ctx, res)) = rx.recv().await {
+    let deadline = tokio::time::Instant::now() + deadline;
+    loop {
+        let (i, ctx, res) = match app.jobs.until(deadline, rx.recv()).await {
+            Ok(Some(item)) => item,
+            Ok(None) => break,
+            Err(reason) => {
+                // stop the pieces not started yet, and keep the finished ones.
+                semaphore.close();
+                let content_list =
+                    merge_translated(&te.content, res_list.into_iter().flatten(), carried);
+                let mut cols = ColumnsMap::with_capacity(4);
+                cols.set_as("updated_at", &(unix_ms() as i64));
+                cols.set_as("tokens", &(total_tokens as i32));
+                if let Ok(content) = cbor_to_vec(&content_list) {
+                    cols.set_as("content", &content);
+                }
+                cols.set_as("error", &reason.to_string());
+                let _ = doc.upsert_fields(&app.scylla, cols).await;
+
+                log::warn!(target: "translating",
+                    action = "deadline",
+                    task = log::as_serde!(task),
+                    elapsed = start.elapsed().as_millis() as u64,
+                    pieces = pieces,
+                    finished = progress,
+                    total_tokens = total_tokens;
+                    "{}", reason,
+                );
+                let event =
+                    JobEvent::new(db::CounterKind::Translating, &te, JOB_FAILED, total_tokens);
+                publish_event(&app, &task, event).await;
+                return;
+            }
+        };
+
         let ai_elapsed = ctx.start.elapsed().as_millis() as u64;
         let kv = ctx.get_kv().await;
         if let Err(err) = res {
@@ -371,33 +1225,48 @@ async fn translate(
 
             log::error!(target: "translating",
                 action = "call_openai",
-                rid = ctx.rid,
-                cid = te.cid.to_string(),
-                language = te.language.to_639_3().to_string(),
+                task = log::as_serde!(task),
                 start = ctx.unix_ms,
                 elapsed = ai_elapsed,
                 piece_at = i,
                 kv = log::as_serde!(kv);
                 "{}", err.to_string(),
             );
+            let event = JobEvent::new(db::CounterKind::Translating, &te, JOB_FAILED, total_tokens);
+            publish_event(&app, &task, event).await;
             return;
         }
 
-        let (used_tokens, content) = res.unwrap();
+        let (used_model, used_tokens, content) = res.unwrap();
+        if used_model != model {
+            fallback_pieces += 1;
+        }
         total_tokens += used_tokens as usize;
         progress += 1;
         res_list[i] = content;
+        timings.push(i, ai_elapsed, used_tokens);
 
-        let mut cols = ColumnsMap::with_capacity(3);
+        let mut cols = ColumnsMap::with_capacity(4);
         cols.set_as("updated_at", &(unix_ms() as i64));
         cols.set_as("progress", &((progress * 100 / pieces) as i8));
         cols.set_as("tokens", &(total_tokens as i32));
+        // the last piece is saved with the final content below.
+        if progress < pieces && should_flush_content(progress - flushed, flushed_at.elapsed()) {
+            let partial = merge_translated(
+                &te.content,
+                res_list.iter().flatten().cloned(),
+                carried.clone(),
+            );
+            if let Ok(partial) = cbor_to_vec(&partial) {
+                cols.set_as("content", &partial);
+                (flushed, flushed_at) = (progress, Instant::now());
+            }
+        }
         let _ = doc.upsert_fields(&app.scylla, cols).await;
 
         log::info!(target: "translating",
             action = "call_openai",
-            rid = ctx.rid,
-            cid = te.cid.to_string(),
+            task = log::as_serde!(task),
             start = ctx.unix_ms,
             elapsed = ai_elapsed,
             tokens = used_tokens,
@@ -409,14 +1278,19 @@ async fn translate(
         );
     }
 
-    let mut content_list: TEContentList =
-        Vec::with_capacity(res_list.iter().map(|x| x.len()).sum());
-    for content in res_list {
-        content_list.extend(content);
-    }
+    let content_list = merge_translated(&te.content, res_list.into_iter().flatten(), carried);
 
-    // save target lang doc to db
-    let content = cbor_to_vec(&content_list);
+    // save target lang doc to db, with the source to diff by the next version.
+    // a large document is encoded in the blocking pool, see BLOCKING_ENCODE_BYTES.
+    let source_list = std::mem::take(&mut te.content);
+    let size = content_bytes(&content_list) + content_bytes(&source_list);
+    let (source_list, content) = encode_blocking(size, move || {
+        let content = cbor_to_vec(&content_list)
+            .and_then(|content| cbor_to_vec(&source_list).map(|source| (content, source)));
+        (source_list, content)
+    })
+    .await;
+    te.content = source_list;
     if let Err(err) = content {
         let err = err.to_string();
         let mut cols = ColumnsMap::with_capacity(2);
@@ -426,28 +1300,37 @@ async fn translate(
 
         log::warn!(target: "translating",
             action = "to_cbor",
-            rid = &rid,
-            cid = te.cid.to_string();
+            task = log::as_serde!(task);
             "{}", err,
         );
+        let event = JobEvent::new(db::CounterKind::Translating, &te, JOB_FAILED, total_tokens);
+        publish_event(&app, &task, event).await;
         return;
     }
 
-    let mut cols = ColumnsMap::with_capacity(5);
-    let content = content.unwrap();
+    let mut cols = ColumnsMap::with_capacity(7);
+    let (content, source) = content.unwrap();
+    // the result is recorded with the fallback model if any piece was translated by it.
+    if fallback_pieces > 0 {
+        if let Some(fallback) = &fallback {
+            cols.set_as("model", &fallback.to_string());
+        }
+    }
     cols.set_as("updated_at", &(unix_ms() as i64));
     cols.set_as("progress", &100i8);
     cols.set_as("tokens", &(total_tokens as i32));
     cols.set_as("content", &content);
+    cols.set_as("source", &source);
     cols.set_as("error", &"".to_string());
 
     let elapsed = start.elapsed().as_millis() as u64;
+    let mut status = JOB_DONE;
     match doc.upsert_fields(&app.scylla, cols).await {
         Err(err) => {
+            status = JOB_FAILED;
             log::error!(target: "translating",
                 action = "to_scylla",
-                rid = &rid,
-                cid = te.cid.to_string(),
+                task = log::as_serde!(task),
                 elapsed = start.elapsed().as_millis() as u64 - elapsed,
                 content_length = content.len();
                 "{}", err,
@@ -456,8 +1339,7 @@ async fn translate(
         Ok(_) => {
             log::info!(target: "translating",
                 action = "to_scylla",
-                rid = &rid,
-                cid = te.cid.to_string(),
+                task = log::as_serde!(task),
                 elapsed = start.elapsed().as_millis() as u64 - elapsed,
                 content_length = content.len();
                 "success",
@@ -465,15 +1347,238 @@ async fn translate(
         }
     };
 
+    if let Some(summary) = timings.summary() {
+        log::log!(target: "translating", timings.level(),
+            action = "segment_timings",
+            task = log::as_serde!(task),
+            summary = log::as_serde!(summary);
+            "",
+        );
+    }
+    trace::set_attribute("tokens", total_tokens as i64);
     log::info!(target: "translating",
         action = "finish_job",
-        rid = rid,
-        cid = te.cid.to_string(),
+        task = log::as_serde!(task),
         elapsed = start.elapsed().as_millis() as u64,
         pieces = pieces,
+        fallback_pieces = fallback_pieces,
         total_tokens = total_tokens;
         "",
     );
 
-    let _ = tokio_translating.as_str(); // avoid unused warning
+    incr_counter(
+        &app,
+        db::CounterKind::Translating,
+        &task,
+        user,
+        te.gid,
+        pieces,
+        total_tokens,
+    )
+    .await;
+    let event = JobEvent::new(db::CounterKind::Translating, &te, status, total_tokens);
+    publish_event(&app, &task, event).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct CreateInput<T: Serialize> {
+        gid: PackObject<xid::Id>,
+        cid: PackObject<xid::Id>,
+        language: &'static str,
+        version: u16,
+        content: T,
+    }
+
+    impl<T: Serialize> CreateInput<T> {
+        fn new(to: &PackObject<()>, content: T) -> Self {
+            Self {
+                gid: to.with(xid::new()),
+                cid: to.with(xid::new()),
+                language: "zho",
+                version: 1,
+                content,
+            }
+        }
+    }
+
+    #[test]
+    fn text_to_content_works() {
+        assert!(text_to_content("").is_empty());
+        assert!(text_to_content(" \n\n  ").is_empty());
+
+        let content = text_to_content("Hello\n\n\nline 1\n  line 2  \n \nBye");
+        assert_eq!(
+            content,
+            vec![
+                TEContent {
+                    id: "1".to_string(),
+                    texts: vec!["Hello".to_string()],
+                },
+                TEContent {
+                    id: "2".to_string(),
+                    texts: vec!["line 1".to_string(), "  line 2".to_string()],
+                },
+                TEContent {
+                    id: "3".to_string(),
+                    texts: vec!["Bye".to_string()],
+                },
+            ]
+        );
+        assert_eq!(
+            content_to_text(&content),
+            "Hello\n\nline 1\n  line 2\n\nBye"
+        );
+        assert_eq!(content_to_text(&Vec::new()), "");
+    }
+
+    #[test]
+    fn create_input_content_works() {
+        let content: TEContentList = vec![TEContent {
+            id: "abc".to_string(),
+            texts: vec!["Hello world".to_string()],
+        }];
+        let json = PackObject::Json(());
+        let cbor = PackObject::Cbor(());
+
+        // JSON request with the content list directly
+        let data = serde_json::to_vec(&CreateInput::new(&json, content.clone())).unwrap();
+        let input: TranslatingInput = serde_json::from_slice(&data).unwrap();
+        assert!(matches!(input.content, Some(TEContentInput::List(_))));
+        assert_eq!(input.content.unwrap().decode(false).unwrap(), content);
+
+        // JSON request with the content encoded in CBOR as base64url string
+        let encoded = json.with(cbor_to_vec(&content).unwrap());
+        let data = serde_json::to_vec(&CreateInput::new(&json, encoded)).unwrap();
+        let input: TranslatingInput = serde_json::from_slice(&data).unwrap();
+        assert!(matches!(input.content, Some(TEContentInput::Cbor(_))));
+        assert_eq!(input.content.unwrap().decode(false).unwrap(), content);
+
+        // CBOR request with the content encoded in CBOR as bytes
+        let encoded = cbor.with(cbor_to_vec(&content).unwrap());
+        let data = cbor_to_vec(&CreateInput::new(&cbor, encoded)).unwrap();
+        let input: TranslatingInput = cbor_from_slice(&data).unwrap();
+        assert!(matches!(input.content, Some(TEContentInput::Cbor(_))));
+        assert_eq!(input.content.unwrap().decode(false).unwrap(), content);
+
+        // CBOR request with the content list directly
+        let data = cbor_to_vec(&CreateInput::new(&cbor, content.clone())).unwrap();
+        let input: TranslatingInput = cbor_from_slice(&data).unwrap();
+        assert_eq!(input.content.unwrap().decode(false).unwrap(), content);
+
+        // invalid CBOR content
+        let encoded = cbor.with(b"not cbor".to_vec());
+        let data = cbor_to_vec(&CreateInput::new(&cbor, encoded)).unwrap();
+        let input: TranslatingInput = cbor_from_slice(&data).unwrap();
+        let err = input.content.unwrap().decode(false).unwrap_err();
+        assert_eq!(err.code, 400);
+
+        // missing content
+        let data = serde_json::to_vec(&CreateInput::new(&json, ())).unwrap();
+        let input: TranslatingInput = serde_json::from_slice(&data).unwrap();
+        assert!(input.content.is_none());
+    }
+
+    fn te(id: &str, texts: &[&str]) -> TEContent {
+        TEContent {
+            id: id.to_string(),
+            texts: texts.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn unchanged_translated_works() {
+        let base_source = vec![te("a", &["Hello"]), te("b", &["World"]), te("c", &["Bye"])];
+        let base_translated = vec![te("a", &["你好"]), te("b", &["世界"]), te("c", &["再见"])];
+        // "b" is changed, "c" is deleted, "d" is added.
+        let content = vec![te("a", &["Hello"]), te("b", &["World!"]), te("d", &["New"])];
+
+        let carried = unchanged_translated(&content, &base_source, base_translated);
+        assert_eq!(carried.len(), 1);
+        assert_eq!(carried.get("a"), Some(&te("a", &["你好"])));
+
+        let translated = vec![te("d", &["新"]), te("b", &["世界！"])];
+        let res = merge_translated(&content, translated.into_iter(), carried);
+        assert_eq!(
+            res,
+            vec![te("a", &["你好"]), te("b", &["世界！"]), te("d", &["新"])]
+        );
+    }
+
+    #[test]
+    fn input_language_works() {
+        let json = PackObject::Json(());
+        let mut input = CreateInput::new(&json, ());
+        for (language, expected, script) in [
+            ("zho", Language::Zho, ""),
+            ("zh-Hans", Language::Zho, "Hans"),
+            ("zho-Hant", Language::Zho, "Hant"),
+            ("zh-TW", Language::Zho, "Hant"),
+            ("en", Language::Eng, ""),
+            ("pt-BR", Language::Por, ""),
+        ] {
+            input.language = language;
+            let data = serde_json::to_vec(&input).unwrap();
+            let res: TranslatingInput = serde_json::from_slice(&data).unwrap();
+            assert_eq!(*res.language, expected);
+            assert_eq!(res.language.script, script);
+        }
+
+        input.language = "xx-YY";
+        let data = serde_json::to_vec(&input).unwrap();
+        assert!(serde_json::from_slice::<TranslatingInput>(&data).is_err());
+    }
+
+    #[test]
+    fn should_flush_content_works() {
+        assert!(!should_flush_content(0, CONTENT_FLUSH_INTERVAL));
+        assert!(!should_flush_content(1, Duration::from_secs(1)));
+        assert!(should_flush_content(
+            CONTENT_FLUSH_PIECES,
+            Duration::from_secs(1)
+        ));
+        assert!(should_flush_content(1, CONTENT_FLUSH_INTERVAL));
+    }
+
+    #[test]
+    fn check_translatable_works() {
+        assert!(
+            check_translatable(Language::Eng, Language::Zho, "", false, 1.0, "")
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            check_translatable(Language::Eng, Language::Zho, "", false, 0.1, "")
+                .unwrap()
+                .is_none()
+        );
+
+        let err = check_translatable(Language::Eng, Language::Eng, "", false, 1.0, "").unwrap_err();
+        assert_eq!(err.code, 400);
+        let err = check_translatable(Language::Und, Language::Eng, "", true, 1.0, "").unwrap_err();
+        assert_eq!(err.code, 400);
+        let err =
+            check_translatable(Language::Und, Language::Eng, "", true, 1.0, "zh").unwrap_err();
+        assert!(err.message.starts_with("无法从"), "{}", err.message);
+
+        // forced run
+        let res = check_translatable(Language::Eng, Language::Eng, "", true, 1.0, "").unwrap();
+        assert!(res.unwrap().starts_with("forced"));
+
+        // confidence-based bypass
+        let res = check_translatable(Language::Eng, Language::Eng, "", false, 0.3, "").unwrap();
+        assert!(res.unwrap().contains("low confidence"));
+        let err = check_translatable(Language::Eng, Language::Eng, "", false, 0.5, "").unwrap_err();
+        assert_eq!(err.code, 400);
+
+        // converting to a requested script
+        assert!(
+            check_translatable(Language::Zho, Language::Zho, "Hant", false, 1.0, "")
+                .unwrap()
+                .is_none()
+        );
+    }
 }
@@ -0,0 +1,218 @@
+use axum::{extract::State, Extension};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use validator::Validate;
+
+use axum_web::context::ReqContext;
+use axum_web::erring::{HTTPError, SuccessResponse};
+use axum_web::object::PackObject;
+
+use crate::api::AppState;
+use crate::db;
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct CounterInput {
+    pub uid: PackObject<xid::Id>, // user id, the user started the jobs
+    pub gid: PackObject<xid::Id>, // group id, content belong to
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct CounterOutput {
+    pub uid: PackObject<xid::Id>,
+    pub gid: PackObject<xid::Id>,
+    pub translating_calls: i64,
+    pub translating_tokens: i64,
+    pub summarizing_calls: i64,
+    pub summarizing_tokens: i64,
+    pub embedding_calls: i64,
+    pub embedding_tokens: i64,
+}
+
+// the lifetime usage of the user in the group, 404 if the user has no job in the group.
+pub async fn get_counter(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<CounterInput>,
+) -> Result<PackObject<SuccessResponse<CounterOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+
+    let uid = *input.uid;
+    let gid = *input.gid;
+
+    ctx.set_kvs(vec![
+        ("action", "get_counter".into()),
+        ("uid", uid.to_string().into()),
+        ("gid", gid.to_string().into()),
+    ])
+    .await;
+
+    let mut doc = db::Counter::with_pk(uid, gid);
+    doc.get_one(&app.scylla, vec![]).await?;
+    Ok(to.with(SuccessResponse::new(CounterOutput {
+        uid: to.with(doc.uid),
+        gid: to.with(doc.gid),
+        translating_calls: doc.translating_calls,
+        translating_tokens: doc.translating_tokens,
+        summarizing_calls: doc.summarizing_calls,
+        summarizing_tokens: doc.summarizing_tokens,
+        embedding_calls: doc.embedding_calls,
+        embedding_tokens: doc.embedding_tokens,
+    })))
+}
+
+// the max days of a report, about a quarter.
+const REPORT_MAX_DAYS: i64 = 92;
+const REPORT_DEFAULT_PAGE_SIZE: u16 = 31;
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct ReportInput {
+    pub gid: PackObject<xid::Id>, // group id, content belong to
+    pub start_day: i32,           // the first UTC day, yyyymmdd, example: 20231101
+    pub end_day: i32,             // the last UTC day, yyyymmdd, inclusive
+    #[validate(range(min = 1, max = 92))]
+    pub page_size: Option<u16>, // the max number of days to return, default 31
+    pub page_token: Option<PackObject<Vec<u8>>>, // the next_page_token of the previous page
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct JobUsage {
+    pub calls: i64,
+    pub tokens: i64,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct DailyUsageOutput {
+    pub day: i32,
+    pub translating: JobUsage,
+    pub summarizing: JobUsage,
+    pub embedding: JobUsage,
+}
+
+impl From<db::UsageDaily> for DailyUsageOutput {
+    fn from(doc: db::UsageDaily) -> Self {
+        Self {
+            day: doc.day,
+            translating: JobUsage {
+                calls: doc.translating_calls,
+                tokens: doc.translating_tokens,
+            },
+            summarizing: JobUsage {
+                calls: doc.summarizing_calls,
+                tokens: doc.summarizing_tokens,
+            },
+            embedding: JobUsage {
+                calls: doc.embedding_calls,
+                tokens: doc.embedding_tokens,
+            },
+        }
+    }
+}
+
+// the daily usage of the group in the days range, the days without usage are omitted.
+pub async fn report(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<ReportInput>,
+) -> Result<PackObject<SuccessResponse<Vec<DailyUsageOutput>>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+
+    let gid = *input.gid;
+    ctx.set_kvs(vec![
+        ("action", "usage_report".into()),
+        ("gid", gid.to_string().into()),
+        ("start_day", input.start_day.into()),
+        ("end_day", input.end_day.into()),
+    ])
+    .await;
+
+    check_days_range(input.start_day, input.end_day)?;
+    let start_day = match input.page_token {
+        None => input.start_day,
+        Some(token) => decode_page_token(&token, input.start_day, input.end_day)?,
+    };
+
+    let page_size = input.page_size.unwrap_or(REPORT_DEFAULT_PAGE_SIZE) as usize;
+    let mut docs = db::UsageDaily::list(
+        &app.scylla,
+        gid,
+        start_day,
+        input.end_day,
+        page_size as i32 + 1,
+        vec![],
+    )
+    .await?;
+
+    let next_page_token = if docs.len() > page_size {
+        let next = docs[page_size].day;
+        docs.truncate(page_size);
+        Some(to.with(next.to_be_bytes().to_vec()))
+    } else {
+        None
+    };
+
+    ctx.set("days", docs.len().into()).await;
+    Ok(to.with(SuccessResponse {
+        total_size: None,
+        next_page_token,
+        result: docs.into_iter().map(DailyUsageOutput::from).collect(),
+    }))
+}
+
+fn check_days_range(start_day: i32, end_day: i32) -> Result<(), HTTPError> {
+    let start = db::days_of(start_day)
+        .ok_or_else(|| HTTPError::new(400, format!("Invalid start_day: {}", start_day)))?;
+    let end = db::days_of(end_day)
+        .ok_or_else(|| HTTPError::new(400, format!("Invalid end_day: {}", end_day)))?;
+    if end < start || end - start >= REPORT_MAX_DAYS {
+        return Err(HTTPError::new(
+            400,
+            format!(
+                "Invalid days range {} to {}, should be at most {} days",
+                start_day, end_day, REPORT_MAX_DAYS
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// the page token is the next day to list, in 4 bytes big-endian.
+fn decode_page_token(token: &[u8], start_day: i32, end_day: i32) -> Result<i32, HTTPError> {
+    let day: [u8; 4] = token
+        .try_into()
+        .map_err(|_| HTTPError::new(400, "Invalid page_token".to_string()))?;
+    let day = i32::from_be_bytes(day);
+    if day < start_day || day > end_day {
+        return Err(HTTPError::new(400, "Invalid page_token".to_string()));
+    }
+    Ok(day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_days_range_works() {
+        assert!(check_days_range(20231101, 20231101).is_ok());
+        assert!(check_days_range(20231101, 20240131).is_ok());
+        // 92 days
+        assert!(check_days_range(20231001, 20231231).is_ok());
+        assert!(check_days_range(20231001, 20240101).is_err());
+        assert!(check_days_range(20231102, 20231101).is_err());
+        assert!(check_days_range(20231131, 20231201).is_err());
+    }
+
+    #[test]
+    fn decode_page_token_works() {
+        let token = 20231115i32.to_be_bytes();
+        assert_eq!(
+            decode_page_token(&token, 20231101, 20231130).unwrap(),
+            20231115
+        );
+        assert!(decode_page_token(&token, 20231116, 20231130).is_err());
+        assert!(decode_page_token(&token, 20231101, 20231114).is_err());
+        assert!(decode_page_token(&token[..3], 20231101, 20231130).is_err());
+    }
+}
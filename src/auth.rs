@@ -0,0 +1,123 @@
+use axum::{
+    extract::{ConnectInfo, State},
+    http::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{net::SocketAddr, sync::Arc};
+
+use axum_web::erring::HTTPError;
+
+use crate::conf;
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+// the probes of the load balancer and the orchestrator do not carry a key.
+const EXEMPT_PATHS: [&str; 3] = ["/", "/healthz", "/readyz"];
+
+// the admin routes are forbidden if the authentication is disabled.
+pub const ADMIN_PATH_PREFIX: &str = "/admin/";
+
+pub struct ApiKeys {
+    keys: Vec<Vec<u8>>,
+}
+
+impl ApiKeys {
+    pub fn new(cfg: &conf::Auth) -> Self {
+        Self {
+            keys: cfg
+                .api_keys
+                .iter()
+                .map(|k| k.trim())
+                .filter(|k| !k.is_empty())
+                .map(|k| k.as_bytes().to_vec())
+                .collect(),
+        }
+    }
+
+    // no keys configured, the authentication is disabled.
+    pub fn is_disabled(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    // every configured key is compared, so that a rotated key is accepted until removed.
+    pub fn verify(&self, key: &[u8]) -> bool {
+        self.keys
+            .iter()
+            .fold(false, |ok, k| constant_time_eq(k, key) | ok)
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+// reject the requests without a valid API key before the context middleware trusts the
+// user headers. the key is never logged.
+pub async fn middleware<B>(
+    State(keys): State<Arc<ApiKeys>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if EXEMPT_PATHS.contains(&req.uri().path()) {
+        return next.run(req).await;
+    }
+    if keys.is_disabled() {
+        if req.uri().path().starts_with(ADMIN_PATH_PREFIX) {
+            return HTTPError::new(403, "admin routes require API keys".to_string())
+                .into_response();
+        }
+        return next.run(req).await;
+    }
+
+    let reason = match req.headers().get(API_KEY_HEADER) {
+        None => "missing",
+        Some(key) if keys.verify(key.as_bytes()) => return next.run(req).await,
+        Some(_) => "invalid",
+    };
+
+    let ip = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map_or_else(|| "unknown".to_string(), |ci| ci.0.ip().to_string());
+    log::warn!(target: "auth",
+        action = "reject",
+        ip = ip,
+        method = req.method().as_str(),
+        path = req.uri().path();
+        "{} API key", reason,
+    );
+    HTTPError::new(401, format!("{} API key", reason)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn api_keys_works() {
+        let keys = ApiKeys::new(&conf::Auth::default());
+        assert!(keys.is_disabled());
+        assert!(!keys.verify(b""));
+
+        let keys = ApiKeys::new(&conf::Auth {
+            api_keys: vec![
+                "old-key".to_string(),
+                " new-key ".to_string(),
+                "".to_string(),
+            ],
+        });
+        assert!(!keys.is_disabled());
+        assert!(keys.verify(b"old-key"));
+        assert!(keys.verify(b"new-key"));
+        assert!(!keys.verify(b"new-ke"));
+        assert!(!keys.verify(b"new-key2"));
+        assert!(!keys.verify(b""));
+    }
+}
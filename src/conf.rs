@@ -1,5 +1,9 @@
 use config::{Config, ConfigError, File, FileFormat};
 use serde::Deserialize;
+use std::{collections::HashMap, str::FromStr};
+
+// the default system user, the owner of the jobs not started by a user.
+pub static DEFAULT_SYSTEM_USER: &str = "0000000000000jarvis0";
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Log {
@@ -14,11 +18,60 @@ pub struct Server {
     pub graceful_shutdown: usize,
 }
 
+#[derive(Deserialize, Clone, Default)]
+pub struct Auth {
+    #[serde(default)]
+    pub api_keys: Vec<String>, // the accepted keys of the x-api-key header, empty to disable
+}
+
+// the keys are never logged.
+impl std::fmt::Debug for Auth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Auth")
+            .field("api_keys", &format!("{} keys", self.api_keys.len()))
+            .finish()
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct ScyllaDB {
     pub nodes: Vec<String>,
     pub username: String,
     pub password: String,
+    #[serde(default)]
+    pub tls: Option<ScyllaTLS>, // connect with TLS if present
+    #[serde(default)]
+    pub ttl: HashMap<String, u32>, // seconds, the TTL of the rows written per table, 0 to keep forever
+    #[serde(default = "default_scylla_consistency")]
+    pub read_consistency: String, // the consistency of SELECT statements, example: "LocalQuorum"
+    #[serde(default = "default_scylla_consistency")]
+    pub write_consistency: String, // the consistency of the other statements and batches
+    #[serde(default = "default_scylla_request_timeout")]
+    pub request_timeout: u64, // seconds
+}
+
+fn default_scylla_consistency() -> String {
+    "Quorum".to_string()
+}
+
+fn default_scylla_request_timeout() -> u64 {
+    5
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ScyllaTLS {
+    #[serde(default)]
+    pub ca_file: String, // the CA certificate file (PEM) to verify the nodes
+    #[serde(default)]
+    pub cert_file: String, // the client certificate file (PEM) for mutual TLS
+    #[serde(default)]
+    pub key_file: String, // the client private key file (PEM) for mutual TLS
+    #[serde(default = "default_scylla_tls_verify_peer")]
+    pub verify_peer: bool, // verify the certificates of the nodes
+}
+
+fn default_scylla_tls_verify_peer() -> bool {
+    true
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -26,6 +79,85 @@ pub struct Qdrant {
     pub url: String,
     #[serde(default)]
     pub api_key: String,
+    #[serde(default)]
+    pub public_url: String, // the url of the public collection, empty to use the url
+    #[serde(default)]
+    pub public_api_key: String, // the api_key for the public_url, empty to use the api_key
+    #[serde(default)]
+    pub collection: String, // the private collection, empty to use the Scylla keyspace
+    #[serde(default)]
+    pub collection_public: String, // the public collection, empty to use the collection + "_pub"
+    #[serde(default)]
+    pub ensure_collections: bool, // create the missing collections and payload indexes at startup
+    #[serde(default)]
+    pub ensure_payload_indexes: bool, // create the keyword payload indexes at startup
+    #[serde(default = "default_qdrant_vector_size")]
+    pub vector_size: u64, // the vector size of the collections created
+    #[serde(default = "default_qdrant_distance")]
+    pub distance: String, // the distance of the collections created: "Cosine", "Euclid", "Dot"
+    #[serde(default)]
+    pub vector_name: String, // the name of the dense vector in the collections, empty for the single unnamed vector
+    #[serde(default = "default_qdrant_retry_attempts")]
+    pub retry_attempts: u32, // the max attempts of an operation failed with a transient error
+    #[serde(default = "default_qdrant_retry_backoff")]
+    pub retry_backoff: u64, // ms, the delay before the first retry, doubled on each retry
+    #[serde(default = "default_qdrant_batch_size")]
+    pub batch_size: usize, // the max number of points in one upsert request
+}
+
+fn default_qdrant_batch_size() -> usize {
+    200
+}
+
+fn default_qdrant_retry_attempts() -> u32 {
+    3
+}
+
+fn default_qdrant_retry_backoff() -> u64 {
+    200
+}
+
+fn default_qdrant_vector_size() -> u64 {
+    1536
+}
+
+fn default_qdrant_distance() -> String {
+    "Cosine".to_string()
+}
+
+impl Qdrant {
+    pub fn public_url(&self) -> &str {
+        if self.public_url.is_empty() {
+            &self.url
+        } else {
+            &self.public_url
+        }
+    }
+
+    pub fn public_api_key(&self) -> &str {
+        if self.public_api_key.is_empty() {
+            &self.api_key
+        } else {
+            &self.public_api_key
+        }
+    }
+
+    // the keyspace is the name before the collection was configurable.
+    pub fn collection(&self, keyspace: &str) -> String {
+        if self.collection.is_empty() {
+            keyspace.to_string()
+        } else {
+            self.collection.clone()
+        }
+    }
+
+    pub fn collection_public(&self, keyspace: &str) -> String {
+        if self.collection_public.is_empty() {
+            self.collection(keyspace) + "_pub"
+        } else {
+            self.collection_public.clone()
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -37,6 +169,8 @@ pub struct AzureAI {
     pub embedding_model: String,
     pub chat_model: String,
     pub gpt4_chat_model: String,
+    #[serde(default)]
+    pub disable_request_gzip: bool, // send the request body uncompressed to this endpoint
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -44,6 +178,8 @@ pub struct OpenAI {
     pub agent_endpoint: String,
     pub api_key: String,
     pub org_id: String,
+    #[serde(default)]
+    pub disable_request_gzip: bool, // send the request body uncompressed to this endpoint
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -52,11 +188,39 @@ pub struct Agent {
     pub client_root_cert_file: String,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct EmbeddingLimit {
+    pub model: String,     // the embedding model, example: "text-embedding-ada-002"
+    pub max_array: usize,  // the max number of inputs in one request
+    pub max_tokens: usize, // the max total tokens of inputs in one request
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct AI {
     pub agent: Agent,
     pub openai: OpenAI,
     pub azureais: Vec<AzureAI>,
+    #[serde(default)]
+    pub embedding_limits: Vec<EmbeddingLimit>,
+    #[serde(default = "default_ai_compress_min_length")]
+    pub compress_min_length: usize, // gzip the request body from this length in bytes, 0 to disable
+    #[serde(default)]
+    pub model_by_language: HashMap<String, String>, // the translating model of a target language
+    #[serde(default)]
+    pub fallback_model: String, // the translating model after the retries got 429, empty to disable
+    #[serde(default)]
+    pub injection: Injection,
+}
+
+#[derive(Debug, Default, Deserialize, Clone)]
+pub struct Injection {
+    pub patterns: Option<Vec<String>>, // the suspicious patterns, None for the built-in ones
+    #[serde(default)]
+    pub wrap: bool, // wrap the flagged content in delimiters before summarizing
+}
+
+fn default_ai_compress_min_length() -> usize {
+    256
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -66,6 +230,220 @@ pub struct Redis {
     pub username: String,
     pub password: String,
     pub max_connections: u16,
+    #[serde(default = "default_redis_data_ttl")]
+    pub data_ttl: u64, // seconds, the TTL of job data, refreshed on every progress update
+    #[serde(default = "default_redis_events_channel")]
+    pub events_channel: String, // the pub/sub channel of the job events, empty to disable
+}
+
+fn default_redis_data_ttl() -> u64 {
+    600
+}
+
+fn default_redis_events_channel() -> String {
+    "jarvis:events".to_string()
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Janitor {
+    pub interval: u64,      // seconds, the interval to scan stalled jobs, 0 to disable
+    pub stalled_after: u64, // seconds, a unfinished job not updated for this long is stalled
+}
+
+impl Default for Janitor {
+    fn default() -> Self {
+        Self {
+            interval: 600,
+            stalled_after: 3600,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Dedup {
+    pub translating: u64, // seconds, a finished translating updated within it is not created again
+    pub summarizing: u64, // seconds, a finished summarizing updated within it is not created again
+    #[serde(default = "default_dedup_embedding")]
+    pub embedding: u64, // seconds, a finished embedding updated within it is not created again
+    pub in_flight: u64, // seconds, a running job updated within it is not started again, even if forced
+}
+
+fn default_dedup_embedding() -> u64 {
+    600
+}
+
+impl Default for Dedup {
+    fn default() -> Self {
+        Self {
+            translating: 3600,
+            summarizing: 3600,
+            embedding: default_dedup_embedding(),
+            in_flight: 3600,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MaxTokens {
+    pub translating: usize, // the max tokens of the content to translate, 0 for no limit
+    pub summarizing: usize, // the max tokens of the content to summarize, 0 for no limit
+    pub embedding: usize,   // the max tokens of the content to embed, 0 for no limit
+}
+
+impl Default for MaxTokens {
+    fn default() -> Self {
+        Self {
+            translating: 200000,
+            summarizing: 200000,
+            embedding: 500000,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MaxContent {
+    pub nodes: usize, // the max nodes of the content to start a job, 0 for no limit
+    pub bytes: usize, // the max bytes of the texts of the content to start a job, 0 for no limit
+}
+
+impl Default for MaxContent {
+    fn default() -> Self {
+        Self {
+            nodes: 10000,
+            bytes: 4 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Deadline {
+    pub default: u64, // seconds, the deadline of a job if the request does not set it
+    pub max: u64,     // seconds, the ceiling of the deadline a request can set, 0 for no ceiling
+}
+
+impl Default for Deadline {
+    fn default() -> Self {
+        Self {
+            default: 1800,
+            max: 3600,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MaxJobs {
+    pub translating: usize, // the max running translating jobs, including the retries, 0 for no limit
+    pub message_translating: usize, // the max running message translating jobs, 0 for no limit
+    pub summarizing: usize, // the max running summarizing jobs, 0 for no limit
+    pub embedding: usize,   // the max running embedding jobs, 0 for no limit
+    pub reembedding: usize, // the max running reembedding jobs, 0 for no limit
+    pub public_copy: usize, // the max running public copy jobs, 0 for no limit
+    pub retry_after: u64,   // seconds, the Retry-After hint of the rejected jobs
+}
+
+impl Default for MaxJobs {
+    fn default() -> Self {
+        Self {
+            translating: 200,
+            message_translating: 200,
+            summarizing: 100,
+            embedding: 200,
+            reembedding: 20,
+            public_copy: 100,
+            retry_after: 30,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Lang {
+    #[serde(default)]
+    pub languages: Vec<String>, // ISO 639-3 codes of the languages to detect, empty for all
+    #[serde(default = "default_lang_preload")]
+    pub preload: bool, // load the language models at startup, otherwise on the first use
+    #[serde(default = "default_lang_cache_size")]
+    pub cache_size: u64, // the max entries of the detected languages cache, 0 to disable
+}
+
+fn default_lang_cache_size() -> u64 {
+    10000
+}
+
+fn default_lang_preload() -> bool {
+    true
+}
+
+impl Default for Lang {
+    fn default() -> Self {
+        Self {
+            languages: Vec::new(),
+            preload: true,
+            cache_size: 10000,
+        }
+    }
+}
+
+// the OpenTelemetry exporter, used only with the `tracing` feature.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Tracing {
+    #[serde(default)]
+    pub otlp_endpoint: String, // the OTLP gRPC endpoint, e.g. "http://127.0.0.1:4317", empty to disable
+    #[serde(default = "default_tracing_service_name")]
+    pub service_name: String,
+}
+
+fn default_tracing_service_name() -> String {
+    "jarvis".to_string()
+}
+
+impl Default for Tracing {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: String::new(),
+            service_name: default_tracing_service_name(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Summarizing {
+    #[serde(default)]
+    pub reduce_strategy: ReduceStrategy,
+    #[serde(default = "default_summarizing_branching_factor")]
+    pub branching_factor: usize, // the max summaries reduced in one call of the hierarchical reduce, >= 2
+}
+
+fn default_summarizing_branching_factor() -> usize {
+    8
+}
+
+impl Default for Summarizing {
+    fn default() -> Self {
+        Self {
+            reduce_strategy: ReduceStrategy::default(),
+            branching_factor: default_summarizing_branching_factor(),
+        }
+    }
+}
+
+// how to reduce the summaries of the pieces when they are too long to summarize at once.
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReduceStrategy {
+    DropMiddle, // drop the summaries in the middle, the beginning and the end matter more
+    #[default]
+    HierarchicalReduce, // summarize the groups of summaries level by level until one is left
+    Error,      // fail the job rather than drop content
+}
+
+impl ReduceStrategy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReduceStrategy::DropMiddle => "drop_middle",
+            ReduceStrategy::HierarchicalReduce => "hierarchical_reduce",
+            ReduceStrategy::Error => "error",
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -73,10 +451,42 @@ pub struct Conf {
     pub env: String,
     pub log: Log,
     pub server: Server,
+    #[serde(default)]
+    pub auth: Auth,
     pub scylla: ScyllaDB,
     pub qdrant: Qdrant,
     pub redis: Redis,
     pub ai: AI,
+    #[serde(default)]
+    pub janitor: Janitor,
+    #[serde(default)]
+    pub dedup: Dedup,
+    #[serde(default)]
+    pub max_tokens: MaxTokens,
+    #[serde(default)]
+    pub max_content: MaxContent,
+    #[serde(default)]
+    pub deadline: Deadline,
+    #[serde(default)]
+    pub max_jobs: MaxJobs,
+    #[serde(default)]
+    pub summarizing: Summarizing,
+    #[serde(default)]
+    pub lang: Lang,
+    #[serde(default)]
+    pub tracing: Tracing,
+    #[serde(default = "default_system_user")]
+    pub system_user: String, // the xid of the system user
+    #[serde(default = "default_normalize_text")]
+    pub normalize_text: bool, // normalize the content texts of requests before tokenizing
+}
+
+fn default_normalize_text() -> bool {
+    true
+}
+
+fn default_system_user() -> String {
+    DEFAULT_SYSTEM_USER.to_string()
 }
 
 impl Conf {
@@ -88,6 +498,68 @@ impl Conf {
 
     pub fn from(file_name: &str) -> Result<Self, ConfigError> {
         let builder = Config::builder().add_source(File::new(file_name, FileFormat::Toml));
-        builder.build()?.try_deserialize::<Conf>()
+        let cfg = builder.build()?.try_deserialize::<Conf>()?;
+        parse_system_user(&cfg.system_user)?;
+        if cfg.summarizing.branching_factor < 2 {
+            return Err(ConfigError::Message(format!(
+                "invalid summarizing.branching_factor {}, should >= 2",
+                cfg.summarizing.branching_factor
+            )));
+        }
+        Ok(cfg)
+    }
+
+    // the system_user is validated when loading, so it is always a valid xid.
+    pub fn system_user(&self) -> xid::Id {
+        parse_system_user(&self.system_user).expect("invalid system_user")
+    }
+}
+
+fn parse_system_user(s: &str) -> Result<xid::Id, ConfigError> {
+    xid::Id::from_str(s)
+        .map_err(|err| ConfigError::Message(format!("invalid system_user {:?}: {:?}", s, err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn system_user_works() {
+        let id = parse_system_user(DEFAULT_SYSTEM_USER).unwrap();
+        assert_eq!(id.to_string(), DEFAULT_SYSTEM_USER);
+        assert_eq!(xid::Id::from_str(&id.to_string()).unwrap(), id);
+
+        assert!(parse_system_user("").is_err());
+        assert!(parse_system_user("jarvis00000000000000x").is_err());
+    }
+
+    #[test]
+    fn dedup_works() {
+        let cfg: Dedup = serde_json::from_value(serde_json::json!({
+            "translating": 60, "summarizing": 60, "in_flight": 120
+        }))
+        .unwrap();
+        assert_eq!(cfg.embedding, 600);
+        assert_eq!(cfg.in_flight, 120);
+
+        let cfg = Dedup::default();
+        assert_eq!(cfg.translating, 3600);
+        assert_eq!(cfg.summarizing, 3600);
+    }
+
+    #[test]
+    fn qdrant_collection_works() {
+        let mut cfg: Qdrant =
+            serde_json::from_value(serde_json::json!({"url": "http://127.0.0.1:6334"})).unwrap();
+        assert_eq!(cfg.collection("jarvis_test"), "jarvis_test");
+        assert_eq!(cfg.collection_public("jarvis_test"), "jarvis_test_pub");
+
+        cfg.collection = "docs".to_string();
+        assert_eq!(cfg.collection("jarvis_test"), "docs");
+        assert_eq!(cfg.collection_public("jarvis_test"), "docs_pub");
+
+        cfg.collection_public = "public_docs".to_string();
+        assert_eq!(cfg.collection_public("jarvis_test"), "public_docs");
     }
 }
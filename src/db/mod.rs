@@ -1,13 +1,27 @@
+mod model_counter;
+mod model_deleting;
 mod model_embedding;
+mod model_embedding_job;
+mod model_public_copy;
+mod model_qdrant_pending;
+mod model_reembedding;
 mod model_summarizing;
 mod model_translating;
+mod model_usage_daily;
 
 pub mod qdrant;
 pub mod redis;
 pub mod scylladb;
 
+pub use model_counter::{Counter, CounterKind};
+pub use model_deleting::Deleting;
 pub use model_embedding::Embedding;
+pub use model_embedding_job::EmbeddingJob;
+pub use model_public_copy::{
+    PublicCopy, PUBLIC_COPY_DONE, PUBLIC_COPY_FAILED, PUBLIC_COPY_PENDING, PUBLIC_COPY_REMOVED,
+};
+pub use model_qdrant_pending::QdrantPending;
+pub use model_reembedding::Reembedding;
 pub use model_summarizing::Summarizing;
 pub use model_translating::Translating;
-
-pub static USER_JARVIS: &str = "0000000000000jarvis0"; // system user
+pub use model_usage_daily::{day_of, days_of, UsageDaily};
@@ -0,0 +1,164 @@
+use axum_web::erring::HTTPError;
+use scylla_orm::{ColumnsMap, ToCqlVal};
+use scylla_orm_macros::CqlOrm;
+
+use crate::db::scylladb;
+
+// the usage of a user in a group, the columns are CQL counters.
+#[derive(Debug, Default, Clone, CqlOrm)]
+pub struct Counter {
+    pub uid: xid::Id,
+    pub gid: xid::Id,
+    pub translating_calls: i64,
+    pub translating_tokens: i64,
+    pub summarizing_calls: i64,
+    pub summarizing_tokens: i64,
+    pub embedding_calls: i64,
+    pub embedding_tokens: i64,
+
+    pub _fields: Vec<String>, // selected fields，`_` 前缀字段会被 CqlOrm 忽略
+}
+
+// the kind of the counted jobs, the prefix of the counter columns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CounterKind {
+    Translating,
+    Summarizing,
+    Embedding,
+}
+
+impl CounterKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CounterKind::Translating => "translating",
+            CounterKind::Summarizing => "summarizing",
+            CounterKind::Embedding => "embedding",
+        }
+    }
+
+    // the SET clause to increment the calls and tokens columns of the kind.
+    pub fn incr_columns(&self) -> String {
+        let k = self.as_str();
+        format!("{k}_calls={k}_calls+?,{k}_tokens={k}_tokens+?", k = k)
+    }
+}
+
+impl Counter {
+    pub fn with_pk(uid: xid::Id, gid: xid::Id) -> Self {
+        Self {
+            uid,
+            gid,
+            ..Default::default()
+        }
+    }
+
+    pub fn select_fields(select_fields: Vec<String>, with_pk: bool) -> anyhow::Result<Vec<String>> {
+        if select_fields.is_empty() {
+            return Ok(Self::fields());
+        }
+
+        let fields = Self::fields();
+        for field in &select_fields {
+            if !fields.contains(field) {
+                return Err(HTTPError::new(400, format!("Invalid field: {}", field)).into());
+            }
+        }
+
+        let mut select_fields = select_fields;
+        if with_pk {
+            for field in ["uid", "gid"] {
+                let field = field.to_string();
+                if !select_fields.contains(&field) {
+                    select_fields.push(field);
+                }
+            }
+        }
+
+        Ok(select_fields)
+    }
+
+    pub async fn get_one(
+        &mut self,
+        db: &scylladb::ScyllaDB,
+        select_fields: Vec<String>,
+    ) -> anyhow::Result<()> {
+        let fields = Self::select_fields(select_fields, false)?;
+        self._fields = fields.clone();
+
+        let query = format!(
+            "SELECT {} FROM counter WHERE uid=? AND gid=? LIMIT 1",
+            fields.join(",")
+        );
+        let params = (self.uid.to_cql(), self.gid.to_cql());
+        let res = db.execute(query, params).await?.single_row()?;
+
+        let mut cols = ColumnsMap::with_capacity(fields.len());
+        cols.fill(res, &fields)?;
+        self.fill(&cols);
+
+        Ok(())
+    }
+
+    // counters can only be incremented, the row is created by the first increment.
+    // the increment is not idempotent, a retried write may count twice.
+    // it is written with Quorum, whatever the configured write consistency is.
+    pub async fn incr(
+        &self,
+        db: &scylladb::ScyllaDB,
+        kind: CounterKind,
+        calls: i64,
+        tokens: i64,
+    ) -> anyhow::Result<()> {
+        let query = Self::incr_query(kind);
+        let params = (calls, tokens, self.uid.to_cql(), self.gid.to_cql());
+        let _ = db
+            .execute_with(query, params, scylladb::Consistency::Quorum)
+            .await?;
+        Ok(())
+    }
+
+    fn incr_query(kind: CounterKind) -> String {
+        format!(
+            "UPDATE counter SET {} WHERE uid=? AND gid=?",
+            kind.incr_columns()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scylla_orm::CqlValue;
+
+    #[test]
+    fn incr_query_works() {
+        assert_eq!(
+            Counter::incr_query(CounterKind::Translating),
+            "UPDATE counter SET translating_calls=translating_calls+?,translating_tokens=translating_tokens+? WHERE uid=? AND gid=?"
+        );
+        for kind in [
+            CounterKind::Translating,
+            CounterKind::Summarizing,
+            CounterKind::Embedding,
+        ] {
+            let fields = Counter::fields();
+            assert!(fields.contains(&format!("{}_calls", kind.as_str())));
+            assert!(fields.contains(&format!("{}_tokens", kind.as_str())));
+        }
+    }
+
+    #[test]
+    fn fill_counter_works() {
+        let mut cols = ColumnsMap::with_capacity(2);
+        cols.set_as("uid", &xid::new());
+        cols.set_as(
+            "embedding_tokens",
+            &CqlValue::Counter(scylla::frame::value::Counter(42)),
+        );
+
+        let mut doc = Counter::default();
+        doc.fill(&cols);
+        assert_eq!(doc.embedding_tokens, 42);
+        assert_eq!(doc.embedding_calls, 0);
+    }
+}
@@ -8,6 +8,13 @@ use scylla_orm_macros::CqlOrm;
 
 use crate::db::{qdrant, scylladb};
 
+// the max rows in one batch, the rows carry the content so the batch should not be too large.
+const SAVE_BATCH_SIZE: usize = 16;
+// the rows in one page of the scans, the rows carry the content.
+const LIST_PAGE_SIZE: i32 = 500;
+// the max rows deleted in one batch.
+const DELETE_BATCH_SIZE: usize = 100;
+
 #[derive(Debug, Default, Clone, CqlOrm)]
 pub struct Embedding {
     pub uuid: uuid::Uuid,
@@ -17,6 +24,11 @@ pub struct Embedding {
     pub ids: String,
     pub gid: xid::Id,
     pub content: Vec<u8>,
+    pub hash: Vec<u8>,
+    pub model: String,
+    pub created_at: i64,
+    pub payload: HashMap<String, String>,
+    pub deleted_at: Option<i64>, // the tombstone, the row is kept for audit but excluded from search
 
     pub _fields: Vec<String>, // selected fields，`_` 前缀字段会被 CqlOrm 忽略
 }
@@ -44,6 +56,11 @@ impl Embedding {
         doc
     }
 
+    // the SHA3-256 digest of the unit's embedding string, used to skip unchanged content.
+    pub fn content_hash(input: &str) -> Vec<u8> {
+        Sha3_256::digest(input.as_bytes()).to_vec()
+    }
+
     pub fn select_fields(select_fields: Vec<String>, with_pk: bool) -> anyhow::Result<Vec<String>> {
         if select_fields.is_empty() {
             return Ok(Self::fields());
@@ -67,13 +84,43 @@ impl Embedding {
         Ok(select_fields)
     }
 
-    pub fn qdrant_point(&self, vectors: Vec<f32>) -> qdrant::PointStruct {
+    // the columns written by `save` and `save_batch`, the tombstone is only set by `set_deleted`,
+    // so that embedding a forgotten uuid again does not clear it.
+    pub fn insert_fields() -> Vec<String> {
+        Self::fields()
+            .into_iter()
+            .filter(|f| f != "deleted_at")
+            .collect()
+    }
+
+    // select the tombstone to exclude the tombstoned rows.
+    pub fn with_deleted_at(mut fields: Vec<String>) -> Vec<String> {
+        let field = "deleted_at".to_string();
+        if !fields.contains(&field) {
+            fields.push(field);
+        }
+        fields
+    }
+
+    // the point with the dense vector, named if the collections use named vectors.
+    pub fn qdrant_point(
+        &self,
+        vector_name: Option<&str>,
+        vectors: Vec<f32>,
+    ) -> qdrant::PointStruct {
         let mut point = qdrant::PointStruct {
             id: Some(qdrant::PointId::from(self.uuid.to_string())),
-            vectors: Some(qdrant::Vectors::from(vectors)),
+            vectors: Some(qdrant::point_vectors(vector_name, vectors)),
             payload: HashMap::new(),
         };
 
+        // the custom fields first, they can not overwrite the built-in fields below.
+        for (k, v) in &self.payload {
+            point
+                .payload
+                .insert(k.clone(), qdrant::Value::from(v.clone()));
+        }
+
         point
             .payload
             .insert("cid".to_string(), qdrant::Value::from(self.cid.to_string()));
@@ -85,6 +132,13 @@ impl Embedding {
             .payload
             .insert("gid".to_string(), qdrant::Value::from(self.gid.to_string()));
         point
+            .payload
+            .insert("model".to_string(), qdrant::Value::from(self.model.clone()));
+        point.payload.insert(
+            "created_at".to_string(),
+            qdrant::Value::from(self.created_at),
+        );
+        point
     }
 
     pub async fn get_one(
@@ -110,7 +164,7 @@ impl Embedding {
     }
 
     pub async fn save(&mut self, db: &scylladb::ScyllaDB) -> anyhow::Result<bool> {
-        let fields = Self::fields();
+        let fields = Self::insert_fields();
         self._fields = fields.clone();
 
         let mut cols_name: Vec<&str> = Vec::with_capacity(fields.len());
@@ -135,6 +189,65 @@ impl Embedding {
         Ok(true)
     }
 
+    // insert the rows in unlogged batches of SAVE_BATCH_SIZE, overwrite with new values.
+    // the rows are of different partitions, a failed batch may be partially applied.
+    pub async fn save_batch(db: &scylladb::ScyllaDB, docs: &mut [Embedding]) -> anyhow::Result<()> {
+        let fields = Self::insert_fields();
+        let query = format!(
+            "INSERT INTO embedding ({}) VALUES ({})",
+            fields.join(","),
+            vec!["?"; fields.len()].join(",")
+        );
+
+        for chunk in docs.chunks_mut(SAVE_BATCH_SIZE) {
+            let cols: Vec<ColumnsMap> = chunk
+                .iter_mut()
+                .map(|doc| {
+                    doc._fields = fields.clone();
+                    doc.to()
+                })
+                .collect();
+            let params: Vec<Vec<&CqlValue>> = cols
+                .iter()
+                .map(|cols| fields.iter().map(|f| cols.get(f).unwrap()).collect())
+                .collect();
+            let _ = db
+                .unlogged_batch(vec![query.as_str(); chunk.len()], params)
+                .await?;
+        }
+        Ok(())
+    }
+
+    // set the tombstone of the row, the point should be removed from the collections.
+    pub async fn set_deleted(
+        &mut self,
+        db: &scylladb::ScyllaDB,
+        deleted_at: i64,
+    ) -> anyhow::Result<bool> {
+        let query = "UPDATE embedding SET deleted_at=? WHERE uuid=?";
+        let params = (deleted_at.to_cql(), self.uuid.to_cql());
+        let _ = db.execute(query, params).await?;
+        self.deleted_at = Some(deleted_at);
+        Ok(true)
+    }
+
+    // delete the rows listed by the caller, e.g. by `list_all_by_cid`, in unlogged batches of
+    // DELETE_BATCH_SIZE. the rows are of different partitions, a failed batch may be partially
+    // applied. return the number of deleted rows.
+    pub async fn delete_by_uuids(
+        db: &scylladb::ScyllaDB,
+        uuids: &[uuid::Uuid],
+    ) -> anyhow::Result<usize> {
+        let query = "DELETE FROM embedding WHERE uuid=?";
+        let mut deleted = 0usize;
+        for chunk in uuids.chunks(DELETE_BATCH_SIZE) {
+            let params: Vec<(CqlValue,)> = chunk.iter().map(|uuid| (uuid.to_cql(),)).collect();
+            let _ = db.unlogged_batch(vec![query; chunk.len()], params).await?;
+            deleted += chunk.len();
+        }
+        Ok(deleted)
+    }
+
     pub async fn list_by_cid(
         db: &scylladb::ScyllaDB,
         cid: xid::Id,
@@ -143,13 +256,83 @@ impl Embedding {
         version: i16,
         select_fields: Vec<String>,
     ) -> anyhow::Result<Vec<Embedding>> {
-        let fields = Self::select_fields(select_fields, true)?;
+        Self::list_by_cid_with(db, cid, gid, Some(lang), Some(version), select_fields).await
+    }
+
+    // one page of the embedding rows of the group by the gid index, the tombstoned rows are
+    // excluded so a page may be empty before the last one. pass the returned paging state to
+    // get the next page, None after the last one. used by background jobs only.
+    pub async fn list_by_gid_page(
+        db: &scylladb::ScyllaDB,
+        gid: xid::Id,
+        select_fields: Vec<String>,
+        paging_state: Option<scylladb::Bytes>,
+    ) -> anyhow::Result<(Vec<Embedding>, Option<scylladb::Bytes>)> {
+        let fields = Self::with_deleted_at(Self::select_fields(select_fields, true)?);
 
         let query = format!(
-            "SELECT {} FROM embedding WHERE cid=? AND language=? AND version=? AND gid=? LIMIT 1000 ALLOW FILTERING BYPASS CACHE USING TIMEOUT 10s",
-            fields.clone().join(",")
+            "SELECT {} FROM embedding WHERE gid=? BYPASS CACHE USING TIMEOUT 30s",
+            fields.join(",")
         );
-        let params = (cid.to_cql(), lang.to_cql(), version, gid.to_cql());
+        let params = (gid.to_cql(),);
+        let (rows, paging_state) = db
+            .execute_paged(query, params, LIST_PAGE_SIZE, paging_state)
+            .await?;
+
+        let mut res: Vec<Embedding> = Vec::with_capacity(rows.len());
+        for row in rows {
+            let mut doc = Embedding::default();
+            let mut cols = ColumnsMap::with_capacity(fields.len());
+            cols.fill(row, &fields)?;
+            doc.fill(&cols);
+            doc._fields = fields.clone();
+            if doc.deleted_at.is_none() {
+                res.push(doc);
+            }
+        }
+
+        Ok((res, paging_state))
+    }
+
+    // the rows of the creation, the tombstoned rows are excluded.
+    pub async fn list_by_cid_with(
+        db: &scylladb::ScyllaDB,
+        cid: xid::Id,
+        gid: xid::Id,
+        lang: Option<Language>,
+        version: Option<i16>,
+        select_fields: Vec<String>,
+    ) -> anyhow::Result<Vec<Embedding>> {
+        let fields = Self::with_deleted_at(Self::select_fields(select_fields, true)?);
+        let docs = Self::list_all_by_cid(db, cid, gid, lang, version, fields).await?;
+        Ok(docs
+            .into_iter()
+            .filter(|doc| doc.deleted_at.is_none())
+            .collect())
+    }
+
+    // the rows of the creation, including the tombstoned ones, for the hard deletion.
+    // all the rows are listed, the driver fetches them page by page.
+    pub async fn list_all_by_cid(
+        db: &scylladb::ScyllaDB,
+        cid: xid::Id,
+        gid: xid::Id,
+        lang: Option<Language>,
+        version: Option<i16>,
+        select_fields: Vec<String>,
+    ) -> anyhow::Result<Vec<Embedding>> {
+        let fields = Self::select_fields(select_fields, true)?;
+
+        let mut params: Vec<CqlValue> = vec![cid.to_cql()];
+        if let Some(lang) = lang {
+            params.push(lang.to_cql());
+        }
+        if let Some(version) = version {
+            params.push(version.to_cql());
+        }
+        params.push(gid.to_cql());
+
+        let query = Self::list_by_cid_query(&fields, lang.is_some(), version.is_some());
         let rows = db.execute_iter(query, params).await?;
 
         let mut res: Vec<Embedding> = Vec::with_capacity(rows.len());
@@ -164,4 +347,172 @@ impl Embedding {
 
         Ok(res)
     }
+
+    fn list_by_cid_query(fields: &[String], with_lang: bool, with_version: bool) -> String {
+        let mut conds: Vec<&str> = vec!["cid=?"];
+        if with_lang {
+            conds.push("language=?");
+        }
+        if with_version {
+            conds.push("version=?");
+        }
+        conds.push("gid=?");
+
+        format!(
+            "SELECT {} FROM embedding WHERE {} ALLOW FILTERING BYPASS CACHE USING TIMEOUT 10s",
+            fields.join(","),
+            conds.join(" AND ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use tokio::sync::OnceCell;
+
+    use crate::conf;
+
+    use super::*;
+
+    static DB: OnceCell<scylladb::ScyllaDB> = OnceCell::const_new();
+
+    async fn get_db() -> scylladb::ScyllaDB {
+        let cfg = conf::Conf::new().unwrap_or_else(|err| panic!("config error: {}", err));
+        let res = scylladb::ScyllaDB::new(cfg.scylla, "jarvis_test").await;
+        res.unwrap()
+    }
+
+    #[test]
+    fn insert_fields_works() {
+        let fields = Embedding::insert_fields();
+        assert!(!fields.contains(&"deleted_at".to_string()));
+        assert_eq!(fields.len(), Embedding::fields().len() - 1);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn embedding_tombstone_works() {
+        let db = DB.get_or_init(get_db).await;
+        let cid = xid::new();
+        let gid = xid::Id::from_str(conf::DEFAULT_SYSTEM_USER).unwrap();
+        let mut doc = Embedding::from(cid, Language::Eng, "1,2".to_string());
+        doc.gid = gid;
+        doc.version = 1;
+        doc.created_at = 1;
+        doc.save(db).await.unwrap();
+
+        let docs = Embedding::list_by_cid(db, cid, gid, Language::Eng, 1, vec![])
+            .await
+            .unwrap();
+        assert_eq!(docs.len(), 1);
+
+        // forget, then embed the same unit again.
+        doc.set_deleted(db, 1000).await.unwrap();
+        let mut again = Embedding::from(cid, Language::Eng, "1,2".to_string());
+        again.gid = gid;
+        again.version = 1;
+        again.created_at = 2;
+        Embedding::save_batch(db, &mut [again]).await.unwrap();
+        doc.save(db).await.unwrap();
+
+        let mut row = Embedding::with_pk(doc.uuid);
+        row.get_one(db, vec![]).await.unwrap();
+        assert_eq!(row.deleted_at, Some(1000));
+        let docs = Embedding::list_by_cid(db, cid, gid, Language::Eng, 1, vec![])
+            .await
+            .unwrap();
+        assert!(docs.is_empty());
+
+        let docs = Embedding::list_all_by_cid(db, cid, gid, None, None, vec![])
+            .await
+            .unwrap();
+        assert_eq!(docs.len(), 1);
+        let deleted = Embedding::delete_by_uuids(db, &[doc.uuid]).await.unwrap();
+        assert_eq!(deleted, 1);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn list_by_gid_page_works() {
+        let db = DB.get_or_init(get_db).await;
+        let gid = xid::new();
+        let cid = xid::new();
+        let mut docs: Vec<Embedding> = ["1", "2", "3"]
+            .iter()
+            .map(|ids| {
+                let mut doc = Embedding::from(cid, Language::Eng, ids.to_string());
+                doc.gid = gid;
+                doc.version = 1;
+                doc
+            })
+            .collect();
+        Embedding::save_batch(db, &mut docs).await.unwrap();
+        docs[1].set_deleted(db, 1000).await.unwrap();
+
+        let mut uuids: Vec<uuid::Uuid> = Vec::new();
+        let mut paging_state: Option<scylladb::Bytes> = None;
+        loop {
+            let (page, next) =
+                Embedding::list_by_gid_page(db, gid, vec!["ids".to_string()], paging_state)
+                    .await
+                    .unwrap();
+            uuids.extend(page.iter().map(|doc| doc.uuid));
+            match next {
+                Some(next) => paging_state = Some(next),
+                None => break,
+            }
+        }
+        uuids.sort();
+        let mut expected = vec![docs[0].uuid, docs[2].uuid];
+        expected.sort();
+        assert_eq!(uuids, expected);
+
+        let uuids: Vec<uuid::Uuid> = docs.iter().map(|doc| doc.uuid).collect();
+        Embedding::delete_by_uuids(db, &uuids).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn delete_by_uuids_works() {
+        let db = DB.get_or_init(get_db).await;
+        let gid = xid::new();
+        let cid = xid::new();
+        // more than a page of the old capped listing, and more than a batch of the deletion.
+        let mut docs: Vec<Embedding> = (0..1050)
+            .map(|i| {
+                let mut doc = Embedding::from(cid, Language::Eng, i.to_string());
+                doc.gid = gid;
+                doc.version = 1;
+                doc
+            })
+            .collect();
+        Embedding::save_batch(db, &mut docs).await.unwrap();
+
+        let listed = Embedding::list_all_by_cid(db, cid, gid, None, Some(1), vec![])
+            .await
+            .unwrap();
+        assert_eq!(listed.len(), 1050);
+        let uuids: Vec<uuid::Uuid> = listed.iter().map(|doc| doc.uuid).collect();
+        assert_eq!(Embedding::delete_by_uuids(db, &uuids).await.unwrap(), 1050);
+
+        let listed = Embedding::list_all_by_cid(db, cid, gid, None, None, vec![])
+            .await
+            .unwrap();
+        assert!(listed.is_empty());
+        assert_eq!(Embedding::delete_by_uuids(db, &[]).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn list_by_cid_query_works() {
+        let fields = vec!["uuid".to_string(), "ids".to_string()];
+        assert_eq!(
+            Embedding::list_by_cid_query(&fields, false, false),
+            "SELECT uuid,ids FROM embedding WHERE cid=? AND gid=? ALLOW FILTERING BYPASS CACHE USING TIMEOUT 10s"
+        );
+        assert_eq!(
+            Embedding::list_by_cid_query(&fields, true, true),
+            "SELECT uuid,ids FROM embedding WHERE cid=? AND language=? AND version=? AND gid=? ALLOW FILTERING BYPASS CACHE USING TIMEOUT 10s"
+        );
+    }
 }
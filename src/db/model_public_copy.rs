@@ -0,0 +1,183 @@
+use isolang::Language;
+
+use axum_web::erring::HTTPError;
+use scylla_orm::{ColumnsMap, CqlValue, ToCqlVal};
+use scylla_orm_macros::CqlOrm;
+
+use crate::db::scylladb;
+
+pub static PUBLIC_COPY_PENDING: &str = "pending";
+pub static PUBLIC_COPY_DONE: &str = "done";
+pub static PUBLIC_COPY_FAILED: &str = "failed";
+pub static PUBLIC_COPY_REMOVED: &str = "removed";
+
+// the status record of copying a creation's embedding points to the public collection.
+#[derive(Debug, Default, Clone, CqlOrm)]
+pub struct PublicCopy {
+    pub gid: xid::Id,
+    pub cid: xid::Id,
+    pub language: Language,
+    pub version: i16,
+    pub status: String,
+    pub points: i32,
+    pub updated_at: i64,
+    pub error: String,
+
+    pub _fields: Vec<String>, // selected fields，`_` 前缀字段会被 CqlOrm 忽略
+}
+
+impl PublicCopy {
+    pub fn with_pk(gid: xid::Id, cid: xid::Id, language: Language, version: i16) -> Self {
+        Self {
+            gid,
+            cid,
+            language,
+            version,
+            ..Default::default()
+        }
+    }
+
+    pub fn select_fields(select_fields: Vec<String>, with_pk: bool) -> anyhow::Result<Vec<String>> {
+        if select_fields.is_empty() {
+            return Ok(Self::fields());
+        }
+
+        let fields = Self::fields();
+        for field in &select_fields {
+            if !fields.contains(field) {
+                return Err(HTTPError::new(400, format!("Invalid field: {}", field)).into());
+            }
+        }
+
+        let mut select_fields = select_fields;
+        if with_pk {
+            let field = "gid".to_string();
+            if !select_fields.contains(&field) {
+                select_fields.push(field);
+            }
+            let field = "cid".to_string();
+            if !select_fields.contains(&field) {
+                select_fields.push(field);
+            }
+            let field = "language".to_string();
+            if !select_fields.contains(&field) {
+                select_fields.push(field);
+            }
+            let field = "version".to_string();
+            if !select_fields.contains(&field) {
+                select_fields.push(field);
+            }
+        }
+
+        Ok(select_fields)
+    }
+
+    pub async fn get_one(
+        &mut self,
+        db: &scylladb::ScyllaDB,
+        select_fields: Vec<String>,
+    ) -> anyhow::Result<()> {
+        let fields = Self::select_fields(select_fields, false)?;
+        self._fields = fields.clone();
+
+        let query = format!(
+            "SELECT {} FROM public_copy WHERE gid=? AND cid=? AND language=? AND version=? LIMIT 1",
+            fields.join(",")
+        );
+        let params = (
+            self.gid.to_cql(),
+            self.cid.to_cql(),
+            self.language.to_cql(),
+            self.version,
+        );
+        let res = db.execute(query, params).await?.single_row()?;
+
+        let mut cols = ColumnsMap::with_capacity(fields.len());
+        cols.fill(res, &fields)?;
+        self.fill(&cols);
+
+        Ok(())
+    }
+
+    pub async fn upsert_fields(
+        &mut self,
+        db: &scylladb::ScyllaDB,
+        cols: ColumnsMap,
+    ) -> anyhow::Result<bool> {
+        let valid_fields = ["status", "points", "updated_at", "error"];
+
+        let mut set_fields: Vec<String> = Vec::with_capacity(cols.len());
+        let mut params: Vec<CqlValue> = Vec::with_capacity(cols.len() + 4);
+        for (k, v) in cols.iter() {
+            if !valid_fields.contains(&k.as_str()) {
+                return Err(HTTPError::new(400, format!("Invalid field: {}", k)).into());
+            }
+            set_fields.push(format!("{}=?", k));
+            params.push(v.to_owned());
+        }
+
+        let query = format!(
+            "UPDATE public_copy SET {} WHERE gid=? AND cid=? AND language=? AND version=?",
+            set_fields.join(",")
+        );
+        params.push(self.gid.to_cql());
+        params.push(self.cid.to_cql());
+        params.push(self.language.to_cql());
+        params.push(self.version.to_cql());
+
+        let _ = db.execute(query, params).await?;
+        Ok(true)
+    }
+
+    // list the pending copies that have not been updated since `updated_before`, used by the janitor.
+    pub async fn list_stalled(
+        db: &scylladb::ScyllaDB,
+        updated_before: i64,
+        select_fields: Vec<String>,
+    ) -> anyhow::Result<Vec<PublicCopy>> {
+        let fields = Self::select_fields(select_fields, true)?;
+
+        let query = format!(
+            "SELECT {} FROM public_copy WHERE status=? AND updated_at<? ALLOW FILTERING BYPASS CACHE USING TIMEOUT 60s",
+            fields.clone().join(",")
+        );
+        let params = (PUBLIC_COPY_PENDING, updated_before);
+        let rows = db.execute_iter(query, params).await?;
+
+        let mut res: Vec<PublicCopy> = Vec::with_capacity(rows.len());
+        for row in rows {
+            let mut doc = PublicCopy::default();
+            let mut cols = ColumnsMap::with_capacity(fields.len());
+            cols.fill(row, &fields)?;
+            doc.fill(&cols);
+            doc._fields = fields.clone();
+            res.push(doc);
+        }
+
+        Ok(res)
+    }
+
+    // mark the copy as failed with the write timestamp of `updated_before`, see
+    // scylladb::stale_timestamp. any write of the copy after it wins over the mark.
+    pub async fn mark_stalled(
+        &mut self,
+        db: &scylladb::ScyllaDB,
+        error: &str,
+        updated_before: i64,
+    ) -> anyhow::Result<()> {
+        let query = "UPDATE public_copy USING TIMESTAMP ? SET status=?, error=? WHERE gid=? AND cid=? AND language=? AND version=?";
+        let params = (
+            scylladb::stale_timestamp(updated_before),
+            PUBLIC_COPY_FAILED,
+            error.to_string(),
+            self.gid.to_cql(),
+            self.cid.to_cql(),
+            self.language.to_cql(),
+            self.version,
+        );
+        let _ = db.execute(query, params).await?;
+        self.status = PUBLIC_COPY_FAILED.to_string();
+        self.error = error.to_string();
+        Ok(())
+    }
+}
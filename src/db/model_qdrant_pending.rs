@@ -0,0 +1,107 @@
+use axum_web::erring::HTTPError;
+use scylla_orm::{ColumnsMap, CqlValue, ToCqlVal};
+use scylla_orm_macros::CqlOrm;
+
+use crate::db::scylladb;
+
+// the embedding row whose point failed to write to qdrant, it should be re-inserted later.
+#[derive(Debug, Default, Clone, CqlOrm)]
+pub struct QdrantPending {
+    pub uuid: uuid::Uuid,
+    pub created_at: i64,
+    pub error: String,
+
+    pub _fields: Vec<String>, // selected fields，`_` 前缀字段会被 CqlOrm 忽略
+}
+
+impl QdrantPending {
+    pub fn with_pk(uuid: uuid::Uuid) -> Self {
+        Self {
+            uuid,
+            ..Default::default()
+        }
+    }
+
+    pub fn select_fields(select_fields: Vec<String>, with_pk: bool) -> anyhow::Result<Vec<String>> {
+        if select_fields.is_empty() {
+            return Ok(Self::fields());
+        }
+
+        let fields = Self::fields();
+        for field in &select_fields {
+            if !fields.contains(field) {
+                return Err(HTTPError::new(400, format!("Invalid field: {}", field)).into());
+            }
+        }
+
+        let mut select_fields = select_fields;
+        if with_pk {
+            let field = "uuid".to_string();
+            if !select_fields.contains(&field) {
+                select_fields.push(field);
+            }
+        }
+
+        Ok(select_fields)
+    }
+
+    pub async fn save(&mut self, db: &scylladb::ScyllaDB) -> anyhow::Result<bool> {
+        let fields = Self::fields();
+        self._fields = fields.clone();
+
+        let mut cols_name: Vec<&str> = Vec::with_capacity(fields.len());
+        let mut vals_name: Vec<&str> = Vec::with_capacity(fields.len());
+        let mut params: Vec<&CqlValue> = Vec::with_capacity(fields.len());
+        let cols = self.to();
+
+        for field in &fields {
+            cols_name.push(field);
+            vals_name.push("?");
+            params.push(cols.get(field).unwrap());
+        }
+
+        let query = format!(
+            "INSERT INTO qdrant_pending ({}) VALUES ({})",
+            cols_name.join(","),
+            vals_name.join(",")
+        );
+
+        let _ = db.execute(query, params).await?;
+        Ok(true)
+    }
+
+    pub async fn delete(&mut self, db: &scylladb::ScyllaDB) -> anyhow::Result<bool> {
+        let query = "DELETE FROM qdrant_pending WHERE uuid=?";
+        let params = (self.uuid.to_cql(),);
+        let _ = db.execute(query, params).await?;
+        Ok(true)
+    }
+
+    // scan the pending rows, the table is expected to be small.
+    pub async fn list(
+        db: &scylladb::ScyllaDB,
+        limit: i32,
+        select_fields: Vec<String>,
+    ) -> anyhow::Result<Vec<QdrantPending>> {
+        let fields = Self::select_fields(select_fields, true)?;
+
+        let query = format!(
+            "SELECT {} FROM qdrant_pending LIMIT ? BYPASS CACHE USING TIMEOUT 30s",
+            fields.clone().join(",")
+        );
+        let params = (limit,);
+        let rows = db.execute_iter(query, params).await?;
+
+        let mut res: Vec<QdrantPending> = Vec::with_capacity(rows.len());
+        for row in rows {
+            let mut doc = QdrantPending::default();
+            let mut cols = ColumnsMap::with_capacity(fields.len());
+            cols.fill(row, &fields)?;
+            doc.fill(&cols);
+            doc._fields = fields.clone();
+            res.push(doc);
+        }
+
+        Ok(res)
+    }
+}
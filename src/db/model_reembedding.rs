@@ -0,0 +1,114 @@
+use axum_web::erring::HTTPError;
+use scylla_orm::{ColumnsMap, CqlValue, ToCqlVal};
+use scylla_orm_macros::CqlOrm;
+
+use crate::db::scylladb;
+
+// the progress record of re-embedding a creation, or all creations of a group if cid is zero.
+#[derive(Debug, Default, Clone, CqlOrm)]
+pub struct Reembedding {
+    pub gid: xid::Id,
+    pub cid: xid::Id,
+    pub progress: i8,
+    pub updated_at: i64,
+    pub pieces: i32,
+    pub done: i32,
+    pub tokens: i32,
+    pub error: String,
+
+    pub _fields: Vec<String>, // selected fields，`_` 前缀字段会被 CqlOrm 忽略
+}
+
+impl Reembedding {
+    pub fn with_pk(gid: xid::Id, cid: xid::Id) -> Self {
+        Self {
+            gid,
+            cid,
+            ..Default::default()
+        }
+    }
+
+    pub fn select_fields(select_fields: Vec<String>, with_pk: bool) -> anyhow::Result<Vec<String>> {
+        if select_fields.is_empty() {
+            return Ok(Self::fields());
+        }
+
+        let fields = Self::fields();
+        for field in &select_fields {
+            if !fields.contains(field) {
+                return Err(HTTPError::new(400, format!("Invalid field: {}", field)).into());
+            }
+        }
+
+        let mut select_fields = select_fields;
+        if with_pk {
+            let field = "gid".to_string();
+            if !select_fields.contains(&field) {
+                select_fields.push(field);
+            }
+            let field = "cid".to_string();
+            if !select_fields.contains(&field) {
+                select_fields.push(field);
+            }
+        }
+
+        Ok(select_fields)
+    }
+
+    pub async fn get_one(
+        &mut self,
+        db: &scylladb::ScyllaDB,
+        select_fields: Vec<String>,
+    ) -> anyhow::Result<()> {
+        let fields = Self::select_fields(select_fields, false)?;
+        self._fields = fields.clone();
+
+        let query = format!(
+            "SELECT {} FROM reembedding WHERE gid=? AND cid=? LIMIT 1",
+            fields.join(",")
+        );
+        let params = (self.gid.to_cql(), self.cid.to_cql());
+        let res = db.execute(query, params).await?.single_row()?;
+
+        let mut cols = ColumnsMap::with_capacity(fields.len());
+        cols.fill(res, &fields)?;
+        self.fill(&cols);
+
+        Ok(())
+    }
+
+    pub async fn upsert_fields(
+        &mut self,
+        db: &scylladb::ScyllaDB,
+        cols: ColumnsMap,
+    ) -> anyhow::Result<bool> {
+        let valid_fields = [
+            "progress",
+            "updated_at",
+            "pieces",
+            "done",
+            "tokens",
+            "error",
+        ];
+
+        let mut set_fields: Vec<String> = Vec::with_capacity(cols.len());
+        let mut params: Vec<CqlValue> = Vec::with_capacity(cols.len() + 2);
+        for (k, v) in cols.iter() {
+            if !valid_fields.contains(&k.as_str()) {
+                return Err(HTTPError::new(400, format!("Invalid field: {}", k)).into());
+            }
+            set_fields.push(format!("{}=?", k));
+            params.push(v.to_owned());
+        }
+
+        let query = format!(
+            "UPDATE reembedding SET {} WHERE gid=? AND cid=?",
+            set_fields.join(",")
+        );
+        params.push(self.gid.to_cql());
+        params.push(self.cid.to_cql());
+
+        let _ = db.execute(query, params).await?;
+        Ok(true)
+    }
+}
@@ -15,7 +15,11 @@ pub struct Summarizing {
     pub model: String,
     pub progress: i8,
     pub updated_at: i64,
+    pub started_at: i64,
     pub tokens: i32,
+    pub map_tokens: i32,     // the map stage, summarizing the pieces
+    pub reduce_tokens: i32,  // the reduce stage, summarizing the summaries of the pieces
+    pub keyword_tokens: i32, // the keywords stage
     pub summary: String,
     pub error: String,
 
@@ -104,13 +108,19 @@ impl Summarizing {
             "model",
             "progress",
             "updated_at",
+            "started_at",
             "tokens",
             "summary",
             "error",
         ];
 
+        // the written columns expire after the ttl, upsert again to refresh it.
+        let ttl = db.ttl("summarizing");
         let mut set_fields: Vec<String> = Vec::with_capacity(cols.len());
-        let mut params: Vec<CqlValue> = Vec::with_capacity(cols.len() + 4);
+        let mut params: Vec<CqlValue> = Vec::with_capacity(cols.len() + 5);
+        if ttl > 0 {
+            params.push(CqlValue::Int(ttl as i32));
+        }
         for (k, v) in cols.iter() {
             if !valid_fields.contains(&k.as_str()) {
                 return Err(HTTPError::new(400, format!("Invalid field: {}", k)).into());
@@ -119,10 +129,7 @@ impl Summarizing {
             params.push(v.to_owned());
         }
 
-        let query = format!(
-            "UPDATE summarizing SET {} WHERE gid=? AND cid=? AND language=? AND version=?",
-            set_fields.join(",")
-        );
+        let query = Self::upsert_query(&set_fields, ttl);
         params.push(self.gid.to_cql());
         params.push(self.cid.to_cql());
         params.push(self.language.to_cql());
@@ -132,6 +139,105 @@ impl Summarizing {
         Ok(true)
     }
 
+    fn upsert_query(set_fields: &[String], ttl: u32) -> String {
+        format!(
+            "UPDATE summarizing{} SET {} WHERE gid=? AND cid=? AND language=? AND version=?",
+            scylladb::using_ttl(ttl),
+            set_fields.join(",")
+        )
+    }
+
+    pub async fn list_by_cid(
+        db: &scylladb::ScyllaDB,
+        gid: xid::Id,
+        cid: xid::Id,
+        version: Option<i16>,
+        select_fields: Vec<String>,
+    ) -> anyhow::Result<Vec<Summarizing>> {
+        let fields = Self::select_fields(select_fields, true)?;
+
+        let rows = match version {
+            Some(version) => {
+                let query = format!(
+                    "SELECT {} FROM summarizing WHERE gid=? AND cid=? AND version=? ALLOW FILTERING",
+                    fields.clone().join(",")
+                );
+                let params = (gid.to_cql(), cid.to_cql(), version);
+                db.execute_iter(query, params).await?
+            }
+            None => {
+                let query = format!(
+                    "SELECT {} FROM summarizing WHERE gid=? AND cid=?",
+                    fields.clone().join(",")
+                );
+                let params = (gid.to_cql(), cid.to_cql());
+                db.execute_iter(query, params).await?
+            }
+        };
+
+        let mut res: Vec<Summarizing> = Vec::with_capacity(rows.len());
+        for row in rows {
+            let mut doc = Summarizing::default();
+            let mut cols = ColumnsMap::with_capacity(fields.len());
+            cols.fill(row, &fields)?;
+            doc.fill(&cols);
+            doc._fields = fields.clone();
+            res.push(doc);
+        }
+
+        Ok(res)
+    }
+
+    // list the unfinished jobs that have not been updated since `updated_before`, used by the janitor.
+    pub async fn list_stalled(
+        db: &scylladb::ScyllaDB,
+        updated_before: i64,
+        select_fields: Vec<String>,
+    ) -> anyhow::Result<Vec<Summarizing>> {
+        let fields = Self::select_fields(select_fields, true)?;
+
+        let query = format!(
+            "SELECT {} FROM summarizing WHERE progress<100 AND updated_at<? ALLOW FILTERING BYPASS CACHE USING TIMEOUT 60s",
+            fields.clone().join(",")
+        );
+        let params = (updated_before,);
+        let rows = db.execute_iter(query, params).await?;
+
+        let mut res: Vec<Summarizing> = Vec::with_capacity(rows.len());
+        for row in rows {
+            let mut doc = Summarizing::default();
+            let mut cols = ColumnsMap::with_capacity(fields.len());
+            cols.fill(row, &fields)?;
+            doc.fill(&cols);
+            doc._fields = fields.clone();
+            res.push(doc);
+        }
+
+        Ok(res)
+    }
+
+    // set the error with the write timestamp of `updated_before`, see scylladb::stale_timestamp.
+    // any write of the job after it wins over the error, e.g. the job resumed and finished.
+    pub async fn mark_stalled(
+        &mut self,
+        db: &scylladb::ScyllaDB,
+        error: &str,
+        updated_before: i64,
+    ) -> anyhow::Result<()> {
+        let query = "UPDATE summarizing USING TIMESTAMP ? SET error=? WHERE gid=? AND cid=? AND language=? AND version=?";
+        let params = (
+            scylladb::stale_timestamp(updated_before),
+            error.to_string(),
+            self.gid.to_cql(),
+            self.cid.to_cql(),
+            self.language.to_cql(),
+            self.version,
+        );
+        let _ = db.execute(query, params).await?;
+        self.error = error.to_string();
+        Ok(())
+    }
+
     pub async fn delete(&mut self, db: &scylladb::ScyllaDB) -> anyhow::Result<bool> {
         let query = "DELETE FROM summarizing WHERE gid=? AND cid=? AND language=? AND version=?";
         let params = (
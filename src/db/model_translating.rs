@@ -12,11 +12,15 @@ pub struct Translating {
     pub cid: xid::Id,
     pub language: Language,
     pub version: i16,
+    pub origin_language: Language,
     pub model: String,
+    pub script: String,
     pub progress: i8,
     pub updated_at: i64,
+    pub started_at: i64,
     pub tokens: i32,
     pub content: Vec<u8>,
+    pub source: Vec<u8>,
     pub error: String,
 
     pub _fields: Vec<String>, // selected fields，`_` 前缀字段会被 CqlOrm 忽略
@@ -101,16 +105,25 @@ impl Translating {
         cols: ColumnsMap,
     ) -> anyhow::Result<bool> {
         let valid_fields = [
+            "origin_language",
             "model",
+            "script",
             "progress",
             "updated_at",
+            "started_at",
             "tokens",
             "content",
+            "source",
             "error",
         ];
 
+        // the written columns expire after the ttl, upsert again to refresh it.
+        let ttl = db.ttl("translating");
         let mut set_fields: Vec<String> = Vec::with_capacity(cols.len());
-        let mut params: Vec<CqlValue> = Vec::with_capacity(cols.len() + 4);
+        let mut params: Vec<CqlValue> = Vec::with_capacity(cols.len() + 5);
+        if ttl > 0 {
+            params.push(CqlValue::Int(ttl as i32));
+        }
         for (k, v) in cols.iter() {
             if !valid_fields.contains(&k.as_str()) {
                 return Err(HTTPError::new(400, format!("Invalid field: {}", k)).into());
@@ -119,10 +132,7 @@ impl Translating {
             params.push(v.to_owned());
         }
 
-        let query = format!(
-            "UPDATE translating SET {} WHERE gid=? AND cid=? AND language=? AND version=?",
-            set_fields.join(",")
-        );
+        let query = Self::upsert_query(&set_fields, ttl);
         params.push(self.gid.to_cql());
         params.push(self.cid.to_cql());
         params.push(self.language.to_cql());
@@ -132,6 +142,105 @@ impl Translating {
         Ok(true)
     }
 
+    fn upsert_query(set_fields: &[String], ttl: u32) -> String {
+        format!(
+            "UPDATE translating{} SET {} WHERE gid=? AND cid=? AND language=? AND version=?",
+            scylladb::using_ttl(ttl),
+            set_fields.join(",")
+        )
+    }
+
+    pub async fn list_by_cid(
+        db: &scylladb::ScyllaDB,
+        gid: xid::Id,
+        cid: xid::Id,
+        version: Option<i16>,
+        select_fields: Vec<String>,
+    ) -> anyhow::Result<Vec<Translating>> {
+        let fields = Self::select_fields(select_fields, true)?;
+
+        let rows = match version {
+            Some(version) => {
+                let query = format!(
+                    "SELECT {} FROM translating WHERE gid=? AND cid=? AND version=? ALLOW FILTERING",
+                    fields.clone().join(",")
+                );
+                let params = (gid.to_cql(), cid.to_cql(), version);
+                db.execute_iter(query, params).await?
+            }
+            None => {
+                let query = format!(
+                    "SELECT {} FROM translating WHERE gid=? AND cid=?",
+                    fields.clone().join(",")
+                );
+                let params = (gid.to_cql(), cid.to_cql());
+                db.execute_iter(query, params).await?
+            }
+        };
+
+        let mut res: Vec<Translating> = Vec::with_capacity(rows.len());
+        for row in rows {
+            let mut doc = Translating::default();
+            let mut cols = ColumnsMap::with_capacity(fields.len());
+            cols.fill(row, &fields)?;
+            doc.fill(&cols);
+            doc._fields = fields.clone();
+            res.push(doc);
+        }
+
+        Ok(res)
+    }
+
+    // list the unfinished jobs that have not been updated since `updated_before`, used by the janitor.
+    pub async fn list_stalled(
+        db: &scylladb::ScyllaDB,
+        updated_before: i64,
+        select_fields: Vec<String>,
+    ) -> anyhow::Result<Vec<Translating>> {
+        let fields = Self::select_fields(select_fields, true)?;
+
+        let query = format!(
+            "SELECT {} FROM translating WHERE progress<100 AND updated_at<? ALLOW FILTERING BYPASS CACHE USING TIMEOUT 60s",
+            fields.clone().join(",")
+        );
+        let params = (updated_before,);
+        let rows = db.execute_iter(query, params).await?;
+
+        let mut res: Vec<Translating> = Vec::with_capacity(rows.len());
+        for row in rows {
+            let mut doc = Translating::default();
+            let mut cols = ColumnsMap::with_capacity(fields.len());
+            cols.fill(row, &fields)?;
+            doc.fill(&cols);
+            doc._fields = fields.clone();
+            res.push(doc);
+        }
+
+        Ok(res)
+    }
+
+    // set the error with the write timestamp of `updated_before`, see scylladb::stale_timestamp.
+    // any write of the job after it wins over the error, e.g. the job resumed and finished.
+    pub async fn mark_stalled(
+        &mut self,
+        db: &scylladb::ScyllaDB,
+        error: &str,
+        updated_before: i64,
+    ) -> anyhow::Result<()> {
+        let query = "UPDATE translating USING TIMESTAMP ? SET error=? WHERE gid=? AND cid=? AND language=? AND version=?";
+        let params = (
+            scylladb::stale_timestamp(updated_before),
+            error.to_string(),
+            self.gid.to_cql(),
+            self.cid.to_cql(),
+            self.language.to_cql(),
+            self.version,
+        );
+        let _ = db.execute(query, params).await?;
+        self.error = error.to_string();
+        Ok(())
+    }
+
     pub async fn delete(&mut self, db: &scylladb::ScyllaDB) -> anyhow::Result<bool> {
         let query = "DELETE FROM translating WHERE gid=? AND cid=? AND language=? AND version=?";
         let params = (
@@ -151,7 +260,6 @@ mod tests {
     use tokio::sync::OnceCell;
 
     use crate::conf;
-    use crate::db::USER_JARVIS;
     use crate::openai;
 
     use super::*;
@@ -164,12 +272,25 @@ mod tests {
         res.unwrap()
     }
 
+    #[test]
+    fn upsert_query_works() {
+        let set_fields = vec!["progress=?".to_string(), "error=?".to_string()];
+        assert_eq!(
+            Translating::upsert_query(&set_fields, 0),
+            "UPDATE translating SET progress=?,error=? WHERE gid=? AND cid=? AND language=? AND version=?"
+        );
+        assert_eq!(
+            Translating::upsert_query(&set_fields, 86400),
+            "UPDATE translating USING TTL ? SET progress=?,error=? WHERE gid=? AND cid=? AND language=? AND version=?"
+        );
+    }
+
     #[tokio::test(flavor = "current_thread")]
     #[ignore]
     async fn translating_model_works() {
         let db = DB.get_or_init(get_db).await;
         let cid = xid::new();
-        let gid = xid::Id::from_str(USER_JARVIS).unwrap();
+        let gid = xid::Id::from_str(conf::DEFAULT_SYSTEM_USER).unwrap();
         let mut doc = Translating::with_pk(gid, cid, Language::Eng, 1);
 
         let res = doc.get_one(db, vec![]).await;
@@ -180,6 +301,7 @@ mod tests {
         let content: Vec<u8> = vec![0x80];
 
         let mut cols = ColumnsMap::with_capacity(4);
+        cols.set_as("origin_language", &Language::Zho);
         cols.set_as("model", &openai::AIModel::GPT3_5.to_string());
         cols.set_as("tokens", &(1000i32));
         cols.set_as("content", &content);
@@ -189,12 +311,14 @@ mod tests {
         let mut doc2 = Translating::with_pk(gid, cid, Language::Eng, 1);
         doc2.get_one(db, vec![]).await.unwrap();
 
+        assert_eq!(doc2.origin_language, Language::Zho);
         assert_eq!(doc2.tokens, 1000i32);
         assert_eq!(doc2.content, content);
         assert_eq!(doc2.error, "".to_string());
 
         let mut doc3 = Translating::with_pk(gid, cid, Language::Eng, 1);
         doc3.get_one(db, vec!["error".to_string()]).await.unwrap();
+        assert_eq!(doc3.origin_language, Language::default());
         assert_eq!(doc3.tokens, 0i32);
         assert_eq!(doc3.content.len(), 0);
         assert_eq!(doc3.error, "".to_string());
@@ -218,4 +342,49 @@ mod tests {
         assert_eq!(doc.content.len(), 0);
         assert_eq!(doc.error, "some error".to_string());
     }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn mark_stalled_works() {
+        let db = DB.get_or_init(get_db).await;
+        let cid = xid::new();
+        let gid = xid::Id::from_str(conf::DEFAULT_SYSTEM_USER).unwrap();
+        let mut doc = Translating::with_pk(gid, cid, Language::Eng, 1);
+        let upsert = |progress: i8| {
+            let mut cols = ColumnsMap::with_capacity(3);
+            cols.set_as("progress", &progress);
+            cols.set_as("updated_at", &(axum_web::context::unix_ms() as i64));
+            cols.set_as("error", &"".to_string());
+            cols
+        };
+        doc.upsert_fields(db, upsert(10)).await.unwrap();
+
+        // the job stopped before the cutoff, the mark wins.
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        let updated_before = axum_web::context::unix_ms() as i64;
+        let mut docs = Translating::list_stalled(db, updated_before, vec![])
+            .await
+            .unwrap();
+        docs.retain(|d| d.cid == cid);
+        assert_eq!(docs.len(), 1);
+        docs[0]
+            .mark_stalled(db, "stalled", updated_before)
+            .await
+            .unwrap();
+        let mut row = Translating::with_pk(gid, cid, Language::Eng, 1);
+        row.get_one(db, vec![]).await.unwrap();
+        assert_eq!(row.error, "stalled");
+
+        // the job resumed after the cutoff, its write wins over the mark, even a late one.
+        doc.upsert_fields(db, upsert(100)).await.unwrap();
+        docs[0]
+            .mark_stalled(db, "stalled", updated_before)
+            .await
+            .unwrap();
+        row.get_one(db, vec![]).await.unwrap();
+        assert_eq!(row.progress, 100);
+        assert_eq!(row.error, "");
+
+        doc.delete(db).await.unwrap();
+    }
 }
@@ -0,0 +1,173 @@
+use axum_web::erring::HTTPError;
+use scylla_orm::{ColumnsMap, ToCqlVal};
+use scylla_orm_macros::CqlOrm;
+
+use crate::db::{scylladb, CounterKind};
+
+// the usage of a group in a UTC day, the columns are CQL counters.
+#[derive(Debug, Default, Clone, CqlOrm)]
+pub struct UsageDaily {
+    pub gid: xid::Id,
+    pub day: i32, // yyyymmdd, example: 20231114
+    pub translating_calls: i64,
+    pub translating_tokens: i64,
+    pub summarizing_calls: i64,
+    pub summarizing_tokens: i64,
+    pub embedding_calls: i64,
+    pub embedding_tokens: i64,
+
+    pub _fields: Vec<String>, // selected fields，`_` 前缀字段会被 CqlOrm 忽略
+}
+
+impl UsageDaily {
+    pub fn with_pk(gid: xid::Id, day: i32) -> Self {
+        Self {
+            gid,
+            day,
+            ..Default::default()
+        }
+    }
+
+    pub fn select_fields(select_fields: Vec<String>, with_pk: bool) -> anyhow::Result<Vec<String>> {
+        if select_fields.is_empty() {
+            return Ok(Self::fields());
+        }
+
+        let fields = Self::fields();
+        for field in &select_fields {
+            if !fields.contains(field) {
+                return Err(HTTPError::new(400, format!("Invalid field: {}", field)).into());
+            }
+        }
+
+        let mut select_fields = select_fields;
+        if with_pk {
+            for field in ["gid", "day"] {
+                let field = field.to_string();
+                if !select_fields.contains(&field) {
+                    select_fields.push(field);
+                }
+            }
+        }
+
+        Ok(select_fields)
+    }
+
+    // the same as Counter::incr, not idempotent.
+    pub async fn incr(
+        &self,
+        db: &scylladb::ScyllaDB,
+        kind: CounterKind,
+        calls: i64,
+        tokens: i64,
+    ) -> anyhow::Result<()> {
+        let query = format!(
+            "UPDATE usage_daily SET {} WHERE gid=? AND day=?",
+            kind.incr_columns()
+        );
+        let params = (calls, tokens, self.gid.to_cql(), self.day);
+        let _ = db
+            .execute_with(query, params, scylladb::Consistency::Quorum)
+            .await?;
+        Ok(())
+    }
+
+    // list the days with usage in [start_day, end_day], in ascending order.
+    pub async fn list(
+        db: &scylladb::ScyllaDB,
+        gid: xid::Id,
+        start_day: i32,
+        end_day: i32,
+        limit: i32,
+        select_fields: Vec<String>,
+    ) -> anyhow::Result<Vec<UsageDaily>> {
+        let fields = Self::select_fields(select_fields, true)?;
+
+        let query = format!(
+            "SELECT {} FROM usage_daily WHERE gid=? AND day>=? AND day<=? LIMIT ?",
+            fields.clone().join(",")
+        );
+        let params = (gid.to_cql(), start_day, end_day, limit);
+        let rows = db.execute_iter(query, params).await?;
+
+        let mut res: Vec<UsageDaily> = Vec::with_capacity(rows.len());
+        for row in rows {
+            let mut doc = UsageDaily::default();
+            let mut cols = ColumnsMap::with_capacity(fields.len());
+            cols.fill(row, &fields)?;
+            doc.fill(&cols);
+            doc._fields = fields.clone();
+            res.push(doc);
+        }
+
+        Ok(res)
+    }
+}
+
+// the UTC day of the unix time in ms, as yyyymmdd.
+pub fn day_of(unix_ms: i64) -> i32 {
+    let (y, m, d) = civil_from_days(unix_ms.div_euclid(86_400_000));
+    y * 10000 + m * 100 + d
+}
+
+// the days since the unix epoch of a yyyymmdd day, None if it is not a valid date.
+pub fn days_of(day: i32) -> Option<i64> {
+    let (y, m, d) = (day / 10000, day / 100 % 100, day % 100);
+    if !(1970..=9999).contains(&y) || !(1..=12).contains(&m) || !(1..=31).contains(&d) {
+        return None;
+    }
+
+    let days = days_from_civil(y, m, d);
+    // an overflowed day, such as 20230230, is another date.
+    if civil_from_days(days) != (y, m, d) {
+        return None;
+    }
+    Some(days)
+}
+
+// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(y: i32, m: i32, d: i32) -> i64 {
+    let y = (if m <= 2 { y - 1 } else { y }) as i64;
+    let (m, d) = (m as i64, d as i64);
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i32, i32, i32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y as i32, m as i32, d as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn day_works() {
+        assert_eq!(day_of(0), 19700101);
+        assert_eq!(day_of(1_700_000_000_000), 20231114);
+        assert_eq!(day_of(1_709_251_199_999), 20240229);
+        assert_eq!(day_of(1_709_251_200_000), 20240301);
+
+        assert_eq!(days_of(19700101), Some(0));
+        assert_eq!(days_of(20231114), Some(1_700_000_000_000 / 86_400_000));
+        assert_eq!(days_of(20240301).unwrap() - days_of(20240229).unwrap(), 1);
+        assert_eq!(days_of(20230229), None);
+        assert_eq!(days_of(20231301), None);
+        assert_eq!(days_of(20231100), None);
+        assert_eq!(days_of(19691231), None);
+        assert_eq!(days_of(2023111), None);
+    }
+}
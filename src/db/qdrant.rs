@@ -1,74 +1,285 @@
 use qdrant_client::client::{QdrantClient, QdrantClientConfig};
-use tokio::time::Duration;
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    sync::Mutex,
+};
+use tokio::time::{sleep, timeout, Duration, Instant};
 
 pub use qdrant_client::qdrant::{
-    r#match::MatchValue, read_consistency, Condition, FieldCondition, Filter, Match, PointId,
-    PointStruct, ReadConsistency, SearchPoints, SearchResponse, Value, Vectors,
-    WithPayloadSelector, WithVectorsSelector,
+    condition::ConditionOneOf, point_id::PointIdOptions, points_selector::PointsSelectorOneOf,
+    r#match::MatchValue, read_consistency, value::Kind, vectors::VectorsOptions, Condition,
+    FieldCondition, Filter, Match, NamedVectors, PointId, PointStruct, PointsIdsList,
+    PointsSelector, ReadConsistency, RetrievedPoint, ScoredPoint, ScrollPoints, ScrollResponse,
+    SearchPoints, SearchResponse, Value, Vector, Vectors, WithPayloadSelector, WithVectorsSelector,
+};
+
+use qdrant_client::qdrant::{
+    vectors_config, CollectionStatus, CreateCollection, Distance, FieldType, VectorParams,
+    VectorParamsMap, VectorsConfig,
 };
 
 use crate::conf;
 
+// the payload fields filtered by, they need keyword indexes.
+const INDEXED_FIELDS: [&str; 3] = ["gid", "cid", "language"];
+
+// the failed operations are counted in this rolling window.
+const ERRORS_WINDOW: Duration = Duration::from_secs(300);
+
 pub struct Qdrant {
     client: QdrantClient,
     client_public: QdrantClient,
     collection_name: String,
     collection_pub: String,
+    retry_attempts: u32,
+    retry_backoff: Duration,
+    batch_size: usize,
+    vector_name: Option<String>, // the name of the dense vector, None for the unnamed vector
+    errors: ErrorCounter,
+}
+
+// the point count and status of a collection, -1 and "unknown" if it is not available.
+pub struct CollectionStats {
+    pub points: i64,
+    pub status: String,
 }
 
 impl Qdrant {
-    pub async fn new(cfg: conf::Qdrant, collection_name: &str) -> anyhow::Result<Self> {
-        let client = QdrantClient::new(Some(QdrantClientConfig {
-            uri: cfg.url.clone(),
-            timeout: Duration::from_secs(5),
-            connect_timeout: Duration::from_secs(3),
-            keep_alive_while_idle: true,
-            api_key: None,
-        }))?;
-        let _ = client.collection_info(collection_name).await?;
-
-        let client_public = QdrantClient::new(Some(QdrantClientConfig {
-            uri: cfg.url,
-            timeout: Duration::from_secs(10),
-            connect_timeout: Duration::from_secs(3),
-            keep_alive_while_idle: true,
-            api_key: None,
-        }))?;
-        let _ = client_public
-            .collection_info(collection_name.to_string() + "_pub")
-            .await?;
-        Ok(Qdrant {
+    // the collections default to the Scylla keyspace and its "_pub" variant,
+    // both must exist unless ensure_collections creates them.
+    pub async fn new(cfg: conf::Qdrant, keyspace: &str) -> anyhow::Result<Self> {
+        let collection_name = cfg.collection(keyspace);
+        let client = QdrantClient::new(Some(client_config(
+            &cfg.url,
+            &cfg.api_key,
+            Duration::from_secs(5),
+        )))?;
+        if cfg.ensure_collections {
+            ensure_collection(&client, &collection_name, &cfg).await?;
+        }
+        check_collection(&client, &collection_name).await?;
+
+        let client_public = QdrantClient::new(Some(client_config(
+            cfg.public_url(),
+            cfg.public_api_key(),
+            Duration::from_secs(10),
+        )))?;
+        let collection_pub = cfg.collection_public(keyspace);
+        if cfg.ensure_collections {
+            ensure_collection(&client_public, &collection_pub, &cfg).await?;
+        }
+        check_collection(&client_public, &collection_pub).await?;
+        let qd = Qdrant {
             client,
             client_public,
-            collection_name: collection_name.to_string(),
-            collection_pub: collection_name.to_string() + "_pub",
-        })
+            collection_name,
+            collection_pub,
+            retry_attempts: cfg.retry_attempts.max(1),
+            retry_backoff: Duration::from_millis(cfg.retry_backoff),
+            batch_size: cfg.batch_size.max(1),
+            vector_name: Some(cfg.vector_name).filter(|s| !s.is_empty()),
+            errors: ErrorCounter::new(ERRORS_WINDOW),
+        };
+        if cfg.ensure_collections || cfg.ensure_payload_indexes {
+            qd.ensure_payload_indexes().await?;
+        }
+        Ok(qd)
+    }
+
+    // create the keyword indexes of the filtered payload fields on both collections,
+    // it is safe to create the existing indexes again.
+    pub async fn ensure_payload_indexes(&self) -> anyhow::Result<()> {
+        for public in [false, true] {
+            let client = if public {
+                &self.client_public
+            } else {
+                &self.client
+            };
+            for field in INDEXED_FIELDS {
+                client
+                    .create_field_index(
+                        self.collection(public),
+                        field,
+                        FieldType::Keyword,
+                        None,
+                        None,
+                    )
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    // the name of the dense vector of the points, None for the single unnamed vector.
+    pub fn vector_name(&self) -> Option<&str> {
+        self.vector_name.as_deref()
+    }
+
+    // the collection that the private or public methods operate on.
+    fn collection(&self, public: bool) -> &str {
+        if public {
+            &self.collection_pub
+        } else {
+            &self.collection_name
+        }
+    }
+
+    // run the operation, retry with exponential backoff if it failed with a transient error.
+    async fn retry<T, F, Fut>(&self, op: F) -> anyhow::Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let mut attempt = 1u32;
+        loop {
+            match op().await {
+                Err(err) if attempt < self.retry_attempts && is_transient(&err) => {
+                    sleep(self.retry_backoff * 2u32.pow(attempt - 1)).await;
+                    attempt += 1;
+                }
+                Err(err) => {
+                    self.errors.record();
+                    return Err(err);
+                }
+                Ok(v) => return Ok(v),
+            }
+        }
     }
 
+    // the number of failed operations in the rolling window.
+    pub fn errors_num(&self) -> u64 {
+        self.errors.count()
+    }
+
+    // it degrades to unknown instead of failing if the collection info is not returned in time.
+    pub async fn collection_stats(&self, public: bool, wait: Duration) -> CollectionStats {
+        let client = if public {
+            &self.client_public
+        } else {
+            &self.client
+        };
+        match timeout(wait, client.collection_info(self.collection(public))).await {
+            Ok(Ok(res)) => match res.result {
+                Some(info) => CollectionStats {
+                    points: info.points_count as i64,
+                    status: CollectionStatus::from_i32(info.status)
+                        .unwrap_or(CollectionStatus::UnknownCollectionStatus)
+                        .as_str_name()
+                        .to_string(),
+                },
+                None => CollectionStats::unknown(),
+            },
+            _ => CollectionStats::unknown(),
+        }
+    }
+
+    // upsert the points in chunks of batch_size, the failed chunks do not stop the others.
     pub async fn add_points(&self, points: Vec<PointStruct>) -> anyhow::Result<()> {
-        self.client
-            .upsert_points(&self.collection_name, points, None)
-            .await
-            .map(|_| ())
+        let chunks = (points.len() + self.batch_size - 1) / self.batch_size;
+        let mut errors: Vec<(usize, anyhow::Error)> = Vec::new();
+        for (i, chunk) in points.chunks(self.batch_size).enumerate() {
+            if let Err(err) = self.upsert_chunk(false, i, chunk).await {
+                errors.push((i, err));
+            }
+        }
+        chunks_result(chunks, errors)
     }
 
-    pub async fn copy_to_public(&self, points: Vec<uuid::Uuid>) -> anyhow::Result<()> {
-        let ids: Vec<PointId> = points
-            .iter()
-            .map(|p| PointId::from(p.to_string()))
-            .collect();
+    async fn upsert_chunk(
+        &self,
+        public: bool,
+        i: usize,
+        chunk: &[PointStruct],
+    ) -> anyhow::Result<()> {
+        let start = Instant::now();
+        let client = if public {
+            &self.client_public
+        } else {
+            &self.client
+        };
+        let res = self
+            .retry(|| client.upsert_points(self.collection(public), chunk.to_vec(), None))
+            .await;
+        log::info!(target: "qdrant",
+            action = "upsert_chunk",
+            collection = self.collection(public),
+            chunk = i,
+            points = chunk.len(),
+            elapsed = start.elapsed().as_millis() as u64,
+            ok = res.is_ok();
+            "",
+        );
+        res.map(|_| ())
+    }
+
+    // return the vectors of the existing points, the missing points are ignored.
+    pub async fn get_vectors(
+        &self,
+        points: Vec<uuid::Uuid>,
+    ) -> anyhow::Result<HashMap<uuid::Uuid, Vec<f32>>> {
         let res = self
             .client
             .get_points(
                 &self.collection_name,
-                &ids,
+                &point_ids(&points),
+                Some(WithVectorsSelector::from(true)),
+                Some(WithPayloadSelector::from(false)),
+                None,
+            )
+            .await?;
+        Ok(retrieved_vectors(res.result, self.vector_name()))
+    }
+
+    pub async fn get_public_vectors(
+        &self,
+        points: Vec<uuid::Uuid>,
+    ) -> anyhow::Result<HashMap<uuid::Uuid, Vec<f32>>> {
+        let res = self
+            .client_public
+            .get_points(
+                &self.collection_pub,
+                &point_ids(&points),
                 Some(WithVectorsSelector::from(true)),
-                Some(WithPayloadSelector::from(true)),
-                Some(ReadConsistency {
-                    value: Some(read_consistency::Value::Type(1)),
-                }),
+                Some(WithPayloadSelector::from(false)),
+                None,
             )
             .await?;
+        Ok(retrieved_vectors(res.result, self.vector_name()))
+    }
+
+    // upsert the points to the public collection in chunks of batch_size,
+    // so it is safe to copy the same points again. return the number of points copied.
+    // the stored payload is copied as is, including the custom fields of the embedding,
+    // so the public points can be filtered by the same fields.
+    pub async fn copy_to_public(&self, points: Vec<uuid::Uuid>) -> anyhow::Result<usize> {
+        let chunks = (points.len() + self.batch_size - 1) / self.batch_size;
+        let mut copied = 0usize;
+        let mut errors: Vec<(usize, anyhow::Error)> = Vec::new();
+        for (i, chunk) in points.chunks(self.batch_size).enumerate() {
+            match self.copy_chunk_to_public(i, chunk).await {
+                Ok(n) => copied += n,
+                Err(err) => errors.push((i, err)),
+            }
+        }
+        chunks_result(chunks, errors).map(|_| copied)
+    }
+
+    async fn copy_chunk_to_public(&self, i: usize, chunk: &[uuid::Uuid]) -> anyhow::Result<usize> {
+        let ids = point_ids(chunk);
+        let res = self
+            .retry(|| {
+                self.client.get_points(
+                    self.collection(false),
+                    &ids,
+                    Some(WithVectorsSelector::from(true)),
+                    Some(WithPayloadSelector::from(true)),
+                    Some(ReadConsistency {
+                        value: Some(read_consistency::Value::Type(1)),
+                    }),
+                )
+            })
+            .await?;
 
         let points: Vec<PointStruct> = res
             .result
@@ -79,59 +290,685 @@ impl Qdrant {
                 vectors: p.vectors,
             })
             .collect();
+        if points.is_empty() {
+            return Ok(0);
+        }
+
+        self.upsert_chunk(true, i, &points).await?;
+        Ok(points.len())
+    }
+
+    pub async fn delete_points(&self, points: Vec<uuid::Uuid>) -> anyhow::Result<()> {
+        if points.is_empty() {
+            return Ok(());
+        }
+
+        self.client
+            .delete_points(&self.collection_name, &points_selector(&points), None)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn delete_public_points(&self, points: Vec<uuid::Uuid>) -> anyhow::Result<()> {
         if points.is_empty() {
             return Ok(());
         }
 
         self.client_public
-            .upsert_points(&self.collection_pub, points, None)
+            .delete_points(&self.collection_pub, &points_selector(&points), None)
             .await
             .map(|_| ())
     }
 
+    // page through the points matched the filter, ordered by the point id.
+    // the next_page_offset of the response is the offset of the next page.
+    pub async fn scroll_points(
+        &self,
+        public: bool,
+        f: Option<Filter>,
+        limit: u32,
+        offset: Option<uuid::Uuid>,
+        with_vectors: bool,
+    ) -> anyhow::Result<ScrollResponse> {
+        let req = ScrollPoints {
+            collection_name: self.collection(public).to_string(),
+            filter: f,
+            offset: offset.map(|id| PointId::from(id.to_string())),
+            limit: Some(limit),
+            with_payload: Some(WithPayloadSelector::from(true)),
+            with_vectors: Some(WithVectorsSelector::from(with_vectors)),
+            ..Default::default()
+        };
+        let client = if public {
+            &self.client_public
+        } else {
+            &self.client
+        };
+        client.scroll(&req).await
+    }
+
     pub async fn search_points(
         &self,
         vector: Vec<f32>,
         f: Option<Filter>,
+        limit: u64,
+        offset: u64,
+        score_threshold: Option<f32>,
+        with_vectors: bool,
     ) -> anyhow::Result<SearchResponse> {
-        let search_result = self
-            .client
-            .search_points(&SearchPoints {
-                collection_name: self.collection_name.to_string(),
-                vector,
-                filter: f,
-                limit: 3,
-                with_vectors: None,
-                with_payload: Some(WithPayloadSelector::from(true)),
-                params: None,
-                score_threshold: None,
-                offset: None,
-                ..Default::default()
-            })
-            .await?;
-        Ok(search_result)
+        let mut req = search_request(
+            self.collection(false),
+            vector,
+            f,
+            limit,
+            offset,
+            score_threshold,
+            with_vectors,
+        );
+        req.vector_name = self.vector_name.clone();
+        self.retry(|| self.client.search_points(&req)).await
     }
 
     pub async fn search_public_points(
         &self,
         vector: Vec<f32>,
         f: Option<Filter>,
+        limit: u64,
+        offset: u64,
+        score_threshold: Option<f32>,
+        with_vectors: bool,
     ) -> anyhow::Result<SearchResponse> {
-        let search_result = self
-            .client_public
-            .search_points(&SearchPoints {
-                collection_name: self.collection_name.to_string(),
-                vector,
-                filter: f,
-                limit: 3,
-                with_vectors: None,
-                with_payload: Some(WithPayloadSelector::from(true)),
-                params: None,
-                score_threshold: None,
-                offset: None,
-                ..Default::default()
+        let mut req = search_request(
+            self.collection(true),
+            vector,
+            f,
+            limit,
+            offset,
+            score_threshold,
+            with_vectors,
+        );
+        req.vector_name = self.vector_name.clone();
+        self.retry(|| self.client_public.search_points(&req)).await
+    }
+}
+
+impl CollectionStats {
+    fn unknown() -> Self {
+        Self {
+            points: -1,
+            status: "unknown".to_string(),
+        }
+    }
+
+    // the stats not requested, when the health probes are skipped.
+    pub fn skipped() -> Self {
+        Self {
+            points: -1,
+            status: "skipped".to_string(),
+        }
+    }
+
+    pub fn is_unknown(&self) -> bool {
+        self.status == "unknown"
+    }
+}
+
+// count the events happened in the last window.
+struct ErrorCounter {
+    window: Duration,
+    events: Mutex<VecDeque<Instant>>,
+}
+
+impl ErrorCounter {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            events: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn record(&self) {
+        let now = Instant::now();
+        let mut events = self.events.lock().unwrap();
+        Self::expire(&mut events, now, self.window);
+        events.push_back(now);
+    }
+
+    fn count(&self) -> u64 {
+        let mut events = self.events.lock().unwrap();
+        Self::expire(&mut events, Instant::now(), self.window);
+        events.len() as u64
+    }
+
+    fn expire(events: &mut VecDeque<Instant>, now: Instant, window: Duration) {
+        while let Some(t) = events.front() {
+            if now.duration_since(*t) < window {
+                break;
+            }
+            events.pop_front();
+        }
+    }
+}
+
+// fail at startup rather than on the first request if the collection does not exist.
+async fn check_collection(client: &QdrantClient, collection_name: &str) -> anyhow::Result<()> {
+    client
+        .collection_info(collection_name)
+        .await
+        .map_err(|err| anyhow::anyhow!("qdrant collection {:?}: {}", collection_name, err))?;
+    Ok(())
+}
+
+// create the collection if it does not exist, the payload indexes are ensured later.
+async fn ensure_collection(
+    client: &QdrantClient,
+    collection_name: &str,
+    cfg: &conf::Qdrant,
+) -> anyhow::Result<()> {
+    if client.has_collection(collection_name).await? {
+        return Ok(());
+    }
+
+    client
+        .create_collection(&create_collection_request(collection_name, cfg)?)
+        .await?;
+
+    log::info!(target: "qdrant",
+        action = "create_collection",
+        collection = collection_name,
+        vector_size = cfg.vector_size,
+        distance = cfg.distance.as_str();
+        "",
+    );
+    Ok(())
+}
+
+fn create_collection_request(
+    collection_name: &str,
+    cfg: &conf::Qdrant,
+) -> anyhow::Result<CreateCollection> {
+    let distance = Distance::from_str_name(&cfg.distance)
+        .filter(|d| *d != Distance::UnknownDistance)
+        .ok_or_else(|| anyhow::anyhow!("invalid qdrant distance: {}", cfg.distance))?;
+    if cfg.vector_size == 0 {
+        return Err(anyhow::anyhow!("invalid qdrant vector_size: 0"));
+    }
+
+    let params = VectorParams {
+        size: cfg.vector_size,
+        distance: distance.into(),
+        on_disk: Some(true),
+        ..Default::default()
+    };
+    let config = if cfg.vector_name.is_empty() {
+        vectors_config::Config::Params(params)
+    } else {
+        vectors_config::Config::ParamsMap(VectorParamsMap {
+            map: HashMap::from([(cfg.vector_name.clone(), params)]),
+        })
+    };
+    Ok(CreateCollection {
+        collection_name: collection_name.to_string(),
+        vectors_config: Some(VectorsConfig {
+            config: Some(config),
+        }),
+        on_disk_payload: Some(true),
+        ..Default::default()
+    })
+}
+
+fn client_config(url: &str, api_key: &str, timeout: Duration) -> QdrantClientConfig {
+    if insecure_api_key(url, api_key) {
+        log::warn!(target: "qdrant",
+            action = "new_client",
+            url = url;
+            "api_key is sent in cleartext, use https instead",
+        );
+    }
+
+    QdrantClientConfig {
+        uri: url.to_string(),
+        timeout,
+        connect_timeout: Duration::from_secs(3),
+        keep_alive_while_idle: true,
+        api_key: if api_key.is_empty() {
+            None
+        } else {
+            Some(api_key.to_string())
+        },
+    }
+}
+
+// an api_key over plain http to a remote host can be sniffed.
+fn insecure_api_key(url: &str, api_key: &str) -> bool {
+    if api_key.is_empty() || url.starts_with("https://") {
+        return false;
+    }
+    let host = url
+        .trim_start_matches("http://")
+        .split(&[':', '/'][..])
+        .next()
+        .unwrap_or_default();
+    !matches!(host, "localhost" | "127.0.0.1")
+}
+
+// convert the payload value to JSON.
+pub fn value_to_json(v: Value) -> serde_json::Value {
+    match v.kind {
+        None | Some(Kind::NullValue(_)) => serde_json::Value::Null,
+        Some(Kind::BoolValue(v)) => serde_json::Value::Bool(v),
+        Some(Kind::IntegerValue(v)) => serde_json::Value::from(v),
+        Some(Kind::DoubleValue(v)) => serde_json::Value::from(v),
+        Some(Kind::StringValue(v)) => serde_json::Value::String(v),
+        Some(Kind::ListValue(v)) => {
+            serde_json::Value::Array(v.values.into_iter().map(value_to_json).collect())
+        }
+        Some(Kind::StructValue(v)) => serde_json::Value::Object(
+            v.fields
+                .into_iter()
+                .map(|(k, v)| (k, value_to_json(v)))
+                .collect(),
+        ),
+    }
+}
+
+// the errors worth retrying: the server is unavailable or overloaded, or the connection failed.
+fn is_transient(err: &anyhow::Error) -> bool {
+    let msg = format!("{:#}", err);
+    [
+        "Unavailable",
+        "DeadlineExceeded",
+        "ResourceExhausted",
+        "transport error",
+        "connection reset",
+    ]
+    .iter()
+    .any(|s| msg.contains(s))
+}
+
+// aggregate the errors of chunks, the error tells which chunks failed.
+fn chunks_result(chunks: usize, errors: Vec<(usize, anyhow::Error)>) -> anyhow::Result<()> {
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    let failed: Vec<String> = errors
+        .iter()
+        .map(|(i, err)| format!("chunk {}: {}", i, err))
+        .collect();
+    Err(anyhow::anyhow!(
+        "{} of {} chunks failed, {}",
+        errors.len(),
+        chunks,
+        failed.join("; ")
+    ))
+}
+
+fn search_request(
+    collection_name: &str,
+    vector: Vec<f32>,
+    f: Option<Filter>,
+    limit: u64,
+    offset: u64,
+    score_threshold: Option<f32>,
+    with_vectors: bool,
+) -> SearchPoints {
+    SearchPoints {
+        collection_name: collection_name.to_string(),
+        vector,
+        filter: f,
+        limit,
+        with_vectors: Some(WithVectorsSelector::from(with_vectors)),
+        with_payload: Some(WithPayloadSelector::from(true)),
+        params: None,
+        score_threshold,
+        offset: Some(offset),
+        ..Default::default()
+    }
+}
+
+fn point_ids(points: &[uuid::Uuid]) -> Vec<PointId> {
+    points
+        .iter()
+        .map(|p| PointId::from(p.to_string()))
+        .collect()
+}
+
+// the vectors of a point with the dense vector only, unnamed or named.
+// more named vectors (e.g. sparse) can be added to the returned vectors.
+pub fn point_vectors(vector_name: Option<&str>, dense: Vec<f32>) -> Vectors {
+    match vector_name {
+        None => Vectors::from(dense),
+        Some(name) => Vectors {
+            vectors_options: Some(VectorsOptions::Vectors(NamedVectors {
+                vectors: HashMap::from([(name.to_string(), Vector { data: dense })]),
+            })),
+        },
+    }
+}
+
+// extract the dense vector from the vectors of a point, unnamed or named.
+pub fn vector_data(vectors: Option<Vectors>, vector_name: Option<&str>) -> Option<Vec<f32>> {
+    match (vectors?.vectors_options?, vector_name) {
+        (VectorsOptions::Vector(v), _) => Some(v.data),
+        (VectorsOptions::Vectors(mut v), Some(name)) => v.vectors.remove(name).map(|v| v.data),
+        (VectorsOptions::Vectors(_), None) => None,
+    }
+}
+
+fn retrieved_vectors(
+    points: Vec<RetrievedPoint>,
+    vector_name: Option<&str>,
+) -> HashMap<uuid::Uuid, Vec<f32>> {
+    let mut vectors = HashMap::with_capacity(points.len());
+    for p in points {
+        let id = match p.id.and_then(|id| id.point_id_options) {
+            Some(PointIdOptions::Uuid(id)) => uuid::Uuid::parse_str(&id).ok(),
+            _ => None,
+        };
+        let vector = vector_data(p.vectors, vector_name);
+        if let (Some(id), Some(vector)) = (id, vector) {
+            vectors.insert(id, vector);
+        }
+    }
+    vectors
+}
+
+fn points_selector(points: &[uuid::Uuid]) -> PointsSelector {
+    PointsSelector {
+        points_selector_one_of: Some(PointsSelectorOneOf::Points(PointsIdsList {
+            ids: points
+                .iter()
+                .map(|p| PointId::from(p.to_string()))
+                .collect(),
+        })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collection_works() {
+        // the clients connect lazily, no server is required.
+        let qd = Qdrant {
+            client: QdrantClient::new(None).unwrap(),
+            client_public: QdrantClient::new(None).unwrap(),
+            collection_name: "jarvis".to_string(),
+            collection_pub: "jarvis_pub".to_string(),
+            retry_attempts: 3,
+            retry_backoff: Duration::from_millis(10),
+            batch_size: 200,
+            vector_name: None,
+            errors: ErrorCounter::new(ERRORS_WINDOW),
+        };
+        assert_eq!(qd.collection(false), "jarvis");
+        assert_eq!(qd.collection(true), "jarvis_pub");
+
+        let req = search_request(qd.collection(true), vec![0.1], None, 10, 0, None, false);
+        assert_eq!(req.collection_name, "jarvis_pub");
+        assert_eq!(req.limit, 10);
+        assert_eq!(req.offset, Some(0));
+    }
+
+    #[test]
+    fn client_config_works() {
+        let cfg = conf::Qdrant {
+            url: "https://qdrant.example.com:6334".to_string(),
+            api_key: "secret".to_string(),
+            public_url: "".to_string(),
+            public_api_key: "".to_string(),
+            collection: "".to_string(),
+            collection_public: "".to_string(),
+            ensure_collections: false,
+            ensure_payload_indexes: false,
+            retry_attempts: 3,
+            retry_backoff: 200,
+            batch_size: 200,
+            vector_size: 1536,
+            distance: "Cosine".to_string(),
+            vector_name: "".to_string(),
+        };
+        let c = client_config(&cfg.url, &cfg.api_key, Duration::from_secs(5));
+        assert_eq!(c.uri, "https://qdrant.example.com:6334");
+        assert_eq!(c.api_key, Some("secret".to_string()));
+        assert_eq!(c.timeout, Duration::from_secs(5));
+
+        let c = client_config(
+            cfg.public_url(),
+            cfg.public_api_key(),
+            Duration::from_secs(10),
+        );
+        assert_eq!(c.uri, "https://qdrant.example.com:6334");
+        assert_eq!(c.api_key, Some("secret".to_string()));
+
+        let cfg = conf::Qdrant {
+            url: "http://127.0.0.1:6334".to_string(),
+            api_key: "".to_string(),
+            public_url: "https://qdrant-pub.example.com:6334".to_string(),
+            public_api_key: "secret_pub".to_string(),
+            collection: "".to_string(),
+            collection_public: "".to_string(),
+            ensure_collections: false,
+            ensure_payload_indexes: false,
+            retry_attempts: 3,
+            retry_backoff: 200,
+            batch_size: 200,
+            vector_size: 1536,
+            distance: "Cosine".to_string(),
+            vector_name: "".to_string(),
+        };
+        let c = client_config(&cfg.url, &cfg.api_key, Duration::from_secs(5));
+        assert_eq!(c.api_key, None);
+        let c = client_config(
+            cfg.public_url(),
+            cfg.public_api_key(),
+            Duration::from_secs(10),
+        );
+        assert_eq!(c.uri, "https://qdrant-pub.example.com:6334");
+        assert_eq!(c.api_key, Some("secret_pub".to_string()));
+    }
+
+    #[test]
+    fn insecure_api_key_works() {
+        assert!(!insecure_api_key("http://qdrant.example.com:6334", ""));
+        assert!(!insecure_api_key(
+            "https://qdrant.example.com:6334",
+            "secret"
+        ));
+        assert!(!insecure_api_key("http://127.0.0.1:6334", "secret"));
+        assert!(!insecure_api_key("http://localhost:6334", "secret"));
+        assert!(insecure_api_key("http://qdrant.example.com:6334", "secret"));
+        assert!(insecure_api_key("http://10.0.0.1", "secret"));
+    }
+
+    #[test]
+    fn create_collection_request_works() {
+        let mut cfg = conf::Qdrant {
+            url: "http://127.0.0.1:6334".to_string(),
+            api_key: "".to_string(),
+            public_url: "".to_string(),
+            public_api_key: "".to_string(),
+            collection: "".to_string(),
+            collection_public: "".to_string(),
+            ensure_collections: true,
+            ensure_payload_indexes: false,
+            retry_attempts: 3,
+            retry_backoff: 200,
+            batch_size: 200,
+            vector_size: 1536,
+            distance: "Cosine".to_string(),
+            vector_name: "".to_string(),
+        };
+        let req = create_collection_request("jarvis_pub", &cfg).unwrap();
+        assert_eq!(req.collection_name, "jarvis_pub");
+        assert_eq!(req.on_disk_payload, Some(true));
+        match req.vectors_config.unwrap().config.unwrap() {
+            vectors_config::Config::Params(params) => {
+                assert_eq!(params.size, 1536);
+                assert_eq!(params.distance, Distance::Cosine as i32);
+            }
+            _ => panic!("unexpected vectors config"),
+        }
+
+        cfg.vector_name = "dense".to_string();
+        let req = create_collection_request("jarvis", &cfg).unwrap();
+        match req.vectors_config.unwrap().config.unwrap() {
+            vectors_config::Config::ParamsMap(params) => {
+                assert_eq!(params.map.len(), 1);
+                assert_eq!(params.map["dense"].size, 1536);
+            }
+            _ => panic!("unexpected vectors config"),
+        }
+
+        cfg.distance = "Manhattan2".to_string();
+        assert!(create_collection_request("jarvis", &cfg).is_err());
+        cfg.distance = "Dot".to_string();
+        cfg.vector_size = 0;
+        assert!(create_collection_request("jarvis", &cfg).is_err());
+    }
+
+    #[test]
+    fn point_vectors_works() {
+        assert_eq!(vector_data(None, None), None);
+
+        let v = point_vectors(None, vec![0.1f32, 0.2f32]);
+        assert_eq!(v, Vectors::from(vec![0.1f32, 0.2f32]));
+        assert_eq!(
+            vector_data(Some(v.clone()), None),
+            Some(vec![0.1f32, 0.2f32])
+        );
+        assert_eq!(
+            vector_data(Some(v), Some("dense")),
+            Some(vec![0.1f32, 0.2f32])
+        );
+
+        let v = point_vectors(Some("dense"), vec![0.1f32, 0.2f32]);
+        match &v.vectors_options {
+            Some(VectorsOptions::Vectors(named)) => assert_eq!(named.vectors.len(), 1),
+            _ => panic!("unexpected vectors"),
+        }
+        assert_eq!(vector_data(Some(v.clone()), None), None);
+        assert_eq!(vector_data(Some(v.clone()), Some("sparse")), None);
+        assert_eq!(
+            vector_data(Some(v), Some("dense")),
+            Some(vec![0.1f32, 0.2f32])
+        );
+    }
+
+    #[test]
+    fn value_to_json_works() {
+        assert_eq!(
+            value_to_json(Value::from("jarvis")),
+            serde_json::json!("jarvis")
+        );
+        assert_eq!(value_to_json(Value::from(42i64)), serde_json::json!(42));
+        assert_eq!(value_to_json(Value::from(true)), serde_json::json!(true));
+        assert_eq!(value_to_json(Value { kind: None }), serde_json::Value::Null);
+        assert_eq!(
+            value_to_json(Value {
+                kind: Some(Kind::ListValue(qdrant_client::qdrant::ListValue {
+                    values: vec![Value::from(1i64), Value::from("a")],
+                })),
+            }),
+            serde_json::json!([1, "a"])
+        );
+    }
+
+    #[test]
+    fn is_transient_works() {
+        assert!(is_transient(&anyhow::anyhow!(
+            "status: Unavailable, message: \"error trying to connect\""
+        )));
+        assert!(is_transient(
+            &anyhow::anyhow!("transport error").context("Failed to upsert points")
+        ));
+        assert!(!is_transient(&anyhow::anyhow!(
+            "status: NotFound, message: \"Collection jarvis not found\""
+        )));
+        assert!(!is_transient(&anyhow::anyhow!(
+            "status: InvalidArgument, message: \"wrong vector size\""
+        )));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn retry_works() {
+        let qd = Qdrant {
+            client: QdrantClient::new(None).unwrap(),
+            client_public: QdrantClient::new(None).unwrap(),
+            collection_name: "jarvis".to_string(),
+            collection_pub: "jarvis_pub".to_string(),
+            retry_attempts: 3,
+            retry_backoff: Duration::from_millis(1),
+            batch_size: 200,
+            vector_name: None,
+            errors: ErrorCounter::new(ERRORS_WINDOW),
+        };
+
+        let calls = std::sync::atomic::AtomicU32::new(0);
+        let res: anyhow::Result<u32> = qd
+            .retry(|| async {
+                let n = calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+                if n < 3 {
+                    Err(anyhow::anyhow!("status: Unavailable"))
+                } else {
+                    Ok(n)
+                }
             })
-            .await?;
-        Ok(search_result)
+            .await;
+        assert_eq!(res.unwrap(), 3);
+
+        calls.store(0, std::sync::atomic::Ordering::SeqCst);
+        let res: anyhow::Result<u32> = qd
+            .retry(|| async {
+                calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Err(anyhow::anyhow!("status: NotFound"))
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        calls.store(0, std::sync::atomic::Ordering::SeqCst);
+        let res: anyhow::Result<u32> = qd
+            .retry(|| async {
+                calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Err(anyhow::anyhow!("status: Unavailable"))
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 3);
+        assert_eq!(qd.errors_num(), 2);
+    }
+
+    #[test]
+    fn error_counter_works() {
+        let counter = ErrorCounter::new(Duration::from_millis(50));
+        assert_eq!(counter.count(), 0);
+        counter.record();
+        counter.record();
+        assert_eq!(counter.count(), 2);
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(counter.count(), 0);
+        counter.record();
+        assert_eq!(counter.count(), 1);
+    }
+
+    #[test]
+    fn chunks_result_works() {
+        assert!(chunks_result(3, vec![]).is_ok());
+
+        let err = chunks_result(
+            3,
+            vec![
+                (0, anyhow::anyhow!("status: Unavailable")),
+                (2, anyhow::anyhow!("message too large")),
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "2 of 3 chunks failed, chunk 0: status: Unavailable; chunk 2: message too large"
+        );
     }
 }
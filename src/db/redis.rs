@@ -3,14 +3,20 @@ use rustis::bb8::{CustomizeConnection, ErrorSink, Pool};
 use rustis::{
     client::{Config, PooledClientManager, ServerConfig},
     commands::{SetCondition, SetExpiration, StringCommands},
-    resp::{BulkString, Command, RespBuf},
+    resp::{self, BulkString, Command, RespBuf},
 };
 use tokio::time::Duration;
 
 use crate::conf;
 
+// the lock is renewed or released only by its holder, the token.
+const RENEW_LOCK_SCRIPT: &str = r#"if redis.call("GET", KEYS[1]) == ARGV[1] then return redis.call("PEXPIRE", KEYS[1], ARGV[2]) else return 0 end"#;
+const RELEASE_LOCK_SCRIPT: &str = r#"if redis.call("GET", KEYS[1]) == ARGV[1] then return redis.call("DEL", KEYS[1]) else return 0 end"#;
+
 pub struct Redis {
     pool: Pool<PooledClientManager>,
+    data_ttl_ms: u64,
+    events_channel: String,
 }
 
 impl Redis {
@@ -34,6 +40,7 @@ impl Redis {
             10
         };
         let min_idle = if max_size <= 10 { 1 } else { max_size / 10 };
+        let data_ttl_ms = if cfg.data_ttl > 0 { cfg.data_ttl } else { 600 } * 1000;
 
         let manager = PooledClientManager::new(config).unwrap();
         let pool = Pool::builder()
@@ -46,7 +53,11 @@ impl Redis {
             .connection_customizer(Box::new(RedisMonitor {}))
             .build(manager)
             .await?;
-        Ok(Redis { pool })
+        Ok(Redis {
+            pool,
+            data_ttl_ms,
+            events_channel: cfg.events_channel,
+        })
     }
 
     pub async fn send(
@@ -59,6 +70,42 @@ impl Redis {
         Ok(res)
     }
 
+    // a cheap round trip to check the server is reachable.
+    pub async fn ping(&self) -> anyhow::Result<()> {
+        let res = self.send(resp::cmd("PING"), None).await?;
+        let pong = res.to::<String>()?;
+        if pong != "PONG" {
+            anyhow::bail!("unexpected PING reply: {}", pong);
+        }
+        Ok(())
+    }
+
+    // the configured TTL of job data in milliseconds.
+    pub fn data_ttl_ms(&self) -> u64 {
+        self.data_ttl_ms
+    }
+
+    // the configured pub/sub channel of the job events, empty if disabled.
+    pub fn events_channel(&self) -> &str {
+        &self.events_channel
+    }
+
+    // publish the message to the channel, return the number of the subscribers received it.
+    pub async fn publish(&self, channel: &str, message: Vec<u8>) -> anyhow::Result<usize> {
+        let res = self
+            .send(
+                resp::cmd("PUBLISH")
+                    .arg(channel)
+                    .arg(BulkString::from(message)),
+                None,
+            )
+            .await?;
+        Ok(res.to::<usize>()?)
+    }
+
+    // set the data only if the key does not exist (NX), return false if it exists.
+    // the TTL is set only here, a running job should refresh it by `update_data`,
+    // otherwise the key may expire in the middle of the job and `update_data` (XX) will fail.
     pub async fn new_data(&self, key: &str, value: Vec<u8>, ttl_ms: u64) -> anyhow::Result<bool> {
         let conn = self.pool.get().await?;
         let res = conn
@@ -73,14 +120,71 @@ impl Redis {
         Ok(res)
     }
 
-    pub async fn update_data(&self, key: &str, value: Vec<u8>) -> anyhow::Result<bool> {
+    // update the data only if the key exists (XX), return false if it was expired.
+    // keep the current TTL if `refresh_ttl_ms` is None, otherwise reset the TTL to it.
+    pub async fn update_data(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        refresh_ttl_ms: Option<u64>,
+    ) -> anyhow::Result<bool> {
         let conn = self.pool.get().await?;
-        let res = conn
-            .set_with_options(key, value, SetCondition::XX, SetExpiration::None, true)
-            .await?;
+        let res = match refresh_ttl_ms {
+            Some(ttl_ms) => {
+                conn.set_with_options(
+                    key,
+                    value,
+                    SetCondition::XX,
+                    SetExpiration::Px(ttl_ms),
+                    false,
+                )
+                .await?
+            }
+            None => {
+                conn.set_with_options(key, value, SetCondition::XX, SetExpiration::None, true)
+                    .await?
+            }
+        };
         Ok(res)
     }
 
+    // acquire the lock for the token with a TTL, return false if it is held by another one.
+    // the lock of a crashed holder expires after the TTL.
+    pub async fn acquire_lock(&self, key: &str, token: &str, ttl_ms: u64) -> anyhow::Result<bool> {
+        self.new_data(key, token.as_bytes().to_vec(), ttl_ms).await
+    }
+
+    // reset the TTL of the lock, return false if the token does not hold it anymore.
+    pub async fn renew_lock(&self, key: &str, token: &str, ttl_ms: u64) -> anyhow::Result<bool> {
+        let res = self
+            .send(
+                resp::cmd("EVAL")
+                    .arg(RENEW_LOCK_SCRIPT)
+                    .arg(1)
+                    .arg(key)
+                    .arg(token)
+                    .arg(ttl_ms),
+                None,
+            )
+            .await?;
+        Ok(res.to::<i64>()? == 1)
+    }
+
+    // release the lock, return false if the token does not hold it.
+    pub async fn release_lock(&self, key: &str, token: &str) -> anyhow::Result<bool> {
+        let res = self
+            .send(
+                resp::cmd("EVAL")
+                    .arg(RELEASE_LOCK_SCRIPT)
+                    .arg(1)
+                    .arg(key)
+                    .arg(token),
+                None,
+            )
+            .await?;
+        Ok(res.to::<i64>()? == 1)
+    }
+
     pub async fn get_data(&self, key: &str) -> anyhow::Result<Vec<u8>> {
         let conn = self.pool.get().await?;
         let res: Option<BulkString> = conn.get(key).await?;
@@ -114,8 +218,6 @@ impl<C: Send + 'static, E: 'static> CustomizeConnection<C, E> for RedisMonitor {
 
 #[cfg(test)]
 mod tests {
-    use rustis::resp;
-
     use super::*;
 
     #[tokio::test]
@@ -126,11 +228,85 @@ mod tests {
             username: String::new(),
             password: String::new(),
             max_connections: 10,
+            data_ttl: 600,
+            events_channel: String::new(),
         })
         .await?;
 
         let data = cli.send(resp::cmd("PING"), None).await?;
         assert_eq!("PONG", data.to::<String>()?);
+        cli.ping().await?;
+        assert_eq!(cli.publish("TEST:EVENTS", b"ping".to_vec()).await?, 0);
+
+        Ok(())
+    }
+
+    // the data flow of a message translating job: created by `create`, updated by the job, read by `get`.
+    #[tokio::test]
+    async fn redis_data_works() -> anyhow::Result<()> {
+        let cli = Redis::new(conf::Redis {
+            host: "127.0.0.1".to_string(),
+            port: 6379,
+            username: String::new(),
+            password: String::new(),
+            max_connections: 10,
+            data_ttl: 600,
+            events_channel: String::new(),
+        })
+        .await?;
+
+        let key = format!("TEST:MT:{}", xid::new());
+        assert!(!cli.update_data(&key, b"0".to_vec(), None).await?);
+        assert!(cli.get_data(&key).await.is_err());
+
+        assert!(cli.new_data(&key, b"1".to_vec(), 1000).await?);
+        assert!(!cli.new_data(&key, b"2".to_vec(), 1000).await?);
+        assert_eq!(cli.get_data(&key).await?, b"1".to_vec());
+
+        assert!(cli.update_data(&key, b"3".to_vec(), None).await?);
+        assert_eq!(cli.get_data(&key).await?, b"3".to_vec());
+        assert!(
+            cli.update_data(&key, b"4".to_vec(), Some(cli.data_ttl_ms()))
+                .await?
+        );
+        assert_eq!(cli.get_data(&key).await?, b"4".to_vec());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn redis_lock_works() -> anyhow::Result<()> {
+        let cli = Redis::new(conf::Redis {
+            host: "127.0.0.1".to_string(),
+            port: 6379,
+            username: String::new(),
+            password: String::new(),
+            max_connections: 10,
+            data_ttl: 600,
+            events_channel: String::new(),
+        })
+        .await?;
+
+        let key = format!("TEST:LOCK:{}", xid::new());
+        assert!(cli.acquire_lock(&key, "a", 200).await?);
+        assert!(!cli.acquire_lock(&key, "b", 200).await?);
+        assert!(!cli.renew_lock(&key, "b", 200).await?);
+        assert!(!cli.release_lock(&key, "b").await?);
+
+        // the holder keeps the lock by renewing it
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        assert!(cli.renew_lock(&key, "a", 200).await?);
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        assert!(!cli.acquire_lock(&key, "b", 200).await?);
+
+        // the lock of a crashed holder expires
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(cli.acquire_lock(&key, "b", 200).await?);
+        assert!(!cli.renew_lock(&key, "a", 200).await?);
+        assert!(!cli.release_lock(&key, "a").await?);
+        assert!(cli.release_lock(&key, "b").await?);
+        assert!(cli.acquire_lock(&key, "a", 200).await?);
+        assert!(cli.release_lock(&key, "a").await?);
 
         Ok(())
     }
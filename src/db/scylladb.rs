@@ -1,43 +1,64 @@
 use futures::{stream::StreamExt, Stream};
+use openssl::ssl::{SslContext, SslContextBuilder, SslFiletype, SslMethod, SslVerifyMode};
 use scylla::{
     frame::value::{BatchValues, ValueList},
-    statement::{Consistency, SerialConsistency},
+    statement::SerialConsistency,
     transport::{query_result::QueryResult, Compression, ExecutionProfile},
     CachingSession, Metrics, Session, SessionBuilder,
 };
-use std::{sync::Arc, time::Duration};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 pub use scylla::{
-    batch::Batch,
+    batch::{Batch, BatchType},
     frame::response::result::{ColumnType, Row},
     query::Query,
+    statement::Consistency,
     Bytes,
 };
 
 use crate::conf;
 
 pub struct ScyllaDB {
+    // CachingSession prepares a query once and keeps the PreparedStatement keyed by
+    // the query string, so `execute` does not re-prepare identical queries.
+    // The driver re-prepares transparently when the server answers with UNPREPARED
+    // (e.g. after a schema change invalidates the statement).
     session: CachingSession,
+    ttl: HashMap<String, u32>,
+    read_consistency: Consistency,
+    write_consistency: Consistency,
 }
 
 impl ScyllaDB {
     pub async fn new(cfg: conf::ScyllaDB, keyspace: &str) -> anyhow::Result<Self> {
-        // use tls https://github.com/scylladb/scylla-rust-driver/blob/main/examples/tls.rs
-
+        let read_consistency = parse_consistency(&cfg.read_consistency)?;
+        let write_consistency = parse_consistency(&cfg.write_consistency)?;
+        // the consistency set on a statement overrides the profile's, so the profile's is only
+        // used by the statements not executed by the methods below.
         let handle = ExecutionProfile::builder()
-            .consistency(Consistency::Quorum)
+            .consistency(write_consistency)
             .serial_consistency(Some(SerialConsistency::Serial))
-            .request_timeout(Some(Duration::from_secs(5)))
+            .request_timeout(Some(Duration::from_secs(cfg.request_timeout.max(1))))
             .build()
             .into_handle();
 
-        let session: Session = SessionBuilder::new()
+        let mut builder = SessionBuilder::new()
             .known_nodes(&cfg.nodes)
             .user(cfg.username, cfg.password)
             .compression(Some(Compression::Lz4))
-            .default_execution_profile_handle(handle)
-            .build()
-            .await?;
+            .default_execution_profile_handle(handle);
+        if let Some(tls) = &cfg.tls {
+            builder = builder.ssl_context(Some(ssl_context(tls)?));
+        }
+
+        let session: Session = builder.build().await.map_err(|err| {
+            anyhow::anyhow!(
+                "failed to connect to scylla {:?}, tls: {}, error: {}",
+                cfg.nodes,
+                cfg.tls.is_some(),
+                err
+            )
+        })?;
 
         if !keyspace.is_empty() {
             session.use_keyspace(keyspace, false).await?;
@@ -45,18 +66,61 @@ impl ScyllaDB {
 
         Ok(Self {
             session: CachingSession::from(session, 100000),
+            ttl: cfg.ttl,
+            read_consistency,
+            write_consistency,
         })
     }
 
+    // the TTL in seconds of the rows written to the table, 0 if not configured.
+    pub fn ttl(&self, table: &str) -> u32 {
+        self.ttl.get(table).copied().unwrap_or(0)
+    }
+
+    pub fn ttls(&self) -> &HashMap<String, u32> {
+        &self.ttl
+    }
+
+    fn statement(&self, query: impl Into<Query>, consistency: Option<Consistency>) -> Query {
+        statement_with(
+            query,
+            consistency,
+            self.read_consistency,
+            self.write_consistency,
+        )
+    }
+
+    // a cheap query to check the cluster is reachable.
+    pub async fn ping(&self) -> anyhow::Result<()> {
+        let _ = self.execute("SELECT now() FROM system.local", ()).await?;
+        Ok(())
+    }
+
     pub fn metrics(&self) -> Arc<Metrics> {
         self.session.get_session().get_metrics()
     }
 
+    // the statement is prepared and cached with the read or write consistency by its kind.
     pub async fn execute(
         &self,
         query: impl Into<Query>,
         params: impl ValueList,
     ) -> anyhow::Result<QueryResult> {
+        let query = self.statement(query, None);
+        let res = self.session.execute(query, params).await?;
+        Ok(res)
+    }
+
+    // execute with the given consistency instead of the configured one,
+    // the same query string should always be executed with the same consistency
+    // since the prepared statement is cached by it.
+    pub async fn execute_with(
+        &self,
+        query: impl Into<Query>,
+        params: impl ValueList,
+        consistency: Consistency,
+    ) -> anyhow::Result<QueryResult> {
+        let query = self.statement(query, Some(consistency));
         let res = self.session.execute(query, params).await?;
         Ok(res)
     }
@@ -66,6 +130,7 @@ impl ScyllaDB {
         query: impl Into<Query>,
         params: impl ValueList,
     ) -> anyhow::Result<Vec<Row>> {
+        let query = self.statement(query, None);
         let mut rows_stream = self.session.execute_iter(query, params).await?;
 
         let (capacity, _) = rows_stream.size_hint();
@@ -76,6 +141,24 @@ impl ScyllaDB {
         Ok(rows)
     }
 
+    // one page of at most `page_size` rows, and the paging state of the next page, None if it
+    // is the last page. the page may have fewer rows, even none, before the last one.
+    pub async fn execute_paged(
+        &self,
+        query: impl Into<Query>,
+        params: impl ValueList,
+        page_size: i32,
+        paging_state: Option<Bytes>,
+    ) -> anyhow::Result<(Vec<Row>, Option<Bytes>)> {
+        let mut query = self.statement(query, None);
+        query.set_page_size(page_size);
+        let res = self
+            .session
+            .execute_paged(query, params, paging_state)
+            .await?;
+        Ok((res.rows.unwrap_or_default(), res.paging_state))
+    }
+
     // https://opensource.docs.scylladb.com/master/cql/dml.html#batch-statement
     // BATCH operations are only isolated within a single partition.
     // BATCH with conditions cannot span multiple tables
@@ -85,12 +168,127 @@ impl ScyllaDB {
         values: impl BatchValues,
     ) -> anyhow::Result<QueryResult> {
         let mut batch: Batch = Default::default();
+        batch.set_consistency(self.write_consistency);
         for statement in statements {
             batch.append_statement(statement);
         }
         let res = self.session.batch(&batch, values).await?;
         Ok(res)
     }
+
+    // UNLOGGED BATCH saves the round trips of the statements, it is not atomic.
+    pub async fn unlogged_batch(
+        &self,
+        statements: Vec<&str>,
+        values: impl BatchValues,
+    ) -> anyhow::Result<QueryResult> {
+        let mut batch = Batch::new(BatchType::Unlogged);
+        batch.set_consistency(self.write_consistency);
+        for statement in statements {
+            batch.append_statement(statement);
+        }
+        let res = self.session.batch(&batch, values).await?;
+        Ok(res)
+    }
+}
+
+// set the consistency of the statement, the read consistency for SELECT, otherwise the write one.
+fn statement_with(
+    query: impl Into<Query>,
+    consistency: Option<Consistency>,
+    read: Consistency,
+    write: Consistency,
+) -> Query {
+    let mut query: Query = query.into();
+    let consistency = consistency.unwrap_or_else(|| {
+        let is_read = query
+            .contents
+            .trim_start()
+            .get(..6)
+            .map_or(false, |s| s.eq_ignore_ascii_case("SELECT"));
+        if is_read {
+            read
+        } else {
+            write
+        }
+    });
+    query.set_consistency(consistency);
+    query
+}
+
+pub fn parse_consistency(s: &str) -> anyhow::Result<Consistency> {
+    match s {
+        "Any" => Ok(Consistency::Any),
+        "One" => Ok(Consistency::One),
+        "Two" => Ok(Consistency::Two),
+        "Three" => Ok(Consistency::Three),
+        "Quorum" => Ok(Consistency::Quorum),
+        "All" => Ok(Consistency::All),
+        "LocalQuorum" => Ok(Consistency::LocalQuorum),
+        "EachQuorum" => Ok(Consistency::EachQuorum),
+        "LocalOne" => Ok(Consistency::LocalOne),
+        _ => Err(anyhow::anyhow!("invalid scylla consistency: {}", s)),
+    }
+}
+
+// https://github.com/scylladb/scylla-rust-driver/blob/main/examples/tls.rs
+fn ssl_context(tls: &conf::ScyllaTLS) -> anyhow::Result<SslContext> {
+    let mut builder = SslContextBuilder::new(SslMethod::tls())?;
+    if !tls.ca_file.is_empty() {
+        builder.set_ca_file(&tls.ca_file).map_err(|err| {
+            anyhow::anyhow!("failed to load scylla tls ca_file {}: {}", tls.ca_file, err)
+        })?;
+    }
+    if !tls.cert_file.is_empty() || !tls.key_file.is_empty() {
+        builder
+            .set_certificate_chain_file(&tls.cert_file)
+            .map_err(|err| {
+                anyhow::anyhow!(
+                    "failed to load scylla tls cert_file {}: {}",
+                    tls.cert_file,
+                    err
+                )
+            })?;
+        builder
+            .set_private_key_file(&tls.key_file, SslFiletype::PEM)
+            .map_err(|err| {
+                anyhow::anyhow!(
+                    "failed to load scylla tls key_file {}: {}",
+                    tls.key_file,
+                    err
+                )
+            })?;
+        builder.check_private_key().map_err(|err| {
+            anyhow::anyhow!(
+                "scylla tls key_file {} does not match the cert_file: {}",
+                tls.key_file,
+                err
+            )
+        })?;
+    }
+    builder.set_verify(if tls.verify_peer {
+        SslVerifyMode::PEER
+    } else {
+        SslVerifyMode::NONE
+    });
+    Ok(builder.build())
+}
+
+// the USING TTL clause with a bind marker if the ttl is set, the ttl should be bound first.
+pub fn using_ttl(ttl: u32) -> &'static str {
+    if ttl > 0 {
+        " USING TTL ?"
+    } else {
+        ""
+    }
+}
+
+// the write timestamp, in microseconds, of a write that should lose to every write made after
+// `unix_ms`. the janitor marks the stalled jobs with the time they were last expected to update,
+// so a job still running overwrites the mark with its next write, without the lightweight
+// transactions that should not be mixed with the plain writes of the jobs to the same rows.
+pub fn stale_timestamp(unix_ms: i64) -> i64 {
+    unix_ms * 1000
 }
 
 pub fn extract_applied(res: QueryResult) -> bool {
@@ -153,6 +351,60 @@ mod tests {
         .await
     }
 
+    #[test]
+    fn statement_consistency_works() {
+        let (read, write) = (Consistency::LocalOne, Consistency::LocalQuorum);
+
+        let q = statement_with("SELECT uuid FROM embedding", None, read, write);
+        assert_eq!(q.get_consistency(), Some(Consistency::LocalOne));
+        let q = statement_with(" select uuid FROM embedding", None, read, write);
+        assert_eq!(q.get_consistency(), Some(Consistency::LocalOne));
+        let q = statement_with("UPDATE translating SET error=?", None, read, write);
+        assert_eq!(q.get_consistency(), Some(Consistency::LocalQuorum));
+        let q = statement_with("DELETE FROM embedding", None, read, write);
+        assert_eq!(q.get_consistency(), Some(Consistency::LocalQuorum));
+        let q = statement_with(
+            "UPDATE translating SET error=? IF progress<100",
+            Some(Consistency::Quorum),
+            read,
+            write,
+        );
+        assert_eq!(q.get_consistency(), Some(Consistency::Quorum));
+
+        assert_eq!(
+            parse_consistency("LocalQuorum").unwrap(),
+            Consistency::LocalQuorum
+        );
+        assert!(parse_consistency("Serial").is_err());
+        assert!(parse_consistency("quorum").is_err());
+    }
+
+    #[test]
+    fn ssl_context_works() {
+        let tls = conf::ScyllaTLS {
+            ca_file: "".to_string(),
+            cert_file: "".to_string(),
+            key_file: "".to_string(),
+            verify_peer: true,
+        };
+        assert!(ssl_context(&tls).is_ok());
+
+        let err = ssl_context(&conf::ScyllaTLS {
+            ca_file: "/not/exists/ca.crt".to_string(),
+            ..tls.clone()
+        })
+        .unwrap_err();
+        assert!(err.to_string().contains("ca_file /not/exists/ca.crt"));
+
+        let err = ssl_context(&conf::ScyllaTLS {
+            cert_file: "/not/exists/client.crt".to_string(),
+            key_file: "/not/exists/client.key".to_string(),
+            ..tls
+        })
+        .unwrap_err();
+        assert!(err.to_string().contains("cert_file /not/exists/client.crt"));
+    }
+
     #[tokio::test(flavor = "current_thread")]
     async fn exec_cqls_works() {
         let db = get_db().await;
@@ -163,4 +415,34 @@ mod tests {
         let schema = std::include_str!("../../cql/schema_table.cql");
         exec_cqls(db, schema).await.unwrap();
     }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn prepared_statement_cache_works() {
+        let db = get_db().await;
+
+        // a statement prepared on the servers with tracing has the tracing ids of the prepare,
+        // a statement from the cache is not prepared again and has none.
+        let mut query = Query::new(format!(
+            "SELECT release_version AS v{} FROM system.local",
+            xid::new()
+        ));
+        query.set_tracing(true);
+        let prepared = db.session.add_prepared_statement(&query).await.unwrap();
+        assert!(!prepared.get_prepare_tracing_ids().is_empty());
+        let cached = db.session.add_prepared_statement(&query).await.unwrap();
+        assert!(cached.get_prepare_tracing_ids().is_empty());
+        assert_eq!(cached.get_id(), prepared.get_id());
+
+        // execute prepares a query once and caches it.
+        let query = format!(
+            "SELECT release_version AS v{} FROM system.local",
+            xid::new()
+        );
+        db.execute(query.as_str(), ()).await.unwrap();
+        db.execute(query.as_str(), ()).await.unwrap();
+        let mut query = Query::new(query);
+        query.set_tracing(true);
+        let cached = db.session.add_prepared_statement(&query).await.unwrap();
+        assert!(cached.get_prepare_tracing_ids().is_empty());
+    }
 }
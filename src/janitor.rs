@@ -0,0 +1,201 @@
+use std::sync::Arc;
+use tokio::time::{interval, Duration, Instant, MissedTickBehavior};
+
+use axum_web::context::unix_ms;
+
+use crate::api::{AppState, JobLock};
+use crate::conf;
+use crate::db;
+
+static STALLED_ERROR: &str = "stalled";
+// one replica scans at a time, the scans are full table scans.
+static SCAN_LOCK_KEY: &str = "LOCK:janitor:scan";
+
+// spawn a background task to mark the stalled jobs as errored, so that they can be retried.
+pub fn spawn(app: Arc<AppState>, cfg: conf::Janitor) {
+    if cfg.interval == 0 {
+        log::info!(target: "janitor", action = "disabled"; "");
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut ticker = interval(Duration::from_secs(cfg.interval));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let token = xid::new().to_string();
+            let lock =
+                match JobLock::acquire(app.redis.clone(), SCAN_LOCK_KEY.to_string(), token).await {
+                    Ok(Some(lock)) => lock,
+                    Ok(None) => {
+                        log::info!(target: "janitor", action = "scan"; "locked by another replica");
+                        continue;
+                    }
+                    Err(err) => {
+                        log::warn!(target: "janitor", action = "lock"; "{}", err);
+                        continue;
+                    }
+                };
+            scan(&app, stalled_before(unix_ms() as i64, cfg.stalled_after)).await;
+            lock.release().await;
+        }
+    });
+}
+
+// the jobs not updated since then are stalled.
+fn stalled_before(now: i64, stalled_after: u64) -> i64 {
+    now - stalled_after as i64 * 1000
+}
+
+// the rows listed as stalled without error are marked, the others failed already.
+fn is_stalled(error: &str, updated_at: i64, updated_before: i64) -> bool {
+    error.is_empty() && updated_at < updated_before
+}
+
+async fn scan(app: &AppState, updated_before: i64) {
+    let start = Instant::now();
+    let fields = vec![
+        "progress".to_string(),
+        "updated_at".to_string(),
+        "error".to_string(),
+    ];
+
+    let mut translating = 0usize;
+    match db::Translating::list_stalled(&app.scylla, updated_before, fields.clone()).await {
+        Ok(docs) => {
+            for mut doc in docs
+                .into_iter()
+                .filter(|doc| is_stalled(&doc.error, doc.updated_at, updated_before))
+            {
+                match doc
+                    .mark_stalled(&app.scylla, STALLED_ERROR, updated_before)
+                    .await
+                {
+                    Ok(()) => {
+                        translating += 1;
+                        log::info!(target: "janitor",
+                            action = "mark_stalled",
+                            table = "translating",
+                            gid = doc.gid.to_string(),
+                            cid = doc.cid.to_string(),
+                            language = doc.language.to_639_3().to_string(),
+                            version = doc.version,
+                            progress = doc.progress,
+                            updated_at = doc.updated_at;
+                            "",
+                        );
+                    }
+                    Err(err) => log_error("translating", "mark_stalled", err),
+                }
+            }
+        }
+        Err(err) => log_error("translating", "list_stalled", err),
+    }
+
+    let mut summarizing = 0usize;
+    match db::Summarizing::list_stalled(&app.scylla, updated_before, fields).await {
+        Ok(docs) => {
+            for mut doc in docs
+                .into_iter()
+                .filter(|doc| is_stalled(&doc.error, doc.updated_at, updated_before))
+            {
+                match doc
+                    .mark_stalled(&app.scylla, STALLED_ERROR, updated_before)
+                    .await
+                {
+                    Ok(()) => {
+                        summarizing += 1;
+                        log::info!(target: "janitor",
+                            action = "mark_stalled",
+                            table = "summarizing",
+                            gid = doc.gid.to_string(),
+                            cid = doc.cid.to_string(),
+                            language = doc.language.to_639_3().to_string(),
+                            version = doc.version,
+                            progress = doc.progress,
+                            updated_at = doc.updated_at;
+                            "",
+                        );
+                    }
+                    Err(err) => log_error("summarizing", "mark_stalled", err),
+                }
+            }
+        }
+        Err(err) => log_error("summarizing", "list_stalled", err),
+    }
+
+    let mut public_copy = 0usize;
+    let fields = vec!["status".to_string(), "updated_at".to_string()];
+    match db::PublicCopy::list_stalled(&app.scylla, updated_before, fields).await {
+        Ok(docs) => {
+            for mut doc in docs
+                .into_iter()
+                .filter(|doc| is_stalled("", doc.updated_at, updated_before))
+            {
+                match doc
+                    .mark_stalled(&app.scylla, STALLED_ERROR, updated_before)
+                    .await
+                {
+                    Ok(()) => {
+                        public_copy += 1;
+                        log::info!(target: "janitor",
+                            action = "mark_stalled",
+                            table = "public_copy",
+                            gid = doc.gid.to_string(),
+                            cid = doc.cid.to_string(),
+                            language = doc.language.to_639_3().to_string(),
+                            version = doc.version,
+                            updated_at = doc.updated_at;
+                            "",
+                        );
+                    }
+                    Err(err) => log_error("public_copy", "mark_stalled", err),
+                }
+            }
+        }
+        Err(err) => log_error("public_copy", "list_stalled", err),
+    }
+
+    log::info!(target: "janitor",
+        action = "scan",
+        elapsed = start.elapsed().as_millis() as u64,
+        translating = translating,
+        summarizing = summarizing,
+        public_copy = public_copy;
+        "",
+    );
+}
+
+fn log_error(table: &str, action: &str, err: anyhow::Error) {
+    log::error!(target: "janitor",
+        action = action,
+        table = table;
+        "{}", err,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stalled_works() {
+        let now = 1_700_000_000_000i64;
+        let updated_before = stalled_before(now, 3600);
+        assert_eq!(updated_before, now - 3600 * 1000);
+
+        assert!(is_stalled("", updated_before - 1, updated_before));
+        assert!(!is_stalled("", updated_before, updated_before));
+        assert!(!is_stalled("", now, updated_before));
+        // failed already, or marked by a previous scan.
+        assert!(!is_stalled("timeout", updated_before - 1, updated_before));
+        assert!(!is_stalled(
+            STALLED_ERROR,
+            updated_before - 1,
+            updated_before
+        ));
+
+        // the mark loses to any write of the job after the cutoff.
+        assert!(db::scylladb::stale_timestamp(updated_before) < (updated_before + 1) * 1000);
+    }
+}
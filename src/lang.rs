@@ -1,31 +1,340 @@
 pub use isolang::Language;
-use lingua::LanguageDetectorBuilder;
-use std::str::FromStr;
+use lingua::{IsoCode639_3, LanguageDetectorBuilder};
+use moka::sync::Cache;
+use sha3::{Digest, Sha3_256};
+use std::{
+    collections::HashSet,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::conf;
 
 pub struct LanguageDetector {
     detector: lingua::LanguageDetector,
+    // the detected languages by the digest of the text, None if disabled.
+    cache: Option<Cache<[u8; 32], Option<lingua::Language>>>,
+    cache_hits: AtomicU64,
 }
 
 impl LanguageDetector {
-    pub fn new() -> Self {
-        Self {
-            detector: LanguageDetectorBuilder::from_all_languages()
-                .with_preloaded_language_models()
-                .build(),
+    pub fn new(cfg: conf::Lang) -> anyhow::Result<Self> {
+        let languages = detect_languages(&cfg.languages)?;
+        let mut builder = if cfg.languages.is_empty() {
+            LanguageDetectorBuilder::from_all_languages()
+        } else {
+            LanguageDetectorBuilder::from_languages(&languages.iter().copied().collect::<Vec<_>>())
+        };
+        if cfg.preload {
+            builder.with_preloaded_language_models();
         }
+
+        log::info!(target: "lang",
+            action = "new_detector",
+            languages = languages.len(),
+            preload = cfg.preload;
+            "{}", if cfg.preload { "preloaded" } else { "lazy loading" },
+        );
+        Ok(Self {
+            detector: builder.build(),
+            cache: new_cache(cfg.cache_size),
+            cache_hits: AtomicU64::new(0),
+        })
     }
 
+    // the same content is often detected again shortly, e.g. translating then embedding it.
     pub fn detect(&self, text: &str) -> Option<lingua::Language> {
-        self.detector.detect_language_of(text)
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return self.detector.detect_language_of(text),
+        };
+
+        let key: [u8; 32] = Sha3_256::digest(text.as_bytes()).into();
+        if let Some(lang) = cache.get(&key) {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+            return lang;
+        }
+        let lang = self.detector.detect_language_of(text);
+        cache.insert(key, lang);
+        lang
+    }
+
+    pub fn cache_hits(&self) -> u64 {
+        self.cache_hits.load(Ordering::Relaxed)
     }
 
     pub fn detect_lang(&self, text: &str) -> Language {
         match self.detect(text) {
-            Some(lang) => match Language::from_str(lang.iso_code_639_3().to_string().as_str()) {
-                Ok(lang) => lang,
-                Err(_) => Language::default(),
-            },
+            Some(lang) => to_language(lang),
             None => Language::default(),
         }
     }
+
+    // return the detected language and its confidence value (0.0 - 1.0).
+    pub fn detect_lang_confidence(&self, text: &str) -> (Language, f64) {
+        match self.detect(text) {
+            Some(lang) => {
+                let confidence = self
+                    .detector
+                    .compute_language_confidence_values(text)
+                    .into_iter()
+                    .find(|(l, _)| *l == lang)
+                    .map(|(_, c)| c)
+                    .unwrap_or(0.0);
+                (to_language(lang), confidence)
+            }
+            None => (Language::default(), 0.0),
+        }
+    }
+
+    // return at most top_n candidate languages ranked by their confidence values (0.0 - 1.0),
+    // the languages with zero confidence or without an ISO 639-3 code are skipped.
+    pub fn detect_lang_candidates(&self, text: &str, top_n: usize) -> Vec<(Language, f64)> {
+        self.detector
+            .compute_language_confidence_values(text)
+            .into_iter()
+            .filter(|(_, c)| *c > 0.0)
+            .map(|(l, c)| (to_language(l), c))
+            .filter(|(l, _)| *l != Language::Und)
+            .take(top_n)
+            .collect()
+    }
+}
+
+fn new_cache(size: u64) -> Option<Cache<[u8; 32], Option<lingua::Language>>> {
+    if size == 0 {
+        None
+    } else {
+        Some(Cache::new(size))
+    }
+}
+
+// the lingua languages of the ISO 639-3 codes, all languages if empty.
+fn detect_languages(codes: &[String]) -> anyhow::Result<HashSet<lingua::Language>> {
+    if codes.is_empty() {
+        return Ok(lingua::Language::all());
+    }
+
+    let mut languages = HashSet::with_capacity(codes.len());
+    for code in codes {
+        let iso = IsoCode639_3::from_str(&code.trim().to_lowercase())
+            .map_err(|_| anyhow::anyhow!("invalid lang.languages code: {:?}", code))?;
+        languages.insert(lingua::Language::from_iso_code_639_3(&iso));
+    }
+    if languages.len() < 2 {
+        anyhow::bail!("lang.languages should have 2 languages at least");
+    }
+    Ok(languages)
+}
+
+fn to_language(lang: lingua::Language) -> Language {
+    Language::from_str(lang.iso_code_639_3().to_string().as_str()).unwrap_or_default()
+}
+
+// normalize a language code from the API to the canonical `Language`, it accepts ISO 639-3,
+// ISO 639-1 codes and BCP-47 tags like "zh-Hans" or "pt_BR" (the subtags are stripped),
+// and English language names as the fallback.
+pub fn normalize_lang(s: &str) -> Option<Language> {
+    let s = s.trim().to_ascii_lowercase().replace('_', "-");
+    let primary = s.split('-').next().unwrap_or_default();
+    let lang = match primary.len() {
+        2 => Language::from_639_1(primary),
+        3 => Language::from_639_3(primary),
+        _ => None,
+    };
+    lang.or_else(|| Language::from_str(&s).ok())
+}
+
+// normalize the script subtag of the target language, only Chinese has distinct scripts for now.
+// return None if the script is not supported by the language, an empty string for the default.
+pub fn normalize_script(lang: Language, script: &str) -> Option<&'static str> {
+    match (lang, script.trim().to_ascii_lowercase().as_str()) {
+        (_, "") => Some(""),
+        (Language::Zho | Language::Cmn, "hans") => Some("Hans"),
+        (Language::Zho | Language::Cmn, "hant") => Some("Hant"),
+        _ => None,
+    }
+}
+
+// the script subtag of a Chinese language tag, "Hant" for "zh-Hant" or "zh-TW", "Hans" for
+// "zh-Hans" or "zh-CN", empty for the other languages or the tags without a script or region.
+pub fn tag_script(s: &str) -> &'static str {
+    let s = s.trim().to_ascii_lowercase().replace('_', "-");
+    let mut subtags = s.split('-');
+    if !matches!(subtags.next(), Some("zh" | "zho" | "cmn")) {
+        return "";
+    }
+    for subtag in subtags {
+        match subtag {
+            "hans" | "cn" | "sg" | "my" => return "Hans",
+            "hant" | "tw" | "hk" | "mo" => return "Hant",
+            _ => {}
+        }
+    }
+    ""
+}
+
+// the frequent characters written differently in the two scripts, paired by position.
+const HANS_CHARS: &str = "这个们来时为说国后会过对还发经现没学动样长开关问门间见东书车电实让给";
+const HANT_CHARS: &str = "這個們來時為說國後會過對還發經現沒學動樣長開關問門間見東書車電實讓給";
+
+// detect the script of a Chinese text by the characters written in only one of the scripts,
+// lingua does not distinguish them. empty if undetermined.
+pub fn detect_chinese_script(text: &str) -> &'static str {
+    let (mut hans, mut hant) = (0usize, 0usize);
+    for c in text.chars() {
+        if HANS_CHARS.contains(c) {
+            hans += 1;
+        } else if HANT_CHARS.contains(c) {
+            hant += 1;
+        }
+    }
+    match hans.cmp(&hant) {
+        std::cmp::Ordering::Greater => "Hans",
+        std::cmp::Ordering::Less => "Hant",
+        std::cmp::Ordering::Equal => "",
+    }
+}
+
+// the English name of the language in the given script, used in the prompts.
+pub fn language_name(lang: Language, script: &str) -> String {
+    match script {
+        "Hans" => format!("Simplified {}", lang.to_name()),
+        "Hant" => format!("Traditional {}", lang.to_name()),
+        _ => lang.to_name().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_lang_works() {
+        for (input, expected) in [
+            ("en", Some(Language::Eng)),
+            ("eng", Some(Language::Eng)),
+            ("EN", Some(Language::Eng)),
+            ("zh", Some(Language::Zho)),
+            ("zh-Hans", Some(Language::Zho)),
+            ("zh_Hant_TW", Some(Language::Zho)),
+            ("pt-BR", Some(Language::Por)),
+            ("  ja ", Some(Language::Jpn)),
+            ("", None),
+            ("xx-YY", None),
+            ("unknown", None),
+        ] {
+            assert_eq!(normalize_lang(input), expected, "input: {:?}", input);
+        }
+    }
+
+    #[test]
+    fn detect_languages_works() {
+        assert_eq!(
+            detect_languages(&[]).unwrap().len(),
+            lingua::Language::all().len()
+        );
+
+        let res = detect_languages(&["eng".to_string(), " ZHO ".to_string()]).unwrap();
+        assert_eq!(
+            res,
+            HashSet::from([lingua::Language::English, lingua::Language::Chinese])
+        );
+
+        assert!(detect_languages(&["eng".to_string()]).is_err());
+        assert!(detect_languages(&["eng".to_string(), "eng".to_string()]).is_err());
+        assert!(detect_languages(&["eng".to_string(), "xxx".to_string()]).is_err());
+    }
+
+    #[test]
+    fn detect_lang_subset_works() {
+        let ld = LanguageDetector::new(conf::Lang {
+            languages: vec!["eng".to_string(), "fra".to_string(), "deu".to_string()],
+            preload: false,
+            cache_size: 0,
+        })
+        .unwrap();
+        assert_eq!(ld.detect_lang("languages are awesome"), Language::Eng);
+        assert_eq!(ld.detect_lang(""), Language::default());
+    }
+
+    #[test]
+    fn detect_cache_works() {
+        let ld = LanguageDetector::new(conf::Lang {
+            languages: vec!["eng".to_string(), "fra".to_string(), "deu".to_string()],
+            preload: false,
+            cache_size: 10,
+        })
+        .unwrap();
+        assert_eq!(ld.detect_lang("languages are awesome"), Language::Eng);
+        assert_eq!(ld.cache_hits(), 0);
+        assert_eq!(ld.detect_lang("languages are awesome"), Language::Eng);
+        assert_eq!(ld.cache_hits(), 1);
+        assert_eq!(ld.detect_lang("Les langues sont géniales"), Language::Fra);
+        assert_eq!(ld.cache_hits(), 1);
+    }
+
+    #[test]
+    fn detect_lang_candidates_works() {
+        let ld = LanguageDetector {
+            detector: LanguageDetectorBuilder::from_languages(&[
+                lingua::Language::English,
+                lingua::Language::French,
+                lingua::Language::German,
+            ])
+            .build(),
+            cache: None,
+            cache_hits: AtomicU64::new(0),
+        };
+
+        let res = ld.detect_lang_candidates("languages are awesome", 2);
+        assert!(!res.is_empty() && res.len() <= 2);
+        assert_eq!(res[0].0, Language::Eng);
+        assert!(res.windows(2).all(|w| w[0].1 >= w[1].1));
+
+        assert!(ld.detect_lang_candidates("", 3).is_empty());
+    }
+
+    #[test]
+    fn tag_script_works() {
+        for (input, expected) in [
+            ("zh-Hans", "Hans"),
+            ("zh_Hant_TW", "Hant"),
+            ("zho-hant", "Hant"),
+            ("zh-CN", "Hans"),
+            ("zh-TW", "Hant"),
+            ("zh-HK", "Hant"),
+            ("zh", ""),
+            ("zho", ""),
+            ("en-US", ""),
+            ("pt-BR", ""),
+        ] {
+            assert_eq!(tag_script(input), expected, "input: {:?}", input);
+        }
+    }
+
+    #[test]
+    fn detect_chinese_script_works() {
+        assert_eq!(HANS_CHARS.chars().count(), HANT_CHARS.chars().count());
+        assert!(!HANS_CHARS.chars().any(|c| HANT_CHARS.contains(c)));
+
+        assert_eq!(detect_chinese_script("这是一个测试，我们来说说。"), "Hans");
+        assert_eq!(detect_chinese_script("這是一個測試，我們來說說。"), "Hant");
+        assert_eq!(detect_chinese_script("中文"), "");
+        assert_eq!(detect_chinese_script("Hello"), "");
+    }
+
+    #[test]
+    fn normalize_script_works() {
+        assert_eq!(normalize_script(Language::Zho, ""), Some(""));
+        assert_eq!(normalize_script(Language::Eng, ""), Some(""));
+        assert_eq!(normalize_script(Language::Zho, "Hans"), Some("Hans"));
+        assert_eq!(normalize_script(Language::Zho, "hant"), Some("Hant"));
+        assert_eq!(normalize_script(Language::Eng, "Hant"), None);
+        assert_eq!(normalize_script(Language::Zho, "Latn"), None);
+
+        assert_eq!(language_name(Language::Zho, ""), "Chinese");
+        assert_eq!(language_name(Language::Zho, "Hans"), "Simplified Chinese");
+        assert_eq!(language_name(Language::Zho, "Hant"), "Traditional Chinese");
+    }
 }
@@ -6,13 +6,17 @@ use tokio::{
 };
 
 mod api;
+mod auth;
 mod conf;
 mod db;
+mod janitor;
 mod json_util;
 mod lang;
+mod metrics;
 mod openai;
 mod router;
 mod tokenizer;
+mod trace;
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 4)]
 async fn main() -> anyhow::Result<()> {
@@ -23,10 +27,13 @@ async fn main() -> anyhow::Result<()> {
         .init();
 
     log::debug!("{:?}", cfg);
+    trace::init(&cfg.tracing)?;
 
     let server_cfg = cfg.server.clone();
     let server_env = cfg.env.clone();
     let (app_state, app) = router::new(cfg).await?;
+    #[cfg(unix)]
+    spawn_reload_on_hangup(app_state.clone());
 
     let addr = SocketAddr::from(([0, 0, 0, 0], server_cfg.port));
     log::info!(
@@ -37,13 +44,27 @@ async fn main() -> anyhow::Result<()> {
         &addr
     );
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .with_graceful_shutdown(shutdown_signal(app_state, server_cfg.graceful_shutdown))
         .await?;
 
+    trace::shutdown();
     Ok(())
 }
 
+// SIGHUP reloads the credentials and the deployments of the AI services from the config file.
+#[cfg(unix)]
+fn spawn_reload_on_hangup(app: Arc<api::AppState>) {
+    let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup())
+        .expect("failed to install SIGHUP handler");
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            log::info!("SIGHUP received, reloading the AI services");
+            let _ = api::reload_ai(&app);
+        }
+    });
+}
+
 async fn shutdown_signal(app: Arc<api::AppState>, wait_secs: usize) {
     let ctrl_c = async {
         signal::ctrl_c()
@@ -68,21 +89,36 @@ async fn shutdown_signal(app: Arc<api::AppState>, wait_secs: usize) {
     }
 
     log::info!("signal received, starting graceful shutdown");
+    // the new jobs are rejected with 503, the running ones have wait_secs to finish.
+    app.jobs.close();
+    if !wait_jobs(&app, wait_secs).await {
+        // the jobs still running record the abort in their rows before the runtime stops.
+        app.jobs.abort();
+        wait_jobs(&app, ABORT_WAIT_SECS).await;
+    }
+    log::info!("Goodbye!"); // Say goodbye and then be terminated...
+}
 
+// the seconds for the aborted jobs to record the abort.
+const ABORT_WAIT_SECS: usize = 5;
+
+// wait for the running jobs to finish, return false if some are still running.
+async fn wait_jobs(app: &api::AppState, wait_secs: usize) -> bool {
     let mut secs = wait_secs;
     loop {
-        let translatings = Arc::strong_count(&app.translating);
-        let embeddings = Arc::strong_count(&app.embedding);
-        if secs == 0 || (translatings <= 1 && embeddings <= 1) {
-            log::info!("Goodbye!"); // Say goodbye and then be terminated...
-            return;
+        let running = app.jobs.total();
+        if running == 0 {
+            return true;
+        }
+        if secs == 0 {
+            return false;
         }
 
         log::info!(
-            "signal received, waiting for {} translatings and {} embeddings to finish, or countdown: {} seconds",
-            translatings,
-            embeddings,
-            secs
+            "signal received, waiting for {} jobs to finish, or countdown: {} seconds, {:?}",
+            running,
+            secs,
+            app.jobs.gauges()
         );
         secs -= 1;
         sleep(Duration::from_secs(1)).await;
@@ -0,0 +1,194 @@
+use axum::{
+    extract::{MatchedPath, State},
+    http::Request,
+    middleware::Next,
+    response::Response,
+};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use scylla::Metrics as ScyllaMetrics;
+use std::{sync::Arc, time::Instant};
+use tokio::time::Duration;
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+// the seconds buckets of the AI calls, they take much longer than the HTTP requests.
+const AI_DURATION_BUCKETS: [f64; 10] = [0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0, 180.0];
+const AI_TOKENS_BUCKETS: [f64; 8] = [100.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0];
+
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_duration: HistogramVec,
+    ai_duration: HistogramVec,
+    ai_tokens: HistogramVec,
+    running_jobs: IntGaugeVec,
+    scylla_latency_avg_ms: IntGauge,
+    scylla_latency_p99_ms: IntGauge,
+    scylla_queries_num: IntGauge,
+    scylla_errors_num: IntGauge,
+    scylla_retries_num: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> anyhow::Result<Self> {
+        let registry = Registry::new_custom(Some("jarvis".to_string()), None)?;
+        let http_requests = IntCounterVec::new(
+            Opts::new(
+                "http_requests_total",
+                "The HTTP requests by route and status.",
+            ),
+            &["method", "route", "status"],
+        )?;
+        let http_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "The latency of the HTTP requests by route.",
+            ),
+            &["method", "route"],
+        )?;
+        let ai_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "ai_request_duration_seconds",
+                "The latency of the AI service requests by model and status.",
+            )
+            .buckets(AI_DURATION_BUCKETS.to_vec()),
+            &["model", "status"],
+        )?;
+        let ai_tokens = HistogramVec::new(
+            HistogramOpts::new(
+                "ai_tokens",
+                "The tokens used by an AI service call by model.",
+            )
+            .buckets(AI_TOKENS_BUCKETS.to_vec()),
+            &["model"],
+        )?;
+        let running_jobs = IntGaugeVec::new(
+            Opts::new("running_jobs", "The background jobs running by kind."),
+            &["kind"],
+        )?;
+        let scylla_latency_avg_ms =
+            IntGauge::new("scylla_latency_avg_ms", "The average latency of ScyllaDB.")?;
+        let scylla_latency_p99_ms =
+            IntGauge::new("scylla_latency_p99_ms", "The p99 latency of ScyllaDB.")?;
+        let scylla_queries_num =
+            IntGauge::new("scylla_queries_num", "The queries sent to ScyllaDB.")?;
+        let scylla_errors_num =
+            IntGauge::new("scylla_errors_num", "The failed queries of ScyllaDB.")?;
+        let scylla_retries_num =
+            IntGauge::new("scylla_retries_num", "The retried queries of ScyllaDB.")?;
+
+        registry.register(Box::new(http_requests.clone()))?;
+        registry.register(Box::new(http_duration.clone()))?;
+        registry.register(Box::new(ai_duration.clone()))?;
+        registry.register(Box::new(ai_tokens.clone()))?;
+        registry.register(Box::new(running_jobs.clone()))?;
+        registry.register(Box::new(scylla_latency_avg_ms.clone()))?;
+        registry.register(Box::new(scylla_latency_p99_ms.clone()))?;
+        registry.register(Box::new(scylla_queries_num.clone()))?;
+        registry.register(Box::new(scylla_errors_num.clone()))?;
+        registry.register(Box::new(scylla_retries_num.clone()))?;
+
+        Ok(Self {
+            registry,
+            http_requests,
+            http_duration,
+            ai_duration,
+            ai_tokens,
+            running_jobs,
+            scylla_latency_avg_ms,
+            scylla_latency_p99_ms,
+            scylla_queries_num,
+            scylla_errors_num,
+            scylla_retries_num,
+        })
+    }
+
+    pub fn observe_http(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        self.http_requests
+            .with_label_values(&[method, route, &status.to_string()])
+            .inc();
+        self.http_duration
+            .with_label_values(&[method, route])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_ai(&self, model: &str, status: u16, elapsed: Duration) {
+        self.ai_duration
+            .with_label_values(&[model, &status.to_string()])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_ai_tokens(&self, model: &str, tokens: u32) {
+        self.ai_tokens
+            .with_label_values(&[model])
+            .observe(tokens as f64);
+    }
+
+    // render all metrics in the Prometheus text format, with the current ScyllaDB metrics
+    // and the running jobs by kind.
+    pub fn render(&self, scylla: &ScyllaMetrics, jobs: &[(&str, i64)]) -> anyhow::Result<String> {
+        for (kind, running) in jobs {
+            self.running_jobs.with_label_values(&[kind]).set(*running);
+        }
+        self.scylla_latency_avg_ms
+            .set(scylla.get_latency_avg_ms().unwrap_or(0) as i64);
+        self.scylla_latency_p99_ms
+            .set(scylla.get_latency_percentile_ms(99.0f64).unwrap_or(0) as i64);
+        self.scylla_queries_num.set(scylla.get_queries_num() as i64);
+        self.scylla_errors_num.set(scylla.get_errors_num() as i64);
+        self.scylla_retries_num.set(scylla.get_retries_num() as i64);
+
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(String::from_utf8(buf)?)
+    }
+}
+
+// count the requests and their latency by the matched route, not the raw path,
+// so that the labels are bounded.
+pub async fn middleware<B>(
+    State(metrics): State<Arc<Metrics>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let start = Instant::now();
+    let res = next.run(req).await;
+    metrics.observe_http(&method, &route, res.status().as_u16(), start.elapsed());
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_works() {
+        let m = Metrics::new().unwrap();
+        m.observe_http("POST", "/v1/translating", 200, Duration::from_millis(20));
+        m.observe_ai("gpt-3.5-turbo", 200, Duration::from_secs(3));
+        m.observe_ai_tokens("gpt-3.5-turbo", 1200);
+
+        let text = m
+            .render(
+                &ScyllaMetrics::new(),
+                &[("translating", 2), ("embedding", 0)],
+            )
+            .unwrap();
+        assert!(text.contains(
+            r#"jarvis_http_requests_total{method="POST",route="/v1/translating",status="200"} 1"#
+        ));
+        assert!(text.contains(r#"jarvis_ai_tokens_count{model="gpt-3.5-turbo"} 1"#));
+        assert!(text.contains(r#"jarvis_running_jobs{kind="translating"} 2"#));
+        assert!(text.contains(r#"jarvis_running_jobs{kind="embedding"} 0"#));
+        assert!(text.contains("jarvis_scylla_queries_num 0"));
+    }
+}
@@ -0,0 +1,112 @@
+use async_openai::types::{CreateEmbeddingRequestArgs, CreateEmbeddingResponse, Embedding};
+use tokio::time::Duration;
+
+use axum_web::{context::ReqContext, erring::HTTPError};
+
+use super::{OpenAI, MODEL_EMBEDDING};
+
+impl OpenAI {
+    pub async fn embedding(
+        &self,
+        ctx: &ReqContext,
+        input: &Vec<String>,
+    ) -> Result<(u32, Vec<Vec<f32>>), HTTPError> {
+        let res = self.do_embedding(ctx, input).await?;
+        self.metrics
+            .observe_ai_tokens(MODEL_EMBEDDING, res.usage.total_tokens);
+        let elapsed = ctx.start.elapsed().as_millis() as u32;
+        ctx.set_kvs(vec![
+            ("elapsed", elapsed.into()),
+            ("prompt_tokens", res.usage.prompt_tokens.into()),
+            ("total_tokens", res.usage.total_tokens.into()),
+            ("embedding_size", res.data.len().into()),
+            ("speed", (res.usage.total_tokens * 1000 / elapsed).into()),
+        ])
+        .await;
+
+        let embeddings = order_embeddings(input.len(), res.data)?;
+        Ok((res.usage.total_tokens, embeddings))
+    }
+
+    // https://learn.microsoft.com/en-us/azure/cognitive-services/openai/how-to/embeddings?tabs=console
+    // Max tokens: 8191, text-embedding-ada-002
+    async fn do_embedding(
+        &self,
+        ctx: &ReqContext,
+        input: &Vec<String>, // max length: 16
+    ) -> Result<CreateEmbeddingResponse, HTTPError> {
+        let mut req_body = CreateEmbeddingRequestArgs::default()
+            .model(MODEL_EMBEDDING)
+            .input(input)
+            .build()
+            .map_err(HTTPError::with_500)?;
+        if !ctx.user.is_zero() {
+            req_body.user = Some(ctx.user.to_string())
+        }
+
+        // unlike chat completions, retry without delay.
+        self.failover(ctx, MODEL_EMBEDDING, &req_body, Duration::ZERO)
+            .await
+    }
+}
+
+// match the returned vectors to the inputs by their index, the service may return them out of order.
+fn order_embeddings(expected: usize, data: Vec<Embedding>) -> Result<Vec<Vec<f32>>, HTTPError> {
+    let got = data.len();
+    let mut res: Vec<Option<Vec<f32>>> = vec![None; expected];
+    for v in data {
+        match res.get_mut(v.index as usize) {
+            Some(slot) if slot.is_none() => *slot = Some(v.embedding),
+            _ => {
+                return Err(HTTPError::new(
+                    500,
+                    format!(
+                        "embedding content array not match, invalid or duplicate index {} of {}",
+                        v.index, expected
+                    ),
+                ))
+            }
+        }
+    }
+
+    res.into_iter().collect::<Option<Vec<_>>>().ok_or_else(|| {
+        HTTPError::new(
+            500,
+            format!(
+                "embedding content array length not match, expected {}, got {}",
+                expected, got
+            ),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn embedding(index: u32) -> Embedding {
+        Embedding {
+            index,
+            object: "embedding".to_string(),
+            embedding: vec![index as f32],
+        }
+    }
+
+    #[test]
+    fn order_embeddings_works() {
+        let res = order_embeddings(3, vec![embedding(0), embedding(1), embedding(2)]).unwrap();
+        assert_eq!(res, vec![vec![0.0], vec![1.0], vec![2.0]]);
+
+        let res = order_embeddings(3, vec![embedding(2), embedding(0), embedding(1)]).unwrap();
+        assert_eq!(res, vec![vec![0.0], vec![1.0], vec![2.0]]);
+
+        let err = order_embeddings(3, vec![embedding(2), embedding(0)]).unwrap_err();
+        assert_eq!(err.code, 500);
+        assert!(err.message.contains("expected 3, got 2"));
+
+        let err = order_embeddings(2, vec![embedding(0), embedding(0)]).unwrap_err();
+        assert_eq!(err.code, 500);
+        let err = order_embeddings(2, vec![embedding(0), embedding(2)]).unwrap_err();
+        assert_eq!(err.code, 500);
+    }
+}
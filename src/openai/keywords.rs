@@ -0,0 +1,79 @@
+use async_openai::types::{
+    ChatCompletionRequestMessageArgs, CreateChatCompletionRequestArgs,
+    CreateChatCompletionResponse, Role, Usage,
+};
+
+use axum_web::{context::ReqContext, erring::HTTPError};
+
+use super::{prompts, AIModel, OpenAI};
+
+impl OpenAI {
+    pub async fn keywords(
+        &self,
+        ctx: &ReqContext,
+        lang: &str,
+        input: &str,
+    ) -> Result<(u32, String), HTTPError> {
+        let res = self.do_keywords(ctx, lang, input).await?;
+        let usage = res.usage.unwrap_or(Usage {
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            total_tokens: 0,
+        });
+
+        let elapsed = ctx.start.elapsed().as_millis() as u32;
+        ctx.set_kvs(vec![
+            ("elapsed", elapsed.into()),
+            ("prompt_tokens", usage.prompt_tokens.into()),
+            ("completion_tokens", usage.completion_tokens.into()),
+            ("total_tokens", usage.total_tokens.into()),
+        ])
+        .await;
+
+        let choice = &res.choices[0];
+        let content = choice.message.content.clone().unwrap_or_default();
+        Ok((usage.total_tokens, content))
+    }
+
+    async fn do_keywords(
+        &self,
+        ctx: &ReqContext,
+        language: &str,
+        text: &str,
+    ) -> Result<CreateChatCompletionResponse, HTTPError> {
+        let model = AIModel::GPT3_5;
+        let model_name = model.openai_name();
+        let messages = vec![
+            ChatCompletionRequestMessageArgs::default()
+                .role(Role::System)
+                .content(prompts::keywords_system(language))
+                .build()
+                .map_err(HTTPError::with_500)?,
+            ChatCompletionRequestMessageArgs::default()
+                .role(Role::User)
+                .content(text)
+                .build()
+                .map_err(HTTPError::with_500)?,
+        ];
+
+        let mut req_body = CreateChatCompletionRequestArgs::default()
+            .max_tokens(256u16)
+            .temperature(0.1f32)
+            .top_p(0.618f32)
+            .model(&model_name)
+            .messages(messages)
+            .build()
+            .map_err(HTTPError::with_500)?;
+        if !ctx.user.is_zero() {
+            req_body.user = Some(ctx.user.to_string())
+        }
+
+        ctx.set_kvs(vec![
+            ("max_tokens", req_body.max_tokens.into()),
+            ("model", model_name.clone().into()),
+        ])
+        .await;
+
+        self.chat(ctx, &model_name, &req_body).await
+    }
+}
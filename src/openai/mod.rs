@@ -0,0 +1,1176 @@
+use async_openai::types::CreateChatCompletionResponse;
+use axum::http::header::{HeaderMap, HeaderName};
+
+use isolang::Language;
+use libflate::gzip::Encoder;
+use reqwest::{header, Client, ClientBuilder, Identity, Response};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::HashMap,
+    path::Path,
+    str::FromStr,
+    string::ToString,
+    sync::{Arc, RwLock},
+};
+use tiktoken_rs::{num_tokens_from_messages, ChatCompletionRequestMessage};
+use tokio::time::{sleep, Duration, Instant};
+
+use crate::conf::{EmbeddingLimit, AI};
+use crate::metrics::Metrics;
+use crate::tokenizer::Encoding;
+use crate::trace;
+use axum_web::{context::ReqContext, erring::HTTPError};
+use injection::InjectionScanner;
+
+mod embedding;
+mod injection;
+mod keywords;
+mod prompts;
+mod summarize;
+mod translate;
+
+pub(crate) static APP_USER_AGENT: &str = concat!(
+    "Mozilla/5.0 yiwen.ai ",
+    env!("CARGO_PKG_NAME"),
+    "/",
+    env!("CARGO_PKG_VERSION"),
+);
+
+static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// GPT-3.5-Turbo-1106 has a max context window of 16,385 tokens and can generate 4,096 output tokens.
+const AI_MODEL_GPT_3_5: &str = "gpt-3.5"; // gpt-35-turbo, 4096
+
+// GPT-4 Turbo Preview has a max context window of 128,000 tokens and can generate 4,096 output tokens
+const AI_MODEL_GPT_4: &str = "gpt-4"; // 8192
+
+pub const MODEL_EMBEDDING: &str = "text-embedding-ada-002"; // 8191
+
+// the tokens limit of an embedding input, the request fails if any input exceeds it.
+pub const EMBEDDING_MAX_INPUT_TOKENS: usize = 8191;
+// https://platform.openai.com/docs/api-reference/embeddings/create
+const EMBEDDING_HARD_MAX_ARRAY: usize = 2048;
+
+// the prices of prompt tokens in USD per 1K tokens, https://openai.com/pricing
+const PRICE_GPT_3_5: f64 = 0.001;
+const PRICE_GPT_4: f64 = 0.03;
+pub const PRICE_EMBEDDING: f64 = 0.0001;
+
+// the tokenizer encoding of the embedding model.
+pub fn embedding_encoding() -> Encoding {
+    Encoding::of_model(MODEL_EMBEDDING)
+}
+const MODEL_GPT_3_5: &str = "gpt-3.5-turbo"; // 4096
+const MODEL_GPT_4: &str = "gpt-4"; // 8192
+
+const X_HOST: &str = "x-forwarded-host";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AIModel {
+    GPT3_5,
+    GPT4,
+}
+
+// gpt-35-16k, 16384
+// gpt-35-turbo, 4096
+// static TRANSLATE_SECTION_TOKENS: usize = 1600;
+// static TRANSLATE_HIGH_TOKENS: usize = 1800;
+
+impl AIModel {
+    pub fn openai_name(&self) -> String {
+        match self {
+            AIModel::GPT3_5 => MODEL_GPT_3_5.to_string(),
+            AIModel::GPT4 => MODEL_GPT_4.to_string(),
+        }
+    }
+
+    // the tokenizer encoding of the model, the segments are measured by it.
+    pub fn encoding(&self) -> Encoding {
+        Encoding::of_model(&self.openai_name())
+    }
+
+    // return (recommend, high)
+    pub fn translating_segment_tokens(&self) -> (usize, usize) {
+        match self {
+            AIModel::GPT3_5 => (2600, 3200),
+            AIModel::GPT4 => (2600, 3200),
+        }
+    }
+
+    pub fn max_tokens(&self) -> usize {
+        match self {
+            AIModel::GPT3_5 => 4096,
+            AIModel::GPT4 => 4096,
+        }
+    }
+
+    // the price of prompt tokens in USD per 1K tokens.
+    pub fn prompt_price(&self) -> f64 {
+        match self {
+            AIModel::GPT3_5 => PRICE_GPT_3_5,
+            AIModel::GPT4 => PRICE_GPT_4,
+        }
+    }
+}
+
+impl FromStr for AIModel {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            AI_MODEL_GPT_3_5 => Ok(AIModel::GPT3_5),
+            AI_MODEL_GPT_4 => Ok(AIModel::GPT4),
+            _ => Err(anyhow::anyhow!("invalid model: {}", s)),
+        }
+    }
+}
+
+// parse the `[ai.model_by_language]` config, the keys are ISO 639-3 codes.
+fn parse_model_by_language(
+    cfg: &HashMap<String, String>,
+) -> anyhow::Result<HashMap<Language, AIModel>> {
+    let mut res = HashMap::with_capacity(cfg.len());
+    for (lang, model) in cfg {
+        let language = Language::from_639_3(&lang.to_lowercase())
+            .ok_or_else(|| anyhow::anyhow!("invalid language in model_by_language: {}", lang))?;
+        res.insert(language, AIModel::from_str(&model.to_lowercase())?);
+    }
+    Ok(res)
+}
+
+fn choose_model(
+    model_by_language: &HashMap<Language, AIModel>,
+    language: Language,
+    requested: Option<&str>,
+) -> anyhow::Result<AIModel> {
+    match requested {
+        Some(model) => AIModel::from_str(&model.to_lowercase()),
+        None => Ok(model_by_language
+            .get(&language)
+            .cloned()
+            .unwrap_or(AIModel::GPT3_5)),
+    }
+}
+
+// the fallback of the translating model, the requested one wins over the configured one,
+// an empty requested model disables the fallback. None if it is the same as the model.
+fn choose_fallback(
+    configured: Option<&AIModel>,
+    model: &AIModel,
+    requested: Option<&str>,
+) -> anyhow::Result<Option<AIModel>> {
+    let fallback = match requested {
+        Some("") => None,
+        Some(name) => Some(AIModel::from_str(&name.to_lowercase())?),
+        None => configured.cloned(),
+    };
+    Ok(fallback.filter(|f| f != model))
+}
+
+// the model to segment the content by, a segment should fit both the model and its fallback.
+pub fn segment_model<'a>(model: &'a AIModel, fallback: Option<&'a AIModel>) -> &'a AIModel {
+    match fallback {
+        Some(f) if f.translating_segment_tokens() < model.translating_segment_tokens() => f,
+        _ => model,
+    }
+}
+
+impl ToString for AIModel {
+    fn to_string(&self) -> String {
+        match self {
+            AIModel::GPT3_5 => AI_MODEL_GPT_3_5.to_string(),
+            AIModel::GPT4 => AI_MODEL_GPT_4.to_string(),
+        }
+    }
+}
+
+// the sampling settings of a chat completion request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sampling {
+    pub temperature: f32,
+    pub top_p: f32,
+}
+
+// the settings for translating, stable but not too rigid.
+impl Default for Sampling {
+    fn default() -> Self {
+        Self {
+            temperature: 0.1,
+            top_p: 0.618,
+        }
+    }
+}
+
+impl Sampling {
+    // https://platform.openai.com/docs/api-reference/chat/create
+    // the unset values fall back to the default, the others are clamped to the valid ranges.
+    pub fn new(temperature: Option<f32>, top_p: Option<f32>) -> Self {
+        let default = Self::default();
+        Self {
+            temperature: temperature
+                .filter(|v| v.is_finite())
+                .map_or(default.temperature, |v| v.clamp(0.0, 2.0)),
+            top_p: top_p
+                .filter(|v| v.is_finite())
+                .map_or(default.top_p, |v| v.clamp(0.01, 1.0)),
+        }
+    }
+}
+
+// the batch limits of a embedding request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EmbeddingLimits {
+    pub max_array: usize,
+    pub max_tokens: usize,
+}
+
+// https://learn.microsoft.com/zh-cn/azure/ai-services/openai/how-to/switching-endpoints#azure-openai-embeddings-multiple-input-support
+impl Default for EmbeddingLimits {
+    fn default() -> Self {
+        Self {
+            max_array: 16,
+            max_tokens: 7000,
+        }
+    }
+}
+
+impl EmbeddingLimits {
+    // return the configured limits of the model, or the default ones.
+    pub fn of_model(list: &[EmbeddingLimit], model: &str) -> anyhow::Result<Self> {
+        let limits = match list.iter().find(|l| l.model == model) {
+            Some(l) => Self {
+                max_array: l.max_array,
+                max_tokens: l.max_tokens,
+            },
+            None => return Ok(Self::default()),
+        };
+
+        if limits.max_array == 0 || limits.max_array > EMBEDDING_HARD_MAX_ARRAY {
+            anyhow::bail!(
+                "invalid embedding max_array {} for {}, should be in 1..={}",
+                limits.max_array,
+                model,
+                EMBEDDING_HARD_MAX_ARRAY
+            );
+        }
+        if limits.max_tokens == 0 {
+            anyhow::bail!("invalid embedding max_tokens 0 for {}", model);
+        }
+        Ok(limits)
+    }
+}
+
+// the failures of the AI services, converted to HTTPError with the status codes the clients know.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AIError {
+    RateLimited,     // 429
+    ContextTooLong,  // 422, the prompt or the output exceeds the tokens limit of the model
+    ContentFiltered, // 451, the request was triggered the content filtering
+    OutputFiltered,  // 452, the output was triggered the content filtering
+    Timeout,         // 504
+    Upstream(u16),   // other errors from the upstream or ourselves
+}
+
+impl AIError {
+    pub fn of(err: &HTTPError) -> Self {
+        Self::from_code(err.code)
+    }
+
+    pub fn from_code(code: u16) -> Self {
+        match code {
+            429 => Self::RateLimited,
+            422 => Self::ContextTooLong,
+            451 => Self::ContentFiltered,
+            452 => Self::OutputFiltered,
+            504 => Self::Timeout,
+            code => Self::Upstream(code),
+        }
+    }
+
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::RateLimited => 429,
+            Self::ContextTooLong => 422,
+            Self::ContentFiltered => 451,
+            Self::OutputFiltered => 452,
+            Self::Timeout => 504,
+            Self::Upstream(code) => *code,
+        }
+    }
+
+    pub fn is_content_filtered(&self) -> bool {
+        matches!(self, Self::ContentFiltered | Self::OutputFiltered)
+    }
+
+    // the error may not occur with another endpoint.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::RateLimited | Self::Timeout => true,
+            Self::Upstream(code) => *code > 500,
+            _ => false,
+        }
+    }
+
+    pub fn with_message(self, message: String) -> HTTPError {
+        HTTPError::new(self.code(), message)
+    }
+}
+
+pub struct OpenAI {
+    client: Client,
+    apis: RwLock<Arc<APIs>>, // swapped by reload, the requests in flight keep the old ones
+    embedding_limits: EmbeddingLimits,
+    model_by_language: HashMap<Language, AIModel>,
+    fallback_model: Option<AIModel>,
+    injection: InjectionScanner,
+    wrap_injection: bool,
+    metrics: Arc<Metrics>,
+}
+
+struct APIParams {
+    headers: header::HeaderMap,
+    compress_min_length: usize, // 0 to send the request body uncompressed
+    embedding_url: Option<reqwest::Url>,
+    chat_url: Option<reqwest::Url>,
+    gpt4_chat_url: Option<reqwest::Url>,
+}
+
+// the endpoints and the credentials of the AI services, reloaded as a whole.
+struct APIs {
+    openai: APIParams,
+    azureais: Vec<APIParams>,
+    deployments: Vec<String>, // "<host>/<model>" of the configured deployments, to log the changes
+}
+
+// the deployments added and removed by a reload.
+#[derive(Debug, Default, Serialize)]
+pub struct ReloadSummary {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub deployments: usize,
+}
+
+impl APIs {
+    // the invalid credentials or endpoints fail here, rather than on the requests.
+    fn new(opts: &AI) -> anyhow::Result<Self> {
+        let mut openai_headers = header::HeaderMap::with_capacity(3);
+        openai_headers.insert(
+            header::AUTHORIZATION,
+            format!("Bearer {}", opts.openai.api_key)
+                .parse()
+                .map_err(|_| anyhow::anyhow!("invalid openai api_key"))?,
+        );
+        openai_headers.insert(
+            "OpenAI-Organization",
+            opts.openai
+                .org_id
+                .parse()
+                .map_err(|_| anyhow::anyhow!("invalid openai org_id"))?,
+        );
+        openai_headers.insert(X_HOST, "api.openai.com".parse().unwrap());
+        let agent = reqwest::Url::parse(&opts.openai.agent_endpoint).map_err(|err| {
+            anyhow::anyhow!(
+                "invalid openai agent_endpoint {:?}: {}",
+                opts.openai.agent_endpoint,
+                err
+            )
+        })?;
+
+        let mut apis = Self {
+            openai: APIParams {
+                headers: openai_headers,
+                compress_min_length: compress_min_length(
+                    opts.compress_min_length,
+                    opts.openai.disable_request_gzip,
+                ),
+                embedding_url: agent.join("/v1/embeddings").ok(),
+                chat_url: agent.join("/v1/chat/completions").ok(),
+                gpt4_chat_url: None,
+            },
+            azureais: Vec::with_capacity(opts.azureais.len()),
+            deployments: Vec::new(),
+        };
+
+        for cfg in &opts.azureais {
+            let host = format!("{}.openai.azure.com", cfg.resource_name);
+            let mut azure_headers = header::HeaderMap::with_capacity(2);
+            azure_headers.insert(
+                "api-key",
+                cfg.api_key
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("invalid azureai api_key of {}", host))?,
+            );
+            azure_headers.insert(
+                X_HOST,
+                host.parse()
+                    .map_err(|_| anyhow::anyhow!("invalid azureai resource_name {:?}", host))?,
+            );
+            let agent = reqwest::Url::parse(&cfg.agent_endpoint).map_err(|err| {
+                anyhow::anyhow!(
+                    "invalid azureai agent_endpoint {:?} of {}: {}",
+                    cfg.agent_endpoint,
+                    host,
+                    err
+                )
+            })?;
+            let deployment_url = |model: &str, path: &str| {
+                if model.is_empty() {
+                    None
+                } else {
+                    agent
+                        .join(&format!(
+                            "/openai/deployments/{}/{}?api-version={}",
+                            model, path, cfg.api_version
+                        ))
+                        .ok()
+                }
+            };
+            for model in [&cfg.embedding_model, &cfg.chat_model, &cfg.gpt4_chat_model] {
+                if !model.is_empty() {
+                    apis.deployments.push(format!("{}/{}", host, model));
+                }
+            }
+            apis.azureais.push(APIParams {
+                headers: azure_headers,
+                compress_min_length: compress_min_length(
+                    opts.compress_min_length,
+                    cfg.disable_request_gzip,
+                ),
+                embedding_url: deployment_url(&cfg.embedding_model, "embeddings"),
+                chat_url: deployment_url(&cfg.chat_model, "chat/completions"),
+                gpt4_chat_url: deployment_url(&cfg.gpt4_chat_model, "chat/completions"),
+            });
+        }
+
+        Ok(apis)
+    }
+
+    fn get_params(&self, model_name: &str, rand_index: usize) -> (&reqwest::Url, &APIParams) {
+        let list: Vec<(&reqwest::Url, &APIParams)> = match model_name {
+            MODEL_EMBEDDING => self
+                .azureais
+                .iter()
+                .filter_map(|p| p.embedding_url.as_ref().map(|u| (u, p)))
+                .collect(),
+            MODEL_GPT_3_5 => self
+                .azureais
+                .iter()
+                .filter_map(|p| p.chat_url.as_ref().map(|u| (u, p)))
+                .collect(),
+            MODEL_GPT_4 => self
+                .azureais
+                .iter()
+                .filter_map(|p| p.gpt4_chat_url.as_ref().map(|u| (u, p)))
+                .collect(),
+            _ => vec![],
+        };
+
+        if list.is_empty() {
+            // should not happen
+            return ((self.openai.chat_url.as_ref().unwrap()), &self.openai);
+        }
+
+        list[rand_index % list.len()]
+    }
+
+    // the deployments in other but not in self.
+    fn deployments_not_in(&self, other: &APIs) -> Vec<String> {
+        other
+            .deployments
+            .iter()
+            .filter(|d| !self.deployments.contains(d))
+            .cloned()
+            .collect()
+    }
+}
+
+impl OpenAI {
+    pub fn new(opts: AI, metrics: Arc<Metrics>) -> Self {
+        let mut common_headers = header::HeaderMap::with_capacity(3);
+        common_headers.insert(header::ACCEPT, "application/json".parse().unwrap());
+        common_headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+        common_headers.insert(header::ACCEPT_ENCODING, "gzip".parse().unwrap());
+
+        let root_cert: Vec<u8> =
+            std::fs::read(Path::new(&opts.agent.client_root_cert_file)).unwrap();
+        let root_cert = reqwest::Certificate::from_pem(&root_cert).unwrap();
+
+        let client_pem: Vec<u8> = std::fs::read(Path::new(&opts.agent.client_pem_file)).unwrap();
+        let identity = Identity::from_pem(&client_pem).unwrap();
+        let client = ClientBuilder::new()
+            .use_rustls_tls()
+            .https_only(true)
+            .http2_keep_alive_interval(Some(Duration::from_secs(25)))
+            .http2_keep_alive_timeout(Duration::from_secs(15))
+            .http2_keep_alive_while_idle(true)
+            .connect_timeout(Duration::from_secs(10))
+            .timeout(Duration::from_secs(180))
+            .user_agent(APP_USER_AGENT)
+            .gzip(true)
+            .default_headers(common_headers)
+            .add_root_certificate(root_cert)
+            .identity(identity)
+            .build()
+            .unwrap();
+
+        let apis = APIs::new(&opts).unwrap_or_else(|err| panic!("config error: {}", err));
+        Self {
+            client,
+            apis: RwLock::new(Arc::new(apis)),
+            embedding_limits: EmbeddingLimits::of_model(&opts.embedding_limits, MODEL_EMBEDDING)
+                .unwrap_or_else(|err| panic!("config error: {}", err)),
+            model_by_language: parse_model_by_language(&opts.model_by_language)
+                .unwrap_or_else(|err| panic!("config error: {}", err)),
+            fallback_model: if opts.fallback_model.is_empty() {
+                None
+            } else {
+                Some(
+                    AIModel::from_str(&opts.fallback_model.to_lowercase())
+                        .unwrap_or_else(|err| panic!("config error: {}", err)),
+                )
+            },
+            injection: InjectionScanner::new(&opts.injection.patterns.unwrap_or_else(|| {
+                injection::DEFAULT_PATTERNS
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect()
+            })),
+            wrap_injection: opts.injection.wrap,
+            metrics,
+        }
+    }
+
+    // swap the endpoints and the credentials of the AI services, the requests in flight keep
+    // the old ones. The other settings and the mTLS identity of the agent are not reloaded.
+    // Nothing is swapped if the new ones are invalid.
+    pub fn reload(&self, opts: &AI) -> anyhow::Result<ReloadSummary> {
+        let apis = Arc::new(APIs::new(opts)?);
+        let mut current = self.apis.write().unwrap_or_else(|err| err.into_inner());
+        let summary = ReloadSummary {
+            added: current.deployments_not_in(&apis),
+            removed: apis.deployments_not_in(&current),
+            deployments: apis.deployments.len(),
+        };
+        *current = apis;
+        Ok(summary)
+    }
+
+    fn apis(&self) -> Arc<APIs> {
+        self.apis
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+
+    pub fn embedding_limits(&self) -> EmbeddingLimits {
+        self.embedding_limits
+    }
+
+    // the model to translate to the language, the requested model wins over the configured one.
+    pub fn translating_model(
+        &self,
+        language: Language,
+        requested: Option<&str>,
+    ) -> anyhow::Result<AIModel> {
+        choose_model(&self.model_by_language, language, requested)
+    }
+
+    // the model to switch to when the translating model is over capacity, None to disable.
+    pub fn fallback_model(
+        &self,
+        model: &AIModel,
+        requested: Option<&str>,
+    ) -> anyhow::Result<Option<AIModel>> {
+        choose_fallback(self.fallback_model.as_ref(), model, requested)
+    }
+
+    // send the request to a random endpoint of the model,
+    // retry once with another endpoint if got 429 or 5xx error.
+    async fn failover<I, O>(
+        &self,
+        ctx: &ReqContext,
+        model_name: &str,
+        body: &I,
+        retry_delay: Duration,
+    ) -> Result<O, HTTPError>
+    where
+        I: Serialize + ?Sized,
+        O: DeserializeOwned,
+    {
+        let apis = self.apis();
+        let mut rand_index = rand::random::<u32>() as usize + 1;
+        let (api_url, params) = apis.get_params(model_name, rand_index);
+        ctx.set("host", host_of(&params.headers).into()).await;
+
+        let res = self
+            .request(ctx, model_name, api_url.clone(), params, body)
+            .await;
+
+        match res {
+            Ok(out) => Ok(out),
+            Err(err) if AIError::of(&err).is_retryable() => {
+                if !retry_delay.is_zero() {
+                    sleep(retry_delay).await;
+                }
+                ctx.set("retry_because", err.to_string().into()).await;
+                rand_index += 1;
+                let (api_url, params) = apis.get_params(model_name, rand_index);
+                ctx.set("retry_host", host_of(&params.headers).into()).await;
+                self.request(ctx, model_name, api_url.clone(), params, body)
+                    .await
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn chat<I>(
+        &self,
+        ctx: &ReqContext,
+        model_name: &str,
+        body: &I,
+    ) -> Result<CreateChatCompletionResponse, HTTPError>
+    where
+        I: Serialize + ?Sized,
+    {
+        let res = match Self::check_chat_response(
+            self.failover(ctx, model_name, body, Duration::from_secs(3))
+                .await,
+        ) {
+            Err(err) if AIError::of(&err).is_content_filtered() => {
+                self.content_filter_fallback(ctx, model_name, body, err)
+                    .await
+            }
+            res => res,
+        };
+        if let Ok(Some(usage)) = res.as_ref().map(|rt| rt.usage.as_ref()) {
+            self.metrics
+                .observe_ai_tokens(model_name, usage.total_tokens);
+        }
+        res
+    }
+
+    // Azure filters content more aggressively than OpenAI, so the false positives
+    // are retried once with the OpenAI endpoint before the error is surfaced.
+    async fn content_filter_fallback<I>(
+        &self,
+        ctx: &ReqContext,
+        model_name: &str,
+        body: &I,
+        err: HTTPError,
+    ) -> Result<CreateChatCompletionResponse, HTTPError>
+    where
+        I: Serialize + ?Sized,
+    {
+        let apis = self.apis();
+        let api_url = match apis.openai.chat_url.as_ref() {
+            Some(url) => url,
+            None => return Err(err),
+        };
+        // the request was sent to OpenAI already.
+        if host_of(&apis.get_params(model_name, 0).1.headers) == host_of(&apis.openai.headers) {
+            return Err(err);
+        }
+
+        ctx.set_kvs(vec![
+            ("filter_fallback_because", err.code.into()),
+            ("filter_fallback_host", host_of(&apis.openai.headers).into()),
+        ])
+        .await;
+        match Self::check_chat_response(
+            self.request(ctx, model_name, api_url.clone(), &apis.openai, body)
+                .await,
+        ) {
+            Ok(res) => {
+                ctx.set("filter_fallback", true.into()).await;
+                log::info!(target: "openai",
+                    action = "content_filter_fallback",
+                    rid = ctx.rid,
+                    user = ctx.user.to_string(),
+                    model = model_name,
+                    code = err.code;
+                    "succeeded",
+                );
+                Ok(res)
+            }
+            Err(er) => {
+                ctx.set_kvs(vec![
+                    ("filter_fallback", false.into()),
+                    ("filter_fallback_error", er.to_string().into()),
+                ])
+                .await;
+                Err(err)
+            }
+        }
+    }
+
+    fn check_chat_response(
+        rt: Result<CreateChatCompletionResponse, HTTPError>,
+    ) -> Result<CreateChatCompletionResponse, HTTPError> {
+        match rt {
+            Err(err) => Err(err),
+            Ok(rt) => {
+                if rt.choices.len() == 1 {
+                    let choice = &rt.choices[0];
+                    match choice.finish_reason.as_ref().map_or("stop", |s| s.as_str()) {
+                        "stop" => {
+                            return Ok(rt);
+                        }
+
+                        "content_filter" => {
+                            return Err(HTTPError {
+                                code: AIError::OutputFiltered.code(),
+                                message: "Content was triggered the filtering model".to_string(),
+                                data: serde_json::to_value(rt).ok(),
+                            });
+                        }
+
+                        "length" => {
+                            return Err(HTTPError {
+                                code: AIError::ContextTooLong.code(),
+                                message: "Incomplete output due to max_tokens parameter"
+                                    .to_string(),
+                                data: serde_json::to_value(rt).ok(),
+                            })
+                        }
+
+                        reason => {
+                            return Err(HTTPError {
+                                code: 500,
+                                message: format!("Unknown finish reason: {}", reason),
+                                data: serde_json::to_value(rt).ok(),
+                            });
+                        }
+                    }
+                }
+
+                Err(HTTPError {
+                    code: 500,
+                    message: format!("Unexpected choices: {}", rt.choices.len()),
+                    data: serde_json::to_value(rt).ok(),
+                })
+            }
+        }
+    }
+
+    // send the request in a span of its own, a child of the job span,
+    // and observe its latency by model and status.
+    async fn request<I, O>(
+        &self,
+        ctx: &ReqContext,
+        model_name: &str,
+        url: reqwest::Url,
+        params: &APIParams,
+        body: &I,
+    ) -> Result<O, HTTPError>
+    where
+        I: Serialize + ?Sized,
+        O: DeserializeOwned,
+    {
+        let attrs = vec![
+            ("rid", ctx.rid.clone()),
+            ("model", model_name.to_string()),
+            ("host", host_of(&params.headers).to_string()),
+        ];
+        let start = Instant::now();
+        let res = trace::in_span("openai.request", attrs, async {
+            let res = self.send_request(ctx, url, params, body).await;
+            if let Err(err) = &res {
+                trace::set_attribute("status", err.code as i64);
+            }
+            res
+        })
+        .await;
+        let status = res.as_ref().map_or_else(|err| err.code, |_| 200);
+        self.metrics.observe_ai(model_name, status, start.elapsed());
+        res
+    }
+
+    async fn send_request<I, O>(
+        &self,
+        ctx: &ReqContext,
+        url: reqwest::Url,
+        params: &APIParams,
+        body: &I,
+    ) -> Result<O, HTTPError>
+    where
+        I: Serialize + ?Sized,
+        O: DeserializeOwned,
+    {
+        let res: Result<Response, HTTPError> = async {
+            let data = serde_json::to_vec(body).map_err(HTTPError::with_500)?;
+            // log::info!(target: "debug",
+            //     action = "request",
+            //     input = unsafe {
+            //         String::from_utf8_unchecked(data.clone())
+            //     };
+            //     "",
+            // );
+            ctx.set_kvs(vec![
+                ("url", url.to_string().into()),
+                ("body_length", data.len().into()),
+            ])
+            .await;
+            let req = self
+                .client
+                .post(url)
+                .headers(params.headers.clone())
+                .header(&X_REQUEST_ID, ctx.rid.as_str());
+
+            let res = if should_compress(params.compress_min_length, data.len()) {
+                use std::io::Write;
+                let mut encoder = Encoder::new(Vec::new()).map_err(HTTPError::with_500)?;
+                encoder.write_all(&data).map_err(HTTPError::with_500)?;
+                let data = encoder
+                    .finish()
+                    .into_result()
+                    .map_err(HTTPError::with_500)?;
+
+                ctx.set("gzip_length", data.len().into()).await;
+                req.header("content-encoding", "gzip")
+                    .body(data)
+                    .send()
+                    .await
+                    .map_err(HTTPError::with_500)?
+            } else {
+                ctx.set("gzip_length", 0.into()).await;
+                req.body(data).send().await.map_err(HTTPError::with_500)?
+            };
+
+            Ok(res)
+        }
+        .await;
+
+        match res {
+            Err(err) => {
+                ctx.set(
+                    "req_body",
+                    serde_json::to_string(body).unwrap_or_default().into(),
+                )
+                .await;
+
+                if err.code == 500
+                    && (err.message.contains("timed out") || err.message.contains("timeout"))
+                {
+                    return Err(AIError::Timeout.with_message(err.message));
+                }
+                Err(err)
+            }
+            Ok(res) => {
+                if res.status().is_success() {
+                    let data = res.bytes().await.map_err(HTTPError::with_500)?;
+                    // log::info!(target: "debug",
+                    //     action = "response",
+                    //     output = unsafe {
+                    //         String::from_utf8_unchecked(data.to_vec())
+                    //     };
+                    //     "",
+                    // );
+                    return serde_json::from_slice::<O>(&data).map_err(HTTPError::with_500);
+                }
+
+                let mut status = res.status().as_u16();
+                let headers = res.headers().clone();
+                let req_body = serde_json::to_string(body).unwrap_or_default();
+                let res_body = res.text().await.map_err(HTTPError::with_500)?;
+                if status == 400 {
+                    if res_body.contains("context_length_exceeded") {
+                        status = AIError::ContextTooLong.code()
+                    } else if res_body.contains("content_filter") {
+                        status = AIError::ContentFiltered.code()
+                    }
+                }
+
+                ctx.set_kvs(vec![
+                    ("req_body", req_body.into()),
+                    ("res_status", status.into()),
+                    ("res_headers", headers_to_json(&headers)),
+                ])
+                .await;
+
+                Err(HTTPError::new(status, res_body))
+            }
+        }
+    }
+}
+
+// the gzip threshold of an endpoint, 0 if the endpoint does not accept gzip requests.
+fn compress_min_length(min_length: usize, disabled: bool) -> usize {
+    if disabled {
+        0
+    } else {
+        min_length
+    }
+}
+
+fn should_compress(min_length: usize, length: usize) -> bool {
+    min_length > 0 && length >= min_length
+}
+
+fn host_of(headers: &header::HeaderMap) -> &str {
+    headers
+        .get(X_HOST)
+        .map(|v| v.to_str().unwrap())
+        .unwrap_or_default()
+}
+
+fn headers_to_json(headers: &HeaderMap) -> serde_json::Value {
+    let mut map = serde_json::Map::new();
+    for (key, value) in headers {
+        map.insert(
+            key.as_str().to_string(),
+            serde_json::Value::String(value.to_str().unwrap_or("").to_string()),
+        );
+    }
+    serde_json::Value::Object(map)
+}
+
+// count the tokens of the system message the same way as the chat completions.
+fn system_tokens(model_name: &str, content: &str) -> usize {
+    let messages = vec![ChatCompletionRequestMessage {
+        role: "system".to_string(),
+        content: Some(content.to_string()),
+        name: None,
+        function_call: None,
+    }];
+    num_tokens_from_messages(model_name, &messages).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ai_error_works() {
+        for (err, code) in [
+            (AIError::RateLimited, 429),
+            (AIError::ContextTooLong, 422),
+            (AIError::ContentFiltered, 451),
+            (AIError::OutputFiltered, 452),
+            (AIError::Timeout, 504),
+            (AIError::Upstream(500), 500),
+            (AIError::Upstream(502), 502),
+        ] {
+            assert_eq!(err.code(), code);
+            assert_eq!(AIError::from_code(code), err);
+            let er = err.with_message("test".to_string());
+            assert_eq!(er.code, code);
+            assert_eq!(AIError::of(&er), err);
+        }
+    }
+
+    #[test]
+    fn is_content_filtered_works() {
+        assert!(AIError::of(&HTTPError::new(451, "".to_string())).is_content_filtered());
+        assert!(AIError::of(&HTTPError::new(452, "".to_string())).is_content_filtered());
+        assert!(!AIError::of(&HTTPError::new(429, "".to_string())).is_content_filtered());
+        assert!(!AIError::of(&HTTPError::new(500, "".to_string())).is_content_filtered());
+    }
+
+    #[test]
+    fn is_retryable_works() {
+        assert!(AIError::RateLimited.is_retryable());
+        assert!(AIError::Timeout.is_retryable());
+        assert!(AIError::Upstream(502).is_retryable());
+        assert!(AIError::Upstream(503).is_retryable());
+        assert!(!AIError::Upstream(500).is_retryable());
+        assert!(!AIError::Upstream(400).is_retryable());
+        assert!(!AIError::ContextTooLong.is_retryable());
+        assert!(!AIError::ContentFiltered.is_retryable());
+        assert!(!AIError::OutputFiltered.is_retryable());
+    }
+
+    #[test]
+    fn choose_model_works() {
+        let cfg = HashMap::from([("jpn".to_string(), "gpt-4".to_string())]);
+        let routing = parse_model_by_language(&cfg).unwrap();
+
+        assert_eq!(
+            choose_model(&routing, Language::Jpn, None).unwrap(),
+            AIModel::GPT4
+        );
+        assert_eq!(
+            choose_model(&routing, Language::Eng, None).unwrap(),
+            AIModel::GPT3_5
+        );
+        // the requested model wins
+        assert_eq!(
+            choose_model(&routing, Language::Jpn, Some("GPT-3.5")).unwrap(),
+            AIModel::GPT3_5
+        );
+        assert!(choose_model(&routing, Language::Jpn, Some("gpt-5")).is_err());
+
+        let cfg = HashMap::from([("ja".to_string(), "gpt-4".to_string())]);
+        assert!(parse_model_by_language(&cfg).is_err());
+        let cfg = HashMap::from([("jpn".to_string(), "gpt-5".to_string())]);
+        assert!(parse_model_by_language(&cfg).is_err());
+    }
+
+    #[test]
+    fn choose_fallback_works() {
+        let gpt4 = AIModel::GPT4;
+        let gpt3 = AIModel::GPT3_5;
+
+        assert_eq!(
+            choose_fallback(Some(&gpt3), &gpt4, None).unwrap(),
+            Some(gpt3.clone())
+        );
+        assert_eq!(choose_fallback(None, &gpt4, None).unwrap(), None);
+        // no fallback to itself
+        assert_eq!(choose_fallback(Some(&gpt3), &gpt3, None).unwrap(), None);
+        // the requested fallback wins, an empty one disables it
+        assert_eq!(
+            choose_fallback(None, &gpt4, Some("GPT-3.5")).unwrap(),
+            Some(gpt3.clone())
+        );
+        assert_eq!(choose_fallback(Some(&gpt3), &gpt4, Some("")).unwrap(), None);
+        assert!(choose_fallback(None, &gpt4, Some("gpt-5")).is_err());
+
+        assert_eq!(segment_model(&gpt4, None), &gpt4);
+        assert_eq!(segment_model(&gpt4, Some(&gpt3)), &gpt4);
+    }
+
+    #[test]
+    fn should_compress_works() {
+        assert!(should_compress(compress_min_length(256, false), 256));
+        assert!(!should_compress(compress_min_length(256, false), 255));
+        assert!(!should_compress(compress_min_length(256, true), 1024));
+        assert!(!should_compress(compress_min_length(0, false), 1024));
+        assert!(should_compress(compress_min_length(1, false), 1));
+    }
+
+    #[test]
+    fn sampling_works() {
+        assert_eq!(Sampling::new(None, None), Sampling::default());
+        assert_eq!(
+            Sampling::new(Some(0.0), Some(1.0)),
+            Sampling {
+                temperature: 0.0,
+                top_p: 1.0,
+            }
+        );
+        assert_eq!(
+            Sampling::new(Some(3.0), Some(0.0)),
+            Sampling {
+                temperature: 2.0,
+                top_p: 0.01,
+            }
+        );
+        assert_eq!(
+            Sampling::new(Some(-1.0), Some(f32::NAN)),
+            Sampling {
+                temperature: 0.0,
+                top_p: 0.618,
+            }
+        );
+    }
+
+    #[test]
+    fn embedding_limits_works() {
+        let list = vec![EmbeddingLimit {
+            model: "text-embedding-3-small".to_string(),
+            max_array: 2048,
+            max_tokens: 100000,
+        }];
+        assert_eq!(
+            EmbeddingLimits::of_model(&list, MODEL_EMBEDDING).unwrap(),
+            EmbeddingLimits::default()
+        );
+        assert_eq!(
+            EmbeddingLimits::of_model(&list, "text-embedding-3-small").unwrap(),
+            EmbeddingLimits {
+                max_array: 2048,
+                max_tokens: 100000,
+            }
+        );
+
+        let list = vec![EmbeddingLimit {
+            model: MODEL_EMBEDDING.to_string(),
+            max_array: 4096,
+            max_tokens: 7000,
+        }];
+        assert!(EmbeddingLimits::of_model(&list, MODEL_EMBEDDING).is_err());
+
+        let list = vec![EmbeddingLimit {
+            model: MODEL_EMBEDDING.to_string(),
+            max_array: 16,
+            max_tokens: 0,
+        }];
+        assert!(EmbeddingLimits::of_model(&list, MODEL_EMBEDDING).is_err());
+    }
+
+    fn ai_opts(resources: &[&str], api_key: &str) -> AI {
+        AI {
+            agent: crate::conf::Agent {
+                client_pem_file: "".to_string(),
+                client_root_cert_file: "".to_string(),
+            },
+            openai: crate::conf::OpenAI {
+                agent_endpoint: "https://jarvis.yiwen.vpc:8443".to_string(),
+                api_key: "sk-test".to_string(),
+                org_id: "org-test".to_string(),
+                disable_request_gzip: false,
+            },
+            azureais: resources
+                .iter()
+                .map(|r| crate::conf::AzureAI {
+                    agent_endpoint: "https://jarvis.yiwen.vpc:8443".to_string(),
+                    resource_name: r.to_string(),
+                    api_key: api_key.to_string(),
+                    api_version: "2023-05-15".to_string(),
+                    embedding_model: "embedding-ada-002".to_string(),
+                    chat_model: "gpt-35-turbo".to_string(),
+                    gpt4_chat_model: "".to_string(),
+                    disable_request_gzip: false,
+                })
+                .collect(),
+            embedding_limits: vec![],
+            compress_min_length: 256,
+            model_by_language: HashMap::new(),
+            fallback_model: "".to_string(),
+            injection: Default::default(),
+        }
+    }
+
+    #[test]
+    fn apis_works() {
+        let apis = APIs::new(&ai_opts(&["yw-a", "yw-b"], "key")).unwrap();
+        assert_eq!(
+            apis.deployments,
+            vec![
+                "yw-a.openai.azure.com/embedding-ada-002",
+                "yw-a.openai.azure.com/gpt-35-turbo",
+                "yw-b.openai.azure.com/embedding-ada-002",
+                "yw-b.openai.azure.com/gpt-35-turbo",
+            ]
+        );
+        let (url, params) = apis.get_params(MODEL_EMBEDDING, 1);
+        assert_eq!(
+            url.as_str(),
+            "https://jarvis.yiwen.vpc:8443/openai/deployments/embedding-ada-002/embeddings?api-version=2023-05-15"
+        );
+        assert_eq!(host_of(&params.headers), "yw-b.openai.azure.com");
+        // no gpt-4 deployment, fall back to OpenAI
+        let (_, params) = apis.get_params(MODEL_GPT_4, 1);
+        assert_eq!(host_of(&params.headers), "api.openai.com");
+
+        let other = APIs::new(&ai_opts(&["yw-b", "yw-c"], "new-key")).unwrap();
+        assert_eq!(
+            apis.deployments_not_in(&other),
+            vec![
+                "yw-c.openai.azure.com/embedding-ada-002",
+                "yw-c.openai.azure.com/gpt-35-turbo",
+            ]
+        );
+        assert_eq!(
+            other.deployments_not_in(&apis),
+            vec![
+                "yw-a.openai.azure.com/embedding-ada-002",
+                "yw-a.openai.azure.com/gpt-35-turbo",
+            ]
+        );
+
+        assert!(APIs::new(&ai_opts(&["yw-a"], "invalid\nkey")).is_err());
+        let mut opts = ai_opts(&["yw-a"], "key");
+        opts.azureais[0].agent_endpoint = "jarvis.yiwen.vpc".to_string();
+        assert!(APIs::new(&opts).is_err());
+    }
+}
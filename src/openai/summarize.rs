@@ -0,0 +1,107 @@
+use async_openai::types::{
+    ChatCompletionRequestMessageArgs, CreateChatCompletionRequestArgs,
+    CreateChatCompletionResponse, Role, Usage,
+};
+
+use axum_web::{context::ReqContext, erring::HTTPError};
+
+use super::{injection, prompts, system_tokens, AIModel, OpenAI};
+use crate::tokenizer;
+
+impl OpenAI {
+    pub async fn summarize(
+        &self,
+        ctx: &ReqContext,
+        lang: &str,
+        input: &str,
+    ) -> Result<(u32, String), HTTPError> {
+        let res = self.do_summarize(ctx, lang, input).await?;
+        let usage = res.usage.unwrap_or(Usage {
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            total_tokens: 0,
+        });
+
+        let elapsed = ctx.start.elapsed().as_millis() as u32;
+        ctx.set_kvs(vec![
+            ("elapsed", elapsed.into()),
+            ("prompt_tokens", usage.prompt_tokens.into()),
+            ("completion_tokens", usage.completion_tokens.into()),
+            ("total_tokens", usage.total_tokens.into()),
+            ("speed", (usage.total_tokens * 1000 / elapsed).into()),
+        ])
+        .await;
+
+        let choice = &res.choices[0];
+        let content = choice.message.content.clone().unwrap_or_default();
+        Ok((usage.total_tokens, content))
+    }
+
+    // estimate the prompt tokens of summarizing the input without calling the model.
+    pub fn estimate_summarize(&self, language: &str, input: &str) -> usize {
+        let system = prompts::summarize_system(language);
+        system_tokens(&AIModel::GPT3_5.openai_name(), &system) + tokenizer::tokens_len(input)
+    }
+
+    // Max tokens: 4096
+    async fn do_summarize(
+        &self,
+        ctx: &ReqContext,
+        language: &str,
+        text: &str,
+    ) -> Result<CreateChatCompletionResponse, HTTPError> {
+        let model = AIModel::GPT3_5;
+        let model_name = model.openai_name();
+
+        let mut text = text.to_string();
+        if let Some(pattern) = self.injection.scan(&text) {
+            ctx.set_kvs(vec![
+                ("possible_injection", true.into()),
+                ("injection_pattern", pattern.to_string().into()),
+                ("injection_wrapped", self.wrap_injection.into()),
+            ])
+            .await;
+            if self.wrap_injection {
+                text = injection::wrap(&text);
+            }
+        }
+
+        let system = prompts::summarize_system(language);
+        let system_tokens = system_tokens(&model_name, &system) as u16;
+        let system_message = ChatCompletionRequestMessageArgs::default()
+            .role(Role::System)
+            .content(system)
+            .build()
+            .map_err(HTTPError::with_500)?;
+
+        let messages = vec![
+            system_message,
+            ChatCompletionRequestMessageArgs::default()
+                .role(Role::User)
+                .content(text)
+                .build()
+                .map_err(HTTPError::with_500)?,
+        ];
+
+        let mut req_body = CreateChatCompletionRequestArgs::default()
+            .max_tokens(800u16)
+            .temperature(0.382f32)
+            .top_p(0.618f32)
+            .model(&model_name)
+            .messages(messages)
+            .build()
+            .map_err(HTTPError::with_500)?;
+        if !ctx.user.is_zero() {
+            req_body.user = Some(ctx.user.to_string())
+        }
+
+        ctx.set_kvs(vec![
+            ("system_tokens", system_tokens.into()),
+            ("max_tokens", req_body.max_tokens.into()),
+            ("model", model_name.clone().into()),
+        ])
+        .await;
+
+        self.chat(ctx, &model_name, &req_body).await
+    }
+}
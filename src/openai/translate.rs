@@ -0,0 +1,329 @@
+use async_openai::types::{
+    ChatCompletionRequestMessageArgs, CreateChatCompletionRequestArgs,
+    CreateChatCompletionResponse, Role, Usage,
+};
+
+use axum_web::{context::ReqContext, erring::HTTPError};
+
+use super::{prompts, system_tokens, AIError, AIModel, OpenAI, Sampling};
+use crate::json_util::RawJSONArray;
+
+impl OpenAI {
+    #[allow(clippy::too_many_arguments)]
+    pub async fn translate(
+        &self,
+        ctx: &ReqContext,
+        model: &AIModel,
+        sampling: &Sampling,
+        context: &str,
+        origin_lang: &str,
+        target_lang: &str,
+        input: &Vec<Vec<String>>,
+    ) -> Result<(u32, Vec<Vec<String>>), HTTPError> {
+        let text =
+            serde_json::to_string(input).expect("OpenAI::translate serde_json::to_string error");
+        // scan the texts rather than the JSON, so the line patterns work.
+        // the JSON array already delimits the texts, it is not wrapped.
+        let texts: Vec<&str> = input.iter().flatten().map(|s| s.as_str()).collect();
+        if let Some(pattern) = self.injection.scan(&texts.join("\n")) {
+            ctx.set_kvs(vec![
+                ("possible_injection", true.into()),
+                ("injection_pattern", pattern.to_string().into()),
+            ])
+            .await;
+        }
+
+        let res = self
+            .do_translate(
+                ctx,
+                model,
+                sampling,
+                context,
+                origin_lang,
+                target_lang,
+                &text,
+            )
+            .await?;
+
+        let usage = res.usage.unwrap_or(Usage {
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            total_tokens: 0,
+        });
+
+        let elapsed = ctx.start.elapsed().as_millis() as u32;
+        ctx.set_kvs(vec![
+            ("elapsed", elapsed.into()),
+            ("prompt_tokens", usage.prompt_tokens.into()),
+            ("completion_tokens", usage.completion_tokens.into()),
+            ("total_tokens", usage.total_tokens.into()),
+            ("speed", (usage.total_tokens * 1000 / elapsed).into()),
+        ])
+        .await;
+
+        let choice = &res.choices[0];
+        let oc = choice.message.content.clone().unwrap_or_default();
+        let translated = parse_translated(&oc);
+        match &translated.fixed {
+            None => {}
+            Some(Ok(())) => {
+                ctx.set("json_fixed", translated.content.is_ok().into())
+                    .await;
+                if let Ok(list) = &translated.content {
+                    if !is_same_shape(input, list) {
+                        ctx.set_kvs(vec![
+                            ("json_input", text.clone().into()),
+                            ("json_output", oc.clone().into()),
+                        ])
+                        .await;
+                    }
+                }
+            }
+            Some(Err(er)) => {
+                ctx.set_kvs(vec![
+                    ("json_fixed", false.into()),
+                    ("json_fix_error", er.clone().into()),
+                ])
+                .await;
+            }
+        }
+
+        let content = match translated.content {
+            Ok(content) => content,
+            Err(er) => {
+                ctx.set_kvs(vec![
+                    ("json_input", text.clone().into()),
+                    ("json_output", oc.clone().into()),
+                    ("json_error", er.clone().into()),
+                ])
+                .await;
+
+                return Err(HTTPError::new(500, er));
+            }
+        };
+
+        if content.len() != input.len() {
+            let er = format!(
+                "translated content array length not match, expected {}, got {}",
+                input.len(),
+                content.len()
+            );
+
+            ctx.set_kvs(vec![
+                ("json_input", text.into()),
+                ("json_output", oc.into()),
+                ("json_error", er.into()),
+            ])
+            .await;
+        }
+
+        Ok((usage.total_tokens, content))
+    }
+
+    // translate with the fallback model if the model is still over capacity (429)
+    // after the failover retry, return the model actually used.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn translate_or_fallback(
+        &self,
+        ctx: &ReqContext,
+        model: &AIModel,
+        fallback: Option<&AIModel>,
+        sampling: &Sampling,
+        context: &str,
+        origin_lang: &str,
+        target_lang: &str,
+        input: &Vec<Vec<String>>,
+    ) -> Result<(AIModel, u32, Vec<Vec<String>>), HTTPError> {
+        match self
+            .translate(
+                ctx,
+                model,
+                sampling,
+                context,
+                origin_lang,
+                target_lang,
+                input,
+            )
+            .await
+        {
+            Ok((tokens, content)) => Ok((model.clone(), tokens, content)),
+            Err(err) if AIError::of(&err) == AIError::RateLimited && fallback.is_some() => {
+                let fallback = fallback.unwrap();
+                ctx.set_kvs(vec![
+                    ("fallback_model", fallback.to_string().into()),
+                    ("fallback_because", err.to_string().into()),
+                ])
+                .await;
+                let (tokens, content) = self
+                    .translate(
+                        ctx,
+                        fallback,
+                        sampling,
+                        context,
+                        origin_lang,
+                        target_lang,
+                        input,
+                    )
+                    .await?;
+                Ok((fallback.clone(), tokens, content))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    // estimate the prompt tokens of translating the input without calling the model.
+    pub fn estimate_translate(
+        &self,
+        model: &AIModel,
+        context: &str,
+        origin_lang: &str,
+        target_lang: &str,
+        input: &[Vec<String>],
+    ) -> usize {
+        let system = prompts::translate_system(origin_lang, target_lang, context);
+        let text = serde_json::to_string(input)
+            .expect("OpenAI::estimate_translate serde_json::to_string error");
+        system_tokens(&model.openai_name(), &system) + model.encoding().tokens_len(&text)
+    }
+
+    // Max tokens: 4096 or 8192
+    #[allow(clippy::too_many_arguments)]
+    async fn do_translate(
+        &self,
+        ctx: &ReqContext,
+        model: &AIModel,
+        sampling: &Sampling,
+        context: &str,
+        origin_lang: &str,
+        target_lang: &str,
+        text: &str,
+    ) -> Result<CreateChatCompletionResponse, HTTPError> {
+        let model_name = model.openai_name();
+
+        let system = prompts::translate_system(origin_lang, target_lang, context);
+        let system_tokens = system_tokens(&model_name, &system) as u16;
+        let system_message = ChatCompletionRequestMessageArgs::default()
+            .role(Role::System)
+            .content(system)
+            .build()
+            .map_err(HTTPError::with_500)?;
+
+        let messages = vec![
+            system_message,
+            ChatCompletionRequestMessageArgs::default()
+                .role(Role::User)
+                .content(text)
+                .build()
+                .map_err(HTTPError::with_500)?,
+        ];
+
+        let mut req_body = CreateChatCompletionRequestArgs::default()
+            .max_tokens(model.max_tokens() as u16)
+            .model(&model_name)
+            .temperature(sampling.temperature)
+            .top_p(sampling.top_p)
+            .messages(messages)
+            .build()
+            .map_err(HTTPError::with_500)?;
+        if !ctx.user.is_zero() {
+            req_body.user = Some(ctx.user.to_string())
+        }
+
+        ctx.set_kvs(vec![
+            ("origin_lang", origin_lang.into()),
+            ("target_lang", target_lang.into()),
+            ("system_tokens", system_tokens.into()),
+            ("max_tokens", req_body.max_tokens.into()),
+            ("model", model_name.clone().into()),
+            ("temperature", sampling.temperature.into()),
+            ("top_p", sampling.top_p.into()),
+        ])
+        .await;
+
+        self.chat(ctx, &model_name, &req_body).await
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Translated {
+    content: Result<Vec<Vec<String>>, String>,
+    fixed: Option<Result<(), String>>, // the result of fixing an invalid JSON output, None if not needed
+}
+
+// parse the translated JSON output, try to fix it if it is invalid.
+fn parse_translated(output: &str) -> Translated {
+    match serde_json::from_str::<Vec<Vec<String>>>(output) {
+        Ok(content) => Translated {
+            content: Ok(content),
+            fixed: None,
+        },
+        Err(err) => match RawJSONArray::new(output).fix_me() {
+            Ok(fixed) => Translated {
+                content: serde_json::from_str::<Vec<Vec<String>>>(&fixed)
+                    .map_err(|e| e.to_string()),
+                fixed: Some(Ok(())),
+            },
+            Err(er) => Translated {
+                content: Err(err.to_string()),
+                fixed: Some(Err(er)),
+            },
+        },
+    }
+}
+
+fn is_same_shape(input: &[Vec<String>], output: &[Vec<String>]) -> bool {
+    input.len() == output.len() && input.iter().zip(output).all(|(a, b)| a.len() == b.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_translated_works() {
+        let res = parse_translated(r#"[["1","你好"],["2","世界"]]"#);
+        assert_eq!(res.fixed, None);
+        assert_eq!(
+            res.content.unwrap(),
+            vec![
+                vec!["1".to_string(), "你好".to_string()],
+                vec!["2".to_string(), "世界".to_string()],
+            ]
+        );
+
+        let res = parse_translated(
+            r#"[[],[""] Stream: ["],["Internet Engineering Task Force \(IETF)"]]"#,
+        );
+        assert_eq!(res.fixed, Some(Ok(())));
+        assert_eq!(
+            res.content.unwrap(),
+            vec![
+                vec![],
+                vec!["] Stream: [".to_string()],
+                vec!["Internet Engineering Task Force \\(IETF)".to_string()],
+            ]
+        );
+
+        let res = parse_translated("not a json");
+        assert!(res.fixed.is_some());
+        assert!(res.content.is_err());
+    }
+
+    #[test]
+    fn is_same_shape_works() {
+        let a = vec![
+            vec!["1".to_string(), "a".to_string()],
+            vec!["2".to_string()],
+        ];
+        let b = vec![
+            vec!["1".to_string(), "b".to_string()],
+            vec!["2".to_string()],
+        ];
+        assert!(is_same_shape(&a, &b));
+        assert!(!is_same_shape(&a, &b[..1]));
+        assert!(!is_same_shape(
+            &a,
+            &[vec!["1".to_string()], vec!["2".to_string()]]
+        ));
+    }
+}
@@ -1,5 +1,5 @@
 use axum::{middleware, routing, Router};
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 use tower::ServiceBuilder;
 use tower_http::{
     catch_panic::CatchPanicLayer,
@@ -10,27 +10,45 @@ use axum_web::context;
 use axum_web::encoding;
 
 use crate::api;
+use crate::auth;
 use crate::conf;
 use crate::db;
+use crate::janitor;
 use crate::lang;
+use crate::metrics;
 use crate::openai;
 
 pub async fn new(cfg: conf::Conf) -> anyhow::Result<(Arc<api::AppState>, Router)> {
+    let janitor_cfg = cfg.janitor.clone();
+    let api_keys = Arc::new(auth::ApiKeys::new(&cfg.auth));
+    if api_keys.is_disabled() {
+        log::warn!(target: "auth", action = "new"; "no API keys, the authentication is disabled");
+    }
     let app_state = Arc::new(new_app_state(cfg).await?);
+    janitor::spawn(app_state.clone(), janitor_cfg);
 
     let mds = ServiceBuilder::new()
         .layer(CatchPanicLayer::new())
+        .layer(middleware::from_fn_with_state(api_keys, auth::middleware))
         .layer(middleware::from_fn(context::middleware))
-        .layer(CompressionLayer::new().compress_when(SizeAbove::new(encoding::MIN_ENCODING_SIZE)));
+        .layer(CompressionLayer::new().compress_when(SizeAbove::new(encoding::MIN_ENCODING_SIZE)))
+        .layer(middleware::from_fn_with_state(
+            app_state.metrics.clone(),
+            metrics::middleware,
+        ));
 
     let app = Router::new()
         .route("/", routing::get(api::version))
         .route("/healthz", routing::get(api::healthz))
+        .route("/readyz", routing::get(api::readyz))
         .nest(
             "/v1/translating",
             Router::new()
                 .route("/", routing::post(api::translating::create))
                 .route("/get", routing::post(api::translating::get))
+                .route("/retry", routing::post(api::translating::retry))
+                .route("/text", routing::post(api::translating::translate_text))
+                .route("/estimate", routing::post(api::translating::estimate))
                 .route(
                     "/list_languages",
                     routing::get(api::translating::list_languages),
@@ -50,24 +68,76 @@ pub async fn new(cfg: conf::Conf) -> anyhow::Result<(Arc<api::AppState>, Router)
             "/v1/summarizing",
             Router::new()
                 .route("/", routing::post(api::summarizing::create))
-                .route("/get", routing::post(api::summarizing::get)),
+                .route("/get", routing::post(api::summarizing::get))
+                .route("/estimate", routing::post(api::summarizing::estimate))
+                .route("/keywords", routing::post(api::summarizing::keywords)),
         )
         .nest(
             "/v1/embedding",
             Router::new()
                 .route("/", routing::post(api::embedding::create))
+                .route("/get", routing::post(api::embedding::get))
+                .route("/estimate", routing::post(api::embedding::estimate))
                 .route("/search", routing::post(api::embedding::search))
-                .route("/public", routing::post(api::embedding::public)),
+                .route("/similar", routing::post(api::embedding::similar))
+                .route("/list_points", routing::post(api::embedding::list_points))
+                .route("/public", routing::post(api::embedding::public))
+                .route("/unpublic", routing::post(api::embedding::unpublic))
+                .route(
+                    "/public_status",
+                    routing::post(api::embedding::public_status),
+                )
+                .route("/delete", routing::post(api::embedding::delete))
+                .route("/forget", routing::post(api::embedding::forget))
+                .route("/reembed", routing::post(api::embedding::reembed))
+                .route("/repair", routing::post(api::embedding::repair))
+                .route(
+                    "/reembed/get",
+                    routing::post(api::embedding::get_reembedding),
+                ),
+        )
+        .nest(
+            "/v1/creations",
+            Router::new()
+                .route(
+                    "/delete_version",
+                    routing::post(api::creation::delete_version),
+                )
+                .route(
+                    "/delete_version/get",
+                    routing::post(api::creation::get_delete_version),
+                ),
+        )
+        .route("/admin/reload", routing::post(api::reload))
+        .route("/v1/tokenize", routing::post(api::tokenize::tokenize))
+        .route("/v1/tokenizer/count", routing::post(api::tokenize::count))
+        .nest(
+            "/v1/usage",
+            Router::new()
+                .route("/counter", routing::post(api::usage::get_counter))
+                .route("/report", routing::post(api::usage::report)),
         )
         .route_layer(mds)
+        // scraped by Prometheus, outside of the layers above so that it is not counted itself.
+        .route("/metrics", routing::get(api::metrics))
         .with_state(app_state.clone());
 
     Ok((app_state, app))
 }
 
 async fn new_app_state(cfg: conf::Conf) -> anyhow::Result<api::AppState> {
-    let ld = lang::LanguageDetector::new();
-    let ai = openai::OpenAI::new(cfg.ai);
+    let ld = lang::LanguageDetector::new(cfg.lang.clone())?;
+    let system_user = cfg.system_user();
+    let dedup = cfg.dedup.clone();
+    let max_tokens = cfg.max_tokens.clone();
+    let max_content = cfg.max_content.clone();
+    let deadline = cfg.deadline.clone();
+    let jobs = api::JobTracker::new(&cfg.max_jobs);
+    let normalize_text = cfg.normalize_text;
+    let reduce_strategy = cfg.summarizing.reduce_strategy;
+    let reduce_branching = cfg.summarizing.branching_factor;
+    let metrics = Arc::new(metrics::Metrics::new()?);
+    let ai = openai::OpenAI::new(cfg.ai, metrics.clone());
 
     let keyspace = if cfg.env == "test" {
         "jarvis_test"
@@ -77,13 +147,39 @@ async fn new_app_state(cfg: conf::Conf) -> anyhow::Result<api::AppState> {
     let scylla = db::scylladb::ScyllaDB::new(cfg.scylla, keyspace).await?;
     let qdrant = db::qdrant::Qdrant::new(cfg.qdrant, keyspace).await?;
     let redis = db::redis::Redis::new(cfg.redis).await?;
+    let http = new_http_client()?;
     Ok(api::AppState {
         ld: Arc::new(ld),
         ai: Arc::new(ai),
         scylla: Arc::new(scylla),
         qdrant: Arc::new(qdrant),
         redis: Arc::new(redis),
-        translating: Arc::new("translating".to_string()),
-        embedding: Arc::new("embedding".to_string()),
+        http,
+        system_user,
+        dedup,
+        max_tokens,
+        max_content,
+        deadline,
+        normalize_text,
+        reduce_strategy,
+        reduce_branching,
+        jobs: Arc::new(jobs),
+        metrics,
     })
 }
+
+// the general purpose client with the public root certificates, not the mTLS client of the AI agent.
+// connect timeout 5s, request timeout 30s, idle connections are kept for 90s.
+// it does not retry, the caller should retry the idempotent requests if needed.
+fn new_http_client() -> anyhow::Result<reqwest::Client> {
+    let client = reqwest::ClientBuilder::new()
+        .use_rustls_tls()
+        .connect_timeout(Duration::from_secs(5))
+        .timeout(Duration::from_secs(30))
+        .pool_idle_timeout(Duration::from_secs(90))
+        .pool_max_idle_per_host(8)
+        .user_agent(openai::APP_USER_AGENT)
+        .gzip(true)
+        .build()?;
+    Ok(client)
+}
@@ -1,9 +1,114 @@
-use tiktoken_rs::cl100k_base_singleton;
+use tiktoken_rs::{
+    cl100k_base_singleton, p50k_base_singleton, p50k_edit_singleton, r50k_base_singleton,
+    tokenizer::{get_tokenizer, Tokenizer},
+};
+use unicode_normalization::UnicodeNormalization;
 
+// the tiktoken encoding of a model, all the current models use cl100k_base.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    Cl100kBase,
+    P50kBase,
+    P50kEdit,
+    R50kBase,
+}
+
+impl Encoding {
+    // the encoding of an OpenAI model name, cl100k_base for the unknown models.
+    pub fn of_model(model_name: &str) -> Self {
+        match get_tokenizer(model_name) {
+            Some(Tokenizer::P50kBase) => Encoding::P50kBase,
+            Some(Tokenizer::P50kEdit) => Encoding::P50kEdit,
+            Some(Tokenizer::R50kBase) | Some(Tokenizer::Gpt2) => Encoding::R50kBase,
+            _ => Encoding::Cl100kBase,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Cl100kBase => "cl100k_base",
+            Encoding::P50kBase => "p50k_base",
+            Encoding::P50kEdit => "p50k_edit",
+            Encoding::R50kBase => "r50k_base",
+        }
+    }
+
+    pub fn encode(&self, s: &str) -> Vec<usize> {
+        match self {
+            Encoding::Cl100kBase => cl100k_base_singleton().lock().encode_with_special_tokens(s),
+            Encoding::P50kBase => p50k_base_singleton().lock().encode_with_special_tokens(s),
+            Encoding::P50kEdit => p50k_edit_singleton().lock().encode_with_special_tokens(s),
+            Encoding::R50kBase => r50k_base_singleton().lock().encode_with_special_tokens(s),
+        }
+    }
+
+    pub fn tokens_len(&self, s: &str) -> usize {
+        self.encode(s).len()
+    }
+
+    // the counting function of the encoding, for the TESegmenter.
+    pub fn tokens_len_fn(&self) -> fn(&str) -> usize {
+        match self {
+            Encoding::Cl100kBase => tokens_len,
+            Encoding::P50kBase => p50k_base_tokens_len,
+            Encoding::P50kEdit => p50k_edit_tokens_len,
+            Encoding::R50kBase => r50k_base_tokens_len,
+        }
+    }
+}
+
+// the tokens of the default encoding, cl100k_base.
 pub fn tokens_len(s: &str) -> usize {
+    Encoding::Cl100kBase.tokens_len(s)
+}
+
+fn p50k_base_tokens_len(s: &str) -> usize {
+    Encoding::P50kBase.tokens_len(s)
+}
+
+fn p50k_edit_tokens_len(s: &str) -> usize {
+    Encoding::P50kEdit.tokens_len(s)
+}
+
+fn r50k_base_tokens_len(s: &str) -> usize {
+    Encoding::R50kBase.tokens_len(s)
+}
+
+// truncate the string to at most `max` tokens, return the original string if not exceeded.
+pub fn truncate_to_tokens(s: &str, max: usize) -> String {
     let bpe = cl100k_base_singleton();
-    let tokens = bpe.lock().encode_with_special_tokens(s);
-    tokens.len()
+    let bpe = bpe.lock();
+    let tokens = bpe.encode_with_special_tokens(s);
+    if tokens.len() <= max {
+        return s.to_string();
+    }
+
+    // a multi-byte character may be split across tokens, back off until it can be decoded.
+    let mut end = max;
+    while end > 0 {
+        if let Ok(res) = bpe.decode(tokens[..end].to_vec()) {
+            return res;
+        }
+        end -= 1;
+    }
+    String::new()
+}
+
+// normalize the pasted text before tokenizing: NFC, strip the invisible characters
+// (zero-width spaces, BOMs, and controls other than tabs and newlines),
+// and replace the no-break spaces with spaces.
+// the joiners (U+200C, U+200D) are kept, they are meaningful in some scripts and emojis.
+pub fn normalize_text(s: &str) -> String {
+    s.nfc()
+        .filter_map(|c| match c {
+            '\u{200B}' | '\u{2060}' | '\u{FEFF}' | '\u{00AD}' => None,
+            '\t' | '\n' | '\r' => Some(c),
+            '\u{00A0}' => Some(' '),
+            c if c.is_control() => None,
+            c => Some(c),
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -21,4 +126,84 @@ mod tests {
         println!("summarization tokens_len: {}", tokens_len("在全球化浪潮下，创作多语言知识文章和技术文档变得至关重要。大模型AI能力的涌现可以帮助我们应对语言转换和文化差异的挑战。本指南以比特币白皮书为例，详细指导如何利用Yiwen AI平台上的ChatGPT大模型，通过一键智能翻译功能将文章翻译成多种语言并发布，让作品拥有全球影响力。指南内容包括根据用户语言偏好自动切换界面和内容语言，创作内容丰富和专业的知识文章，翻译成多语言版本并公开发布，分享知识获得收益，读者也能参与翻译，以及未来功能规划。"));
         // 241
     }
+
+    #[test]
+    fn encoding_works() {
+        assert_eq!(Encoding::of_model("gpt-3.5-turbo"), Encoding::Cl100kBase);
+        assert_eq!(Encoding::of_model("gpt-4"), Encoding::Cl100kBase);
+        assert_eq!(
+            Encoding::of_model("text-embedding-ada-002"),
+            Encoding::Cl100kBase
+        );
+        assert_eq!(Encoding::of_model("text-davinci-003"), Encoding::P50kBase);
+        assert_eq!(
+            Encoding::of_model("text-davinci-edit-001"),
+            Encoding::P50kEdit
+        );
+        assert_eq!(Encoding::of_model("davinci"), Encoding::R50kBase);
+        assert_eq!(Encoding::of_model("unknown-model"), Encoding::Cl100kBase);
+
+        let all = [
+            Encoding::Cl100kBase,
+            Encoding::P50kBase,
+            Encoding::P50kEdit,
+            Encoding::R50kBase,
+        ];
+        for e in all {
+            assert_eq!(e.tokens_len("Hello world"), 2, "{}", e.as_str());
+            assert_eq!(e.tokens_len_fn()("Hello world"), 2, "{}", e.as_str());
+        }
+        assert_eq!(tokens_len("Hello world"), 2);
+
+        // r50k_base has no tokens for the runs of spaces, p50k_base and cl100k_base have.
+        let code = "def f():\n                return 1\n";
+        let r50k = Encoding::R50kBase.tokens_len(code);
+        assert!(r50k > Encoding::P50kBase.tokens_len(code));
+        assert!(r50k > Encoding::Cl100kBase.tokens_len(code));
+        assert_eq!(Encoding::Cl100kBase.tokens_len(code), tokens_len(code));
+
+        // the CJK text is counted quite differently.
+        let s = "在全球化浪潮下，创作多语言知识文章和技术文档变得至关重要。";
+        assert!(Encoding::R50kBase.tokens_len(s) > Encoding::Cl100kBase.tokens_len(s));
+    }
+
+    #[test]
+    fn encode_works() {
+        let s = "Hello world, this is a test.";
+        let ids = Encoding::Cl100kBase.encode(s);
+        assert_eq!(ids.len(), tokens_len(s));
+        assert_eq!(cl100k_base_singleton().lock().decode(ids).unwrap(), s);
+        assert!(Encoding::Cl100kBase.encode("").is_empty());
+    }
+
+    #[test]
+    fn normalize_text_works() {
+        let s = "\u{FEFF}Hello\u{200B} world\u{00A0}!\u{0007}\n\tBye";
+        assert_eq!(normalize_text(s), "Hello world !\n\tBye");
+        assert!(tokens_len(&normalize_text(s)) < tokens_len(s));
+
+        // NFC
+        assert_eq!(normalize_text("Cafe\u{0301}"), "Caf\u{00E9}");
+        // the joiners are kept
+        let s = "\u{0645}\u{06CC}\u{200C}\u{062E}\u{0648}\u{0627}\u{0647}\u{0645} \u{1F468}\u{200D}\u{1F469}";
+        assert_eq!(normalize_text(s), s);
+    }
+
+    #[test]
+    fn truncate_to_tokens_works() {
+        let s = "Hello world, this is a test.";
+        assert_eq!(truncate_to_tokens(s, 100), s);
+        assert_eq!(truncate_to_tokens(s, 0), "");
+
+        let res = truncate_to_tokens(s, 2);
+        assert_eq!(res, "Hello world");
+        assert_eq!(tokens_len(&res), 2);
+
+        let s = "在全球化浪潮下，创作多语言知识文章和技术文档变得至关重要。";
+        for max in 1..tokens_len(s) {
+            let res = truncate_to_tokens(s, max);
+            assert!(s.starts_with(&res));
+            assert!(tokens_len(&res) <= max);
+        }
+    }
 }
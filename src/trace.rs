@@ -0,0 +1,128 @@
+use std::future::Future;
+use tokio::task::JoinHandle;
+
+use crate::conf;
+
+#[cfg(feature = "tracing")]
+use opentelemetry::{
+    global,
+    sdk::{trace as sdktrace, Resource},
+    trace::{FutureExt, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+#[cfg(feature = "tracing")]
+use opentelemetry_otlp::WithExportConfig;
+
+#[cfg(feature = "tracing")]
+const TRACER_NAME: &str = "jarvis";
+
+// install the OTLP exporter of the spans, the spans are dropped if the endpoint is empty.
+#[cfg(feature = "tracing")]
+pub fn init(cfg: &conf::Tracing) -> anyhow::Result<()> {
+    if cfg.otlp_endpoint.is_empty() {
+        return Ok(());
+    }
+
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(&cfg.otlp_endpoint),
+        )
+        .with_trace_config(
+            sdktrace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                cfg.service_name.clone(),
+            )])),
+        )
+        .install_batch(opentelemetry::runtime::Tokio)?;
+    log::info!(target: "trace",
+        action = "init",
+        endpoint = cfg.otlp_endpoint.as_str();
+        "",
+    );
+    Ok(())
+}
+
+// the spans are not exported without the `tracing` feature, even if the endpoint is set.
+#[cfg(not(feature = "tracing"))]
+pub fn init(cfg: &conf::Tracing) -> anyhow::Result<()> {
+    if !cfg.otlp_endpoint.is_empty() {
+        log::warn!(target: "trace",
+            action = "init",
+            endpoint = cfg.otlp_endpoint.as_str(),
+            service = cfg.service_name.as_str();
+            "built without the tracing feature, the spans are not exported",
+        );
+    }
+    Ok(())
+}
+
+// flush the pending spans before exiting.
+pub fn shutdown() {
+    #[cfg(feature = "tracing")]
+    global::shutdown_tracer_provider();
+}
+
+// run the future in a new span, a child of the current span if any.
+#[cfg(feature = "tracing")]
+pub async fn in_span<F: Future>(
+    name: &'static str,
+    attrs: Vec<(&'static str, String)>,
+    fut: F,
+) -> F::Output {
+    let tracer = global::tracer(TRACER_NAME);
+    let span = tracer
+        .span_builder(name)
+        .with_attributes(
+            attrs
+                .into_iter()
+                .map(|(k, v)| KeyValue::new(k, v))
+                .collect::<Vec<_>>(),
+        )
+        .start(&tracer);
+    let cx = Context::current_with_span(span);
+    let res = fut.with_context(cx.clone()).await;
+    cx.span().end();
+    res
+}
+
+#[cfg(not(feature = "tracing"))]
+pub async fn in_span<F: Future>(
+    _name: &'static str,
+    _attrs: Vec<(&'static str, String)>,
+    fut: F,
+) -> F::Output {
+    fut.await
+}
+
+// set the attribute of the current span, e.g. the tokens used when the job finished.
+#[cfg(feature = "tracing")]
+pub fn set_attribute(key: &'static str, value: i64) {
+    Context::current()
+        .span()
+        .set_attribute(KeyValue::new(key, value));
+}
+
+#[cfg(not(feature = "tracing"))]
+pub fn set_attribute(_key: &'static str, _value: i64) {}
+
+// spawn the task in the current span, so that the spans in the task are its children.
+#[cfg(feature = "tracing")]
+pub fn spawn<F>(fut: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(fut.with_context(Context::current()))
+}
+
+#[cfg(not(feature = "tracing"))]
+pub fn spawn<F>(fut: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(fut)
+}